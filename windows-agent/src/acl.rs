@@ -0,0 +1,162 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::ptr;
+
+use anyhow::{anyhow, Context, Result};
+use windows::core::{HSTRING, PWSTR};
+use windows::Win32::Foundation::{CloseHandle, LocalFree, BOOL, HANDLE, HLOCAL, PSID};
+use windows::Win32::Security::Authorization::{
+    ConvertSecurityDescriptorToStringSecurityDescriptorW, ConvertSidToStringSidW,
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, GetNamedSecurityInfoW,
+    SetNamedSecurityInfoW, SDDL_REVISION_1, SE_FILE_OBJECT,
+};
+use windows::Win32::Security::{
+    GetSecurityDescriptorDacl, GetTokenInformation, TokenUser, ACL, DACL_SECURITY_INFORMATION,
+    PROTECTED_DACL_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR, TOKEN_QUERY, TOKEN_USER,
+};
+use windows::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+const SYSTEM_SID: &str = "S-1-5-18";
+
+/// Limits `path` to full control for the current user and SYSTEM, dropping
+/// inherited entries. Falls back to `icacls` if the security API fails.
+pub fn restrict_to_current_user(path: &Path) -> Result<()> {
+    let sid = current_user_sid()?;
+    if let Err(err) = apply_dacl(path, &sid) {
+        log::warn!(
+            "failed to set permissions on {}: {err:?}; trying icacls",
+            path.display()
+        );
+        return icacls(path, &sid);
+    }
+    Ok(())
+}
+
+/// Re-applies the restricted DACL when the file's current one differs.
+/// Missing files are left alone.
+pub fn verify_restricted(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let sid = current_user_sid()?;
+    if current_dacl(path)? == expected_dacl(&sid) {
+        return Ok(());
+    }
+    log::warn!("repairing permissions on {}", path.display());
+    restrict_to_current_user(path)
+}
+
+/// SDDL in the form Windows prints it back, so it can be compared directly.
+fn expected_dacl(user_sid: &str) -> String {
+    format!("D:P(A;;FA;;;SY)(A;;FA;;;{user_sid})")
+}
+
+fn apply_dacl(path: &Path, user_sid: &str) -> Result<()> {
+    let sddl = HSTRING::from(expected_dacl(user_sid));
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+    unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            &sddl,
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )
+        .context("ConvertStringSecurityDescriptorToSecurityDescriptorW failed")?;
+        let mut present = BOOL(0);
+        let mut defaulted = BOOL(0);
+        let mut dacl: *mut ACL = ptr::null_mut();
+        let result = GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted)
+            .context("GetSecurityDescriptorDacl failed")
+            .and_then(|()| {
+                SetNamedSecurityInfoW(
+                    &HSTRING::from(path.to_string_lossy().as_ref()),
+                    SE_FILE_OBJECT,
+                    DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+                    PSID::default(),
+                    PSID::default(),
+                    Some(dacl),
+                    None,
+                )
+                .ok()
+                .context("SetNamedSecurityInfoW failed")
+            });
+        let _ = LocalFree(HLOCAL(descriptor.0));
+        result
+    }
+}
+
+fn current_dacl(path: &Path) -> Result<String> {
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+    unsafe {
+        GetNamedSecurityInfoW(
+            &HSTRING::from(path.to_string_lossy().as_ref()),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            None,
+            None,
+            None,
+            None,
+            &mut descriptor,
+        )
+        .ok()
+        .context("GetNamedSecurityInfoW failed")?;
+        let mut sddl = PWSTR::null();
+        let result = ConvertSecurityDescriptorToStringSecurityDescriptorW(
+            descriptor,
+            SDDL_REVISION_1,
+            DACL_SECURITY_INFORMATION,
+            &mut sddl,
+            None,
+        );
+        let _ = LocalFree(HLOCAL(descriptor.0));
+        result.context("ConvertSecurityDescriptorToStringSecurityDescriptorW failed")?;
+        take_pwstr(sddl)
+    }
+}
+
+fn current_user_sid() -> Result<String> {
+    unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)
+            .context("OpenProcessToken failed")?;
+        let mut len = 0u32;
+        let _ = GetTokenInformation(token, TokenUser, None, 0, &mut len);
+        // u64 backing keeps the TOKEN_USER pointer-aligned.
+        let mut buffer = vec![0u64; (len as usize).div_ceil(8)];
+        let result = GetTokenInformation(
+            token,
+            TokenUser,
+            Some(buffer.as_mut_ptr().cast()),
+            len,
+            &mut len,
+        );
+        let _ = CloseHandle(token);
+        result.context("GetTokenInformation failed")?;
+        let user = &*(buffer.as_ptr() as *const TOKEN_USER);
+        let mut sid = PWSTR::null();
+        ConvertSidToStringSidW(user.User.Sid, &mut sid).context("ConvertSidToStringSidW failed")?;
+        take_pwstr(sid)
+    }
+}
+
+unsafe fn take_pwstr(value: PWSTR) -> Result<String> {
+    let text = value.to_string();
+    let _ = LocalFree(HLOCAL(value.0.cast()));
+    text.context("security string is not valid UTF-16")
+}
+
+fn icacls(path: &Path, user_sid: &str) -> Result<()> {
+    let status = Command::new("icacls")
+        .arg(path)
+        .args(["/inheritance:r", "/grant:r"])
+        .arg(format!("*{SYSTEM_SID}:F"))
+        .arg(format!("*{user_sid}:F"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("run icacls")?;
+    if !status.success() {
+        return Err(anyhow!("icacls failed for {}", path.display()));
+    }
+    Ok(())
+}
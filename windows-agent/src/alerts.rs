@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+
+use crate::config::UsageConfigStore;
+use crate::models::{UploadFailureReason, UploadResult};
+
+const UNREACHABLE_MESSAGE: &str =
+    "NuScape can't reach the server — check your internet or re-link the device.";
+const RELINK_MESSAGE: &str =
+    "NuScape is no longer signed in — open NuScape and re-register this device.";
+
+#[derive(Default)]
+struct AlertState {
+    reason: Option<UploadFailureReason>,
+    consecutive: u32,
+    last_alert: Option<DateTime<Utc>>,
+}
+
+/// Decides when a run of identical upload failures deserves a user-facing
+/// notification.
+pub struct FailureAlerts {
+    config_store: Arc<UsageConfigStore>,
+    state: Mutex<AlertState>,
+}
+
+impl FailureAlerts {
+    pub fn new(config_store: Arc<UsageConfigStore>) -> Self {
+        Self {
+            config_store,
+            state: Mutex::new(AlertState::default()),
+        }
+    }
+
+    /// Records one upload cycle and returns the message to show, if any.
+    pub fn observe(&self, result: &UploadResult, now: DateTime<Utc>) -> Option<&'static str> {
+        let mut state = self.state.lock();
+        let reason = match result.failure_reason {
+            // Rate limiting means the server is healthy; it clears on its own.
+            Some(UploadFailureReason::RateLimited) | None => {
+                state.reason = None;
+                state.consecutive = 0;
+                return None;
+            }
+            Some(reason) => reason,
+        };
+        if state.reason.map(|r| r.needs_relink()) == Some(reason.needs_relink()) {
+            state.consecutive = state.consecutive.saturating_add(1);
+        } else {
+            state.reason = Some(reason);
+            state.consecutive = 1;
+        }
+
+        if self.config_store.notifications_silenced()
+            || state.consecutive < self.config_store.failure_alert_cycles()
+        {
+            return None;
+        }
+        let interval = chrono::Duration::from_std(self.config_store.failure_alert_interval())
+            .unwrap_or_else(|_| chrono::Duration::hours(24));
+        if state.last_alert.is_some_and(|last| now - last < interval) {
+            return None;
+        }
+        state.last_alert = Some(now);
+        Some(if reason.needs_relink() {
+            RELINK_MESSAGE
+        } else {
+            UNREACHABLE_MESSAGE
+        })
+    }
+}
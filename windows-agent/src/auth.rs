@@ -1,17 +1,57 @@
-use std::fs;
-use std::path::PathBuf;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD};
+use base64::Engine;
 use chrono::{DateTime, Duration, Utc};
 use log;
 use parking_lot::Mutex;
-use reqwest;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+use tokio::sync::Mutex as AsyncMutex;
 use uuid::Uuid;
 
+use crate::clock::ClockSkew;
+use crate::collectors::status;
 use crate::config::{DeviceIdStore, UsageConfigStore};
-use crate::storage::StoragePaths;
+use crate::credentials::CredentialBackend;
+use crate::dpapi::{self, DpapiScope};
+use crate::http;
+use crate::models::{AuthAuditEntry, AuthEvent, AuthMode, RegistrationOutcome, UploadConfig};
+use crate::signing;
+use crate::storage::{self, AuthAuditLog, Schema};
+
+const PAIRING_CODE_LENGTH: usize = 6;
+const DEFAULT_TOKEN_LIFETIME_SECONDS: i64 = 86_400;
+
+/// The backend refused registration with a status that retrying won't fix.
+#[derive(Debug)]
+pub struct RegistrationRejected {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl fmt::Display for RegistrationRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "device registration rejected: {} {}",
+            self.status, self.body
+        )
+    }
+}
+
+impl std::error::Error for RegistrationRejected {}
+
+/// Layout of the stored token record.
+const TOKENS_SCHEMA: Schema = Schema {
+    name: "tokens",
+    migrations: &[storage::unversioned],
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TokenRecord {
@@ -19,26 +59,64 @@ struct TokenRecord {
     refresh_token: String,
     issued_at: DateTime<Utc>,
     expires_in_seconds: i64,
+    /// Absolute expiry, from the JWT `exp` claim when present. Records
+    /// written by older agents only have `issued_at + expires_in_seconds`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// Request-signing secret, base64-encoded and DPAPI-protected on its own
+    /// so it stays unreadable wherever the record is copied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signing_secret: Option<String>,
 }
 
+/// Tokens cached in memory and persisted through a [`CredentialBackend`].
 pub struct TokenStore {
-    path: PathBuf,
+    backend: Box<dyn CredentialBackend>,
+    scope: DpapiScope,
     cache: Mutex<Option<TokenRecord>>,
+    generation: AtomicU64,
+    audit: Arc<AuthAuditLog>,
 }
 
 impl TokenStore {
-    pub fn new(paths: &StoragePaths) -> Result<Self> {
-        let path = paths.tokens_path();
-        let cache = if path.exists() {
-            let data = fs::read_to_string(&path)?;
-            serde_json::from_str(&data).ok()
-        } else {
+    /// Anything the backend cannot return or parse is treated as "no tokens"
+    /// so the agent re-registers instead of failing to start.
+    pub fn new(
+        backend: Box<dyn CredentialBackend>,
+        scope: DpapiScope,
+        audit: Arc<AuthAuditLog>,
+    ) -> Self {
+        let stored = backend.read().and_then(|data| match data {
+            Some(data) => TOKENS_SCHEMA.decode::<Option<TokenRecord>>(&data),
+            None => Ok(None),
+        });
+        let cache = stored.unwrap_or_else(|err| {
+            match backend.quarantine() {
+                Ok(Some(moved)) => log::error!(
+                    "stored tokens are unreadable, moved them to {}: {err:?}",
+                    moved.display()
+                ),
+                Ok(None) => log::error!("stored tokens are unreadable, discarding: {err:?}"),
+                Err(quarantine_err) => log::error!(
+                    "stored tokens are unreadable ({err:?}) and could not be moved aside: \
+                     {quarantine_err:?}"
+                ),
+            }
             None
-        };
-        Ok(Self {
-            path,
+        });
+        Self {
+            backend,
+            scope,
             cache: Mutex::new(cache),
-        })
+            generation: AtomicU64::new(0),
+            audit,
+        }
+    }
+
+    fn persist_locked(&self, record: &Option<TokenRecord>) -> Result<()> {
+        // Compact, to stay well under the Credential Manager's blob limit.
+        self.backend
+            .write(&serde_json::to_vec(&TOKENS_SCHEMA.envelope(record))?)
     }
 
     fn load(&self) -> Option<TokenRecord> {
@@ -53,34 +131,79 @@ impl TokenStore {
         self.load().map(|t| t.refresh_token)
     }
 
-    pub fn is_access_token_expired(&self, now: DateTime<Utc>) -> bool {
+    /// True once the access token is within `margin` of its expiry.
+    pub fn is_access_token_expired(&self, now: DateTime<Utc>, margin: StdDuration) -> bool {
+        let margin = Duration::from_std(margin).unwrap_or(Duration::zero());
         self.load()
             .map(|t| {
-                let expiry =
-                    t.issued_at + Duration::seconds(t.expires_in_seconds) - Duration::seconds(120);
-                expiry <= now
+                let expiry = t
+                    .expires_at
+                    .unwrap_or_else(|| t.issued_at + Duration::seconds(t.expires_in_seconds));
+                expiry - margin <= now
             })
             .unwrap_or(false)
     }
 
+    /// Stores a token pair. The access token's `exp` claim wins over
+    /// `expires_in`; opaque tokens without either get a one-day lifetime.
     pub fn save_tokens(
         &self,
         access_token: String,
         refresh_token: String,
-        expires_in_seconds: i64,
+        expires_in_seconds: Option<i64>,
         issued_at: DateTime<Utc>,
     ) -> Result<()> {
-        let record = TokenRecord {
+        let expires_in_seconds = expires_in_seconds.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECONDS);
+        let expires_at = jwt_expiry(&access_token)
+            .unwrap_or_else(|| issued_at + Duration::seconds(expires_in_seconds));
+        let mut guard = self.cache.lock();
+        let signing_secret = guard.as_ref().and_then(|t| t.signing_secret.clone());
+        *guard = Some(TokenRecord {
             access_token,
             refresh_token,
             issued_at,
             expires_in_seconds,
-        };
+            expires_at: Some(expires_at),
+            signing_secret,
+        });
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.persist_locked(&guard)
+    }
+
+    pub fn audit(&self) -> &AuthAuditLog {
+        &self.audit
+    }
+
+    /// Bumped whenever the tokens are replaced or cleared.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Stores the per-device signing secret, itself DPAPI-protected.
+    pub fn save_signing_secret(&self, secret: &[u8]) -> Result<()> {
+        let protected = BASE64.encode(dpapi::protect(secret, self.scope)?);
         let mut guard = self.cache.lock();
-        *guard = Some(record);
-        let serialized = serde_json::to_string_pretty(&*guard)?;
-        fs::write(&self.path, serialized)?;
-        Ok(())
+        let record = guard
+            .as_mut()
+            .ok_or_else(|| anyhow!("cannot store a signing secret without tokens"))?;
+        record.signing_secret = Some(protected);
+        self.persist_locked(&guard)
+    }
+
+    /// Decrypted signing secret, if the backend issued one.
+    pub fn signing_secret(&self) -> Option<Vec<u8>> {
+        let protected = self.load()?.signing_secret?;
+        let decrypted = BASE64
+            .decode(protected)
+            .map_err(anyhow::Error::from)
+            .and_then(|blob| dpapi::unprotect(&blob));
+        match decrypted {
+            Ok(secret) => Some(secret),
+            Err(err) => {
+                log::warn!("stored signing secret is unreadable: {err:?}");
+                None
+            }
+        }
     }
 
     pub fn has_tokens(&self) -> bool {
@@ -91,10 +214,11 @@ impl TokenStore {
         {
             let mut guard = self.cache.lock();
             *guard = None;
+            self.generation.fetch_add(1, Ordering::SeqCst);
         }
-        if self.path.exists() {
-            let _ = fs::remove_file(&self.path);
-        }
+        self.audit
+            .record(AuthAuditEntry::new(AuthEvent::TokensCleared, true));
+        self.backend.delete()?;
         Ok(())
     }
 
@@ -106,6 +230,160 @@ impl TokenStore {
         }
     }
 }
+
+/// Serializes token refreshes for every caller. Refresh tokens rotate, so two
+/// concurrent refreshes would invalidate each other; a caller that lost the
+/// race sees the token generation move and reuses the new token instead.
+pub struct TokenRefresher {
+    client: Client,
+    config_store: Arc<UsageConfigStore>,
+    token_store: Arc<TokenStore>,
+    clock: Arc<ClockSkew>,
+    lock: AsyncMutex<()>,
+}
+
+impl TokenRefresher {
+    pub fn new(
+        config_store: Arc<UsageConfigStore>,
+        token_store: Arc<TokenStore>,
+        clock: Arc<ClockSkew>,
+    ) -> Result<Self> {
+        let client = http::client_builder(&config_store)?.build()?;
+        Ok(Self {
+            client,
+            config_store,
+            token_store,
+            clock,
+            lock: AsyncMutex::new(()),
+        })
+    }
+
+    /// Refreshes the tokens unless they changed since `seen`, the
+    /// [`TokenStore::generation`] read before the stale token was used.
+    /// Returns whether a usable access token is now stored.
+    pub async fn refresh(&self, config: &UploadConfig, seen: u64) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        if self.token_store.generation() != seen {
+            return Ok(self.token_store.access_token().is_some()
+                && !self.token_store.is_access_token_expired(
+                    self.clock.server_now(),
+                    self.config_store.token_expiry_margin(),
+                ));
+        }
+        let refresh = match self.token_store.refresh_token() {
+            Some(token) => token,
+            None => return Ok(false),
+        };
+        let refresh_url = config
+            .base_url
+            .clone()
+            .join("api/v1/devices/refresh")
+            .context("refresh url")?;
+        let builder = self
+            .client
+            .post(refresh_url)
+            .headers(self.config_store.custom_header_map())
+            .bearer_auth(&refresh)
+            .header("Content-Type", "application/json");
+        let request = signing::sign(
+            builder,
+            self.token_store.signing_secret().as_deref(),
+            self.clock.server_now(),
+            b"{}",
+        )
+        .body("{}")
+        .build()?;
+        let audit = self.token_store.audit();
+        let response = match self.client.execute(request).await {
+            Ok(response) => response,
+            Err(err) => {
+                audit.record(
+                    AuthAuditEntry::new(AuthEvent::Refresh, false).with_detail(err.to_string()),
+                );
+                return Err(err.into());
+            }
+        };
+        self.clock.observe(response.headers());
+        audit.record(
+            AuthAuditEntry::new(AuthEvent::Refresh, response.status().is_success())
+                .with_status(response.status().as_u16()),
+        );
+        if !response.status().is_success() {
+            log::warn!("refresh failed: {}", response.status());
+            if response.status() == StatusCode::UNAUTHORIZED {
+                let _ = self.token_store.clear();
+            }
+            return Ok(false);
+        }
+        let body = response.text().await.unwrap_or_default();
+        let json: Value = serde_json::from_str(&body)?;
+        let access = json
+            .get("access_token")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("access_token missing"))?;
+        let refresh_token = json
+            .get("refresh_token")
+            .and_then(|v| v.as_str())
+            .unwrap_or(&refresh)
+            .to_string();
+        let expires = json.get("expires_in").and_then(|v| v.as_i64());
+        self.token_store.save_tokens(
+            access.to_string(),
+            refresh_token,
+            expires,
+            self.clock.server_now(),
+        )?;
+        Ok(true)
+    }
+}
+
+/// Asks the backend to unlink this device so it disappears from the dashboard.
+pub async fn unregister(
+    config_store: &UsageConfigStore,
+    token_store: &TokenStore,
+    clock: &ClockSkew,
+) -> Result<()> {
+    let upload_cfg = config_store.resolve_upload_config()?;
+    let unregister_url = upload_cfg.base_url.join("api/v1/devices/unregister")?;
+    let client = http::client_builder(config_store)?.build()?;
+    let builder = client
+        .post(unregister_url)
+        .headers(config_store.custom_header_map())
+        .header("Content-Type", "application/json");
+    let builder = match config_store.auth_mode() {
+        AuthMode::DeviceToken => {
+            builder.bearer_auth(token_store.access_token().context("no access token")?)
+        }
+        AuthMode::ApiKey => {
+            builder.header("X-Api-Key", config_store.api_key().context("no api key")?)
+        }
+    };
+    let request = signing::sign(
+        builder,
+        token_store.signing_secret().as_deref(),
+        clock.server_now(),
+        b"{}",
+    )
+    .body("{}")
+    .build()?;
+    let response = client.execute(request).await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        return Err(anyhow!("device unregister failed: {status} {text}"));
+    }
+    Ok(())
+}
+
+/// Reads the `exp` claim from a JWT payload without verifying the signature;
+/// the backend verifies it, we only need to know when to refresh.
+fn jwt_expiry(token: &str) -> Option<DateTime<Utc>> {
+    let payload = token.split('.').nth(1)?;
+    let decoded = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: Value = serde_json::from_slice(&decoded).ok()?;
+    DateTime::from_timestamp(claims.get("exp")?.as_i64()?, 0)
+}
+
 #[derive(Debug, Deserialize)]
 struct RegisterResponsePayload {
     device_id: String,
@@ -113,17 +391,49 @@ struct RegisterResponsePayload {
     refresh_token: String,
     #[serde(default)]
     expires_in: Option<i64>,
+    #[serde(default)]
+    signing_secret: Option<String>,
 }
 
 pub async fn ensure_registered(
     config_store: &UsageConfigStore,
     token_store: &TokenStore,
     device_store: &DeviceIdStore,
+    clock: &ClockSkew,
 ) -> Result<()> {
-    if token_store.has_tokens() {
+    if config_store.auth_mode() == AuthMode::ApiKey || token_store.has_tokens() {
         return Ok(());
     }
+    match register(config_store, token_store, device_store, clock, None).await? {
+        RegistrationOutcome::Registered => Ok(()),
+        outcome => Err(anyhow!("device registration rejected: {outcome:?}")),
+    }
+}
 
+/// Links this device to the parent account that issued `code`, replacing any
+/// existing tokens. Expired and already-used codes are returned as outcomes
+/// rather than errors so the UI can prompt for a new code.
+pub async fn pair_device(
+    config_store: &UsageConfigStore,
+    token_store: &TokenStore,
+    device_store: &DeviceIdStore,
+    clock: &ClockSkew,
+    code: &str,
+) -> Result<RegistrationOutcome> {
+    let code = code.trim();
+    if code.len() != PAIRING_CODE_LENGTH || !code.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("pairing code must be {PAIRING_CODE_LENGTH} digits"));
+    }
+    register(config_store, token_store, device_store, clock, Some(code)).await
+}
+
+async fn register(
+    config_store: &UsageConfigStore,
+    token_store: &TokenStore,
+    device_store: &DeviceIdStore,
+    clock: &ClockSkew,
+    pairing_code: Option<&str>,
+) -> Result<RegistrationOutcome> {
     let upload_cfg = config_store.resolve_upload_config()?;
     let register_url = upload_cfg.base_url.join("api/v1/devices/register")?;
 
@@ -134,39 +444,211 @@ pub async fn ensure_registered(
         "hostname": computer_name,
         "username": user_name,
         "os": std::env::consts::OS,
-        "arch": std::env::consts::ARCH
+        "os_version": status::os_version(),
+        "os_edition": status::os_edition(),
+        "arch": std::env::consts::ARCH,
+        "agent_version": http::AGENT_VERSION
     });
 
-    let body = json!({
+    let mut body = json!({
         "platform": "windows",
         "name": computer_name,
         "hardware": hardware
     });
+    // Sending the known id lets the backend re-link an unlinked device
+    // instead of creating a duplicate.
+    if let Some(device_id) = device_store.get() {
+        body["device_id"] = json!(device_id);
+    }
+    if let Some(code) = pairing_code {
+        body["pairing_code"] = json!(code);
+    }
 
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()?;
+    let client = http::client_builder(config_store)?.build()?;
 
     log::info!("registering device at {}", register_url);
-    let response = client.post(register_url).json(&body).send().await?;
+    let audit = |entry: AuthAuditEntry| match pairing_code {
+        Some(_) => token_store
+            .audit()
+            .record(entry.with_detail("pairing code")),
+        None => token_store.audit().record(entry),
+    };
+    let response = match client
+        .post(register_url)
+        .headers(config_store.custom_header_map())
+        .json(&body)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            audit(AuthAuditEntry::new(AuthEvent::Registration, false));
+            return Err(err.into());
+        }
+    };
+    clock.observe(response.headers());
+    audit(
+        AuthAuditEntry::new(AuthEvent::Registration, response.status().is_success())
+            .with_status(response.status().as_u16()),
+    );
+    match response.status() {
+        StatusCode::GONE => return Ok(RegistrationOutcome::CodeExpired),
+        StatusCode::CONFLICT => return Ok(RegistrationOutcome::AlreadyLinked),
+        _ => {}
+    }
     if !response.status().is_success() {
         let status = response.status();
         let text = response.text().await.unwrap_or_default();
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            return Err(RegistrationRejected { status, body: text }.into());
+        }
         return Err(anyhow!("device registration failed: {status} {text}"));
     }
 
     let payload: RegisterResponsePayload = response.json().await?;
-    let expires = payload.expires_in.unwrap_or(86_400) as i64;
     token_store.save_tokens(
         payload.access_token.clone(),
         payload.refresh_token.clone(),
-        expires,
-        Utc::now(),
+        payload.expires_in,
+        clock.server_now(),
     )?;
+    if let Some(secret) = payload.signing_secret.as_deref() {
+        token_store.save_signing_secret(secret.as_bytes())?;
+    }
 
     if let Ok(device_id) = Uuid::parse_str(&payload.device_id) {
         device_store.save(device_id)?;
     }
 
-    Ok(())
+    Ok(RegistrationOutcome::Registered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::credentials::{self, CredentialBackendKind};
+    use crate::storage::StoragePaths;
+
+    /// Keeps the token record in memory.
+    #[derive(Default)]
+    struct MemoryBackend(Mutex<Option<Vec<u8>>>);
+
+    impl CredentialBackend for MemoryBackend {
+        fn read(&self) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().clone())
+        }
+        fn write(&self, data: &[u8]) -> Result<()> {
+            *self.0.lock() = Some(data.to_vec());
+            Ok(())
+        }
+        fn delete(&self) -> Result<()> {
+            *self.0.lock() = None;
+            Ok(())
+        }
+        fn quarantine(&self) -> Result<Option<PathBuf>> {
+            Ok(None)
+        }
+    }
+
+    /// Answers the first refresh with a rotated token pair and every later
+    /// one with a 500, counting the requests it sees.
+    async fn refresh_server(requests: Arc<AtomicUsize>) -> reqwest::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n{}") {
+                    let read = socket.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let response = if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                    let body = r#"{"access_token":"access-2","refresh_token":"refresh-2","expires_in":3600}"#;
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                } else {
+                    "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+                };
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        reqwest::Url::parse(&url).unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_refreshes_send_a_single_request() {
+        let root = std::env::temp_dir().join(format!("nuscape-auth-{}", Uuid::new_v4()));
+        let paths = StoragePaths::with_root(root).unwrap();
+        let config_store = Arc::new(UsageConfigStore::new(&paths).unwrap());
+        let token_store = Arc::new(TokenStore::new(
+            Box::<MemoryBackend>::default(),
+            DpapiScope::default(),
+            Arc::new(AuthAuditLog::new(&paths)),
+        ));
+        token_store
+            .save_tokens(
+                "access-1".to_string(),
+                "refresh-1".to_string(),
+                Some(60),
+                Utc::now() - Duration::hours(1),
+            )
+            .unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base_url = refresh_server(requests.clone()).await;
+        let config = UploadConfig {
+            batch_url: base_url.join("api/v1/usage").unwrap(),
+            heartbeat_url: base_url.join("api/v1/heartbeat").unwrap(),
+            base_url,
+        };
+        let refresher = TokenRefresher::new(
+            config_store,
+            token_store.clone(),
+            Arc::new(ClockSkew::new()),
+        )
+        .unwrap();
+
+        let seen = token_store.generation();
+        let (first, second) = tokio::join!(
+            refresher.refresh(&config, seen),
+            refresher.refresh(&config, seen)
+        );
+        assert!(first.unwrap());
+        assert!(second.unwrap());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(token_store.refresh_token().as_deref(), Some("refresh-2"));
+    }
+
+    #[test]
+    fn an_unreadable_token_file_is_quarantined() {
+        let root = std::env::temp_dir().join(format!("nuscape-auth-{}", Uuid::new_v4()));
+        let paths = StoragePaths::with_root(root).unwrap();
+        std::fs::write(paths.tokens_path(), r#"{"access_token": 5}"#).unwrap();
+
+        let token_store = TokenStore::new(
+            credentials::open(CredentialBackendKind::File, &paths, DpapiScope::default()),
+            DpapiScope::default(),
+            Arc::new(AuthAuditLog::new(&paths)),
+        );
+        assert!(!token_store.has_tokens());
+        assert!(!paths.tokens_path().exists());
+        let moved = std::fs::read_dir(paths.tokens_path().parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().contains(".corrupt-"));
+        assert!(moved);
+    }
 }
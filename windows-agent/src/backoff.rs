@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::CircuitState;
+
+/// Fraction of a delay that may be added or removed at random.
+pub const JITTER_RATIO: f64 = 0.3;
+
+/// Source of uniformly distributed samples in `[0, 1)`, injectable so retry
+/// timing can be made deterministic.
+pub trait JitterSource: Send + Sync {
+    fn sample(&self) -> f64;
+}
+
+pub struct RandomJitter;
+
+impl JitterSource for RandomJitter {
+    fn sample(&self) -> f64 {
+        rand::random()
+    }
+}
+
+/// Spreads `delay` by ±`JITTER_RATIO` so a fleet of agents doesn't retry in lockstep.
+pub fn apply_jitter(delay: Duration, jitter: &dyn JitterSource) -> Duration {
+    let factor = 1.0 + JITTER_RATIO * (2.0 * jitter.sample().clamp(0.0, 1.0) - 1.0);
+    delay.mul_f64(factor.max(0.0))
+}
+
+/// Upload backoff persisted across restarts so a rebooted fleet does not
+/// retry a struggling backend all at once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistedBackoff {
+    pub last_failure_at: DateTime<Utc>,
+    pub backoff_ms: u64,
+    pub consecutive_failures: u32,
+    pub cooldown_ms: u64,
+    pub open_until: Option<DateTime<Utc>>,
+}
+
+impl PersistedBackoff {
+    /// Earliest time the next upload attempt may start.
+    pub fn resume_at(&self) -> DateTime<Utc> {
+        self.last_failure_at + chrono::Duration::milliseconds(self.backoff_ms as i64)
+    }
+}
+
+/// Stops upload attempts after repeated transport/server failures, probing
+/// again after an exponentially growing cool-down.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    base_cooldown: Duration,
+    max_cooldown: Duration,
+    consecutive_failures: u32,
+    cooldown: Duration,
+    open_until: Option<DateTime<Utc>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, base_cooldown: Duration, max_cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            base_cooldown,
+            max_cooldown,
+            consecutive_failures: 0,
+            cooldown: base_cooldown,
+            open_until: None,
+        }
+    }
+
+    /// Captures the breaker after a failure at `now`, with `backoff` as the
+    /// wait before the next attempt.
+    pub fn persist(&self, now: DateTime<Utc>, backoff: Duration) -> PersistedBackoff {
+        PersistedBackoff {
+            last_failure_at: now,
+            backoff_ms: backoff.as_millis() as u64,
+            consecutive_failures: self.consecutive_failures,
+            cooldown_ms: self.cooldown.as_millis() as u64,
+            open_until: self.open_until,
+        }
+    }
+
+    pub fn restore(&mut self, saved: &PersistedBackoff) {
+        self.consecutive_failures = saved.consecutive_failures;
+        self.cooldown =
+            Duration::from_millis(saved.cooldown_ms).clamp(self.base_cooldown, self.max_cooldown);
+        self.open_until = saved.open_until;
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn state(&self, now: DateTime<Utc>) -> CircuitState {
+        match self.open_until {
+            Some(until) if now < until => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+            None => CircuitState::Closed,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.cooldown = self.base_cooldown;
+        self.open_until = None;
+    }
+
+    pub fn record_failure(&mut self, now: DateTime<Utc>) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.open_until.is_some() {
+            // The half-open probe failed; stay open for longer.
+            self.cooldown = (self.cooldown * 2).min(self.max_cooldown);
+        } else if self.consecutive_failures < self.threshold {
+            return;
+        }
+        self.open_until = chrono::Duration::from_std(self.cooldown)
+            .ok()
+            .map(|cooldown| now + cooldown);
+        log::warn!(
+            "upload circuit open for {:?} after {} consecutive failures",
+            self.cooldown,
+            self.consecutive_failures
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(f64);
+
+    impl JitterSource for Fixed {
+        fn sample(&self) -> f64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn jitter_spans_thirty_percent_either_way() {
+        let delay = Duration::from_secs(10);
+        assert_eq!(apply_jitter(delay, &Fixed(0.0)), Duration::from_secs(7));
+        assert_eq!(apply_jitter(delay, &Fixed(0.5)), delay);
+        assert_eq!(apply_jitter(delay, &Fixed(1.0)), Duration::from_secs(13));
+    }
+
+    #[test]
+    fn out_of_range_samples_are_clamped() {
+        let delay = Duration::from_secs(10);
+        assert_eq!(apply_jitter(delay, &Fixed(-4.0)), Duration::from_secs(7));
+        assert_eq!(apply_jitter(delay, &Fixed(9.0)), Duration::from_secs(13));
+    }
+
+    #[test]
+    fn random_jitter_stays_within_bounds() {
+        let delay = Duration::from_secs(10);
+        for _ in 0..100 {
+            let jittered = apply_jitter(delay, &RandomJitter);
+            assert!(jittered >= Duration::from_secs(7) && jittered <= Duration::from_secs(13));
+        }
+    }
+}
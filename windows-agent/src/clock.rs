@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::{HeaderMap, DATE};
+
+/// Skew beyond which the device clock is considered wrong enough to report.
+const SKEW_WARNING_MS: i64 = 60_000;
+
+/// Offset between the backend's clock (from HTTP `Date` headers) and ours.
+#[derive(Default)]
+pub struct ClockSkew {
+    skew_ms: AtomicI64,
+    known: AtomicBool,
+}
+
+impl ClockSkew {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the offset implied by a server `Date` observed at `local`.
+    pub fn record(&self, server: DateTime<Utc>, local: DateTime<Utc>) {
+        let skew = (server - local).num_milliseconds();
+        let previous = self.skew_ms.swap(skew, Ordering::Relaxed);
+        let was_known = self.known.swap(true, Ordering::Relaxed);
+        if skew.abs() > SKEW_WARNING_MS && (!was_known || (previous - skew).abs() > SKEW_WARNING_MS)
+        {
+            log::warn!("device clock differs from server by {skew} ms");
+        }
+    }
+
+    /// Records the skew from a response's `Date` header, if it has one.
+    pub fn observe(&self, headers: &HeaderMap) {
+        let server = headers
+            .get(DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+        if let Some(server) = server {
+            self.record(server.with_timezone(&Utc), Utc::now());
+        }
+    }
+
+    pub fn skew_ms(&self) -> Option<i64> {
+        self.known
+            .load(Ordering::Relaxed)
+            .then(|| self.skew_ms.load(Ordering::Relaxed))
+    }
+
+    /// Current time corrected to the server's clock when the skew is known.
+    pub fn server_now(&self) -> DateTime<Utc> {
+        Utc::now() + Duration::milliseconds(self.skew_ms().unwrap_or(0))
+    }
+}
@@ -0,0 +1,143 @@
+//! Antivirus products registered with Windows Security Center, read through
+//! WMI (`root\SecurityCenter2`). Security Center does not exist on Windows
+//! Server, where nothing is reported.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use windows::core::{w, BSTR, VARIANT};
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED,
+};
+use windows::Win32::System::Wmi::{
+    IWbemClassObject, IWbemLocator, WbemLocator, WBEM_FLAG_FORWARD_ONLY,
+    WBEM_FLAG_RETURN_IMMEDIATELY,
+};
+
+/// How long `build_status` waits for WMI. A corrupt repository can block
+/// for minutes.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Real-time protection bits of `productState`: `0x1000` on, `0x1100`
+/// snoozed.
+const STATE_MASK: u32 = 0xF000;
+const STATE_ON: u32 = 0x1000;
+
+/// Set while a query thread runs, so a hung WMI does not pile up threads.
+static QUERY_RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Antivirus {
+    pub enabled: bool,
+    /// Products with real-time protection on, or all of them when none is.
+    pub name: String,
+}
+
+/// Security Center's view of antivirus protection, or `None` when it cannot
+/// be read within `QUERY_TIMEOUT`, lists no product, or an earlier query
+/// is still hanging.
+pub fn antivirus_status() -> Option<Antivirus> {
+    if QUERY_RUNNING.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(query_products());
+        QUERY_RUNNING.store(false, Ordering::Release);
+    });
+    match receiver.recv_timeout(QUERY_TIMEOUT) {
+        Ok(Ok(products)) => summarize(&products),
+        Ok(Err(err)) => {
+            log::debug!("antivirus query failed: {err:?}");
+            None
+        }
+        Err(_) => {
+            log::warn!("antivirus query timed out after {QUERY_TIMEOUT:?}");
+            None
+        }
+    }
+}
+
+fn summarize(products: &[(String, u32)]) -> Option<Antivirus> {
+    if products.is_empty() {
+        return None;
+    }
+    let on: Vec<&str> = products
+        .iter()
+        .filter(|(_, state)| state & STATE_MASK == STATE_ON)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let name = if on.is_empty() {
+        products
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    } else {
+        on.join(", ")
+    };
+    Some(Antivirus {
+        enabled: !on.is_empty(),
+        name,
+    })
+}
+
+/// Display name and `productState` of every registered product.
+fn query_products() -> Result<Vec<(String, u32)>> {
+    unsafe {
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let locator: IWbemLocator = CoCreateInstance(&WbemLocator, None, CLSCTX_INPROC_SERVER)
+            .context("failed to create WMI locator")?;
+        let services = locator
+            .ConnectServer(
+                &BSTR::from("ROOT\\SecurityCenter2"),
+                &BSTR::new(),
+                &BSTR::new(),
+                &BSTR::new(),
+                0,
+                &BSTR::new(),
+                None,
+            )
+            .context("failed to connect to SecurityCenter2")?;
+        let products = services
+            .ExecQuery(
+                &BSTR::from("WQL"),
+                &BSTR::from("SELECT displayName, productState FROM AntiVirusProduct"),
+                WBEM_FLAG_FORWARD_ONLY | WBEM_FLAG_RETURN_IMMEDIATELY,
+                None,
+            )
+            .context("AntiVirusProduct query failed")?;
+        let mut found = Vec::new();
+        loop {
+            let mut row = [None];
+            let mut returned = 0u32;
+            let timeout_ms = QUERY_TIMEOUT.as_millis() as i32;
+            products.Next(timeout_ms, &mut row, &mut returned).ok()?;
+            let [Some(product)] = row else {
+                break;
+            };
+            if let Some(entry) = product_entry(&product) {
+                found.push(entry);
+            }
+        }
+        Ok(found)
+    }
+}
+
+unsafe fn product_entry(product: &IWbemClassObject) -> Option<(String, u32)> {
+    let mut name = VARIANT::default();
+    product
+        .Get(w!("displayName"), 0, &mut name, None, None)
+        .ok()?;
+    let mut state = VARIANT::default();
+    product
+        .Get(w!("productState"), 0, &mut state, None, None)
+        .ok()?;
+    let name = BSTR::try_from(&name).ok()?.to_string();
+    let state = u32::try_from(&state)
+        .or_else(|_| i32::try_from(&state).map(|state| state as u32))
+        .ok()?;
+    Some((name, state))
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use windows::Win32::Foundation::{CloseHandle, FILETIME, HANDLE};
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+use windows::Win32::System::Threading::{
+    GetProcessTimes, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+};
+
+use crate::collectors::sessions::glob_match;
+use crate::config::UsageConfigStore;
+use crate::models::UsageSession;
+
+/// 100ns intervals between 1601-01-01 (FILETIME epoch) and the Unix epoch.
+const FILETIME_UNIX_OFFSET: i64 = 116_444_736_000_000_000;
+
+/// Reports runtime of watch-listed processes whether or not they are in the
+/// foreground, e.g. a game server or torrent client left running. Nothing is
+/// reported while the watch-list is empty.
+pub struct BackgroundCollector {
+    config_store: Arc<UsageConfigStore>,
+    last_collect: Mutex<DateTime<Utc>>,
+}
+
+impl BackgroundCollector {
+    pub fn new(config_store: Arc<UsageConfigStore>) -> Self {
+        Self {
+            config_store,
+            last_collect: Mutex::new(Utc::now()),
+        }
+    }
+
+    /// One session per watched process running now, covering the time since
+    /// the previous collect, or since the process started if that is later.
+    pub fn collect(&self) -> Result<Vec<UsageSession>> {
+        let now = Utc::now();
+        let since = std::mem::replace(&mut *self.last_collect.lock(), now);
+        let watchlist = self.config_store.background_watchlist();
+        if watchlist.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut sessions = Vec::new();
+        for (pid, package) in running_processes()? {
+            if !watchlist
+                .iter()
+                .any(|pattern| glob_match(&pattern.to_lowercase(), &package))
+            {
+                continue;
+            }
+            let start = process_start(pid).map_or(since, |started| started.max(since));
+            if start < now {
+                sessions.push(UsageSession::background(package, start, now));
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+/// Process ids and lowercased executable names of all running processes.
+pub(crate) fn running_processes() -> Result<Vec<(u32, String)>> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) }
+        .context("failed to snapshot processes")?;
+    let mut entry = PROCESSENTRY32W {
+        dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+        ..Default::default()
+    };
+    let mut processes = Vec::new();
+    let mut next = unsafe { Process32FirstW(snapshot, &mut entry) };
+    while next.is_ok() {
+        let len = entry
+            .szExeFile
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(entry.szExeFile.len());
+        let name = String::from_utf16_lossy(&entry.szExeFile[..len]).to_lowercase();
+        processes.push((entry.th32ProcessID, name));
+        next = unsafe { Process32NextW(snapshot, &mut entry) };
+    }
+    unsafe {
+        let _ = CloseHandle(snapshot);
+    }
+    Ok(processes)
+}
+
+fn process_start(pid: u32) -> Option<DateTime<Utc>> {
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) }.ok()?;
+    let created = creation_time(handle);
+    unsafe {
+        let _ = CloseHandle(handle);
+    }
+    created
+}
+
+fn creation_time(handle: HANDLE) -> Option<DateTime<Utc>> {
+    let mut created = FILETIME::default();
+    let mut unused = [FILETIME::default(); 3];
+    unsafe {
+        GetProcessTimes(
+            handle,
+            &mut created,
+            &mut unused[0],
+            &mut unused[1],
+            &mut unused[2],
+        )
+    }
+    .ok()?;
+    let ticks = (i64::from(created.dwHighDateTime) << 32) | i64::from(created.dwLowDateTime);
+    let micros = (ticks - FILETIME_UNIX_OFFSET) / 10;
+    DateTime::from_timestamp_micros(micros)
+}
@@ -0,0 +1,205 @@
+//! Per-site attribution for browser sessions: the page title comes from the
+//! window title, and the domain (opt-in) from the address bar via UI
+//! Automation. Anything that cannot be parsed falls back to plain browser
+//! tracking.
+
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use windows::core::VARIANT;
+use windows::Win32::Foundation::HWND;
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED,
+};
+use windows::Win32::UI::Accessibility::{
+    CUIAutomation, IUIAutomation, IUIAutomationValuePattern, TreeScope_Descendants,
+    UIA_ControlTypePropertyId, UIA_EditControlTypeId, UIA_ValuePatternId,
+};
+
+const BROWSERS: [&str; 6] = [
+    "chrome.exe",
+    "msedge.exe",
+    "firefox.exe",
+    "brave.exe",
+    "opera.exe",
+    "vivaldi.exe",
+];
+
+/// Window title suffixes, matched case-sensitively after the last separator.
+const BROWSER_NAMES: [&str; 7] = [
+    "Google Chrome",
+    "Microsoft Edge",
+    "Microsoft\u{200b} Edge",
+    "Mozilla Firefox",
+    "Brave",
+    "Opera",
+    "Vivaldi",
+];
+const TITLE_SEPARATORS: [&str; 2] = [" - ", " \u{2014} "];
+/// Second-level labels that are part of a country-code public suffix
+/// (`example.co.uk`), good enough for coarse attribution without a full
+/// public suffix list.
+const SECOND_LEVEL_SUFFIXES: [&str; 7] = ["co", "com", "net", "org", "gov", "edu", "ac"];
+/// How long a sample waits for the address bar before leaving the domain
+/// out; well under the shortest sample interval.
+const ADDRESS_BAR_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// A window to read the address bar of, and where to send the text.
+type AddressBarRequest = (HWND, Sender<Option<String>>);
+
+/// The thread reading address bars. Rendezvous, so a request is only
+/// accepted while the thread is idle.
+static ADDRESS_BAR_READER: Lazy<Option<SyncSender<AddressBarRequest>>> = Lazy::new(|| {
+    let (requests, incoming) = mpsc::sync_channel(0);
+    thread::Builder::new()
+        .name("address-bar".to_string())
+        .spawn(move || read_address_bars(incoming))
+        .map_err(|err| log::warn!("failed to start the address bar reader: {err}"))
+        .ok()?;
+    Some(requests)
+});
+
+pub fn is_browser(package: &str) -> bool {
+    BROWSERS.contains(&package)
+}
+
+/// Strips the trailing browser name (and Edge's profile name) from a window
+/// title, leaving the page title. Titles without a known suffix are returned
+/// unchanged.
+pub fn page_title(window_title: &str) -> &str {
+    for separator in TITLE_SEPARATORS {
+        let Some((rest, browser)) = window_title.rsplit_once(separator) else {
+            continue;
+        };
+        if !BROWSER_NAMES.contains(&browser) {
+            continue;
+        }
+        // Edge: "Page - Profile 1 - Microsoft Edge". A page title that itself
+        // contains " - " loses its last segment here, which is acceptable.
+        if browser.contains("Edge") {
+            if let Some((page, _profile)) = rest.rsplit_once(separator) {
+                return page;
+            }
+        }
+        return rest;
+    }
+    window_title
+}
+
+/// Registrable domain of an address bar value such as
+/// `https://www.youtube.com/watch?v=...` or `docs.google.com/document`.
+pub fn registrable_domain(address: &str) -> Option<String> {
+    let address = address.trim();
+    if address.is_empty() || address.contains(char::is_whitespace) {
+        return None;
+    }
+    let url = if address.contains("://") {
+        reqwest::Url::parse(address).ok()?
+    } else {
+        reqwest::Url::parse(&format!("https://{address}")).ok()?
+    };
+    match url.domain() {
+        Some(host) => domain_of_host(host),
+        // IP addresses have no registrable part.
+        None => url.host_str().map(str::to_string),
+    }
+}
+
+fn domain_of_host(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() < 2 || labels.iter().any(|label| label.is_empty()) {
+        return None;
+    }
+    let tld = labels[labels.len() - 1];
+    let second = labels[labels.len() - 2];
+    let keep = if labels.len() >= 3 && tld.len() == 2 && SECOND_LEVEL_SUFFIXES.contains(&second) {
+        3
+    } else {
+        2
+    };
+    Some(labels[labels.len() - keep..].join("."))
+}
+
+/// Reads the first edit control of a browser window, which in Chromium
+/// browsers and Firefox is the address bar. UI Automation calls into the
+/// browser process, so the lookup runs on a thread of its own: a hung
+/// browser stalls that thread rather than the sampler, and lookups are
+/// skipped until it recovers.
+pub fn address_bar_text(hwnd: HWND) -> Option<String> {
+    let (reply, text) = mpsc::channel();
+    ADDRESS_BAR_READER.as_ref()?.try_send((hwnd, reply)).ok()?;
+    text.recv_timeout(ADDRESS_BAR_TIMEOUT).ok().flatten()
+}
+
+fn read_address_bars(requests: Receiver<AddressBarRequest>) {
+    let automation: Option<IUIAutomation> = unsafe {
+        // The thread stays in the MTA for the process lifetime.
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        CoCreateInstance(&CUIAutomation, None, CLSCTX_INPROC_SERVER).ok()
+    };
+    for (hwnd, reply) in requests {
+        let text = automation
+            .as_ref()
+            .and_then(|automation| unsafe { read_address_bar(automation, hwnd) });
+        // The sampler may have given up waiting.
+        let _ = reply.send(text);
+    }
+}
+
+unsafe fn read_address_bar(automation: &IUIAutomation, hwnd: HWND) -> Option<String> {
+    let window = automation.ElementFromHandle(hwnd).ok()?;
+    let condition = automation
+        .CreatePropertyCondition(
+            UIA_ControlTypePropertyId,
+            &VARIANT::from(UIA_EditControlTypeId.0),
+        )
+        .ok()?;
+    let edit = window.FindFirst(TreeScope_Descendants, &condition).ok()?;
+    let value: IUIAutomationValuePattern = edit.GetCurrentPatternAs(UIA_ValuePatternId).ok()?;
+    let text = value.CurrentValue().ok()?.to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_titles_lose_the_browser_suffix() {
+        assert_eq!(page_title("Inbox - Gmail - Google Chrome"), "Inbox - Gmail");
+        assert_eq!(page_title("Rust \u{2014} Mozilla Firefox"), "Rust");
+        assert_eq!(page_title("News - Work - Microsoft\u{200b} Edge"), "News");
+        assert_eq!(page_title("Vivaldi"), "Vivaldi");
+        assert_eq!(page_title("Notes - Untitled"), "Notes - Untitled");
+    }
+
+    #[test]
+    fn registrable_domains_come_from_urls_and_bare_hosts() {
+        assert_eq!(
+            registrable_domain("https://www.youtube.com/watch?v=1").as_deref(),
+            Some("youtube.com")
+        );
+        assert_eq!(
+            registrable_domain("docs.google.com/document").as_deref(),
+            Some("google.com")
+        );
+        assert_eq!(
+            registrable_domain("news.bbc.co.uk").as_deref(),
+            Some("bbc.co.uk")
+        );
+        assert_eq!(
+            registrable_domain("http://192.168.1.1/").as_deref(),
+            Some("192.168.1.1")
+        );
+    }
+
+    #[test]
+    fn unparseable_addresses_have_no_domain() {
+        assert_eq!(registrable_domain(""), None);
+        assert_eq!(registrable_domain("how to tie a tie"), None);
+        assert_eq!(registrable_domain("localhost"), None);
+    }
+}
@@ -0,0 +1,156 @@
+//! Per-process TCP byte counts from the extended TCP table and per-connection
+//! extended statistics (ESTATS). Windows only counts a connection's bytes
+//! once collection is enabled for it, which happens the first time it is
+//! seen, so a connection's first snapshot reports nothing. UDP has no
+//! per-socket counters and stays unattributed, as does traffic on
+//! connections opened and closed between two snapshots.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use windows::Win32::Foundation::{BOOLEAN, ERROR_INSUFFICIENT_BUFFER};
+use windows::Win32::NetworkManagement::IpHelper::{
+    GetExtendedTcpTable, GetPerTcpConnectionEStats, SetPerTcpConnectionEStats,
+    TCP_ESTATS_DATA_ROD_v0, TCP_ESTATS_DATA_RW_v0, TcpConnectionEstatsData, MIB_TCPROW_LH,
+    MIB_TCPROW_LH_0, MIB_TCPTABLE_OWNER_PID, MIB_TCP_STATE_ESTAB, TCP_TABLE_OWNER_PID_CONNECTIONS,
+};
+use windows::Win32::Networking::WinSock::AF_INET;
+
+/// The table can grow between the size query and the read.
+const TABLE_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionBytes {
+    pub pid: u32,
+    /// Bytes sent and received since collection was enabled.
+    pub bytes: u64,
+}
+
+/// Established IPv4 connections keyed by owner and endpoints.
+pub fn snapshot_connections() -> Result<HashMap<String, ConnectionBytes>> {
+    let buffer = tcp_table()?;
+    let rows = unsafe {
+        let table = &*(buffer.as_ptr() as *const MIB_TCPTABLE_OWNER_PID);
+        std::slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize)
+    };
+    let mut connections = HashMap::new();
+    for row in rows {
+        if row.dwState != MIB_TCP_STATE_ESTAB.0 as u32 || row.dwOwningPid == 0 {
+            continue;
+        }
+        // Loopback traffic never reaches a counted interface. Addresses are
+        // in network byte order, so the first octet is the low byte.
+        if row.dwRemoteAddr & 0xff == 127 {
+            continue;
+        }
+        let tcp_row = MIB_TCPROW_LH {
+            Anonymous: MIB_TCPROW_LH_0 {
+                dwState: row.dwState,
+            },
+            dwLocalAddr: row.dwLocalAddr,
+            dwLocalPort: row.dwLocalPort,
+            dwRemoteAddr: row.dwRemoteAddr,
+            dwRemotePort: row.dwRemotePort,
+        };
+        let Some(bytes) = connection_bytes(&tcp_row) else {
+            continue;
+        };
+        let key = format!(
+            "{}:{:08x}:{}:{:08x}:{}",
+            row.dwOwningPid, row.dwLocalAddr, row.dwLocalPort, row.dwRemoteAddr, row.dwRemotePort
+        );
+        connections.insert(
+            key,
+            ConnectionBytes {
+                pid: row.dwOwningPid,
+                bytes,
+            },
+        );
+    }
+    Ok(connections)
+}
+
+/// Raw `MIB_TCPTABLE_OWNER_PID`, in a `u32` buffer for alignment.
+fn tcp_table() -> Result<Vec<u32>> {
+    let mut size = 0u32;
+    for _ in 0..TABLE_ATTEMPTS {
+        let mut buffer = vec![0u32; (size as usize).div_ceil(4)];
+        let table = (!buffer.is_empty()).then(|| buffer.as_mut_ptr().cast());
+        let status = unsafe {
+            GetExtendedTcpTable(
+                table,
+                &mut size,
+                false,
+                u32::from(AF_INET.0),
+                TCP_TABLE_OWNER_PID_CONNECTIONS,
+                0,
+            )
+        };
+        match status {
+            0 => return Ok(buffer),
+            status if status == ERROR_INSUFFICIENT_BUFFER.0 => continue,
+            status => return Err(anyhow!("GetExtendedTcpTable failed: {status}")),
+        }
+    }
+    Err(anyhow!("TCP table kept growing"))
+}
+
+/// Data bytes of one connection, enabling collection when it is off. `None`
+/// when the statistics cannot be read (they need administrator rights).
+fn connection_bytes(row: &MIB_TCPROW_LH) -> Option<u64> {
+    let mut rw = TCP_ESTATS_DATA_RW_v0::default();
+    let mut rod = TCP_ESTATS_DATA_ROD_v0::default();
+    let status = unsafe {
+        GetPerTcpConnectionEStats(
+            row,
+            TcpConnectionEstatsData,
+            Some(as_bytes_mut(&mut rw)),
+            0,
+            None,
+            0,
+            Some(as_bytes_mut(&mut rod)),
+            0,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+    if !rw.EnableCollection.as_bool() {
+        let enable = TCP_ESTATS_DATA_RW_v0 {
+            EnableCollection: BOOLEAN(1),
+        };
+        let status = unsafe {
+            SetPerTcpConnectionEStats(row, TcpConnectionEstatsData, as_bytes(&enable), 0, 0)
+        };
+        return (status == 0).then_some(0);
+    }
+    Some(rod.DataBytesIn + rod.DataBytesOut)
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+fn as_bytes_mut<T>(value: &mut T) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(value as *mut T as *mut u8, std::mem::size_of::<T>()) }
+}
+
+/// Bytes per process since the `previous` snapshot. Connections not seen
+/// before count in full, as do ones whose counter went backwards because
+/// the same endpoints were reused by a new connection.
+pub fn process_deltas(
+    current: &HashMap<String, ConnectionBytes>,
+    previous: &HashMap<String, u64>,
+) -> HashMap<u32, u64> {
+    let mut deltas: HashMap<u32, u64> = HashMap::new();
+    for (key, connection) in current {
+        let delta = match previous.get(key) {
+            Some(&last) if last <= connection.bytes => connection.bytes - last,
+            _ => connection.bytes,
+        };
+        if delta > 0 {
+            *deltas.entry(connection.pid).or_default() += delta;
+        }
+    }
+    deltas
+}
@@ -0,0 +1,129 @@
+use std::collections::{HashMap, HashSet};
+use std::ptr;
+
+use windows::core::GUID;
+use windows::Networking::Connectivity::{ConnectionProfile, NetworkCostType, NetworkInformation};
+use windows::Win32::Foundation::{HANDLE, WIN32_ERROR};
+use windows::Win32::NetworkManagement::IpHelper::{
+    FreeMibTable, GetIpForwardTable2, GetNetworkConnectivityHint, MIB_IPFORWARD_TABLE2,
+};
+use windows::Win32::NetworkManagement::WiFi::{
+    wlan_interface_state_connected, wlan_intf_opcode_current_connection, WlanCloseHandle,
+    WlanEnumInterfaces, WlanFreeMemory, WlanOpenHandle, WlanQueryInterface,
+    WLAN_CONNECTION_ATTRIBUTES, WLAN_INTERFACE_INFO_LIST,
+};
+use windows::Win32::Networking::WinSock::{
+    NetworkConnectivityLevelHintNone, NetworkConnectivityLevelHintUnknown, AF_UNSPEC,
+    NL_NETWORK_CONNECTIVITY_HINT,
+};
+
+/// Cheap check used to skip uploads while the machine has no network. Errs on
+/// the side of "online" when the OS can't tell us.
+pub fn is_online() -> bool {
+    let mut hint = NL_NETWORK_CONNECTIVITY_HINT::default();
+    let status = unsafe { GetNetworkConnectivityHint(&mut hint) };
+    if status == WIN32_ERROR(0) && hint.ConnectivityLevel != NetworkConnectivityLevelHintUnknown {
+        return hint.ConnectivityLevel != NetworkConnectivityLevelHintNone;
+    }
+    default_route_interfaces()
+        .map(|interfaces| !interfaces.is_empty())
+        .unwrap_or(true)
+}
+
+/// Adapters whose connection Windows considers metered (phone hotspot,
+/// capped LTE), by adapter GUID. The WinRT connectivity API fails on some
+/// editions; callers treat an error as "nothing metered".
+pub fn metered_adapters() -> windows::core::Result<HashSet<GUID>> {
+    let mut adapters = HashSet::new();
+    for profile in NetworkInformation::GetConnectionProfiles()? {
+        if is_metered(&profile)? {
+            adapters.insert(profile.NetworkAdapter()?.NetworkAdapterId()?);
+        }
+    }
+    Ok(adapters)
+}
+
+/// Whether the connection currently used for internet access is metered.
+pub fn internet_metered() -> windows::core::Result<bool> {
+    is_metered(&NetworkInformation::GetInternetConnectionProfile()?)
+}
+
+/// SSIDs of the connected wireless adapters, by adapter GUID. Fails when
+/// the WLAN service is not running, e.g. on machines without Wi-Fi.
+pub fn connected_ssids() -> windows::core::Result<HashMap<GUID, String>> {
+    let mut version = 0u32;
+    let mut client = HANDLE::default();
+    WIN32_ERROR(unsafe { WlanOpenHandle(2, None, &mut version, &mut client) }).ok()?;
+    let ssids = unsafe { query_ssids(client) };
+    unsafe {
+        WlanCloseHandle(client, None);
+    }
+    ssids
+}
+
+unsafe fn query_ssids(client: HANDLE) -> windows::core::Result<HashMap<GUID, String>> {
+    let mut list_ptr: *mut WLAN_INTERFACE_INFO_LIST = ptr::null_mut();
+    WIN32_ERROR(WlanEnumInterfaces(client, None, &mut list_ptr)).ok()?;
+    let list = &*list_ptr;
+    let interfaces =
+        std::slice::from_raw_parts(list.InterfaceInfo.as_ptr(), list.dwNumberOfItems as usize);
+    let mut ssids = HashMap::new();
+    for interface in interfaces {
+        if interface.isState != wlan_interface_state_connected {
+            continue;
+        }
+        let mut size = 0u32;
+        let mut data: *mut core::ffi::c_void = ptr::null_mut();
+        let status = WlanQueryInterface(
+            client,
+            &interface.InterfaceGuid,
+            wlan_intf_opcode_current_connection,
+            None,
+            &mut size,
+            &mut data,
+            None,
+        );
+        if status != 0 {
+            continue;
+        }
+        let ssid = &(*(data as *const WLAN_CONNECTION_ATTRIBUTES))
+            .wlanAssociationAttributes
+            .dot11Ssid;
+        let len = (ssid.uSSIDLength as usize).min(ssid.ucSSID.len());
+        if len > 0 {
+            ssids.insert(
+                interface.InterfaceGuid,
+                String::from_utf8_lossy(&ssid.ucSSID[..len]).into_owned(),
+            );
+        }
+        WlanFreeMemory(data);
+    }
+    WlanFreeMemory(list_ptr as _);
+    Ok(ssids)
+}
+
+fn is_metered(profile: &ConnectionProfile) -> windows::core::Result<bool> {
+    let cost = profile.GetConnectionCost()?.NetworkCostType()?;
+    Ok(cost == NetworkCostType::Fixed || cost == NetworkCostType::Variable)
+}
+
+/// LUIDs of the interfaces with an IPv4 or IPv6 default route, or `None`
+/// when the routing table cannot be read.
+pub fn default_route_interfaces() -> Option<HashSet<u64>> {
+    unsafe {
+        let mut table_ptr: *mut MIB_IPFORWARD_TABLE2 = ptr::null_mut();
+        let status = GetIpForwardTable2(AF_UNSPEC, &mut table_ptr);
+        if status != WIN32_ERROR(0) {
+            return None;
+        }
+        let table = &*table_ptr;
+        let rows = std::slice::from_raw_parts(table.Table.as_ptr(), table.NumEntries as usize);
+        let interfaces = rows
+            .iter()
+            .filter(|row| row.DestinationPrefix.PrefixLength == 0 && !row.Loopback.as_bool())
+            .map(|row| row.InterfaceLuid.Value)
+            .collect();
+        FreeMibTable(table_ptr as _);
+        Some(interfaces)
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Days, Local, NaiveDate, Utc};
+
+use crate::models::AppUsageTotal;
+
+/// Per-app foreground time for the current local day, built from sessions as
+/// they are drained for upload. Resets when the local date changes.
+pub struct DailyTotals {
+    day: NaiveDate,
+    totals: HashMap<String, u64>,
+}
+
+impl DailyTotals {
+    pub fn new() -> Self {
+        Self {
+            day: Local::now().date_naive(),
+            totals: HashMap::new(),
+        }
+    }
+
+    /// Adds the part of `start..end` that falls on the current local day.
+    pub fn record(&mut self, package: &str, start: DateTime<Utc>, end: DateTime<Utc>) {
+        self.roll_over();
+        let ms = overlap_ms(self.day, start, end);
+        if ms > 0 {
+            *self.totals.entry(package.to_string()).or_default() += ms;
+        }
+    }
+
+    /// Recorded totals plus `pending` spans that have not been drained yet,
+    /// largest first.
+    pub fn snapshot<'a>(
+        &mut self,
+        pending: impl IntoIterator<Item = (&'a str, DateTime<Utc>, DateTime<Utc>)>,
+    ) -> Vec<AppUsageTotal> {
+        self.roll_over();
+        let mut totals = self.totals.clone();
+        for (package, start, end) in pending {
+            let ms = overlap_ms(self.day, start, end);
+            if ms > 0 {
+                *totals.entry(package.to_string()).or_default() += ms;
+            }
+        }
+        let mut totals: Vec<_> = totals
+            .into_iter()
+            .map(|(package, total_ms)| AppUsageTotal { package, total_ms })
+            .collect();
+        totals.sort_by(|a, b| {
+            b.total_ms
+                .cmp(&a.total_ms)
+                .then_with(|| a.package.cmp(&b.package))
+        });
+        totals
+    }
+
+    fn roll_over(&mut self) {
+        let today = Local::now().date_naive();
+        if today != self.day {
+            self.day = today;
+            self.totals.clear();
+        }
+    }
+}
+
+fn overlap_ms(day: NaiveDate, start: DateTime<Utc>, end: DateTime<Utc>) -> u64 {
+    let day_start = local_day_start(day);
+    let day_end = day
+        .checked_add_days(Days::new(1))
+        .map(local_day_start)
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    let start = start.max(day_start);
+    let end = end.min(day_end);
+    (end - start).num_milliseconds().max(0) as u64
+}
+
+/// First instant of `day` in local time. Days are 23 or 25 hours long across
+/// DST changes, and where midnight is skipped the day starts at the first
+/// valid hour.
+fn local_day_start(day: NaiveDate) -> DateTime<Utc> {
+    (0..24)
+        .find_map(|hour| {
+            day.and_hms_opt(hour, 0, 0)?
+                .and_local_timezone(Local)
+                .earliest()
+        })
+        .map(|start| start.with_timezone(&Utc))
+        .unwrap_or_else(|| day.and_time(Default::default()).and_utc())
+}
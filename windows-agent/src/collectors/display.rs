@@ -0,0 +1,134 @@
+//! Where the foreground window is shown: which monitor, and whether it fills
+//! that monitor the way fullscreen and borderless-windowed games do.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use windows::Win32::Foundation::{BOOL, HWND, LPARAM, RECT};
+use windows::Win32::Graphics::Gdi::{
+    EnumDisplayMonitors, GetMonitorInfoW, MonitorFromWindow, HDC, HMONITOR, MONITORINFO,
+    MONITOR_DEFAULTTONULL,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetWindowLongW, GetWindowRect, GWL_STYLE, MONITORINFOF_PRIMARY, WS_CAPTION,
+};
+
+/// Display state of a window at one sample.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    pub fullscreen: bool,
+    /// Position among the attached monitors; the primary display is 0.
+    pub monitor_index: u32,
+}
+
+/// Returns `None` when the window is not on any monitor (e.g. minimized to
+/// an off-screen position) or its geometry cannot be read.
+pub fn placement(hwnd: HWND) -> Option<Placement> {
+    unsafe {
+        let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTONULL);
+        if monitor.is_invalid() {
+            return None;
+        }
+        let info = monitor_info(monitor)?;
+        let mut rect = RECT::default();
+        GetWindowRect(hwnd, &mut rect).ok()?;
+        // A maximized window also covers its monitor, because the frame
+        // hangs over the edges, but keeps its caption. Fullscreen and
+        // borderless-windowed apps are WS_POPUP windows with no caption.
+        let style = GetWindowLongW(hwnd, GWL_STYLE) as u32;
+        let captioned = style & WS_CAPTION.0 == WS_CAPTION.0;
+        Some(Placement {
+            fullscreen: !captioned && covers(&rect, &info.rcMonitor),
+            monitor_index: monitor_index(monitor).unwrap_or(0),
+        })
+    }
+}
+
+fn covers(window: &RECT, monitor: &RECT) -> bool {
+    window.left <= monitor.left
+        && window.top <= monitor.top
+        && window.right >= monitor.right
+        && window.bottom >= monitor.bottom
+}
+
+unsafe fn monitor_info(monitor: HMONITOR) -> Option<MONITORINFO> {
+    let mut info = MONITORINFO {
+        cbSize: std::mem::size_of::<MONITORINFO>() as u32,
+        ..Default::default()
+    };
+    GetMonitorInfoW(monitor, &mut info)
+        .as_bool()
+        .then_some(info)
+}
+
+unsafe fn is_primary(monitor: HMONITOR) -> bool {
+    monitor_info(monitor).is_some_and(|info| info.dwFlags & MONITORINFOF_PRIMARY != 0)
+}
+
+/// Index of `monitor` with the primary display first and the rest in
+/// enumeration order.
+unsafe fn monitor_index(monitor: HMONITOR) -> Option<u32> {
+    let mut monitors: Vec<HMONITOR> = Vec::new();
+    let _ = EnumDisplayMonitors(
+        HDC::default(),
+        None,
+        Some(collect_monitor),
+        LPARAM(&mut monitors as *mut Vec<HMONITOR> as isize),
+    );
+    monitors.sort_by_key(|&m| Reverse(is_primary(m)));
+    monitors
+        .iter()
+        .position(|&m| m == monitor)
+        .map(|index| index as u32)
+}
+
+unsafe extern "system" fn collect_monitor(
+    monitor: HMONITOR,
+    _hdc: HDC,
+    _rect: *mut RECT,
+    lparam: LPARAM,
+) -> BOOL {
+    let monitors = &mut *(lparam.0 as *mut Vec<HMONITOR>);
+    monitors.push(monitor);
+    BOOL(1)
+}
+
+/// Per-session tally of placements, reduced to the majority value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlacementCounts {
+    samples: u32,
+    fullscreen_samples: u32,
+    monitors: BTreeMap<u32, u32>,
+}
+
+impl PlacementCounts {
+    pub fn record(&mut self, placement: Placement) {
+        self.samples += 1;
+        if placement.fullscreen {
+            self.fullscreen_samples += 1;
+        }
+        *self.monitors.entry(placement.monitor_index).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: PlacementCounts) {
+        self.samples += other.samples;
+        self.fullscreen_samples += other.fullscreen_samples;
+        for (monitor, count) in other.monitors {
+            *self.monitors.entry(monitor).or_default() += count;
+        }
+    }
+
+    /// Fullscreen in more than half of the samples.
+    pub fn fullscreen(&self) -> Option<bool> {
+        (self.samples > 0).then(|| self.fullscreen_samples * 2 > self.samples)
+    }
+
+    /// Monitor seen most often; ties go to the lower index.
+    pub fn monitor_index(&self) -> Option<u32> {
+        self.monitors
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(&monitor, _)| monitor)
+    }
+}
@@ -0,0 +1,575 @@
+//! Blocked DNS queries from dnscrypt-proxy's blocked-names log. The shipped
+//! dnscrypt-proxy.toml sets `[blocked_names] log_file`; nothing is reported
+//! while it is unset.
+//!
+//! Also checks that the filter is in place: dnscrypt-proxy answering on
+//! 127.0.0.1:53 with the filtering settings we ship, and the system resolver
+//! pointing at it.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use windows::Win32::NetworkManagement::IpHelper::{
+    GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_MULTICAST, IP_ADAPTER_ADDRESSES_LH,
+    IP_ADAPTER_DNS_SERVER_ADDRESS_XP,
+};
+use windows::Win32::NetworkManagement::Ndis::IF_OPER_STATUS;
+use windows::Win32::Networking::WinSock::{
+    AF_INET, AF_INET6, AF_UNSPEC, SOCKADDR, SOCKADDR_IN, SOCKADDR_IN6,
+};
+
+use crate::collectors::background;
+use crate::models::DnsBlockEvent;
+
+/// Upper bound on one collect's read, so a huge backlog cannot stall it.
+const MAX_READ_BYTES: u64 = 8 * 1024 * 1024;
+/// How much of the log's first line identifies the file.
+const MAX_HEAD_BYTES: u64 = 4096;
+const DNSCRYPT_PROCESS: &str = "dnscrypt-proxy.exe";
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// The dnscrypt-proxy.toml bundled with the installer.
+const SHIPPED_CONFIG: &str = include_str!("../../dnscrypt/win64/dnscrypt-proxy.toml");
+/// Query id 0x4e53, recursion desired, one question: `. IN NS`.
+const PROBE_QUERY: [u8; 17] = [
+    0x4e, 0x53, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00,
+    0x01,
+];
+
+/// Whether the local filtering resolver is in place.
+pub trait DnsHealth: Send + Sync {
+    fn dnscrypt_running(&self) -> bool;
+    fn dns_configured(&self) -> bool;
+    /// Whether the configuration in use has the filtering settings we ship;
+    /// `None` when dnscrypt was not found.
+    fn config_intact(&self) -> Option<bool>;
+}
+
+/// dnscrypt-proxy listening on 127.0.0.1:53.
+pub struct LocalResolver {
+    config_path: Option<PathBuf>,
+}
+
+impl LocalResolver {
+    /// `config_path` is the dnscrypt-proxy.toml in use, if dnscrypt was found.
+    pub fn new(config_path: Option<PathBuf>) -> Self {
+        Self { config_path }
+    }
+}
+
+impl DnsHealth for LocalResolver {
+    /// The process exists and answers a query; any response counts, since
+    /// a filtered or failed lookup still proves it is serving.
+    fn dnscrypt_running(&self) -> bool {
+        let present = background::running_processes()
+            .map(|processes| processes.iter().any(|(_, name)| name == DNSCRYPT_PROCESS))
+            .unwrap_or(false);
+        present && probe_resolver().is_some()
+    }
+
+    /// Every DNS server of every connected adapter is loopback, so a
+    /// secondary server or a second adapter cannot route around the filter.
+    fn dns_configured(&self) -> bool {
+        unsafe { connected_dns_servers() }.is_some_and(|servers| {
+            !servers.is_empty() && servers.iter().all(|server| server.is_loopback())
+        })
+    }
+
+    fn config_intact(&self) -> Option<bool> {
+        let config_path = self.config_path.as_ref()?;
+        // An unreadable or unparsable configuration counts as modified.
+        let in_use = fs::read_to_string(config_path)
+            .ok()
+            .and_then(|data| toml::from_str::<FilterSettings>(&data).ok());
+        Some(in_use.is_some() && in_use == toml::from_str(SHIPPED_CONFIG).ok())
+    }
+}
+
+/// The parts of dnscrypt-proxy.toml that decide what is filtered and whether
+/// blocks are logged. Comments, caching and other tuning may differ from
+/// what we ship.
+#[derive(Debug, Deserialize, PartialEq)]
+struct FilterSettings {
+    #[serde(default)]
+    listen_addresses: Vec<String>,
+    server_names: Option<Vec<String>>,
+    forwarding_rules: Option<String>,
+    cloaking_rules: Option<String>,
+    #[serde(default)]
+    blocked_names: BlockedNamesSettings,
+    #[serde(default)]
+    allowed_names: AllowedNamesSettings,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct BlockedNamesSettings {
+    blocked_names_file: Option<String>,
+    log_file: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct AllowedNamesSettings {
+    allowed_names_file: Option<String>,
+}
+
+fn probe_resolver() -> Option<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).ok()?;
+    socket.set_read_timeout(Some(PROBE_TIMEOUT)).ok()?;
+    socket.connect((Ipv4Addr::LOCALHOST, 53)).ok()?;
+    socket.send(&PROBE_QUERY).ok()?;
+    let mut response = [0u8; 512];
+    let len = socket.recv(&mut response).ok()?;
+    (len >= 2 && response[..2] == PROBE_QUERY[..2]).then_some(())
+}
+
+/// DNS servers of every connected adapter.
+unsafe fn connected_dns_servers() -> Option<Vec<IpAddr>> {
+    let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST;
+    let mut size = 0u32;
+    GetAdaptersAddresses(u32::from(AF_UNSPEC.0), flags, None, None, &mut size);
+    // u64 elements keep the buffer aligned for the adapter structs.
+    let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+    let first = buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH;
+    if GetAdaptersAddresses(u32::from(AF_UNSPEC.0), flags, None, Some(first), &mut size) != 0 {
+        return None;
+    }
+    let mut servers = Vec::new();
+    let mut adapter = first as *const IP_ADAPTER_ADDRESSES_LH;
+    while let Some(current) = adapter.as_ref() {
+        adapter = current.Next;
+        if current.OperStatus != IF_OPER_STATUS(1) {
+            continue;
+        }
+        let mut server = current.FirstDnsServerAddress as *const IP_ADAPTER_DNS_SERVER_ADDRESS_XP;
+        while let Some(entry) = server.as_ref() {
+            server = entry.Next;
+            let address = match sockaddr_ip(entry.Address.lpSockaddr) {
+                Some(address) if !unconfigured_ipv6_dns(&address) => address,
+                _ => continue,
+            };
+            servers.push(address);
+        }
+    }
+    Some(servers)
+}
+
+unsafe fn sockaddr_ip(sockaddr: *const SOCKADDR) -> Option<IpAddr> {
+    let family = sockaddr.as_ref()?.sa_family;
+    if family == AF_INET {
+        let addr = &*(sockaddr as *const SOCKADDR_IN);
+        Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.S_un.S_addr)).into())
+    } else if family == AF_INET6 {
+        let addr = &*(sockaddr as *const SOCKADDR_IN6);
+        Some(Ipv6Addr::from(addr.sin6_addr.u.Byte).into())
+    } else {
+        None
+    }
+}
+
+/// `fec0:0:0:ffff::1` to `::3`, which Windows lists on adapters that have
+/// no IPv6 DNS server configured.
+fn unconfigured_ipv6_dns(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V6(address) => {
+            let segments = address.segments();
+            segments[..4] == [0xfec0, 0, 0, 0xffff] && segments[4..7] == [0, 0, 0]
+        }
+        IpAddr::V4(_) => false,
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct DnscryptConfig {
+    #[serde(default)]
+    blocked_names: BlockedNamesConfig,
+}
+
+#[derive(Default, Deserialize)]
+struct BlockedNamesConfig {
+    log_file: Option<String>,
+    log_format: Option<String>,
+}
+
+/// Read position in the log, and the file it belongs to.
+#[derive(Clone)]
+struct LogTail {
+    path: PathBuf,
+    /// Creation time of the file the offset refers to; a new time means the
+    /// log was rotated.
+    created: Option<SystemTime>,
+    /// First line of that file. File system tunneling gives a log recreated
+    /// under the same name the old creation time, so a different first line
+    /// also means it was rotated.
+    head: Vec<u8>,
+    offset: u64,
+}
+
+pub struct DnsBlockCollector {
+    config_path: Option<PathBuf>,
+    tail: Mutex<Option<LogTail>>,
+}
+
+impl DnsBlockCollector {
+    /// `config_path` is the dnscrypt-proxy.toml in use, if dnscrypt was found.
+    pub fn new(config_path: Option<PathBuf>) -> Self {
+        Self {
+            config_path,
+            tail: Mutex::new(None),
+        }
+    }
+
+    pub fn config_path(&self) -> Option<&Path> {
+        self.config_path.as_deref()
+    }
+
+    /// Blocks logged since the last committed position, one event per
+    /// domain, and the position after them. The log's existing contents are
+    /// skipped when it is first seen.
+    pub fn collect(&self) -> Result<(Vec<DnsBlockEvent>, DnsLogPosition<'_>)> {
+        let position = |next| DnsLogPosition {
+            tail: &self.tail,
+            next,
+        };
+        let Some(config_path) = &self.config_path else {
+            return Ok((Vec::new(), position(None)));
+        };
+        let Some((log_path, ltsv)) = blocked_names_log(config_path)? else {
+            return Ok((Vec::new(), position(None)));
+        };
+        let file = fs::metadata(&log_path)
+            .ok()
+            .map(|meta| (meta.created().ok(), meta.len()));
+        let current = self.tail.lock().clone();
+        let Some(mut tail) = current.filter(|tail| tail.path == log_path) else {
+            // Start at the end of an existing log; one that does not exist
+            // yet is read from its start once it appears.
+            let (created, offset) = file.unwrap_or((None, 0));
+            let head = if file.is_some() {
+                first_line(&log_path)?
+            } else {
+                Vec::new()
+            };
+            let tail = LogTail {
+                path: log_path,
+                created,
+                head,
+                offset,
+            };
+            return Ok((Vec::new(), position(Some(tail))));
+        };
+        let Some((created, len)) = file else {
+            tail.created = None;
+            tail.head.clear();
+            tail.offset = 0;
+            return Ok((Vec::new(), position(Some(tail))));
+        };
+        let head = first_line(&tail.path)?;
+        // A first line can only appear once; after that it only changes
+        // when the file is replaced.
+        let replaced = !tail.head.is_empty() && head != tail.head;
+        if created != tail.created || len < tail.offset || replaced {
+            tail.offset = 0;
+        }
+        tail.created = created;
+        tail.head = head;
+        let lines = read_complete_lines(&tail.path, &mut tail.offset)?;
+        let events = aggregate(lines.lines().filter_map(|line| {
+            if ltsv {
+                parse_ltsv(line)
+            } else {
+                parse_tsv(line)
+            }
+        }));
+        Ok((events, position(Some(tail))))
+    }
+}
+
+/// Where [`DnsBlockCollector::collect`] stopped reading. Dropping it without
+/// [`commit`](Self::commit) leaves the tail where it was, so the next
+/// collect reads the same blocks again.
+pub struct DnsLogPosition<'a> {
+    tail: &'a Mutex<Option<LogTail>>,
+    next: Option<LogTail>,
+}
+
+impl DnsLogPosition<'_> {
+    pub fn commit(self) {
+        *self.tail.lock() = self.next;
+    }
+}
+
+/// Path and format (`true` for LTSV) of the blocked-names log, or `None`
+/// when logging is disabled. Relative paths are resolved against the
+/// configuration's directory.
+fn blocked_names_log(config_path: &Path) -> Result<Option<(PathBuf, bool)>> {
+    let data = fs::read_to_string(config_path)
+        .with_context(|| format!("read {}", config_path.display()))?;
+    let config: DnscryptConfig =
+        toml::from_str(&data).with_context(|| format!("parse {}", config_path.display()))?;
+    let Some(log_file) = config
+        .blocked_names
+        .log_file
+        .filter(|file| !file.is_empty())
+    else {
+        return Ok(None);
+    };
+    let base = config_path.parent().unwrap_or(Path::new("."));
+    let ltsv = config.blocked_names.log_format.as_deref() == Some("ltsv");
+    Ok(Some((base.join(log_file), ltsv)))
+}
+
+/// The file's first complete line, at most `MAX_HEAD_BYTES` of it; empty
+/// while there is none.
+fn first_line(path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut head = Vec::new();
+    file.take(MAX_HEAD_BYTES).read_to_end(&mut head)?;
+    match head.iter().position(|&byte| byte == b'\n') {
+        Some(end) => head.truncate(end + 1),
+        None if head.len() as u64 == MAX_HEAD_BYTES => {}
+        None => head.clear(),
+    }
+    Ok(head)
+}
+
+/// Text from `offset` up to the last complete line, advancing `offset`
+/// past it. A partly written last line is left for the next read.
+fn read_complete_lines(path: &Path, offset: &mut u64) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut buffer = Vec::new();
+    file.take(MAX_READ_BYTES).read_to_end(&mut buffer)?;
+    let Some(end) = buffer.iter().rposition(|&byte| byte == b'\n') else {
+        return Ok(String::new());
+    };
+    buffer.truncate(end + 1);
+    *offset += buffer.len() as u64;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// `[2024-01-31 17:46:00]\t127.0.0.1\texample.com\t*.example.*`, in local time.
+fn parse_tsv(line: &str) -> Option<(String, DateTime<Utc>)> {
+    let mut fields = line.split('\t');
+    let time = fields.next()?.trim_matches(|c| c == '[' || c == ']');
+    let domain = fields.nth(1)?;
+    let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map_or_else(Utc::now, |time| time.with_timezone(&Utc));
+    Some((domain.to_string(), time))
+}
+
+/// `time:1706719560\thost:127.0.0.1\tqname:example.com\tmessage:...`.
+fn parse_ltsv(line: &str) -> Option<(String, DateTime<Utc>)> {
+    let mut domain = None;
+    let mut time = None;
+    for field in line.split('\t') {
+        match field.split_once(':') {
+            Some(("qname", value)) => domain = Some(value),
+            Some(("time", value)) => {
+                time = value
+                    .parse()
+                    .ok()
+                    .and_then(|secs| DateTime::from_timestamp(secs, 0));
+            }
+            _ => {}
+        }
+    }
+    Some((domain?.to_string(), time.unwrap_or_else(Utc::now)))
+}
+
+fn aggregate(blocks: impl Iterator<Item = (String, DateTime<Utc>)>) -> Vec<DnsBlockEvent> {
+    let mut events: BTreeMap<String, DnsBlockEvent> = BTreeMap::new();
+    for (domain, time) in blocks {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        if domain.is_empty() {
+            continue;
+        }
+        let event = events
+            .entry(domain.clone())
+            .or_insert_with(|| DnsBlockEvent {
+                domain,
+                count: 0,
+                first_seen: time,
+                last_seen: time,
+            });
+        event.count += 1;
+        event.first_seen = event.first_seen.min(time);
+        event.last_seen = event.last_seen.max(time);
+    }
+    events.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn config_file(contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nuscape-dns-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnscrypt-proxy.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn intact(contents: &str) -> Option<bool> {
+        LocalResolver::new(Some(config_file(contents))).config_intact()
+    }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, text.as_bytes()).unwrap();
+    }
+
+    fn block(secs: i64, domain: &str) -> String {
+        format!("time:{secs}\thost:127.0.0.1\tqname:{domain}\tmessage:*.{domain}\n")
+    }
+
+    fn collect(collector: &DnsBlockCollector) -> Vec<DnsBlockEvent> {
+        let (events, position) = collector.collect().unwrap();
+        position.commit();
+        events
+    }
+
+    fn domains(collector: &DnsBlockCollector) -> Vec<String> {
+        let events = collect(collector);
+        events.into_iter().map(|event| event.domain).collect()
+    }
+
+    #[test]
+    fn tsv_lines_are_read_in_local_time() {
+        let (domain, time) =
+            parse_tsv("[2024-01-31 17:46:00]\t127.0.0.1\texample.com\t*.example.*").unwrap();
+        assert_eq!(domain, "example.com");
+        let local = Local.with_ymd_and_hms(2024, 1, 31, 17, 46, 0).earliest();
+        assert_eq!(Some(time), local.map(|time| time.with_timezone(&Utc)));
+        assert_eq!(parse_tsv("[2024-01-31 17:46:00]\t127.0.0.1"), None);
+    }
+
+    #[test]
+    fn ltsv_lines_need_a_query_name() {
+        let (domain, time) = parse_ltsv(&block(1706719560, "example.com")).unwrap();
+        assert_eq!(domain, "example.com");
+        assert_eq!(time, DateTime::from_timestamp(1706719560, 0).unwrap());
+        assert_eq!(parse_ltsv("time:1706719560\thost:127.0.0.1"), None);
+    }
+
+    #[test]
+    fn blocks_are_aggregated_per_domain() {
+        let at = |secs| DateTime::from_timestamp(secs, 0).unwrap();
+        let events = aggregate(
+            [
+                ("Example.com.".to_string(), at(20)),
+                ("example.com".to_string(), at(10)),
+                (".".to_string(), at(15)),
+                ("ads.test".to_string(), at(30)),
+                ("example.com".to_string(), at(40)),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].domain, "ads.test");
+        assert_eq!(events[0].count, 1);
+        assert_eq!(events[1].domain, "example.com");
+        assert_eq!(events[1].count, 3);
+        assert_eq!(events[1].first_seen, at(10));
+        assert_eq!(events[1].last_seen, at(40));
+    }
+
+    #[test]
+    fn the_log_is_tailed_across_partial_lines_truncation_and_rotation() {
+        let config_path =
+            config_file("[blocked_names]\nlog_file = 'blocked.log'\nlog_format = 'ltsv'\n");
+        let log = config_path.with_file_name("blocked.log");
+        append(&log, &block(1, "old.test"));
+        let collector = DnsBlockCollector::new(Some(config_path));
+        assert!(domains(&collector).is_empty(), "existing lines are skipped");
+
+        let partial = block(2, "partial.test");
+        let (start, end) = partial.split_at(10);
+        append(&log, &format!("{}{start}", block(2, "a.test")));
+        assert_eq!(domains(&collector), ["a.test"]);
+        append(&log, end);
+        assert_eq!(domains(&collector), ["partial.test"]);
+
+        // Truncated below the read position: read again from the start.
+        fs::write(&log, block(3, "b.test")).unwrap();
+        assert_eq!(domains(&collector), ["b.test"]);
+
+        // Rewritten in place past the read position, keeping the creation
+        // time as tunneling would: the new first line gives it away.
+        let rotated = [4, 5, 6].map(|secs| block(secs, "c.test")).concat();
+        fs::write(&log, rotated).unwrap();
+        let events = collect(&collector);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].count, 3);
+
+        fs::remove_file(&log).unwrap();
+        assert!(domains(&collector).is_empty());
+        append(&log, &block(7, "d.test"));
+        assert_eq!(domains(&collector), ["d.test"]);
+
+        // Blocks read by a collect that was never stored are read again.
+        append(&log, &block(8, "e.test"));
+        let (events, _) = collector.collect().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(domains(&collector), ["e.test"]);
+    }
+
+    #[test]
+    fn the_shipped_configuration_logs_blocked_names() {
+        let config_path = config_file(SHIPPED_CONFIG);
+        let (log_path, ltsv) = blocked_names_log(&config_path).unwrap().unwrap();
+        assert_eq!(log_path, config_path.with_file_name("blocked-names.log"));
+        assert!(!ltsv);
+        assert_eq!(intact(SHIPPED_CONFIG), Some(true));
+    }
+
+    #[test]
+    fn tuning_and_comments_do_not_count_as_tampering() {
+        let tuned = SHIPPED_CONFIG
+            .replace("cache_size = 4096", "cache_size = 512")
+            .replacen("# log_format = 'tsv'", "log_format = 'ltsv'", 1);
+        assert_eq!(intact(&format!("# edited by hand\n{tuned}")), Some(true));
+    }
+
+    #[test]
+    fn filtering_changes_count_as_tampering() {
+        let edits = [
+            (
+                "\nlisten_addresses = ['127.0.0.1:53', '[::1]:53']",
+                "\nlisten_addresses = ['0.0.0.0:53']",
+            ),
+            ("\n# server_names = [", "\nserver_names = ["),
+            ("\n# forwarding_rules =", "\nforwarding_rules ="),
+            ("\n# blocked_names_file =", "\nblocked_names_file ="),
+            ("\nlog_file = 'blocked-names.log'", "\n"),
+        ];
+        for (from, to) in edits {
+            assert!(SHIPPED_CONFIG.contains(from), "{from}");
+            assert_eq!(
+                intact(&SHIPPED_CONFIG.replacen(from, to, 1)),
+                Some(false),
+                "{to}"
+            );
+        }
+        assert_eq!(intact("listen_addresses = ["), Some(false));
+        let missing =
+            LocalResolver::new(Some(std::env::temp_dir().join(Uuid::new_v4().to_string())));
+        assert_eq!(missing.config_intact(), Some(false));
+        assert_eq!(LocalResolver::new(None).config_intact(), None);
+    }
+}
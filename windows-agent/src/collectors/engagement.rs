@@ -0,0 +1,63 @@
+//! Opt-in engagement measurement. The only input read is the system's
+//! last-input timestamp, so all this records is *whether* any keyboard or
+//! mouse input happened between two samples. No keys, buttons or pointer
+//! positions are ever observed.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use windows::Win32::UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO};
+
+/// Tick count (milliseconds since boot) of the last input in this session.
+pub fn last_input_tick() -> Result<u32> {
+    let mut info = LASTINPUTINFO {
+        cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+        dwTime: 0,
+    };
+    unsafe { GetLastInputInfo(&mut info) }
+        .ok()
+        .context("GetLastInputInfo failed")?;
+    Ok(info.dwTime)
+}
+
+/// Detects input between consecutive samples.
+#[derive(Default)]
+pub struct InputActivity {
+    last_input_tick: Option<u32>,
+}
+
+impl InputActivity {
+    /// True when there was input since the previous call. The first call
+    /// only records a baseline.
+    pub fn sample(&mut self) -> Result<bool> {
+        let tick = last_input_tick()?;
+        let active = self.last_input_tick.is_some_and(|last| last != tick);
+        self.last_input_tick = Some(tick);
+        Ok(active)
+    }
+}
+
+/// Per-session count of samples, and of those with input.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct EngagementCounts {
+    samples: u32,
+    active_samples: u32,
+}
+
+impl EngagementCounts {
+    pub fn record(&mut self, active: bool) {
+        self.samples += 1;
+        if active {
+            self.active_samples += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: EngagementCounts) {
+        self.samples += other.samples;
+        self.active_samples += other.active_samples;
+    }
+
+    /// Share of samples with input, or `None` if nothing was recorded.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.samples > 0).then(|| f64::from(self.active_samples) / f64::from(self.samples))
+    }
+}
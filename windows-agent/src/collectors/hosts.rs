@@ -0,0 +1,139 @@
+//! Attribution for interpreter processes (`javaw.exe`, `python.exe`, ...),
+//! which would otherwise all report as the interpreter: the main jar, class
+//! or script is taken from the process command line.
+
+use std::path::Path;
+
+use windows::Wdk::System::Threading::{NtQueryInformationProcess, ProcessCommandLineInformation};
+use windows::Win32::Foundation::{CloseHandle, UNICODE_STRING};
+use windows::Win32::System::Threading::{OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION};
+
+pub const DEFAULT_SCRIPT_HOSTS: [&str; 6] = [
+    "java.exe",
+    "javaw.exe",
+    "python.exe",
+    "pythonw.exe",
+    "wscript.exe",
+    "cscript.exe",
+];
+
+/// Java options whose value is a separate argument.
+const JAVA_VALUE_OPTIONS: [&str; 6] = [
+    "-cp",
+    "-classpath",
+    "--class-path",
+    "-p",
+    "--module-path",
+    "--add-modules",
+];
+
+/// Command line of another process, or `None` if it cannot be read (e.g.
+/// an elevated process).
+pub fn command_line(pid: u32) -> Option<String> {
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) }.ok()?;
+    // The result is a UNICODE_STRING header followed by its characters.
+    let mut buffer = vec![0u64; 4096];
+    let mut needed = 0u32;
+    let status = unsafe {
+        NtQueryInformationProcess(
+            handle,
+            ProcessCommandLineInformation,
+            buffer.as_mut_ptr().cast(),
+            (buffer.len() * std::mem::size_of::<u64>()) as u32,
+            &mut needed,
+        )
+    };
+    unsafe {
+        let _ = CloseHandle(handle);
+    }
+    if status.is_err() {
+        return None;
+    }
+    let text = unsafe {
+        let header = &*(buffer.as_ptr() as *const UNICODE_STRING);
+        if header.Buffer.is_null() || header.Length == 0 {
+            return None;
+        }
+        std::slice::from_raw_parts(header.Buffer.0, usize::from(header.Length) / 2)
+    };
+    Some(String::from_utf16_lossy(text))
+}
+
+/// Stable name of what `host` is running: the jar, main class, module or
+/// script file name, lowercased. `None` when the command line names none.
+pub fn args_hint(host: &str, command_line: &str) -> Option<String> {
+    let args = split_args(command_line);
+    let mut args = args.iter().skip(1).map(String::as_str);
+    let target = match host {
+        "java.exe" | "javaw.exe" => java_target(&mut args),
+        "python.exe" | "pythonw.exe" => python_target(&mut args),
+        _ => args.find(|arg| !arg.starts_with('-') && !arg.starts_with('/')),
+    }?;
+    let name = Path::new(target)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| target.to_string());
+    Some(name.to_lowercase())
+}
+
+fn java_target<'a>(args: &mut impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    while let Some(arg) = args.next() {
+        if arg == "-jar" || arg == "-m" || arg == "--module" {
+            return args.next();
+        }
+        if JAVA_VALUE_OPTIONS.contains(&arg) {
+            args.next();
+        } else if !arg.starts_with('-') {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+fn python_target<'a>(args: &mut impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    while let Some(arg) = args.next() {
+        match arg {
+            "-m" => return args.next(),
+            // Inline code has no name worth reporting.
+            "-c" => return None,
+            "-W" | "-X" => {
+                args.next();
+            }
+            _ if arg.starts_with('-') => {}
+            _ => return Some(arg),
+        }
+    }
+    None
+}
+
+/// Splits a Windows command line on whitespace outside double quotes. The
+/// backslash escapes of `CommandLineToArgvW` do not matter for the paths and
+/// names looked for here.
+fn split_args(command_line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut in_arg = false;
+    for c in command_line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
@@ -0,0 +1,119 @@
+//! Audio playback detection, so that watching a video without touching the
+//! mouse does not count as idle. Only the output level of each audio session
+//! is read, never the audio itself.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use windows::core::Interface;
+use windows::Win32::Media::Audio::Endpoints::IAudioMeterInformation;
+use windows::Win32::Media::Audio::{
+    eMultimedia, eRender, AudioSessionStateActive, IAudioSessionControl2, IAudioSessionManager2,
+    IMMDeviceEnumerator, MMDeviceEnumerator,
+};
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CLSCTX_ALL, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED,
+};
+
+/// Peak level below which a session counts as silent; an active stream
+/// playing nothing reports exactly 0.
+const AUDIBLE_PEAK: f32 = 0.001;
+
+/// Source of which processes are currently playing sound.
+pub trait AudioActivity: Send + Sync {
+    fn audible_processes(&self) -> Result<Vec<u32>>;
+}
+
+/// Reads the audio sessions of the default playback device through WASAPI.
+pub struct WasapiActivity;
+
+impl AudioActivity for WasapiActivity {
+    fn audible_processes(&self) -> Result<Vec<u32>> {
+        unsafe {
+            // S_FALSE when this thread already joined the MTA; either way the
+            // thread stays initialized for the process lifetime.
+            let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+            let devices: IMMDeviceEnumerator =
+                CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_INPROC_SERVER)
+                    .context("failed to create device enumerator")?;
+            let device = devices
+                .GetDefaultAudioEndpoint(eRender, eMultimedia)
+                .context("no default playback device")?;
+            let manager: IAudioSessionManager2 = device
+                .Activate(CLSCTX_ALL, None)
+                .context("failed to activate audio session manager")?;
+            let sessions = manager.GetSessionEnumerator()?;
+            let mut audible = Vec::new();
+            for index in 0..sessions.GetCount()? {
+                let Ok(session) = sessions.GetSession(index) else {
+                    continue;
+                };
+                if session.GetState().ok() != Some(AudioSessionStateActive) {
+                    continue;
+                }
+                let Ok(meter) = session.cast::<IAudioMeterInformation>() else {
+                    continue;
+                };
+                if meter.GetPeakValue().unwrap_or(0.0) < AUDIBLE_PEAK {
+                    continue;
+                }
+                if let Ok(pid) = session
+                    .cast::<IAudioSessionControl2>()
+                    .and_then(|control| control.GetProcessId())
+                {
+                    audible.push(pid);
+                }
+            }
+            Ok(audible)
+        }
+    }
+}
+
+/// Per-session count of samples, and of those with audible playback.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MediaCounts {
+    samples: u32,
+    media_samples: u32,
+}
+
+impl MediaCounts {
+    pub fn record(&mut self, media: bool) {
+        self.samples += 1;
+        if media {
+            self.media_samples += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: MediaCounts) {
+        self.samples += other.samples;
+        self.media_samples += other.media_samples;
+    }
+
+    /// Playing audio in more than half of the samples.
+    pub fn predominant(&self) -> Option<bool> {
+        (self.samples > 0).then(|| self.media_samples * 2 > self.samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(samples: &[bool]) -> MediaCounts {
+        let mut counts = MediaCounts::default();
+        for &media in samples {
+            counts.record(media);
+        }
+        counts
+    }
+
+    #[test]
+    fn media_is_predominant_above_half_the_samples() {
+        assert_eq!(MediaCounts::default().predominant(), None);
+        assert_eq!(counts(&[true, true, false]).predominant(), Some(true));
+        assert_eq!(counts(&[true, false]).predominant(), Some(false));
+
+        let mut merged = counts(&[false, false]);
+        merged.merge(counts(&[true, true, true]));
+        assert_eq!(merged.predominant(), Some(true));
+    }
+}
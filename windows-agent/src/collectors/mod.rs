@@ -1,3 +1,15 @@
+pub mod antivirus;
+pub mod background;
+pub mod browser;
+pub mod connections;
+pub mod connectivity;
+pub mod daily;
+pub mod display;
+pub mod dns;
+pub mod engagement;
+pub mod hosts;
+pub mod media;
 pub mod network;
 pub mod sessions;
 pub mod status;
+pub mod system_events;
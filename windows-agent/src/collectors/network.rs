@@ -1,61 +1,397 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ptr;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use windows::core::GUID;
 use windows::Win32::Foundation::WIN32_ERROR;
 use windows::Win32::NetworkManagement::IpHelper::{
-    FreeMibTable, GetIfTable2, MIB_IF_ROW2, MIB_IF_TABLE2,
+    FreeMibTable, GetIfTable2, IF_TYPE_SOFTWARE_LOOPBACK, IF_TYPE_TUNNEL, MIB_IF_ROW2,
+    MIB_IF_TABLE2,
 };
 use windows::Win32::NetworkManagement::Ndis::IF_OPER_STATUS;
+use windows::Win32::System::SystemInformation::GetTickCount64;
 
-use crate::models::{NetworkCounters, NetworkDelta};
+use crate::collectors::sessions::query_process_image;
+use crate::collectors::{connections, connectivity};
+use crate::config::UsageConfigStore;
+use crate::models::{AdapterInfo, CounterResetMode, NetworkCounters, NetworkDelta};
 use crate::storage::NetworkCounterStore;
 
-const WIFI_TYPE: u32 = 71;
+const ETHERNET_TYPE: u32 = 6;
 const CELLULAR_TYPES: [u32; 2] = [243, 244];
+const WIFI_TYPE: u32 = 71;
+/// Adapters listed in the device status, default-route ones first.
+const MAX_STATUS_ADAPTERS: usize = 4;
+/// Boot times derived from the tick count drift with clock adjustments;
+/// anything closer than this is the same boot.
+const BOOT_TIME_TOLERANCE_SECS: i64 = 120;
+/// Counters are keyed by interface LUID; older agents keyed them by
+/// description, which is neither unique nor stable across driver updates.
+const LUID_KEY_PREFIX: &str = "luid:";
+/// `InterfaceAndOperStatusFlags` bits.
+const HARDWARE_INTERFACE_FLAG: u8 = 0x01;
+const FILTER_INTERFACE_FLAG: u8 = 0x02;
+/// Description fragments of virtual adapters whose traffic is either local
+/// or already counted on a physical adapter.
+const VIRTUAL_ADAPTERS: [&str; 9] = [
+    "hyper-v",
+    "vethernet",
+    "virtualbox",
+    "vmware",
+    "wsl",
+    "docker",
+    "tap-windows",
+    "wintun",
+    "loopback",
+];
 
 pub struct NetworkUsageCollector {
     store: Arc<NetworkCounterStore>,
+    config_store: Arc<UsageConfigStore>,
 }
 
 impl NetworkUsageCollector {
-    pub fn new(store: Arc<NetworkCounterStore>) -> Self {
-        Self { store }
+    pub fn new(store: Arc<NetworkCounterStore>, config_store: Arc<UsageConfigStore>) -> Self {
+        Self {
+            store,
+            config_store,
+        }
     }
 
-    pub fn collect(&self) -> Result<Vec<NetworkDelta>> {
+    /// Traffic since the baseline, and the counters that replace it once
+    /// the deltas are stored.
+    pub fn collect(&self) -> Result<(Vec<NetworkDelta>, NetworkBaseline<'_>)> {
         let now = Utc::now();
-        let totals = unsafe { snapshot_interfaces(now)? };
+        let exclusions = self.config_store.network_exclusions();
+        let metered = connectivity::metered_adapters().unwrap_or_else(|err| {
+            log::debug!("connection cost unavailable: {err:?}");
+            HashSet::new()
+        });
+        let ssids = self.ssids();
+        let totals = unsafe { snapshot_interfaces(now, &exclusions, &metered, &ssids)? };
         let previous = self.store.load();
+        let boot = boot_time(now);
+        let rebooted = self
+            .store
+            .boot_time()
+            .is_some_and(|stored| (stored - boot).num_seconds().abs() > BOOT_TIME_TOLERANCE_SECS);
+        let reset_mode = self.config_store.counter_reset_mode();
+        // Counters in an older format cannot be diffed against current ones,
+        // so this collect only records a fresh baseline.
+        let migrating = !previous.is_empty() && self.store.outdated();
         let mut outputs = Vec::new();
 
-        for (iface, total) in totals.iter() {
-            let last = previous.get(iface);
-            let delta_wifi = last
-                .map(|c| total.wifi_total.saturating_sub(c.wifi_total))
-                .unwrap_or(total.wifi_total);
-            let delta_cell = last
-                .map(|c| total.cell_total.saturating_sub(c.cell_total))
-                .unwrap_or(total.cell_total);
-            if delta_wifi == 0 && delta_cell == 0 {
+        for (iface, total) in totals.iter().filter(|_| !migrating) {
+            let delta = counter_delta(total, previous.get(iface), rebooted, reset_mode);
+            if delta.is_empty() {
                 continue;
             }
-            outputs.push(NetworkDelta {
-                package: format!("iface::{iface}"),
-                sampled_at: now,
-                wifi_bytes: delta_wifi,
-                cellular_bytes: delta_cell,
-            });
+            let link = Link {
+                metered: total.metered,
+                ssid: total.ssid.clone(),
+            };
+            outputs.push(delta.into_delta(format!("iface::{}", total.description), now, &link));
+        }
+
+        let (usage, connections) = self.process_usage();
+        let baseline = NetworkBaseline {
+            store: &self.store,
+            totals,
+            boot,
+            connections,
+        };
+        Ok((attribute(outputs, usage, now), baseline))
+    }
+
+    /// Bytes counted on the interfaces since the last collect, without
+    /// moving the baseline. Interfaces without a usable baseline count as
+    /// nothing.
+    pub fn pending_bytes(&self) -> Result<u64> {
+        if self.store.outdated() {
+            return Ok(0);
+        }
+        let exclusions = self.config_store.network_exclusions();
+        let totals = unsafe {
+            snapshot_interfaces(Utc::now(), &exclusions, &HashSet::new(), &HashMap::new())?
+        };
+        let previous = self.store.load();
+        Ok(totals
+            .iter()
+            .filter_map(|(iface, total)| {
+                let last = Traffic::of_counters(previous.get(iface)?);
+                Traffic::of_counters(total).since(last)
+            })
+            .map(Traffic::total)
+            .sum())
+    }
+
+    /// Wi-Fi network the machine is connected to, for the device status.
+    pub fn current_ssid(&self) -> Option<String> {
+        let mut ssids: Vec<String> = self.ssids().into_values().collect();
+        ssids.sort();
+        ssids.into_iter().next()
+    }
+
+    /// Connected SSIDs by adapter GUID; empty when collection is disabled
+    /// or the WLAN service is unavailable.
+    fn ssids(&self) -> HashMap<GUID, String> {
+        if !self.config_store.ssid_collection() {
+            return HashMap::new();
+        }
+        connectivity::connected_ssids().unwrap_or_else(|err| {
+            log::debug!("Wi-Fi SSID unavailable: {err:?}");
+            HashMap::new()
+        })
+    }
+
+    /// TCP bytes per process image since the previous collect, and the
+    /// connection counters they were taken from. Failures leave all traffic
+    /// on the interfaces.
+    fn process_usage(&self) -> (HashMap<String, u64>, Option<HashMap<String, u64>>) {
+        let current = match connections::snapshot_connections() {
+            Ok(current) => current,
+            Err(err) => {
+                log::warn!("failed to read TCP connections: {err:?}");
+                return (HashMap::new(), None);
+            }
+        };
+        let deltas = connections::process_deltas(&current, &self.store.load_connections());
+        let counters = current
+            .into_iter()
+            .map(|(key, connection)| (key, connection.bytes))
+            .collect();
+        let mut usage: HashMap<String, u64> = HashMap::new();
+        for (pid, bytes) in deltas {
+            if let Ok(Some(package)) = query_process_image(pid) {
+                *usage.entry(package).or_default() += bytes;
+            }
         }
+        (usage, Some(counters))
+    }
+}
+
+/// Counters read by [`NetworkUsageCollector::collect`]. Dropping them
+/// without [`commit`](Self::commit) keeps the old baseline, so the next
+/// collect reports the same traffic again.
+pub struct NetworkBaseline<'a> {
+    store: &'a NetworkCounterStore,
+    totals: HashMap<String, NetworkCounters>,
+    boot: DateTime<Utc>,
+    connections: Option<HashMap<String, u64>>,
+}
 
-        self.store.save(totals)?;
-        Ok(outputs)
+impl NetworkBaseline<'_> {
+    /// Makes these counters the baseline. The store keeps them in memory
+    /// even when writing them fails, so this process does not report the
+    /// traffic twice.
+    pub fn commit(self) {
+        if let Err(err) = self.store.save(self.totals, self.boot) {
+            log::warn!("failed to save network counters: {err:?}");
+        }
+        if let Some(connections) = self.connections {
+            if let Err(err) = self.store.save_connections(connections) {
+                log::warn!("failed to save connection counters: {err:?}");
+            }
+        }
     }
 }
 
-unsafe fn snapshot_interfaces(now: DateTime<Utc>) -> Result<HashMap<String, NetworkCounters>> {
+/// Moves the traffic claimed by processes out of the interface deltas, so
+/// all entries together still add up to the interface counters. Process
+/// bytes are split between Wi-Fi, cellular and Ethernet in the interfaces'
+/// overall proportion, and scaled down if processes claim more than the
+/// interfaces saw (TCP payload vs. octets on the wire). Likewise, a process
+/// gets one entry per link (cost and Wi-Fi network) in proportion to the
+/// link's share of the traffic.
+fn attribute(
+    interfaces: Vec<NetworkDelta>,
+    processes: HashMap<String, u64>,
+    now: DateTime<Utc>,
+) -> Vec<NetworkDelta> {
+    let mut by_link: BTreeMap<Link, Traffic> = BTreeMap::new();
+    for delta in &interfaces {
+        let traffic = by_link.entry(Link::of_delta(delta)).or_default();
+        *traffic = traffic.plus(Traffic::of_delta(delta));
+    }
+    let total: u64 = by_link.values().map(|traffic| traffic.total()).sum();
+    let claimed: u64 = processes.values().sum();
+    if total == 0 || claimed == 0 {
+        return interfaces;
+    }
+    let attributed = claimed.min(total);
+    let mut outputs: Vec<NetworkDelta> = processes
+        .into_iter()
+        .flat_map(|(package, bytes)| {
+            let share = scale(bytes, attributed, claimed);
+            by_link
+                .iter()
+                .map(|(link, traffic)| {
+                    traffic
+                        .split(share, total)
+                        .into_delta(package.clone(), now, link)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let remaining = total - attributed;
+    outputs.extend(interfaces.into_iter().map(|delta| {
+        Traffic::of_delta(&delta)
+            .split(remaining, total)
+            .into_delta(delta.package.clone(), now, &Link::of_delta(&delta))
+    }));
+    outputs.retain(|delta| !Traffic::of_delta(delta).is_empty());
+    outputs
+}
+
+/// Connection properties that set deltas apart beyond their bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Link {
+    metered: bool,
+    ssid: Option<String>,
+}
+
+impl Link {
+    fn of_delta(delta: &NetworkDelta) -> Self {
+        Self {
+            metered: delta.metered,
+            ssid: delta.ssid.clone(),
+        }
+    }
+}
+
+/// Bytes per transport category, and the same bytes by direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Traffic {
+    wifi: u64,
+    cell: u64,
+    ethernet: u64,
+    rx: u64,
+    tx: u64,
+}
+
+impl Traffic {
+    fn of_counters(counters: &NetworkCounters) -> Self {
+        Self {
+            wifi: counters.wifi_total,
+            cell: counters.cell_total,
+            ethernet: counters.ethernet_total,
+            rx: counters.rx_total,
+            tx: counters.tx_total,
+        }
+    }
+
+    fn of_delta(delta: &NetworkDelta) -> Self {
+        Self {
+            wifi: delta.wifi_bytes,
+            cell: delta.cellular_bytes,
+            ethernet: delta.ethernet_bytes,
+            rx: delta.rx_bytes,
+            tx: delta.tx_bytes,
+        }
+    }
+
+    fn total(self) -> u64 {
+        self.wifi + self.cell + self.ethernet
+    }
+
+    fn is_empty(self) -> bool {
+        self.total() == 0
+    }
+
+    fn plus(self, other: Self) -> Self {
+        Self {
+            wifi: self.wifi + other.wifi,
+            cell: self.cell + other.cell,
+            ethernet: self.ethernet + other.ethernet,
+            rx: self.rx + other.rx,
+            tx: self.tx + other.tx,
+        }
+    }
+
+    /// `None` if any counter is below its value in `earlier`.
+    fn since(self, earlier: Self) -> Option<Self> {
+        Some(Self {
+            wifi: self.wifi.checked_sub(earlier.wifi)?,
+            cell: self.cell.checked_sub(earlier.cell)?,
+            ethernet: self.ethernet.checked_sub(earlier.ethernet)?,
+            rx: self.rx.checked_sub(earlier.rx)?,
+            tx: self.tx.checked_sub(earlier.tx)?,
+        })
+    }
+
+    /// `bytes` split between the categories and directions in this
+    /// traffic's proportion, whose total is `total`.
+    fn split(self, bytes: u64, total: u64) -> Self {
+        Self {
+            wifi: scale(self.wifi, bytes, total),
+            cell: scale(self.cell, bytes, total),
+            ethernet: scale(self.ethernet, bytes, total),
+            rx: scale(self.rx, bytes, total),
+            tx: scale(self.tx, bytes, total),
+        }
+    }
+
+    fn into_delta(self, package: String, sampled_at: DateTime<Utc>, link: &Link) -> NetworkDelta {
+        NetworkDelta {
+            package,
+            sampled_at,
+            wifi_bytes: self.wifi,
+            cellular_bytes: self.cell,
+            ethernet_bytes: self.ethernet,
+            metered: link.metered,
+            rx_bytes: self.rx,
+            tx_bytes: self.tx,
+            ssid: link.ssid.clone(),
+            spike: false,
+        }
+    }
+}
+
+/// `value * numerator / denominator` without overflow.
+fn scale(value: u64, numerator: u64, denominator: u64) -> u64 {
+    (u128::from(value) * u128::from(numerator) / u128::from(denominator)) as u64
+}
+
+/// Connected physical adapters for the device status.
+pub fn adapters() -> Result<Vec<AdapterInfo>> {
+    let default_routes = connectivity::default_route_interfaces().unwrap_or_default();
+    let mut adapters = Vec::new();
+    unsafe {
+        for_each_counted_interface(&[], |row, description| {
+            adapters.push(AdapterInfo {
+                description,
+                media: media_name(row.Type).to_string(),
+                link_speed_bps: row.TransmitLinkSpeed,
+                default_route: default_routes.contains(&row.InterfaceLuid.Value),
+            });
+        })?;
+    }
+    adapters.sort_by(|a, b| {
+        b.default_route
+            .cmp(&a.default_route)
+            .then_with(|| a.description.cmp(&b.description))
+    });
+    adapters.truncate(MAX_STATUS_ADAPTERS);
+    Ok(adapters)
+}
+
+fn media_name(if_type: u32) -> &'static str {
+    match if_type {
+        WIFI_TYPE => "wifi",
+        ETHERNET_TYPE => "ethernet",
+        t if CELLULAR_TYPES.contains(&t) => "cellular",
+        _ => "other",
+    }
+}
+
+/// Calls `f` with each connected interface that counts towards the totals,
+/// and its description.
+unsafe fn for_each_counted_interface(
+    exclusions: &[String],
+    mut f: impl FnMut(&MIB_IF_ROW2, String),
+) -> Result<()> {
     let mut table_ptr: *mut MIB_IF_TABLE2 = ptr::null_mut();
     let status = GetIfTable2(&mut table_ptr);
     if status != WIN32_ERROR(0) {
@@ -63,7 +399,6 @@ unsafe fn snapshot_interfaces(now: DateTime<Utc>) -> Result<HashMap<String, Netw
     }
     let table = &*table_ptr;
     let rows = std::slice::from_raw_parts(table.Table.as_ptr(), table.NumEntries as usize);
-    let mut map = HashMap::new();
     for row in rows {
         if row.OperStatus != IF_OPER_STATUS(1) {
             continue;
@@ -72,32 +407,380 @@ unsafe fn snapshot_interfaces(now: DateTime<Utc>) -> Result<HashMap<String, Netw
         if desc.is_empty() {
             continue;
         }
-        let (wifi, cell) = categorize_bytes(row);
+        let flags = row.InterfaceAndOperStatusFlags._bitfield;
+        if let Some(reason) = exclusion_reason(row.Type, flags, &desc, exclusions) {
+            log::debug!("excluding interface {desc:?} (type {}): {reason}", row.Type);
+            continue;
+        }
+        f(row, desc);
+    }
+    FreeMibTable(table_ptr as _);
+    Ok(())
+}
+
+unsafe fn snapshot_interfaces(
+    now: DateTime<Utc>,
+    exclusions: &[String],
+    metered: &HashSet<GUID>,
+    ssids: &HashMap<GUID, String>,
+) -> Result<HashMap<String, NetworkCounters>> {
+    let mut map = HashMap::new();
+    for_each_counted_interface(exclusions, |row, desc| {
+        let traffic = categorize_bytes(row);
         map.insert(
-            desc,
+            format!("{LUID_KEY_PREFIX}{:016x}", row.InterfaceLuid.Value),
             NetworkCounters {
-                wifi_total: wifi,
-                cell_total: cell,
+                wifi_total: traffic.wifi,
+                cell_total: traffic.cell,
+                ethernet_total: traffic.ethernet,
+                rx_total: traffic.rx,
+                tx_total: traffic.tx,
                 sampled_at: now,
+                description: desc,
+                metered: metered.contains(&row.InterfaceGuid),
+                ssid: ssids.get(&row.InterfaceGuid).cloned(),
             },
         );
-    }
-    FreeMibTable(table_ptr as _);
+    })?;
     Ok(map)
 }
 
-fn wide_to_string(buf: &[u16]) -> String {
+/// Why an interface is left out of the totals, or `None` if it counts.
+/// `extra` holds configured description fragments on top of the built-in
+/// virtual adapter list; all matching is case-insensitive.
+fn exclusion_reason(
+    if_type: u32,
+    flags: u8,
+    description: &str,
+    extra: &[String],
+) -> Option<&'static str> {
+    if if_type == IF_TYPE_SOFTWARE_LOOPBACK {
+        return Some("loopback");
+    }
+    if if_type == IF_TYPE_TUNNEL {
+        return Some("tunnel");
+    }
+    if flags & FILTER_INTERFACE_FLAG != 0 {
+        return Some("filter driver on another interface");
+    }
+    if flags & HARDWARE_INTERFACE_FLAG == 0 {
+        return Some("not backed by hardware");
+    }
+    let description = description.to_lowercase();
+    if VIRTUAL_ADAPTERS
+        .iter()
+        .any(|fragment| description.contains(fragment))
+    {
+        return Some("virtual adapter");
+    }
+    if extra
+        .iter()
+        .any(|fragment| description.contains(&fragment.to_lowercase()))
+    {
+        return Some("configured exclusion");
+    }
+    None
+}
+
+fn boot_time(now: DateTime<Utc>) -> DateTime<Utc> {
+    now - Duration::milliseconds(unsafe { GetTickCount64() } as i64)
+}
+
+/// Bytes per category since `last`. After a reboot, or when a counter went
+/// backwards because the adapter was re-enumerated, the stored baseline is
+/// meaningless and `reset_mode` decides what is reported. An interface seen
+/// for the first time reports everything since boot.
+fn counter_delta(
+    total: &NetworkCounters,
+    last: Option<&NetworkCounters>,
+    rebooted: bool,
+    reset_mode: CounterResetMode,
+) -> Traffic {
+    let since_reset = Traffic::of_counters(total);
+    let Some(last) = last.map(Traffic::of_counters) else {
+        return since_reset;
+    };
+    match (since_reset.since(last).filter(|_| !rebooted), reset_mode) {
+        (Some(delta), _) => delta,
+        (None, CounterResetMode::SinceReset) => since_reset,
+        (None, CounterResetMode::Zero) => Traffic::default(),
+    }
+}
+
+pub(crate) fn wide_to_string(buf: &[u16]) -> String {
     let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
     String::from_utf16_lossy(&buf[..len]).trim().to_string()
 }
 
-fn categorize_bytes(row: &MIB_IF_ROW2) -> (u64, u64) {
+/// Wireless (type 71) and all other interface types count as Wi-Fi, as
+/// they always have.
+fn categorize_bytes(row: &MIB_IF_ROW2) -> Traffic {
     let total = row.InOctets + row.OutOctets;
-    if row.Type == WIFI_TYPE {
-        (total, 0)
+    let traffic = Traffic {
+        rx: row.InOctets,
+        tx: row.OutOctets,
+        ..Traffic::default()
+    };
+    if row.Type == ETHERNET_TYPE {
+        Traffic {
+            ethernet: total,
+            ..traffic
+        }
     } else if CELLULAR_TYPES.contains(&row.Type) {
-        (0, total)
+        Traffic {
+            cell: total,
+            ..traffic
+        }
     } else {
-        (total, 0)
+        Traffic {
+            wifi: total,
+            ..traffic
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traffic(wifi: u64, cell: u64, ethernet: u64) -> Traffic {
+        let total = wifi + cell + ethernet;
+        Traffic {
+            wifi,
+            cell,
+            ethernet,
+            rx: total - total / 4,
+            tx: total / 4,
+        }
+    }
+
+    fn counters(traffic: Traffic) -> NetworkCounters {
+        NetworkCounters {
+            wifi_total: traffic.wifi,
+            cell_total: traffic.cell,
+            ethernet_total: traffic.ethernet,
+            rx_total: traffic.rx,
+            tx_total: traffic.tx,
+            sampled_at: Utc::now(),
+            description: "Wi-Fi".to_string(),
+            metered: false,
+            ssid: None,
+        }
+    }
+
+    fn bytes_of(deltas: &[NetworkDelta], package: &str) -> u64 {
+        deltas
+            .iter()
+            .filter(|delta| delta.package == package)
+            .map(|delta| Traffic::of_delta(delta).total())
+            .sum()
+    }
+
+    #[test]
+    fn process_traffic_is_moved_out_of_the_interface_totals() {
+        let now = Utc::now();
+        let interfaces = vec![traffic(800, 0, 200).into_delta(
+            "iface::Wi-Fi".to_string(),
+            now,
+            &Link::default(),
+        )];
+        let processes = HashMap::from([
+            ("chrome.exe".to_string(), 300),
+            ("steam.exe".to_string(), 200),
+        ]);
+
+        let deltas = attribute(interfaces, processes, now);
+        assert_eq!(bytes_of(&deltas, "chrome.exe"), 300);
+        assert_eq!(bytes_of(&deltas, "steam.exe"), 200);
+        assert_eq!(bytes_of(&deltas, "iface::Wi-Fi"), 500);
+        let chrome = deltas
+            .iter()
+            .find(|delta| delta.package == "chrome.exe")
+            .unwrap();
+        assert_eq!((chrome.wifi_bytes, chrome.ethernet_bytes), (240, 60));
+    }
+
+    #[test]
+    fn over_claimed_process_traffic_is_scaled_to_the_interfaces() {
+        let now = Utc::now();
+        let interfaces = vec![traffic(1_000, 0, 0).into_delta(
+            "iface::Wi-Fi".to_string(),
+            now,
+            &Link::default(),
+        )];
+        let processes = HashMap::from([("a.exe".to_string(), 3_000), ("b.exe".to_string(), 1_000)]);
+
+        let deltas = attribute(interfaces, processes, now);
+        assert_eq!(bytes_of(&deltas, "a.exe"), 750);
+        assert_eq!(bytes_of(&deltas, "b.exe"), 250);
+        assert!(deltas.iter().all(|delta| delta.package != "iface::Wi-Fi"));
+    }
+
+    #[test]
+    fn without_process_data_the_interfaces_are_reported_as_is() {
+        let now = Utc::now();
+        let interfaces = vec![traffic(1_000, 0, 0).into_delta(
+            "iface::Wi-Fi".to_string(),
+            now,
+            &Link::default(),
+        )];
+        let deltas = attribute(interfaces, HashMap::new(), now);
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(bytes_of(&deltas, "iface::Wi-Fi"), 1_000);
+    }
+
+    #[test]
+    fn counter_deltas_are_taken_against_the_baseline() {
+        let last = counters(traffic(1_000, 0, 0));
+        let total = counters(traffic(1_400, 0, 0));
+        for mode in [CounterResetMode::SinceReset, CounterResetMode::Zero] {
+            assert_eq!(counter_delta(&total, Some(&last), false, mode).total(), 400);
+        }
+        // A new interface reports everything since boot.
+        assert_eq!(
+            counter_delta(&total, None, false, CounterResetMode::Zero).total(),
+            1_400
+        );
+    }
+
+    #[test]
+    fn a_reset_counter_follows_the_reset_mode() {
+        let last = counters(traffic(5_000, 0, 0));
+        let reset = counters(traffic(300, 0, 0));
+        assert_eq!(
+            counter_delta(&reset, Some(&last), false, CounterResetMode::SinceReset).total(),
+            300
+        );
+        assert!(counter_delta(&reset, Some(&last), false, CounterResetMode::Zero).is_empty());
+
+        // After a reboot even a counter that grew past the baseline restarted
+        // from zero.
+        let grown = counters(traffic(6_000, 0, 0));
+        assert_eq!(
+            counter_delta(&grown, Some(&last), true, CounterResetMode::SinceReset).total(),
+            6_000
+        );
+        assert!(counter_delta(&grown, Some(&last), true, CounterResetMode::Zero).is_empty());
+    }
+
+    #[test]
+    fn loopback_tunnel_and_virtual_adapters_are_excluded() {
+        let hardware = HARDWARE_INTERFACE_FLAG;
+        let reason =
+            |if_type, flags, description: &str| exclusion_reason(if_type, flags, description, &[]);
+        assert_eq!(
+            reason(IF_TYPE_SOFTWARE_LOOPBACK, hardware, "Loopback"),
+            Some("loopback")
+        );
+        assert_eq!(reason(IF_TYPE_TUNNEL, hardware, "Teredo"), Some("tunnel"));
+        assert_eq!(
+            reason(ETHERNET_TYPE, 0, "Microsoft Wi-Fi Direct Virtual Adapter"),
+            Some("not backed by hardware")
+        );
+        assert_eq!(
+            reason(
+                ETHERNET_TYPE,
+                hardware | FILTER_INTERFACE_FLAG,
+                "Intel(R) Ethernet"
+            ),
+            Some("filter driver on another interface")
+        );
+        assert_eq!(
+            reason(ETHERNET_TYPE, hardware, "vEthernet (WSL)"),
+            Some("virtual adapter")
+        );
+        assert_eq!(reason(WIFI_TYPE, hardware, "Intel(R) Wi-Fi 6 AX201"), None);
+    }
+
+    #[test]
+    fn configured_exclusions_match_case_insensitively() {
+        let extra = vec!["Cisco AnyConnect".to_string()];
+        assert_eq!(
+            exclusion_reason(
+                ETHERNET_TYPE,
+                HARDWARE_INTERFACE_FLAG,
+                "CISCO ANYCONNECT Virtual Miniport",
+                &extra
+            ),
+            Some("configured exclusion")
+        );
+        assert_eq!(
+            exclusion_reason(
+                ETHERNET_TYPE,
+                HARDWARE_INTERFACE_FLAG,
+                "Realtek PCIe GbE",
+                &extra
+            ),
+            None
+        );
+    }
+
+    fn row(if_type: u32, rx: u64, tx: u64) -> MIB_IF_ROW2 {
+        MIB_IF_ROW2 {
+            Type: if_type,
+            InOctets: rx,
+            OutOctets: tx,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ethernet_is_counted_apart_from_wifi() {
+        let ethernet = categorize_bytes(&row(ETHERNET_TYPE, 700, 300));
+        assert_eq!(
+            (ethernet.ethernet, ethernet.wifi, ethernet.cell),
+            (1_000, 0, 0)
+        );
+        let wifi = categorize_bytes(&row(WIFI_TYPE, 700, 300));
+        assert_eq!((wifi.ethernet, wifi.wifi, wifi.cell), (0, 1_000, 0));
+        let cellular = categorize_bytes(&row(CELLULAR_TYPES[0], 700, 300));
+        assert_eq!(
+            (cellular.ethernet, cellular.wifi, cellular.cell),
+            (0, 0, 1_000)
+        );
+        // Anything else keeps counting as Wi-Fi.
+        assert_eq!(categorize_bytes(&row(1, 700, 300)).wifi, 1_000);
+        assert_eq!(media_name(ETHERNET_TYPE), "ethernet");
+        assert_eq!(media_name(1), "other");
+    }
+
+    #[test]
+    fn process_traffic_is_split_by_metered_link() {
+        let now = Utc::now();
+        let hotspot = Link {
+            metered: true,
+            ssid: Some("Phone".to_string()),
+        };
+        let interfaces = vec![
+            traffic(600, 0, 0).into_delta("iface::Wi-Fi".to_string(), now, &hotspot),
+            traffic(0, 0, 400).into_delta("iface::Ethernet".to_string(), now, &Link::default()),
+        ];
+        let processes = HashMap::from([("steam.exe".to_string(), 500)]);
+
+        let deltas = attribute(interfaces, processes, now);
+        let steam: Vec<_> = deltas
+            .iter()
+            .filter(|delta| delta.package == "steam.exe")
+            .map(|delta| (delta.metered, Traffic::of_delta(delta).total()))
+            .collect();
+        assert_eq!(steam.len(), 2);
+        assert!(steam.contains(&(true, 300)));
+        assert!(steam.contains(&(false, 200)));
+        assert!(deltas
+            .iter()
+            .filter(|delta| delta.metered)
+            .all(|delta| delta.ssid.as_deref() == Some("Phone")));
+    }
+
+    #[test]
+    fn directions_are_kept_alongside_the_category_totals() {
+        let wifi = categorize_bytes(&row(WIFI_TYPE, 700, 300));
+        assert_eq!((wifi.rx, wifi.tx), (700, 300));
+
+        let last = counters(wifi);
+        let total = counters(categorize_bytes(&row(WIFI_TYPE, 1_500, 400)));
+        let delta = counter_delta(&total, Some(&last), false, CounterResetMode::Zero);
+        assert_eq!((delta.rx, delta.tx), (800, 100));
+        assert_eq!(delta.total(), delta.rx + delta.tx);
     }
 }
@@ -1,38 +1,176 @@
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration as StdDuration;
 
 use anyhow::Result;
 use chrono::{DateTime, Duration, Utc};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
 use tauri::async_runtime;
 use tauri::async_runtime::JoinHandle;
 use tokio::time;
-use windows::Win32::Foundation::{CloseHandle, HWND};
+use tokio_util::sync::CancellationToken;
+use windows::core::PWSTR;
+use windows::Win32::Foundation::{CloseHandle, BOOL, ERROR_SUCCESS, HANDLE, HWND, LPARAM};
+use windows::Win32::Storage::Packaging::Appx::{GetApplicationUserModelId, GetPackageFamilyName};
 use windows::Win32::System::ProcessStatus::K32GetModuleBaseNameW;
+use windows::Win32::System::StationsAndDesktops::{
+    CloseDesktop, GetUserObjectInformationW, OpenInputDesktop, DESKTOP_CONTROL_FLAGS,
+    DESKTOP_READOBJECTS, UOI_NAME,
+};
+use windows::Win32::System::SystemInformation::GetTickCount;
 use windows::Win32::System::Threading::{
-    OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION,
+    OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_INFORMATION,
+    PROCESS_QUERY_LIMITED_INFORMATION,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    EnumChildWindows, GetForegroundWindow, GetWindowTextW, GetWindowThreadProcessId,
 };
-use windows::Win32::UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId};
 
-use crate::models::UsageSession;
+use crate::collectors::browser::{self, is_browser};
+use crate::collectors::daily::DailyTotals;
+use crate::collectors::display::{self, Placement, PlacementCounts};
+use crate::collectors::engagement::{self, EngagementCounts, InputActivity};
+use crate::collectors::hosts;
+use crate::collectors::media::{AudioActivity, MediaCounts, WasapiActivity};
+use crate::collectors::{status, system_events};
+use crate::config::UsageConfigStore;
+use crate::models::{AppUsageTotal, SessionPolicy, UsageSession};
+use crate::storage::{self, StoragePaths};
 
-const MIN_SESSION_MS: i64 = 5_000;
-const MERGE_GAP_MS: i64 = 10_000;
-const MAX_SESSION_MS: i64 = 8 * 60 * 60 * 1_000;
-const SAMPLE_INTERVAL_MS: u64 = 5_000;
+const MAX_TITLE_CHARS: usize = 256;
+const SNAPSHOT_VERSION: u32 = 1;
+const SNAPSHOT_INTERVAL: StdDuration = StdDuration::from_secs(60);
+const DEFAULT_BLACKLIST: [&str; 9] = [
+    "explorer.exe",
+    "systemsettings.exe",
+    "taskmgr.exe",
+    "searchui.exe",
+    "sihost.exe",
+    "fontdrvhost*",
+    "applicationframehost*",
+    "shellexperiencehost*",
+    "startmenuexperiencehost*",
+];
+/// Hosts the windows of Store (UWP) apps, which run in their own process.
+const FRAME_HOST: &str = "applicationframehost.exe";
+/// The interactive desktop; UAC prompts and the lock screen run on others.
+const DEFAULT_DESKTOP: &str = "Default";
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct RawSession {
     package: String,
+    #[serde(default)]
+    app_id: Option<String>,
+    #[serde(default)]
+    args_hint: Option<String>,
+    title: Option<String>,
     start: DateTime<Utc>,
     end: DateTime<Utc>,
+    #[serde(default)]
+    engagement: EngagementCounts,
+    #[serde(default)]
+    domain: Option<String>,
+    #[serde(default)]
+    placement: PlacementCounts,
+    #[serde(default)]
+    media: MediaCounts,
+    #[serde(default)]
+    remote: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct ActiveSession {
     package: String,
+    #[serde(default)]
+    app_id: Option<String>,
+    #[serde(default)]
+    args_hint: Option<String>,
+    title: Option<String>,
     started_at: DateTime<Utc>,
     last_seen: DateTime<Utc>,
+    #[serde(default)]
+    engagement: EngagementCounts,
+    #[serde(default)]
+    domain: Option<String>,
+    #[serde(default)]
+    placement: PlacementCounts,
+    #[serde(default)]
+    media: MediaCounts,
+    #[serde(default)]
+    remote: bool,
+}
+
+/// What was in the foreground at a sample.
+#[derive(Clone, Debug)]
+struct ForegroundWindow {
+    /// Process behind the window; for Store apps the hosted app, not the
+    /// frame host.
+    pid: u32,
+    package: String,
+    /// Tells apart processes sharing an executable name, see [`app_id`].
+    app_id: Option<String>,
+    /// What an interpreter process runs, part of the session identity.
+    args_hint: Option<String>,
+    title: Option<String>,
+    /// Whether there was input since the previous sample; `None` unless
+    /// engagement tracking is enabled.
+    input: Option<bool>,
+    /// Site shown in a browser, when domain lookup is enabled and succeeded.
+    domain: Option<String>,
+    /// Monitor and fullscreen state, when the window geometry was readable.
+    placement: Option<Placement>,
+    /// Whether the app was playing sound; `None` when that could not be
+    /// determined.
+    media: Option<bool>,
+    /// Seen through a Remote Desktop session.
+    remote: bool,
+}
+
+impl ForegroundWindow {
+    /// Whether a title change in this window starts a new session.
+    fn splits_on_title(&self, split_browser_titles: bool) -> bool {
+        split_browser_titles && is_browser(&self.package)
+    }
+}
+
+/// A failed lookup (`None`) on either side does not count as a change.
+fn same_domain(current: &Option<String>, sampled: &Option<String>) -> bool {
+    match (current, sampled) {
+        (Some(current), Some(sampled)) => current == sampled,
+        _ => true,
+    }
+}
+
+impl ActiveSession {
+    fn start(window: ForegroundWindow, now: DateTime<Utc>) -> Self {
+        let mut engagement = EngagementCounts::default();
+        if let Some(input) = window.input {
+            engagement.record(input);
+        }
+        let mut placement = PlacementCounts::default();
+        if let Some(sampled) = window.placement {
+            placement.record(sampled);
+        }
+        let mut media = MediaCounts::default();
+        if let Some(playing) = window.media {
+            media.record(playing);
+        }
+        Self {
+            package: window.package,
+            app_id: window.app_id,
+            args_hint: window.args_hint,
+            title: window.title,
+            started_at: now,
+            last_seen: now,
+            engagement,
+            domain: window.domain,
+            placement,
+            media,
+            remote: window.remote,
+        }
+    }
 }
 
 struct TrackerState {
@@ -40,6 +178,14 @@ struct TrackerState {
     completed: Vec<RawSession>,
 }
 
+/// On-disk copy of [`TrackerState`] so a restart does not lose sessions.
+#[derive(Serialize, Deserialize)]
+struct TrackerSnapshot {
+    version: u32,
+    current: Option<ActiveSession>,
+    completed: Vec<RawSession>,
+}
+
 impl TrackerState {
     fn new() -> Self {
         Self {
@@ -48,117 +194,403 @@ impl TrackerState {
         }
     }
 
-    fn finalize_current(&mut self) {
+    /// Restores a snapshot, ending the session that was active at its last
+    /// sample. Missing, corrupt or outdated snapshots yield an empty state.
+    fn restore(path: &Path, policy: &SessionPolicy) -> Self {
+        let snapshot = fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<TrackerSnapshot>(&data).ok())
+            .filter(|snapshot| snapshot.version == SNAPSHOT_VERSION);
+        let Some(snapshot) = snapshot else {
+            return Self::new();
+        };
+        let mut state = Self {
+            current: snapshot.current,
+            completed: snapshot.completed,
+        };
+        state.finalize_current(policy);
+        state
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let snapshot = TrackerSnapshot {
+            version: SNAPSHOT_VERSION,
+            current: self.current.clone(),
+            completed: self.completed.clone(),
+        };
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    fn finalize_current(&mut self, policy: &SessionPolicy) {
         if let Some(active) = self.current.take() {
             let mut end = active.last_seen;
             if end < active.started_at {
                 end = active.started_at;
             }
-            let total_ms = (end - active.started_at).num_milliseconds();
-            if total_ms >= MIN_SESSION_MS {
+            if end - active.started_at >= policy.min_session() {
                 self.completed.push(RawSession {
                     package: active.package,
+                    app_id: active.app_id,
+                    args_hint: active.args_hint,
+                    title: active.title,
                     start: active.started_at,
                     end,
+                    engagement: active.engagement,
+                    domain: active.domain,
+                    placement: active.placement,
+                    media: active.media,
+                    remote: active.remote,
                 });
             }
         }
     }
 
-    fn observe(&mut self, package: Option<String>, now: DateTime<Utc>) {
-        match (self.current.as_mut(), package) {
-            (Some(active), Some(pkg)) if active.package == pkg => {
+    /// A gap longer than the merge gap since the previous sample (typically a
+    /// suspend) ends the session at that sample even if the app is unchanged.
+    fn observe(
+        &mut self,
+        window: Option<ForegroundWindow>,
+        now: DateTime<Utc>,
+        split_browser_titles: bool,
+        policy: &SessionPolicy,
+    ) {
+        match (self.current.as_mut(), window) {
+            (Some(active), Some(window))
+                if active.package == window.package
+                    && active.app_id == window.app_id
+                    && active.args_hint == window.args_hint
+                    && active.remote == window.remote
+                    && now - active.last_seen <= policy.merge_gap()
+                    && same_domain(&active.domain, &window.domain)
+                    && (!window.splits_on_title(split_browser_titles)
+                        || active.title == window.title) =>
+            {
                 active.last_seen = now;
+                if window.title.is_some() {
+                    active.title = window.title;
+                }
+                if let Some(input) = window.input {
+                    active.engagement.record(input);
+                }
+                if window.domain.is_some() {
+                    active.domain = window.domain;
+                }
+                if let Some(placement) = window.placement {
+                    active.placement.record(placement);
+                }
+                if let Some(playing) = window.media {
+                    active.media.record(playing);
+                }
             }
-            (Some(_), Some(pkg)) => {
-                self.finalize_current();
-                self.current = Some(ActiveSession {
-                    package: pkg,
-                    started_at: now,
-                    last_seen: now,
-                });
+            (Some(_), Some(window)) => {
+                self.finalize_current(policy);
+                self.current = Some(ActiveSession::start(window, now));
             }
-            (None, Some(pkg)) => {
-                self.current = Some(ActiveSession {
-                    package: pkg,
-                    started_at: now,
-                    last_seen: now,
-                });
+            (None, Some(window)) => {
+                self.current = Some(ActiveSession::start(window, now));
             }
             (Some(_), None) => {
-                self.finalize_current();
+                self.finalize_current(policy);
             }
             (None, None) => {}
         }
     }
 
-    fn drain(&mut self, now: DateTime<Utc>, window: Duration) -> Vec<RawSession> {
-        let cutoff = now - window;
-        let mut sessions = Vec::new();
-        self.completed.retain(|raw| {
-            if raw.end < cutoff {
-                false
-            } else {
-                sessions.push(raw.clone());
-                true
+    /// Ends the current session at `idle_since`, dropping the idle time that
+    /// was counted before the threshold was reached.
+    fn observe_idle(&mut self, idle_since: DateTime<Utc>, policy: &SessionPolicy) {
+        if let Some(active) = self.current.as_mut() {
+            if idle_since < active.last_seen {
+                active.last_seen = idle_since;
             }
-        });
+        }
+        self.finalize_current(policy);
+    }
+
+    /// Hands over every completed session exactly once; the active session
+    /// stays until it ends.
+    fn drain(&mut self, now: DateTime<Utc>, policy: &SessionPolicy) -> Vec<RawSession> {
         if let Some(active) = self.current.as_ref() {
-            if now - active.last_seen > Duration::milliseconds(MERGE_GAP_MS) {
-                self.finalize_current();
-                if let Some(last) = self.completed.last() {
-                    if last.end >= cutoff {
-                        sessions.push(last.clone());
-                    }
-                }
+            if now - active.last_seen > policy.merge_gap() {
+                self.finalize_current(policy);
             }
         }
-        sessions
+        std::mem::take(&mut self.completed)
+    }
+
+    /// Puts drained sessions back ahead of any completed since, for a drain
+    /// whose batch was never stored.
+    fn hand_back(&mut self, mut raw: Vec<RawSession>) {
+        raw.append(&mut self.completed);
+        self.completed = raw;
+    }
+}
+
+/// Sessions taken by [`SessionCollector::drain_sessions`]. Dropping it
+/// without [`commit`](Self::commit) hands them back to the tracker, so a
+/// collect that fails after draining sends them with the next one.
+pub struct DrainedSessions {
+    collector: SessionCollector,
+    raw: Vec<RawSession>,
+    /// Foreground spans of `sessions`, counted into the daily totals on
+    /// commit.
+    spans: Vec<(String, DateTime<Utc>, DateTime<Utc>)>,
+    pub sessions: Vec<UsageSession>,
+    committed: bool,
+}
+
+impl DrainedSessions {
+    /// Marks the sessions as stored: they count towards today's totals and
+    /// the tracker's snapshot no longer holds them.
+    pub fn commit(mut self) {
+        self.committed = true;
+        let mut daily = self.collector.daily.lock();
+        for (package, start, end) in &self.spans {
+            daily.record(package, *start, *end);
+        }
+        drop(daily);
+        self.collector.save_snapshot(self.collector.state.lock());
+    }
+}
+
+impl Drop for DrainedSessions {
+    fn drop(&mut self) {
+        if !self.committed && !self.raw.is_empty() {
+            self.collector
+                .state
+                .lock()
+                .hand_back(std::mem::take(&mut self.raw));
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct SessionCollector {
     state: Arc<Mutex<TrackerState>>,
+    config_store: Arc<UsageConfigStore>,
+    snapshot_path: PathBuf,
+    /// Serializes snapshot writes, which happen outside the state lock.
+    snapshot_write: Arc<Mutex<()>>,
+    input: Arc<Mutex<InputActivity>>,
+    daily: Arc<Mutex<DailyTotals>>,
+    /// Windows account the agent runs as, attached to every session.
+    user: Option<String>,
+    audio: Arc<dyn AudioActivity>,
+    /// When the sampler last ran, for the health status.
+    last_sample: Arc<Mutex<Option<time::Instant>>>,
 }
 
 impl SessionCollector {
-    pub fn new() -> Self {
+    pub fn new(paths: &StoragePaths, config_store: Arc<UsageConfigStore>) -> Self {
+        let snapshot_path = paths.session_state_path();
+        let state = TrackerState::restore(&snapshot_path, &config_store.session_policy());
         Self {
-            state: Arc::new(Mutex::new(TrackerState::new())),
+            state: Arc::new(Mutex::new(state)),
+            config_store,
+            snapshot_path,
+            snapshot_write: Arc::default(),
+            input: Arc::default(),
+            daily: Arc::new(Mutex::new(DailyTotals::new())),
+            user: std::env::var("USERNAME")
+                .ok()
+                .filter(|user| !user.is_empty()),
+            audio: Arc::new(WasapiActivity),
+            last_sample: Arc::default(),
         }
     }
 
-    pub fn spawn_sampler(&self) -> JoinHandle<()> {
+    /// Encodes `state`, then releases it before writing so sampling never
+    /// waits on the disk. The write lock is taken first, keeping snapshots in
+    /// order.
+    fn save_snapshot(&self, state: MutexGuard<'_, TrackerState>) {
+        let encoded = state.encode();
+        let _write = self.snapshot_write.lock();
+        drop(state);
+        let saved =
+            encoded.and_then(|snapshot| storage::atomic_write(&self.snapshot_path, snapshot));
+        if let Err(err) = saved {
+            log::warn!("failed to save session state: {err:?}");
+        }
+    }
+
+    /// Samples until `cancel` fires; the active session is left for
+    /// `finalize`.
+    pub fn spawn_sampler(&self, cancel: CancellationToken) -> JoinHandle<()> {
+        system_events::start();
         let collector = self.clone();
         async_runtime::spawn(async move {
-            let mut interval = time::interval(StdDuration::from_millis(SAMPLE_INTERVAL_MS));
+            let mut period = collector.config_store.sample_interval();
+            let mut interval = sample_interval(time::Instant::now(), period);
+            let mut last_snapshot = time::Instant::now();
             loop {
-                interval.tick().await;
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancel.cancelled() => break,
+                }
                 if let Err(err) = collector.sample_once() {
                     log::warn!("session sample failed: {err:?}");
                 }
+                *collector.last_sample.lock() = Some(time::Instant::now());
+                if last_snapshot.elapsed() >= SNAPSHOT_INTERVAL {
+                    let saver = collector.clone();
+                    let _ = async_runtime::spawn_blocking(move || {
+                        saver.save_snapshot(saver.state.lock())
+                    })
+                    .await;
+                    last_snapshot = time::Instant::now();
+                }
+                let next = collector.config_store.sample_interval();
+                if next != period {
+                    period = next;
+                    interval = sample_interval(time::Instant::now() + period, period);
+                }
             }
         })
     }
 
+    /// Ends the active session at its last sample so the next drain hands it
+    /// over, e.g. when the agent shuts down.
+    pub fn finalize(&self) {
+        let policy = self.policy();
+        let mut state = self.state.lock();
+        state.finalize_current(&policy);
+        self.save_snapshot(state);
+    }
+
+    /// Whether the sampler ran within the last two sample intervals.
+    pub fn sampler_alive(&self) -> bool {
+        let window = self.config_store.sample_interval() * 2;
+        self.last_sample
+            .lock()
+            .is_some_and(|last| last.elapsed() <= window)
+    }
+
+    /// The configured policy with the merge gap widened for slow sample
+    /// intervals, so every sample gap is covered.
+    fn policy(&self) -> SessionPolicy {
+        let mut policy = self.config_store.session_policy();
+        let interval = self.config_store.sample_interval().as_millis() as u64;
+        policy.merge_gap_ms = policy.merge_gap_ms.max(interval * 2);
+        policy
+    }
+
     fn sample_once(&self) -> Result<()> {
         let now = Utc::now();
-        let package = foreground_package()?;
+        let split_titles = self.config_store.split_browser_titles();
+        let policy = self.policy();
+        // A Remote Desktop session never owns the console, so the fast user
+        // switching and display checks only apply to local sessions.
+        let remote = status::remote_session();
+        if status::screen_locked().unwrap_or(false)
+            || (!remote
+                && (!status::console_session_active().unwrap_or(true)
+                    || !system_events::display_on()))
+        {
+            self.state.lock().observe(None, now, split_titles, &policy);
+            return Ok(());
+        }
+        let mut window = foreground_window(
+            &self.config_store.tracking_rules().read(),
+            self.config_store.browser_domains(),
+            &self.config_store.script_hosts(),
+        )?;
+        if let Some(window) = window.as_mut() {
+            window.remote = remote;
+            window.media = match self.audio.audible_processes() {
+                Ok(audible) => Some(plays_audio(window, &audible)),
+                Err(err) => {
+                    log::debug!("audio sessions unavailable: {err:?}");
+                    None
+                }
+            };
+        }
+        let playing = window.as_ref().and_then(|window| window.media) == Some(true);
+        // Playback counts as activity, so a video is not cut off as idle.
+        if let Some(threshold) = self.config_store.idle_threshold().filter(|_| !playing) {
+            let idle = idle_duration()?;
+            if idle >= threshold {
+                let idle_since = now - Duration::from_std(idle)?;
+                self.state.lock().observe_idle(idle_since, &policy);
+                return Ok(());
+            }
+        }
+        if self.config_store.track_engagement() {
+            let input = self.input.lock().sample()?;
+            if let Some(window) = window.as_mut() {
+                window.input = Some(input);
+            }
+        }
         let mut state = self.state.lock();
-        state.observe(package, now);
+        state.observe(window, now, split_titles, &policy);
         Ok(())
     }
 
-    pub fn drain_sessions(&self, window: Duration) -> Vec<UsageSession> {
+    /// Sessions completed since the previous drain that was committed.
+    pub fn drain_sessions(&self) -> DrainedSessions {
         let now = Utc::now();
-        let mut state = self.state.lock();
-        let raw = state.drain(now, window);
-        merge_and_convert(raw)
+        let policy = self.policy();
+        let raw = self.state.lock().drain(now, &policy);
+        let bucket = self
+            .config_store
+            .session_bucket()
+            .and_then(|bucket| Duration::from_std(bucket).ok());
+        let mut sessions = merge_and_convert(
+            raw.clone(),
+            &policy,
+            self.config_store.split_browser_titles(),
+            bucket,
+        );
+        let mut spans = Vec::with_capacity(sessions.len());
+        for session in &mut sessions {
+            session.user = self.user.clone();
+            spans.push((
+                session.package.clone(),
+                session.window_start,
+                session.window_end,
+            ));
+        }
+        DrainedSessions {
+            collector: self.clone(),
+            raw,
+            spans,
+            sessions,
+            committed: false,
+        }
     }
+
+    /// Foreground time per app for the current local day, including sessions
+    /// that are still in progress or not yet drained.
+    pub fn today_totals(&self) -> Vec<AppUsageTotal> {
+        let state = self.state.lock();
+        let completed = state
+            .completed
+            .iter()
+            .map(|raw| (raw.package.as_str(), raw.start, raw.end));
+        let active = state
+            .current
+            .iter()
+            .map(|active| (active.package.as_str(), active.started_at, active.last_seen));
+        self.daily.lock().snapshot(completed.chain(active))
+    }
+}
+
+/// Missed ticks (e.g. after sleep) are skipped rather than fired in a burst
+/// of samples with the same timestamp.
+fn sample_interval(start: time::Instant, period: StdDuration) -> time::Interval {
+    let mut interval = time::interval_at(start, period);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    interval
 }
 
-fn merge_and_convert(raw: Vec<RawSession>) -> Vec<UsageSession> {
+/// Merges adjacent samples of the same app. With a `bucket`, long sessions
+/// are split at bucket boundaries; without one they are truncated at the
+/// policy's maximum.
+fn merge_and_convert(
+    raw: Vec<RawSession>,
+    policy: &SessionPolicy,
+    split_browser_titles: bool,
+    bucket: Option<Duration>,
+) -> Vec<UsageSession> {
     if raw.is_empty() {
         return Vec::new();
     }
@@ -167,12 +599,24 @@ fn merge_and_convert(raw: Vec<RawSession>) -> Vec<UsageSession> {
     let mut merged: Vec<RawSession> = Vec::new();
     for session in sorted {
         if let Some(last) = merged.last_mut() {
+            let split = split_browser_titles && is_browser(&session.package);
             if last.package == session.package
-                && (session.start - last.end).num_milliseconds() <= MERGE_GAP_MS
+                && last.app_id == session.app_id
+                && last.args_hint == session.args_hint
+                && last.remote == session.remote
+                && last.domain == session.domain
+                && (!split || last.title == session.title)
+                && session.start - last.end <= policy.merge_gap()
             {
                 if session.end > last.end {
                     last.end = session.end;
                 }
+                if session.title.is_some() {
+                    last.title = session.title;
+                }
+                last.engagement.merge(session.engagement);
+                last.placement.merge(session.placement);
+                last.media.merge(session.media);
                 continue;
             }
         }
@@ -181,37 +625,303 @@ fn merge_and_convert(raw: Vec<RawSession>) -> Vec<UsageSession> {
 
     merged
         .into_iter()
-        .filter_map(|mut s| {
-            let total_ms = (s.end - s.start).num_milliseconds();
-            if total_ms < MIN_SESSION_MS {
-                return None;
-            }
-            if total_ms > MAX_SESSION_MS {
-                s.end = s.start + Duration::milliseconds(MAX_SESSION_MS);
+        .filter(|s| s.end - s.start >= policy.min_session())
+        .flat_map(|s| match bucket {
+            Some(bucket) => split_at_boundaries(s, bucket, policy.min_session()),
+            None => vec![s],
+        })
+        .map(|mut s| {
+            if bucket.is_none() && s.end - s.start > policy.max_session() {
+                s.end = s.start + policy.max_session();
             }
             let total = (s.end - s.start).num_milliseconds().max(0) as u64;
-            Some(UsageSession {
+            UsageSession {
                 package: s.package,
+                app_id: s.app_id,
+                args_hint: s.args_hint,
+                title: s.title,
+                engagement: s.engagement.ratio(),
+                fullscreen: s.placement.fullscreen(),
+                monitor_index: s.placement.monitor_index(),
+                media: s.media.predominant(),
+                remote: Some(s.remote),
+                domain: s.domain,
+                user: None,
                 window_start: s.start,
                 window_end: s.end,
                 total_ms: total,
                 foreground: true,
-            })
+            }
         })
         .collect()
 }
 
-fn foreground_package() -> Result<Option<String>> {
+/// Cuts `session` into contiguous pieces at multiples of `bucket` since the
+/// Unix epoch (clock hours for a one-hour bucket). A first or last piece
+/// shorter than `min_session` is folded into its neighbour.
+fn split_at_boundaries(
+    session: RawSession,
+    bucket: Duration,
+    min_session: Duration,
+) -> Vec<RawSession> {
+    let bucket_ms = bucket.num_milliseconds();
+    if bucket_ms <= 0 {
+        return vec![session];
+    }
+    let mut pieces: Vec<RawSession> = Vec::new();
+    let mut start = session.start;
+    while start < session.end {
+        let next_ms = (start.timestamp_millis().div_euclid(bucket_ms) + 1) * bucket_ms;
+        let end = DateTime::from_timestamp_millis(next_ms)
+            .unwrap_or(session.end)
+            .min(session.end);
+        pieces.push(RawSession {
+            start,
+            end,
+            ..session.clone()
+        });
+        start = end;
+    }
+    let too_short = |piece: &RawSession| piece.end - piece.start < min_session;
+    if pieces.len() > 1 && too_short(&pieces[0]) {
+        let first = pieces.remove(0);
+        pieces[0].start = first.start;
+    }
+    if pieces.len() > 1 && pieces.last().is_some_and(too_short) {
+        let last = pieces.pop().expect("more than one piece");
+        if let Some(previous) = pieces.last_mut() {
+            previous.end = last.end;
+        }
+    }
+    pieces
+}
+
+fn foreground_window(
+    rules: &TrackingRules,
+    browser_domains: bool,
+    script_hosts: &[String],
+) -> Result<Option<ForegroundWindow>> {
     let hwnd = unsafe { GetForegroundWindow() };
     if hwnd.0 == 0 {
         return Ok(None);
     }
     let pid = unsafe { window_process_id(hwnd) };
-    if pid == 0 {
+    if !trackable_pid(pid) || !on_default_desktop() {
+        return Ok(None);
+    }
+    let (package, app_pid) = resolve_package(
+        query_process_image(pid)?,
+        pid,
+        || hosted_app_pid(hwnd, pid),
+        package_family_name,
+    );
+    let Some(package) = package.filter(|pkg| rules.should_track(pkg)) else {
         return Ok(None);
+    };
+    let mut title = window_title(hwnd);
+    let mut domain = None;
+    if browser::is_browser(&package) {
+        title = title.map(|title| browser::page_title(&title).to_string());
+        if browser_domains {
+            domain = browser::address_bar_text(hwnd)
+                .and_then(|address| browser::registrable_domain(&address));
+        }
+    }
+    // Unreadable command lines leave the session attributed to the host.
+    let args_hint = if script_hosts.contains(&package) {
+        hosts::command_line(app_pid).and_then(|line| hosts::args_hint(&package, &line))
+    } else {
+        None
+    };
+    Ok(Some(ForegroundWindow {
+        pid: app_pid,
+        package,
+        app_id: app_id(app_pid),
+        args_hint,
+        title,
+        input: None,
+        domain,
+        placement: display::placement(hwnd),
+        media: None,
+        remote: false,
+    }))
+}
+
+/// False for windows without a process and for the agent's own windows.
+fn trackable_pid(pid: u32) -> bool {
+    pid != 0 && pid != std::process::id()
+}
+
+/// Browsers play audio from a helper process rather than the one owning the
+/// window, so any process with the same executable name counts too.
+fn plays_audio(window: &ForegroundWindow, audible: &[u32]) -> bool {
+    audible.iter().any(|&pid| {
+        pid == window.pid
+            || query_process_image(pid)
+                .ok()
+                .flatten()
+                .is_some_and(|image| image == window.package)
+    })
+}
+
+/// False while input goes to a desktop other than `Default`, such as the
+/// secure desktop shown for UAC prompts. The secure desktop cannot be opened
+/// by a user process at all, so failing to open it counts as not default.
+fn on_default_desktop() -> bool {
+    let Ok(desktop) =
+        (unsafe { OpenInputDesktop(DESKTOP_CONTROL_FLAGS(0), false, DESKTOP_READOBJECTS) })
+    else {
+        return false;
+    };
+    let mut buffer = [0u16; 64];
+    let mut needed = 0u32;
+    let named = unsafe {
+        GetUserObjectInformationW(
+            HANDLE(desktop.0),
+            UOI_NAME,
+            Some(buffer.as_mut_ptr().cast()),
+            std::mem::size_of_val(&buffer) as u32,
+            Some(&mut needed),
+        )
+    };
+    unsafe {
+        let _ = CloseDesktop(desktop);
+    }
+    if named.is_err() {
+        return false;
+    }
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    String::from_utf16_lossy(&buffer[..len]).eq_ignore_ascii_case(DEFAULT_DESKTOP)
+}
+
+/// Package and process a foreground window is attributed to: the image
+/// name of `pid`, or for ApplicationFrameHost the package family of the
+/// hosted Store app. `None` when the hosted app cannot be identified.
+fn resolve_package(
+    image: Option<String>,
+    pid: u32,
+    hosted_pid: impl FnOnce() -> Option<u32>,
+    family_name: impl FnOnce(u32) -> Option<String>,
+) -> (Option<String>, u32) {
+    match image {
+        Some(image) if image == FRAME_HOST => match hosted_pid() {
+            Some(hosted) => (family_name(hosted), hosted),
+            None => (None, pid),
+        },
+        image => (image, pid),
+    }
+}
+
+struct HostedAppSearch {
+    frame_pid: u32,
+    hosted_pid: u32,
+}
+
+/// Process of the Store app shown in an ApplicationFrameHost window.
+fn hosted_app_pid(frame: HWND, frame_pid: u32) -> Option<u32> {
+    let mut search = HostedAppSearch {
+        frame_pid,
+        hosted_pid: 0,
+    };
+    unsafe {
+        let _ = EnumChildWindows(
+            frame,
+            Some(find_hosted_app),
+            LPARAM(&mut search as *mut HostedAppSearch as isize),
+        );
+    }
+    (search.hosted_pid != 0).then_some(search.hosted_pid)
+}
+
+unsafe extern "system" fn find_hosted_app(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    let search = &mut *(lparam.0 as *mut HostedAppSearch);
+    let pid = window_process_id(hwnd);
+    if pid != 0 && pid != search.frame_pid {
+        search.hosted_pid = pid;
+        return BOOL(0);
+    }
+    BOOL(1)
+}
+
+/// Lowercased package family name (e.g. `microsoft.minecraftuwp_8wekyb3d8bbwe`)
+/// of a packaged process.
+fn package_family_name(pid: u32) -> Option<String> {
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) }.ok()?;
+    let mut buffer = [0u16; 128];
+    let mut len = buffer.len() as u32;
+    let result = unsafe { GetPackageFamilyName(handle, &mut len, PWSTR(buffer.as_mut_ptr())) };
+    unsafe {
+        let _ = CloseHandle(handle);
+    }
+    if result != ERROR_SUCCESS || len <= 1 {
+        return None;
+    }
+    // `len` counts the terminating null.
+    let name = String::from_utf16_lossy(&buffer[..len as usize - 1]);
+    Some(name.to_lowercase())
+}
+
+/// Identity of the app behind a process beyond its executable name, so that
+/// e.g. two Electron apps both running as `electron.exe` stay apart: the
+/// AppUserModelID of packaged apps, else the lowercased full image path.
+/// `None` when neither can be read and only the base name is known.
+fn app_id(pid: u32) -> Option<String> {
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) }.ok()?;
+    let id = application_user_model_id(handle).or_else(|| full_image_path(handle));
+    unsafe {
+        let _ = CloseHandle(handle);
+    }
+    id
+}
+
+fn application_user_model_id(handle: HANDLE) -> Option<String> {
+    let mut buffer = [0u16; 256];
+    let mut len = buffer.len() as u32;
+    let result = unsafe { GetApplicationUserModelId(handle, &mut len, PWSTR(buffer.as_mut_ptr())) };
+    if result != ERROR_SUCCESS || len <= 1 {
+        return None;
     }
-    let package = query_process_image(pid)?;
-    Ok(package.filter(|pkg| should_track(pkg)))
+    // `len` counts the terminating null.
+    Some(String::from_utf16_lossy(&buffer[..len as usize - 1]))
+}
+
+fn full_image_path(handle: HANDLE) -> Option<String> {
+    let mut buffer = [0u16; 1024];
+    let mut len = buffer.len() as u32;
+    unsafe {
+        QueryFullProcessImageNameW(
+            handle,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut len,
+        )
+    }
+    .ok()?;
+    (len > 0).then(|| String::from_utf16_lossy(&buffer[..len as usize]).to_lowercase())
+}
+
+fn window_title(hwnd: HWND) -> Option<String> {
+    let mut buffer = [0u16; 512];
+    let len = unsafe { GetWindowTextW(hwnd, &mut buffer) };
+    if len <= 0 {
+        return None;
+    }
+    sanitize_title(&String::from_utf16_lossy(&buffer[..len as usize]))
+}
+
+/// Drops control characters, trims and caps the length. Blank titles are
+/// treated as absent.
+fn sanitize_title(raw: &str) -> Option<String> {
+    let cleaned: String = raw.chars().filter(|c| !c.is_control()).collect();
+    let title: String = cleaned.trim().chars().take(MAX_TITLE_CHARS).collect();
+    let title = title.trim_end();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+/// Time since the last keyboard or mouse input in this session.
+fn idle_duration() -> Result<StdDuration> {
+    let elapsed_ms = unsafe { GetTickCount() }.wrapping_sub(engagement::last_input_tick()?);
+    Ok(StdDuration::from_millis(elapsed_ms.into()))
 }
 
 unsafe fn window_process_id(hwnd: HWND) -> u32 {
@@ -220,7 +930,7 @@ unsafe fn window_process_id(hwnd: HWND) -> u32 {
     pid
 }
 
-fn query_process_image(pid: u32) -> Result<Option<String>> {
+pub(crate) fn query_process_image(pid: u32) -> Result<Option<String>> {
     let handle = unsafe {
         match OpenProcess(
             PROCESS_QUERY_INFORMATION | PROCESS_QUERY_LIMITED_INFORMATION,
@@ -243,28 +953,497 @@ fn query_process_image(pid: u32) -> Result<Option<String>> {
     Ok(Some(name))
 }
 
-fn should_track(package: &str) -> bool {
-    if package.is_empty() {
-        return false;
+/// Which foreground processes produce sessions. Patterns are
+/// case-insensitive globs where `*` matches any run of characters and `?`
+/// any single character.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackingRules {
+    pub blacklist: Vec<String>,
+    /// When set, only matching packages are tracked and the blacklist is
+    /// ignored.
+    #[serde(default)]
+    pub whitelist: Option<Vec<String>>,
+}
+
+impl Default for TrackingRules {
+    fn default() -> Self {
+        Self {
+            blacklist: DEFAULT_BLACKLIST
+                .iter()
+                .map(|p| p.to_string())
+                .chain(own_executable())
+                .collect(),
+            whitelist: None,
+        }
     }
-    let blacklist = [
-        "explorer.exe",
-        "systemsettings.exe",
-        "taskmgr.exe",
-        "searchui.exe",
-        "sihost.exe",
-    ];
-    if blacklist.contains(&package) {
-        return false;
+}
+
+/// The agent's own executable name, so its windows never count even if the
+/// PID check is bypassed (e.g. a second instance).
+fn own_executable() -> Option<String> {
+    let exe = std::env::current_exe().ok()?;
+    Some(exe.file_name()?.to_string_lossy().to_lowercase())
+}
+
+impl TrackingRules {
+    pub fn should_track(&self, package: &str) -> bool {
+        if package.is_empty() {
+            return false;
+        }
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(&pattern.to_lowercase(), package))
+        };
+        match &self.whitelist {
+            Some(whitelist) => matches(whitelist),
+            None => !matches(&self.blacklist),
+        }
     }
-    let prefixes = [
-        "fontdrvhost",
-        "applicationframehost",
-        "shellexperiencehost",
-        "startmenuexperiencehost",
-    ];
-    if prefixes.iter().any(|p| package.starts_with(p)) {
-        return false;
+}
+
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text index it is currently matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(package: &str) -> ForegroundWindow {
+        ForegroundWindow {
+            pid: 1,
+            package: package.to_string(),
+            app_id: None,
+            args_hint: None,
+            title: None,
+            input: None,
+            domain: None,
+            placement: None,
+            media: None,
+            remote: false,
+        }
+    }
+
+    fn raw(package: &str, start: DateTime<Utc>, seconds: i64) -> RawSession {
+        RawSession {
+            package: package.to_string(),
+            app_id: None,
+            args_hint: None,
+            title: None,
+            start,
+            end: start + Duration::seconds(seconds),
+            engagement: EngagementCounts::default(),
+            domain: None,
+            placement: PlacementCounts::default(),
+            media: MediaCounts::default(),
+            remote: false,
+        }
+    }
+
+    fn active(package: &str, started_at: DateTime<Utc>, last_seen: DateTime<Utc>) -> ActiveSession {
+        ActiveSession {
+            package: package.to_string(),
+            app_id: None,
+            args_hint: None,
+            title: None,
+            started_at,
+            last_seen,
+            engagement: EngagementCounts::default(),
+            domain: None,
+            placement: PlacementCounts::default(),
+            media: MediaCounts::default(),
+            remote: false,
+        }
+    }
+
+    /// Observes `window` every five seconds from `from` through `to`.
+    fn observe_span(
+        state: &mut TrackerState,
+        window: &ForegroundWindow,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        policy: &SessionPolicy,
+    ) {
+        let mut now = from;
+        while now <= to {
+            state.observe(Some(window.clone()), now, false, policy);
+            now += Duration::seconds(5);
+        }
+    }
+
+    fn packages(sessions: &[RawSession]) -> Vec<&str> {
+        sessions.iter().map(|raw| raw.package.as_str()).collect()
+    }
+
+    #[test]
+    fn idle_time_is_trimmed_and_input_starts_a_new_session() {
+        let policy = SessionPolicy::default();
+        let t0 = Utc::now();
+        let mut state = TrackerState::new();
+        observe_span(
+            &mut state,
+            &window("a.exe"),
+            t0,
+            t0 + Duration::seconds(60),
+            &policy,
+        );
+        state.observe_idle(t0 + Duration::seconds(20), &policy);
+
+        assert!(state.current.is_none());
+        assert_eq!(state.completed.len(), 1);
+        assert_eq!(state.completed[0].end, t0 + Duration::seconds(20));
+
+        let back = t0 + Duration::seconds(90);
+        state.observe(Some(window("a.exe")), back, false, &policy);
+        assert_eq!(state.current.as_ref().unwrap().started_at, back);
+        assert_eq!(state.completed.len(), 1);
+    }
+
+    #[test]
+    fn a_lock_ends_the_session_until_unlock() {
+        let policy = SessionPolicy::default();
+        let t0 = Utc::now();
+        let mut state = TrackerState::new();
+        observe_span(
+            &mut state,
+            &window("a.exe"),
+            t0,
+            t0 + Duration::seconds(30),
+            &policy,
+        );
+        // Locked samples observe nothing.
+        state.observe(None, t0 + Duration::seconds(35), false, &policy);
+        state.observe(None, t0 + Duration::seconds(40), false, &policy);
+        assert!(state.current.is_none());
+        assert_eq!(state.completed.len(), 1);
+        assert_eq!(state.completed[0].end, t0 + Duration::seconds(30));
+
+        let unlocked = t0 + Duration::minutes(10);
+        state.observe(Some(window("a.exe")), unlocked, false, &policy);
+        assert_eq!(state.current.as_ref().unwrap().started_at, unlocked);
+        assert_eq!(state.completed.len(), 1);
+    }
+
+    #[test]
+    fn frame_host_windows_resolve_to_the_hosted_package() {
+        let family =
+            |pid| (pid == 4242).then(|| "microsoft.minecraftuwp_8wekyb3d8bbwe".to_string());
+
+        assert_eq!(
+            resolve_package(Some(FRAME_HOST.to_string()), 100, || Some(4242), family),
+            (
+                Some("microsoft.minecraftuwp_8wekyb3d8bbwe".to_string()),
+                4242
+            )
+        );
+        // No hosted child (e.g. a suspended app) or an unreadable package
+        // leaves nothing to track.
+        assert_eq!(
+            resolve_package(Some(FRAME_HOST.to_string()), 100, || None, family),
+            (None, 100)
+        );
+        assert_eq!(
+            resolve_package(Some(FRAME_HOST.to_string()), 100, || Some(7), family),
+            (None, 7)
+        );
+    }
+
+    #[test]
+    fn win32_windows_keep_their_image_name() {
+        let (package, pid) = resolve_package(
+            Some("notepad.exe".to_string()),
+            100,
+            || panic!("only frame host windows are searched"),
+            |_| panic!("only hosted apps have a package family"),
+        );
+        assert_eq!(package.as_deref(), Some("notepad.exe"));
+        assert_eq!(pid, 100);
+    }
+
+    #[test]
+    fn glob_patterns_match_whole_names() {
+        assert!(glob_match("*.exe", "notepad.exe"));
+        assert!(glob_match("chrome?.exe", "chrome2.exe"));
+        assert!(glob_match("*proctor*", "examproctor64.exe"));
+        assert!(glob_match("exact.exe", "exact.exe"));
+        assert!(!glob_match("exact.exe", "exact.exe.bak"));
+        assert!(!glob_match("chrome?.exe", "chrome.exe"));
+        assert!(!glob_match("*.com", "notepad.exe"));
+        assert!(glob_match("**", ""));
+    }
+
+    #[test]
+    fn tracking_rules_use_the_whitelist_over_the_blacklist() {
+        let mut rules = TrackingRules {
+            blacklist: vec!["*Proctor*".to_string()],
+            whitelist: None,
+        };
+        assert!(!rules.should_track("examproctor.exe"));
+        assert!(rules.should_track("explorer.exe"));
+        assert!(!rules.should_track(""));
+
+        rules.whitelist = Some(vec!["explorer.exe".to_string(), "kiosk-*".to_string()]);
+        assert!(rules.should_track("explorer.exe"));
+        assert!(rules.should_track("kiosk-viewer.exe"));
+        assert!(!rules.should_track("notepad.exe"));
+    }
+
+    #[test]
+    fn default_rules_skip_the_agent_itself() {
+        let rules = TrackingRules::default();
+        assert!(!rules.should_track(&own_executable().unwrap()));
+        assert!(rules.should_track("notepad.exe"));
+    }
+
+    fn snapshot_path() -> PathBuf {
+        std::env::temp_dir().join(format!("nuscape-sessions-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn a_restart_recovers_completed_and_active_sessions() {
+        let policy = SessionPolicy::default();
+        let t0 = Utc::now() - Duration::minutes(5);
+        let mut state = TrackerState::new();
+        state.completed.push(raw("a.exe", t0, 60));
+        state.current = Some(active(
+            "b.exe",
+            t0 + Duration::seconds(90),
+            t0 + Duration::seconds(150),
+        ));
+        let path = snapshot_path();
+        fs::write(&path, state.encode().unwrap()).unwrap();
+
+        let mut restored = TrackerState::restore(&path, &policy);
+        assert!(restored.current.is_none());
+        let sessions = restored.drain(Utc::now(), &policy);
+        assert_eq!(packages(&sessions), ["a.exe", "b.exe"]);
+        assert_eq!(sessions[0].end, t0 + Duration::seconds(60));
+        // The active session ends at its last sample.
+        assert_eq!(sessions[1].start, t0 + Duration::seconds(90));
+        assert_eq!(sessions[1].end, t0 + Duration::seconds(150));
+    }
+
+    #[test]
+    fn unreadable_snapshots_start_empty() {
+        let policy = SessionPolicy::default();
+        let path = snapshot_path();
+        assert!(TrackerState::restore(&path, &policy).completed.is_empty());
+
+        fs::write(&path, b"{not json").unwrap();
+        assert!(TrackerState::restore(&path, &policy).completed.is_empty());
+
+        let mut state = TrackerState::new();
+        state.completed.push(raw("a.exe", Utc::now(), 60));
+        let mut snapshot: serde_json::Value =
+            serde_json::from_slice(&state.encode().unwrap()).unwrap();
+        snapshot["version"] = (SNAPSHOT_VERSION + 1).into();
+        fs::write(&path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+        assert!(TrackerState::restore(&path, &policy).completed.is_empty());
+    }
+
+    #[test]
+    fn a_suspend_gap_splits_the_session() {
+        let policy = SessionPolicy::default();
+        let t0 = Utc::now() - Duration::hours(11);
+        let resumed = t0 + Duration::hours(10);
+        let game = window("game.exe");
+        let mut state = TrackerState::new();
+        observe_span(&mut state, &game, t0, t0 + Duration::seconds(30), &policy);
+        observe_span(
+            &mut state,
+            &game,
+            resumed,
+            resumed + Duration::seconds(30),
+            &policy,
+        );
+        state.finalize_current(&policy);
+
+        let spans: Vec<_> = state
+            .completed
+            .iter()
+            .map(|raw| (raw.start, raw.end))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (t0, t0 + Duration::seconds(30)),
+                (resumed, resumed + Duration::seconds(30))
+            ]
+        );
+    }
+
+    #[test]
+    fn drain_returns_each_session_exactly_once() {
+        let policy = SessionPolicy::default();
+        let now = Utc::now();
+        let mut state = TrackerState::new();
+        state
+            .completed
+            .push(raw("a.exe", now - Duration::minutes(10), 60));
+        state.current = Some(active("b.exe", now - Duration::minutes(2), now));
+
+        assert_eq!(packages(&state.drain(now, &policy)), ["a.exe"]);
+        assert!(state.drain(now, &policy).is_empty());
+        // The active session ends once its last sample is older than the
+        // merge gap, and is handed over once then.
+        let later = now + Duration::minutes(1);
+        assert_eq!(packages(&state.drain(later, &policy)), ["b.exe"]);
+        assert!(state.drain(later, &policy).is_empty());
+        assert!(state.current.is_none());
+    }
+
+    #[test]
+    fn handed_back_sessions_are_drained_again_in_order() {
+        let policy = SessionPolicy::default();
+        let now = Utc::now();
+        let mut state = TrackerState::new();
+        state
+            .completed
+            .push(raw("a.exe", now - Duration::minutes(10), 60));
+        let drained = state.drain(now, &policy);
+        state.completed.push(raw("c.exe", now, 60));
+        state.hand_back(drained);
+
+        assert_eq!(packages(&state.drain(now, &policy)), ["a.exe", "c.exe"]);
+        assert!(state.drain(now, &policy).is_empty());
+    }
+
+    #[test]
+    fn a_browser_session_splits_when_the_domain_changes() {
+        let policy = SessionPolicy::default();
+        let t0 = Utc::now();
+        let on = |domain: Option<&str>| ForegroundWindow {
+            domain: domain.map(str::to_string),
+            ..window("chrome.exe")
+        };
+        let mut state = TrackerState::new();
+        observe_span(
+            &mut state,
+            &on(Some("youtube.com")),
+            t0,
+            t0 + Duration::seconds(20),
+            &policy,
+        );
+        // A failed lookup keeps the current site.
+        state.observe(Some(on(None)), t0 + Duration::seconds(25), false, &policy);
+        observe_span(
+            &mut state,
+            &on(Some("wikipedia.org")),
+            t0 + Duration::seconds(30),
+            t0 + Duration::seconds(50),
+            &policy,
+        );
+        state.finalize_current(&policy);
+
+        let domains: Vec<_> = state
+            .completed
+            .iter()
+            .map(|raw| (raw.domain.as_deref(), raw.end))
+            .collect();
+        assert_eq!(
+            domains,
+            [
+                (Some("youtube.com"), t0 + Duration::seconds(25)),
+                (Some("wikipedia.org"), t0 + Duration::seconds(50))
+            ]
+        );
+    }
+
+    #[test]
+    fn the_agents_own_windows_are_not_tracked() {
+        assert!(!trackable_pid(std::process::id()));
+        assert!(!trackable_pid(0));
+        assert!(trackable_pid(std::process::id() + 1));
+    }
+
+    #[test]
+    fn apps_sharing_an_exe_name_stay_apart() {
+        let policy = SessionPolicy::default();
+        let t0 = Utc::now();
+        let app = |path: &str| ForegroundWindow {
+            app_id: Some(path.to_string()),
+            ..window("electron.exe")
+        };
+        let (slack, discord) = (
+            app("c:\\apps\\slack\\electron.exe"),
+            app("c:\\apps\\discord\\electron.exe"),
+        );
+        let mut state = TrackerState::new();
+        observe_span(&mut state, &slack, t0, t0 + Duration::seconds(20), &policy);
+        observe_span(
+            &mut state,
+            &discord,
+            t0 + Duration::seconds(25),
+            t0 + Duration::seconds(45),
+            &policy,
+        );
+        state.finalize_current(&policy);
+        assert_eq!(state.completed.len(), 2);
+
+        // Back-to-back pieces are not merged across identities either.
+        let sessions = merge_and_convert(state.completed.clone(), &policy, false, None);
+        let ids: Vec<_> = sessions
+            .iter()
+            .map(|session| (session.package.as_str(), session.app_id.as_deref()))
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                ("electron.exe", slack.app_id.as_deref()),
+                ("electron.exe", discord.app_id.as_deref())
+            ]
+        );
+    }
+
+    #[test]
+    fn playback_marks_the_session_as_media() {
+        let policy = SessionPolicy::default();
+        let t0 = Utc::now();
+        let player = window("vlc.exe");
+        assert!(plays_audio(&player, &[player.pid]));
+
+        let mut state = TrackerState::new();
+        for (offset, playing) in [
+            (0, Some(true)),
+            (5, Some(true)),
+            (10, None),
+            (15, Some(false)),
+        ] {
+            let sample = ForegroundWindow {
+                media: playing,
+                ..player.clone()
+            };
+            state.observe(Some(sample), t0 + Duration::seconds(offset), false, &policy);
+        }
+        state.finalize_current(&policy);
+        let sessions = merge_and_convert(state.completed.clone(), &policy, false, None);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].media, Some(true));
     }
-    true
 }
@@ -1,69 +1,351 @@
+use std::mem;
 use std::ptr;
+use std::sync::Arc;
 
-use windows::Win32::Foundation::WIN32_ERROR;
+use windows::Wdk::System::SystemServices::RtlGetVersion;
+
+use windows::core::{w, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{BOOL, WIN32_ERROR};
 use windows::Win32::NetworkManagement::IpHelper::{FreeMibTable, GetIfTable2, MIB_IF_TABLE2};
 use windows::Win32::NetworkManagement::Ndis::IF_OPER_STATUS;
 use windows::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+use windows::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ};
+use windows::Win32::System::RemoteDesktop::{
+    ProcessIdToSessionId, WTSFreeMemory, WTSGetActiveConsoleSessionId, WTSQuerySessionInformationW,
+    WTSSessionInfoEx, WTSINFOEXW, WTS_CURRENT_SERVER_HANDLE, WTS_CURRENT_SESSION,
+    WTS_SESSIONSTATE_LOCK,
+};
+use windows::Win32::System::SystemInformation::{
+    GlobalMemoryStatusEx, MEMORYSTATUSEX, OSVERSIONINFOW,
+};
 use windows::Win32::System::Time::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
 use windows::Win32::UI::Shell::IsUserAnAdmin;
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetSystemMetrics, SystemParametersInfoW, SM_REMOTESESSION, SPI_GETSCREENSAVERRUNNING,
+    SYSTEM_PARAMETERS_INFO_UPDATE_FLAGS,
+};
 
+use crate::collectors::antivirus;
+use crate::collectors::connectivity;
+use crate::collectors::dns::DnsHealth;
+use crate::collectors::network::{self, wide_to_string};
+use crate::collectors::sessions::SessionCollector;
+use crate::collectors::system_events;
+use crate::http::{self, AGENT_VERSION};
 use crate::models::DeviceStatus;
+use crate::runtime;
+use crate::storage::{self, UsageBatchStore};
 
+/// PPP, tunnel and L2TP interface types. Windows keeps some of these up
+/// permanently (Teredo, 6to4), so they only count when carrying the default
+/// route.
 const VPN_TYPES: [u32; 3] = [23, 131, 166];
+/// `SYSTEM_POWER_STATUS` values.
+const AC_ONLINE: u8 = 1;
+const NO_SYSTEM_BATTERY: u8 = 128;
+const UNKNOWN_BATTERY_PERCENT: u8 = 255;
+const BATTERY_SAVER_ON: u8 = 1;
+/// `DeviceStatus::tamper_flags` values.
+const TAMPER_DNSCRYPT_STOPPED: &str = "dnscrypt_stopped";
+const TAMPER_DNS_CHANGED: &str = "dns_changed";
+const TAMPER_CONFIG_MODIFIED: &str = "dnscrypt_config_modified";
+/// Description or alias fragments of VPN clients, with their display names.
+/// More specific fragments come first.
+const VPN_VENDORS: [(&str, &str); 19] = [
+    ("anyconnect", "Cisco AnyConnect"),
+    ("globalprotect", "GlobalProtect"),
+    ("pangp", "GlobalProtect"),
+    ("fortinet", "FortiClient"),
+    ("juniper", "Juniper"),
+    ("pulse secure", "Pulse Secure"),
+    ("sonicwall", "SonicWall"),
+    ("nordlynx", "NordVPN"),
+    ("nordvpn", "NordVPN"),
+    ("expressvpn", "ExpressVPN"),
+    ("protonvpn", "Proton VPN"),
+    ("mullvad", "Mullvad"),
+    ("surfshark", "Surfshark"),
+    ("windscribe", "Windscribe"),
+    ("cloudflare warp", "Cloudflare WARP"),
+    ("tailscale", "Tailscale"),
+    ("zerotier", "ZeroTier"),
+    ("openvpn", "OpenVPN"),
+    ("wireguard", "WireGuard"),
+];
+/// Userspace tunnel drivers shared by many VPNs, which report as Ethernet.
+const TUNNEL_DRIVERS: [&str; 2] = ["wintun", "tap-windows"];
+/// Adapters installed by this product, which never count as a VPN.
+const OWN_ADAPTERS: [&str; 1] = ["nuscape"];
+
+/// What VPN detection looks at for one interface.
+struct InterfaceFacts<'a> {
+    if_type: u32,
+    description: &'a str,
+    alias: &'a str,
+    up: bool,
+    default_route: bool,
+}
+
+/// A VPN tunnel among the interfaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedVpn {
+    pub vendor: Option<&'static str>,
+    pub interface: String,
+    pub default_route: bool,
+}
 
-pub struct DeviceStatusProvider;
+impl DetectedVpn {
+    pub fn name(&self) -> String {
+        self.vendor
+            .map_or_else(|| self.interface.clone(), str::to_string)
+    }
+}
+
+pub struct DeviceStatusProvider {
+    sessions: Arc<SessionCollector>,
+    batch_store: Arc<UsageBatchStore>,
+    dns: Arc<dyn DnsHealth>,
+}
 
 impl DeviceStatusProvider {
-    pub fn new() -> Self {
-        Self
+    pub fn new(
+        sessions: Arc<SessionCollector>,
+        batch_store: Arc<UsageBatchStore>,
+        dns: Arc<dyn DnsHealth>,
+    ) -> Self {
+        Self {
+            sessions,
+            batch_store,
+            dns,
+        }
     }
 
+    /// Everything but the clock skew, Wi-Fi network and low-disk flag, which
+    /// `UsageCollectionManager::device_status` fills in.
     pub fn build_status(&self) -> DeviceStatus {
+        let vpn = detect_vpn().unwrap_or(None);
+        let power = power_state().unwrap_or_default();
+        let sampler_alive = self.sessions.sampler_alive();
+        let dnscrypt_running = self.dns.dnscrypt_running();
+        let dns_configured = self.dns.dns_configured();
+        let tamper_flags = tamper_flags(dnscrypt_running, dns_configured, self.dns.config_intact());
+        let antivirus = antivirus::antivirus_status();
         DeviceStatus {
             usage_access: is_running_as_admin().unwrap_or(false),
-            accessibility: false,
-            overlay: true,
-            vpn: detect_vpn().unwrap_or(false),
-            battery_pct: battery_percentage().unwrap_or(-1.0),
+            accessibility: sampler_alive,
+            overlay: dnscrypt_running && dns_configured,
+            sampler_alive,
+            task_restarts: runtime::task_restarts(),
+            dnscrypt_running,
+            dns_configured,
+            tamper_flags,
+            queue_size: self.batch_store.queue_size(),
+            vpn: vpn.is_some(),
+            vpn_name: vpn.map(|vpn| vpn.name()),
+            battery_pct: power.battery_pct,
+            charging: power.charging,
+            battery_saver: power.battery_saver,
             time_zone_id: timezone_identifier().unwrap_or_else(|_| "UTC".to_string()),
+            clock_skew_ms: None,
+            agent_version: AGENT_VERSION.to_string(),
+            os_version: os_version(),
+            os_edition: os_edition(),
+            disk_free_bytes: system_drive_free(),
+            storage_bytes: self.batch_store.storage_bytes(),
+            low_disk: false,
+            memory_used_pct: memory_used_pct(),
+            antivirus_enabled: antivirus.as_ref().map(|antivirus| antivirus.enabled),
+            antivirus_name: antivirus.map(|antivirus| antivirus.name),
+            mtls: http::mtls_active(),
+            screen_locked: screen_locked().unwrap_or(false),
+            display_on: system_events::display_on(),
+            remote: remote_session(),
+            metered_connection: connectivity::internet_metered().unwrap_or(false),
+            current_ssid: None,
+            adapters: network::adapters().unwrap_or_else(|err| {
+                log::debug!("failed to list network adapters: {err:?}");
+                Vec::new()
+            }),
         }
     }
+
+    /// Just the tamper checks of `build_status`, cheap enough to poll.
+    pub fn tamper_flags(&self) -> Vec<String> {
+        tamper_flags(
+            self.dns.dnscrypt_running(),
+            self.dns.dns_configured(),
+            self.dns.config_intact(),
+        )
+    }
+}
+
+fn tamper_flags(
+    dnscrypt_running: bool,
+    dns_configured: bool,
+    config_intact: Option<bool>,
+) -> Vec<String> {
+    let mut flags = Vec::new();
+    if !dnscrypt_running {
+        flags.push(TAMPER_DNSCRYPT_STOPPED.to_string());
+    }
+    if !dns_configured {
+        flags.push(TAMPER_DNS_CHANGED.to_string());
+    }
+    if config_intact == Some(false) {
+        flags.push(TAMPER_CONFIG_MODIFIED.to_string());
+    }
+    flags
+}
+
+/// True while the workstation is locked or the screensaver is running.
+pub fn screen_locked() -> windows::core::Result<bool> {
+    Ok(session_locked()? || screensaver_running()?)
+}
+
+/// True when the agent's session is displayed over Remote Desktop.
+pub fn remote_session() -> bool {
+    unsafe { GetSystemMetrics(SM_REMOTESESSION) != 0 }
+}
+
+/// False while another user's session owns the console (fast user
+/// switching), or while no session is attached to it during a switch.
+pub fn console_session_active() -> windows::core::Result<bool> {
+    let mut own = 0u32;
+    unsafe {
+        ProcessIdToSessionId(std::process::id(), &mut own)?;
+        Ok(WTSGetActiveConsoleSessionId() == own)
+    }
+}
+
+fn session_locked() -> windows::core::Result<bool> {
+    let mut buffer = PWSTR::null();
+    let mut len = 0u32;
+    unsafe {
+        WTSQuerySessionInformationW(
+            WTS_CURRENT_SERVER_HANDLE,
+            WTS_CURRENT_SESSION,
+            WTSSessionInfoEx,
+            &mut buffer,
+            &mut len,
+        )?;
+        let info = &*(buffer.0 as *const WTSINFOEXW);
+        let locked = info.Level == 1
+            && info.Data.WTSInfoExLevel1.SessionFlags == WTS_SESSIONSTATE_LOCK as i32;
+        WTSFreeMemory(buffer.0.cast());
+        Ok(locked)
+    }
+}
+
+fn screensaver_running() -> windows::core::Result<bool> {
+    let mut running = BOOL(0);
+    unsafe {
+        SystemParametersInfoW(
+            SPI_GETSCREENSAVERRUNNING,
+            0,
+            Some(&mut running as *mut BOOL as *mut _),
+            SYSTEM_PARAMETERS_INFO_UPDATE_FLAGS(0),
+        )?;
+    }
+    Ok(running.as_bool())
 }
 
 fn is_running_as_admin() -> windows::core::Result<bool> {
     unsafe { Ok(IsUserAnAdmin().as_bool()) }
 }
 
-fn detect_vpn() -> windows::core::Result<bool> {
+/// The active VPN, preferring one that carries the default route.
+fn detect_vpn() -> windows::core::Result<Option<DetectedVpn>> {
+    let default_routes = connectivity::default_route_interfaces().unwrap_or_default();
     unsafe {
         let mut table_ptr: *mut MIB_IF_TABLE2 = ptr::null_mut();
         let status = GetIfTable2(&mut table_ptr);
         if status != WIN32_ERROR(0) {
-            return Ok(false);
+            return Ok(None);
         }
         let table = &*table_ptr;
         let rows = std::slice::from_raw_parts(table.Table.as_ptr(), table.NumEntries as usize);
-        let mut vpn = false;
-        for row in rows {
-            if VPN_TYPES.contains(&row.Type) && row.OperStatus == IF_OPER_STATUS(1) {
-                vpn = true;
-                break;
-            }
-        }
+        let mut detected: Vec<DetectedVpn> = rows
+            .iter()
+            .filter_map(|row| {
+                let description = wide_to_string(&row.Description);
+                let alias = wide_to_string(&row.Alias);
+                classify_vpn(&InterfaceFacts {
+                    if_type: row.Type,
+                    description: &description,
+                    alias: &alias,
+                    up: row.OperStatus == IF_OPER_STATUS(1),
+                    default_route: default_routes.contains(&row.InterfaceLuid.Value),
+                })
+            })
+            .collect();
         FreeMibTable(table_ptr as _);
-        Ok(vpn)
+        detected.sort_by_key(|vpn| !vpn.default_route);
+        Ok(detected.into_iter().next())
     }
 }
 
-fn battery_percentage() -> windows::core::Result<f64> {
-    unsafe {
-        let mut status = SYSTEM_POWER_STATUS::default();
-        GetSystemPowerStatus(&mut status)?;
-        if status.BatteryLifePercent == u8::MAX {
-            return Ok(-1.0);
-        }
-        Ok(status.BatteryLifePercent as f64 / 100.0)
+/// Whether an interface is a VPN tunnel. Known vendors count whenever they
+/// are up; generic tunnel types and drivers only when the default route
+/// goes through them, which rules out idle transition tunnels.
+fn classify_vpn(facts: &InterfaceFacts) -> Option<DetectedVpn> {
+    if !facts.up {
+        return None;
+    }
+    let description = facts.description.to_lowercase();
+    let alias = facts.alias.to_lowercase();
+    let matches = |fragment: &str| description.contains(fragment) || alias.contains(fragment);
+    if OWN_ADAPTERS.iter().any(|fragment| matches(fragment)) {
+        return None;
     }
+    let interface = if facts.alias.is_empty() {
+        facts.description
+    } else {
+        facts.alias
+    };
+    let detected = |vendor| DetectedVpn {
+        vendor,
+        interface: interface.to_string(),
+        default_route: facts.default_route,
+    };
+    if let Some((_, vendor)) = VPN_VENDORS.iter().find(|(fragment, _)| matches(fragment)) {
+        return Some(detected(Some(*vendor)));
+    }
+    let generic =
+        VPN_TYPES.contains(&facts.if_type) || TUNNEL_DRIVERS.iter().any(|driver| matches(driver));
+    (generic && facts.default_route).then(|| detected(None))
+}
+
+fn system_drive_free() -> Option<u64> {
+    let drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+    storage::free_space(std::path::Path::new(&format!("{drive}\\")))
+}
+
+fn memory_used_pct() -> Option<u32> {
+    let mut status = MEMORYSTATUSEX {
+        dwLength: mem::size_of::<MEMORYSTATUSEX>() as u32,
+        ..Default::default()
+    };
+    unsafe { GlobalMemoryStatusEx(&mut status) }.ok()?;
+    Some(status.dwMemoryLoad)
+}
+
+#[derive(Default)]
+struct PowerState {
+    battery_pct: Option<f64>,
+    charging: bool,
+    battery_saver: bool,
+}
+
+fn power_state() -> windows::core::Result<PowerState> {
+    let mut status = SYSTEM_POWER_STATUS::default();
+    unsafe { GetSystemPowerStatus(&mut status)? };
+    let has_battery = status.BatteryFlag & NO_SYSTEM_BATTERY == 0;
+    Ok(PowerState {
+        battery_pct: (has_battery && status.BatteryLifePercent != UNKNOWN_BATTERY_PERCENT)
+            .then(|| f64::from(status.BatteryLifePercent)),
+        charging: has_battery && status.ACLineStatus == AC_ONLINE,
+        battery_saver: status.SystemStatusFlag == BATTERY_SAVER_ON,
+    })
 }
 
 fn timezone_identifier() -> windows::core::Result<String> {
@@ -83,3 +365,140 @@ fn timezone_identifier() -> windows::core::Result<String> {
             .to_string())
     }
 }
+
+/// Windows version as reported by the kernel with the marketing release
+/// when known, e.g. `Windows 10.0.18363 (1909)`. `RtlGetVersion` is used
+/// because `GetVersionEx` lies to unmanifested apps.
+pub fn os_version() -> String {
+    let kernel = unsafe {
+        let mut info = OSVERSIONINFOW {
+            dwOSVersionInfoSize: mem::size_of::<OSVERSIONINFOW>() as u32,
+            ..Default::default()
+        };
+        if RtlGetVersion(&mut info).is_err() {
+            return "Windows".to_string();
+        }
+        format!(
+            "Windows {}.{}.{}",
+            info.dwMajorVersion, info.dwMinorVersion, info.dwBuildNumber
+        )
+    };
+    // DisplayVersion (e.g. 22H2) replaced ReleaseId (e.g. 1909) in 20H2.
+    match current_version_value(w!("DisplayVersion"))
+        .or_else(|| current_version_value(w!("ReleaseId")))
+    {
+        Some(release) => format!("{kernel} ({release})"),
+        None => kernel,
+    }
+}
+
+/// Windows edition, e.g. `Professional` or `Core` (Home).
+pub fn os_edition() -> Option<String> {
+    current_version_value(w!("EditionID"))
+}
+
+/// String value under `HKLM\SOFTWARE\Microsoft\Windows NT\CurrentVersion`.
+fn current_version_value(name: PCWSTR) -> Option<String> {
+    let mut buffer = [0u16; 128];
+    let mut size = mem::size_of_val(&buffer) as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            w!("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion"),
+            name,
+            RRF_RT_REG_SZ,
+            None,
+            Some(buffer.as_mut_ptr().cast()),
+            Some(&mut size),
+        )
+    };
+    if status != WIN32_ERROR(0) {
+        return None;
+    }
+    let value = wide_to_string(&buffer);
+    (!value.is_empty()).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts<'a>(if_type: u32, description: &'a str, alias: &'a str) -> InterfaceFacts<'a> {
+        InterfaceFacts {
+            if_type,
+            description,
+            alias,
+            up: true,
+            default_route: true,
+        }
+    }
+
+    #[test]
+    fn known_vendors_are_named_even_off_the_default_route() {
+        let anyconnect = InterfaceFacts {
+            default_route: false,
+            ..facts(
+                6,
+                "Cisco AnyConnect Secure Mobility Client Virtual Miniport",
+                "Ethernet 3",
+            )
+        };
+        assert_eq!(
+            classify_vpn(&anyconnect),
+            Some(DetectedVpn {
+                vendor: Some("Cisco AnyConnect"),
+                interface: "Ethernet 3".to_string(),
+                default_route: false,
+            })
+        );
+        let nordlynx = classify_vpn(&facts(53, "WireGuard Tunnel", "NordLynx")).unwrap();
+        assert_eq!(nordlynx.name(), "NordVPN");
+    }
+
+    #[test]
+    fn generic_tunnels_count_only_on_the_default_route() {
+        let wintun = facts(6, "Wintun Userspace Tunnel", "");
+        assert_eq!(
+            classify_vpn(&wintun).map(|vpn| vpn.name()),
+            Some("Wintun Userspace Tunnel".to_string())
+        );
+        let off_route = InterfaceFacts {
+            default_route: false,
+            ..facts(VPN_TYPES[0], "WAN Miniport (PPTP)", "")
+        };
+        assert_eq!(classify_vpn(&off_route), None);
+    }
+
+    #[test]
+    fn own_down_and_plain_adapters_are_not_vpns() {
+        assert_eq!(
+            classify_vpn(&facts(53, "WireGuard Tunnel", "NuScape")),
+            None
+        );
+        let down = InterfaceFacts {
+            up: false,
+            ..facts(6, "Mullvad Tunnel", "")
+        };
+        assert_eq!(classify_vpn(&down), None);
+        assert_eq!(
+            classify_vpn(&facts(71, "Intel(R) Wi-Fi 6 AX201", "Wi-Fi")),
+            None
+        );
+    }
+
+    #[test]
+    fn each_failed_check_raises_its_tamper_flag() {
+        assert!(tamper_flags(true, true, Some(true)).is_empty());
+        // A missing dnscrypt has no configuration to check.
+        assert!(tamper_flags(true, true, None).is_empty());
+        assert_eq!(
+            tamper_flags(false, false, Some(false)),
+            [
+                TAMPER_DNSCRYPT_STOPPED,
+                TAMPER_DNS_CHANGED,
+                TAMPER_CONFIG_MODIFIED
+            ]
+        );
+        assert_eq!(tamper_flags(true, false, None), [TAMPER_DNS_CHANGED]);
+    }
+}
@@ -0,0 +1,124 @@
+//! System notifications that Windows only delivers as window messages,
+//! received by a hidden window on a dedicated thread: the console display
+//! state and the end of the Windows session. Session lock notifications can
+//! be registered on the same window.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, OnceLock};
+use std::thread;
+
+use windows::core::{w, Error, PCWSTR};
+use windows::Win32::Foundation::{HANDLE, HWND, LPARAM, LRESULT, WPARAM};
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::System::Power::{RegisterPowerSettingNotification, POWERBROADCAST_SETTING};
+use windows::Win32::System::SystemServices::GUID_CONSOLE_DISPLAY_STATE;
+use windows::Win32::UI::WindowsAndMessaging::{
+    CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW,
+    DEVICE_NOTIFY_WINDOW_HANDLE, MSG, PBT_POWERSETTINGCHANGE, WINDOW_EX_STYLE, WINDOW_STYLE,
+    WM_ENDSESSION, WM_POWERBROADCAST, WNDCLASSW,
+};
+
+const CLASS_NAME: PCWSTR = w!("NuscapeSystemEvents");
+/// `GUID_CONSOLE_DISPLAY_STATE` data; dimmed (2) counts as on.
+const DISPLAY_OFF: u8 = 0;
+
+static START: Once = Once::new();
+/// Assumed on until the first notification, which Windows sends right
+/// after registering.
+static DISPLAY_ON: AtomicBool = AtomicBool::new(true);
+static SESSION_END: OnceLock<Box<dyn Fn() + Send + Sync>> = OnceLock::new();
+
+/// Starts the listener thread once; later calls do nothing.
+pub fn start() {
+    START.call_once(|| {
+        let spawned = thread::Builder::new()
+            .name("system-events".into())
+            .spawn(|| {
+                if let Err(err) = unsafe { run() } {
+                    log::warn!("system event listener stopped: {err:?}");
+                }
+            });
+        if let Err(err) = spawned {
+            log::warn!("failed to start system event listener: {err:?}");
+        }
+    });
+}
+
+/// Sets what runs when the user logs off or Windows shuts down. It runs on
+/// the listener thread, and Windows ends the process once it returns, so it
+/// must finish within a few seconds. Only the first hook is kept.
+pub fn on_session_end(hook: impl Fn() + Send + Sync + 'static) {
+    let _ = SESSION_END.set(Box::new(hook));
+}
+
+/// Whether the console display is on. Stays `true` when the listener could
+/// not start.
+pub fn display_on() -> bool {
+    DISPLAY_ON.load(Ordering::Relaxed)
+}
+
+unsafe fn run() -> windows::core::Result<()> {
+    let instance = GetModuleHandleW(None)?;
+    let class = WNDCLASSW {
+        lpfnWndProc: Some(window_proc),
+        hInstance: instance.into(),
+        lpszClassName: CLASS_NAME,
+        ..Default::default()
+    };
+    if RegisterClassW(&class) == 0 {
+        return Err(Error::from_win32());
+    }
+    // A hidden top-level window rather than a message-only one, which
+    // would miss broadcasts.
+    let hwnd = CreateWindowExW(
+        WINDOW_EX_STYLE::default(),
+        CLASS_NAME,
+        w!(""),
+        WINDOW_STYLE::default(),
+        0,
+        0,
+        0,
+        0,
+        None,
+        None,
+        instance,
+        None,
+    );
+    if hwnd == HWND::default() {
+        return Err(Error::from_win32());
+    }
+    RegisterPowerSettingNotification(
+        HANDLE(hwnd.0),
+        &GUID_CONSOLE_DISPLAY_STATE,
+        DEVICE_NOTIFY_WINDOW_HANDLE,
+    )?;
+    let mut msg = MSG::default();
+    while GetMessageW(&mut msg, None, 0, 0).0 > 0 {
+        DispatchMessageW(&msg);
+    }
+    Ok(())
+}
+
+extern "system" fn window_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if msg == WM_POWERBROADCAST && wparam.0 as u32 == PBT_POWERSETTINGCHANGE {
+        let setting = unsafe { &*(lparam.0 as *const POWERBROADCAST_SETTING) };
+        if setting.PowerSetting == GUID_CONSOLE_DISPLAY_STATE {
+            let on = setting.Data[0] != DISPLAY_OFF;
+            if DISPLAY_ON.swap(on, Ordering::Relaxed) != on {
+                log::debug!("display turned {}", if on { "on" } else { "off" });
+            }
+        }
+        return LRESULT(1);
+    }
+    // `WM_QUERYENDSESSION` is left to the default, which lets the session
+    // end; a nonzero `wparam` here means it is ending.
+    if msg == WM_ENDSESSION {
+        if wparam.0 != 0 {
+            if let Some(hook) = SESSION_END.get() {
+                hook();
+            }
+        }
+        return LRESULT(0);
+    }
+    unsafe { DefWindowProcW(hwnd, msg, wparam, lparam) }
+}
@@ -0,0 +1,707 @@
+use std::collections::HashMap;
+
+use tauri::{AppHandle, State};
+
+use crate::collectors::sessions::TrackingRules;
+use crate::credentials::CredentialBackendKind;
+use crate::dpapi::DpapiScope;
+use crate::metrics::MetricsSnapshot;
+use crate::models::{
+    AppUsageTotal, AuthAuditEntry, AuthMode, CounterResetMode, FailureAlertPolicy, ProfileSummary,
+    RegistrationOutcome, RetryPolicy, SessionPolicy, StorageStats, Timeouts,
+};
+use crate::runtime;
+use crate::storage::{self, UsageBatchStore};
+use crate::AgentState;
+
+#[tauri::command]
+pub fn get_retry_policy(state: State<'_, AgentState>) -> RetryPolicy {
+    state.config_store.retry_policy()
+}
+
+#[tauri::command]
+pub fn set_retry_policy(state: State<'_, AgentState>, policy: RetryPolicy) -> Result<(), String> {
+    state
+        .config_store
+        .set_retry_policy(policy)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_proxy_url(state: State<'_, AgentState>) -> Option<String> {
+    state.config_store.proxy_url()
+}
+
+/// An empty or missing `url` falls back to the system proxy. The uploader
+/// picks the change up when the agent or profile restarts.
+#[tauri::command]
+pub fn set_proxy(
+    state: State<'_, AgentState>,
+    url: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_proxy(url.as_deref(), username.as_deref(), password.as_deref())
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_tls_pins(state: State<'_, AgentState>) -> Vec<String> {
+    state.config_store.tls_pins()
+}
+
+/// SPKI pins as `sha256/<base64>`; an empty list disables pinning. The
+/// uploader picks the change up when the agent or profile restarts.
+#[tauri::command]
+pub fn set_tls_pins(state: State<'_, AgentState>, pins: Vec<String>) -> Result<(), String> {
+    state
+        .config_store
+        .set_tls_pins(pins)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_agent_metrics(state: State<'_, AgentState>) -> MetricsSnapshot {
+    MetricsSnapshot {
+        last_auth_event: state.token_store().audit().last(),
+        corrupt_files_quarantined: storage::quarantined_files(),
+        usage_batches_dropped: storage::dropped_batches(),
+        sessions_purged: storage::purged_sessions(),
+        batches_deduplicated: storage::deduplicated_batches(),
+        task_restarts: runtime::task_restarts(),
+        ..state.metrics.snapshot()
+    }
+}
+
+/// Per-app foreground time for the current local day, largest first.
+#[tauri::command]
+pub fn get_today_usage(state: State<'_, AgentState>) -> Vec<AppUsageTotal> {
+    state.sessions().today_totals()
+}
+
+#[tauri::command]
+pub fn get_timeouts(state: State<'_, AgentState>) -> Timeouts {
+    Timeouts {
+        request_timeout_ms: state.config_store.request_timeout().as_millis() as u64,
+        connect_timeout_ms: state.config_store.connect_timeout().as_millis() as u64,
+    }
+}
+
+/// The uploader picks the change up when the agent or profile restarts.
+#[tauri::command]
+pub fn set_timeouts(state: State<'_, AgentState>, timeouts: Timeouts) -> Result<(), String> {
+    state
+        .config_store
+        .set_timeouts(timeouts.request_timeout_ms, timeouts.connect_timeout_ms)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_custom_headers(state: State<'_, AgentState>) -> HashMap<String, String> {
+    state.config_store.custom_headers()
+}
+
+#[tauri::command]
+pub fn set_custom_headers(
+    state: State<'_, AgentState>,
+    headers: HashMap<String, String>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_custom_headers(headers)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_dry_run(state: State<'_, AgentState>) -> bool {
+    state.config_store.dry_run()
+}
+
+#[tauri::command]
+pub fn set_dry_run(state: State<'_, AgentState>, enabled: bool) -> Result<(), String> {
+    log::info!(
+        "dry-run uploads {}",
+        if enabled { "enabled" } else { "disabled" }
+    );
+    state
+        .config_store
+        .set_dry_run(enabled)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_tracking_rules(state: State<'_, AgentState>) -> TrackingRules {
+    state.config_store.tracking_rules().read().clone()
+}
+
+#[tauri::command]
+pub fn set_tracking_rules(
+    state: State<'_, AgentState>,
+    rules: TrackingRules,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_tracking_rules(rules)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_session_policy(state: State<'_, AgentState>) -> SessionPolicy {
+    state.config_store.session_policy()
+}
+
+#[tauri::command]
+pub fn set_session_policy(
+    state: State<'_, AgentState>,
+    policy: SessionPolicy,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_session_policy(policy)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_background_watchlist(state: State<'_, AgentState>) -> Vec<String> {
+    state.config_store.background_watchlist()
+}
+
+#[tauri::command]
+pub fn set_background_watchlist(
+    state: State<'_, AgentState>,
+    patterns: Vec<String>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_background_watchlist(patterns)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_script_hosts(state: State<'_, AgentState>) -> Vec<String> {
+    state.config_store.script_hosts()
+}
+
+#[tauri::command]
+pub fn set_script_hosts(state: State<'_, AgentState>, hosts: Vec<String>) -> Result<(), String> {
+    state
+        .config_store
+        .set_script_hosts(hosts)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_network_exclusions(state: State<'_, AgentState>) -> Vec<String> {
+    state.config_store.network_exclusions()
+}
+
+#[tauri::command]
+pub fn set_network_exclusions(
+    state: State<'_, AgentState>,
+    fragments: Vec<String>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_network_exclusions(fragments)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_ssid_collection(state: State<'_, AgentState>) -> bool {
+    state.config_store.ssid_collection()
+}
+
+#[tauri::command]
+pub fn set_ssid_collection(state: State<'_, AgentState>, enabled: bool) -> Result<(), String> {
+    state
+        .config_store
+        .set_ssid_collection(enabled)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_queue_compression(state: State<'_, AgentState>) -> bool {
+    state.config_store.queue_compression()
+}
+
+/// Takes effect for new batches right away; the queued ones are rewritten by
+/// the next daily compaction.
+#[tauri::command]
+pub fn set_queue_compression(state: State<'_, AgentState>, enabled: bool) -> Result<(), String> {
+    state
+        .config_store
+        .set_queue_compression(enabled)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_counter_reset_mode(state: State<'_, AgentState>) -> CounterResetMode {
+    state.config_store.counter_reset_mode()
+}
+
+#[tauri::command]
+pub fn set_counter_reset_mode(
+    state: State<'_, AgentState>,
+    mode: CounterResetMode,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_counter_reset_mode(mode)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_collect_interval_minutes(state: State<'_, AgentState>) -> u64 {
+    state.config_store.collect_interval().as_secs() / 60
+}
+
+#[tauri::command]
+pub fn set_collect_interval_minutes(
+    state: State<'_, AgentState>,
+    minutes: u64,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_collect_interval_minutes(minutes)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_upload_interval_seconds(state: State<'_, AgentState>) -> u64 {
+    state.config_store.upload_interval().as_secs()
+}
+
+#[tauri::command]
+pub fn set_upload_interval_seconds(
+    state: State<'_, AgentState>,
+    seconds: u64,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_upload_interval_seconds(seconds)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub async fn get_dead_letter_count(state: State<'_, AgentState>) -> Result<usize, String> {
+    on_queue(&state, |store| store.dead_letter_count()).await
+}
+
+#[tauri::command]
+pub async fn retry_dead_letters(state: State<'_, AgentState>) -> Result<usize, String> {
+    let requeued = on_queue(&state, |store| store.retry_dead_letters())
+        .await?
+        .map_err(|err| format!("{err:#}"))?;
+    log::info!("requeued {requeued} dead-lettered batch(es)");
+    Ok(requeued)
+}
+
+#[tauri::command]
+pub fn get_dead_letter_threshold(state: State<'_, AgentState>) -> u32 {
+    state.config_store.dead_letter_threshold()
+}
+
+#[tauri::command]
+pub fn set_dead_letter_threshold(
+    state: State<'_, AgentState>,
+    threshold: u32,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_dead_letter_threshold(threshold)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_upload_concurrency(state: State<'_, AgentState>) -> usize {
+    state.config_store.upload_concurrency()
+}
+
+#[tauri::command]
+pub fn set_upload_concurrency(
+    state: State<'_, AgentState>,
+    concurrency: usize,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_upload_concurrency(concurrency)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_auth_mode(state: State<'_, AgentState>) -> AuthMode {
+    state.config_store.auth_mode()
+}
+
+/// Api-key mode requires `api_key`. The stored key is never read back.
+#[tauri::command]
+pub fn set_auth_mode(
+    state: State<'_, AgentState>,
+    mode: AuthMode,
+    api_key: Option<String>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_auth_mode(mode, api_key.as_deref())
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_heartbeat_interval_minutes(state: State<'_, AgentState>) -> u64 {
+    state.config_store.heartbeat_interval().as_secs() / 60
+}
+
+#[tauri::command]
+pub fn set_heartbeat_interval_minutes(
+    state: State<'_, AgentState>,
+    minutes: u64,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_heartbeat_interval_minutes(minutes)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_ndjson_uploads(state: State<'_, AgentState>) -> bool {
+    state.config_store.ndjson_uploads()
+}
+
+#[tauri::command]
+pub fn set_ndjson_uploads(state: State<'_, AgentState>, enabled: bool) -> Result<(), String> {
+    state
+        .config_store
+        .set_ndjson_uploads(enabled)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_notifications_silenced(state: State<'_, AgentState>) -> bool {
+    state.config_store.notifications_silenced()
+}
+
+#[tauri::command]
+pub fn set_notifications_silenced(
+    state: State<'_, AgentState>,
+    silenced: bool,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_notifications_silenced(silenced)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_failure_alert_policy(state: State<'_, AgentState>) -> FailureAlertPolicy {
+    FailureAlertPolicy {
+        cycles: state.config_store.failure_alert_cycles(),
+        interval_hours: state.config_store.failure_alert_interval().as_secs() / (60 * 60),
+    }
+}
+
+#[tauri::command]
+pub fn set_failure_alert_policy(
+    state: State<'_, AgentState>,
+    policy: FailureAlertPolicy,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_failure_alerts(policy.cycles, policy.interval_hours)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_client_certificate_path(state: State<'_, AgentState>) -> Option<String> {
+    state.config_store.client_certificate_path()
+}
+
+/// A missing `path` turns mutual TLS off. The uploader picks the change up
+/// when the agent or profile restarts.
+#[tauri::command]
+pub fn set_client_certificate(
+    state: State<'_, AgentState>,
+    path: Option<String>,
+    passphrase: Option<String>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_client_certificate(path.as_deref(), passphrase.as_deref())
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_upload_rate_limit(state: State<'_, AgentState>) -> Option<u64> {
+    state.config_store.upload_rate_limit()
+}
+
+#[tauri::command]
+pub fn set_upload_rate_limit(
+    state: State<'_, AgentState>,
+    bytes_per_second: Option<u64>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_upload_rate_limit(bytes_per_second)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_spike_threshold(state: State<'_, AgentState>) -> Option<u64> {
+    state.config_store.spike_threshold()
+}
+
+#[tauri::command]
+pub fn set_spike_threshold(state: State<'_, AgentState>, bytes: Option<u64>) -> Result<(), String> {
+    state
+        .config_store
+        .set_spike_threshold(bytes)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_min_free_disk_mb(state: State<'_, AgentState>) -> u64 {
+    state.config_store.min_free_disk_bytes() / (1024 * 1024)
+}
+
+#[tauri::command]
+pub fn set_min_free_disk_mb(state: State<'_, AgentState>, mb: u64) -> Result<(), String> {
+    state
+        .config_store
+        .set_min_free_disk_mb(mb)
+        .map_err(|err| format!("{err:#}"))
+}
+
+/// Size of the data directory with a per-file breakdown, for support.
+#[tauri::command]
+pub async fn get_storage_stats(state: State<'_, AgentState>) -> Result<StorageStats, String> {
+    on_queue(&state, |store| store.storage_stats())
+        .await?
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_retention_days(state: State<'_, AgentState>) -> u32 {
+    state.config_store.retention_days()
+}
+
+#[tauri::command]
+pub fn set_retention_days(state: State<'_, AgentState>, days: u32) -> Result<(), String> {
+    state
+        .config_store
+        .set_retention_days(days)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_dpapi_scope(state: State<'_, AgentState>) -> DpapiScope {
+    state.config_store.dpapi_scope()
+}
+
+#[tauri::command]
+pub fn set_dpapi_scope(state: State<'_, AgentState>, scope: DpapiScope) -> Result<(), String> {
+    state
+        .config_store
+        .set_dpapi_scope(scope)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_credential_backend(state: State<'_, AgentState>) -> CredentialBackendKind {
+    state.config_store.credential_backend()
+}
+
+/// Takes effect the next time the agent starts.
+#[tauri::command]
+pub fn set_credential_backend(
+    state: State<'_, AgentState>,
+    kind: CredentialBackendKind,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_credential_backend(kind)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub async fn pair_device(
+    state: State<'_, AgentState>,
+    code: String,
+) -> Result<RegistrationOutcome, String> {
+    state.pair(&code).await.map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_token_expiry_margin_seconds(state: State<'_, AgentState>) -> u64 {
+    state.config_store.token_expiry_margin().as_secs()
+}
+
+#[tauri::command]
+pub fn set_token_expiry_margin_seconds(
+    state: State<'_, AgentState>,
+    seconds: u64,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_token_expiry_margin_seconds(seconds)
+        .map_err(|err| format!("{err:#}"))
+}
+
+/// Unlinks the device, wipes local state and exits the agent.
+#[tauri::command]
+pub async fn deprovision(
+    app: AppHandle,
+    state: State<'_, AgentState>,
+    force: bool,
+) -> Result<(), String> {
+    state
+        .deprovision(force)
+        .await
+        .map_err(|err| format!("{err:#}"))?;
+    app.exit(0);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn read_auth_audit(
+    state: State<'_, AgentState>,
+    limit: usize,
+) -> Result<Vec<AuthAuditEntry>, String> {
+    state
+        .token_store()
+        .audit()
+        .read(limit)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn list_profiles(state: State<'_, AgentState>) -> Vec<ProfileSummary> {
+    state.config_store.profiles()
+}
+
+/// Activates `name`, creating it when `api_base` is given and it does not
+/// exist yet.
+#[tauri::command]
+pub fn switch_profile(
+    state: State<'_, AgentState>,
+    name: String,
+    api_base: Option<String>,
+) -> Result<(), String> {
+    state
+        .switch_profile(&name, api_base.as_deref())
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn delete_profile(state: State<'_, AgentState>, name: String) -> Result<(), String> {
+    state
+        .delete_profile(&name)
+        .map_err(|err| format!("{err:#}"))
+}
+
+/// `0` when idle detection is disabled.
+#[tauri::command]
+pub fn get_idle_threshold_seconds(state: State<'_, AgentState>) -> u64 {
+    state
+        .config_store
+        .idle_threshold()
+        .map_or(0, |threshold| threshold.as_secs())
+}
+
+/// `0` disables idle detection.
+#[tauri::command]
+pub fn set_idle_threshold_seconds(
+    state: State<'_, AgentState>,
+    seconds: u64,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_idle_threshold_seconds(seconds)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_split_browser_titles(state: State<'_, AgentState>) -> bool {
+    state.config_store.split_browser_titles()
+}
+
+#[tauri::command]
+pub fn set_split_browser_titles(state: State<'_, AgentState>, enabled: bool) -> Result<(), String> {
+    state
+        .config_store
+        .set_split_browser_titles(enabled)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_sample_interval_ms(state: State<'_, AgentState>) -> u64 {
+    state.config_store.sample_interval().as_millis() as u64
+}
+
+#[tauri::command]
+pub fn set_sample_interval_ms(state: State<'_, AgentState>, ms: u64) -> Result<(), String> {
+    state
+        .config_store
+        .set_sample_interval_ms(ms)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_session_bucket_minutes(state: State<'_, AgentState>) -> Option<u64> {
+    state
+        .config_store
+        .session_bucket()
+        .map(|bucket| bucket.as_secs() / 60)
+}
+
+/// `None` truncates long sessions instead of splitting them.
+#[tauri::command]
+pub fn set_session_bucket_minutes(
+    state: State<'_, AgentState>,
+    minutes: Option<u64>,
+) -> Result<(), String> {
+    state
+        .config_store
+        .set_session_bucket_minutes(minutes)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_track_engagement(state: State<'_, AgentState>) -> bool {
+    state.config_store.track_engagement()
+}
+
+#[tauri::command]
+pub fn set_track_engagement(state: State<'_, AgentState>, enabled: bool) -> Result<(), String> {
+    state
+        .config_store
+        .set_track_engagement(enabled)
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[tauri::command]
+pub fn get_browser_domains(state: State<'_, AgentState>) -> bool {
+    state.config_store.browser_domains()
+}
+
+#[tauri::command]
+pub fn set_browser_domains(state: State<'_, AgentState>, enabled: bool) -> Result<(), String> {
+    state
+        .config_store
+        .set_browser_domains(enabled)
+        .map_err(|err| format!("{err:#}"))
+}
+
+/// Runs `op` against the active profile's queue on the blocking pool, so
+/// SQLite and directory walks stay off the main thread.
+async fn on_queue<T, F>(state: &AgentState, op: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&UsageBatchStore) -> T + Send + 'static,
+{
+    let store = state.batch_store();
+    tauri::async_runtime::spawn_blocking(move || op(&store))
+        .await
+        .map_err(|err| format!("{err:#}"))
+}
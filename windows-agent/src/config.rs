@@ -1,52 +1,1126 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
 
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
 use uuid::Uuid;
 
-use crate::models::UploadConfig;
-use crate::storage::StoragePaths;
+use crate::acl;
+use crate::collectors::hosts::DEFAULT_SCRIPT_HOSTS;
+use crate::collectors::sessions::TrackingRules;
+use crate::credentials::CredentialBackendKind;
+use crate::dpapi::{self, DpapiScope};
+use crate::http;
+use crate::models::{
+    AuthMode, CounterResetMode, ProfileSummary, RetryPolicy, SessionPolicy, UploadConfig,
+};
+use crate::storage::{self, Schema, StoragePaths, DEFAULT_PROFILE};
+
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_DEAD_LETTER_THRESHOLD: u32 = 5;
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 1;
+const MAX_UPLOAD_CONCURRENCY: usize = 16;
+const DEFAULT_HEARTBEAT_INTERVAL_MINUTES: u64 = 20;
+const DEFAULT_FAILURE_ALERT_CYCLES: u32 = 30;
+const DEFAULT_FAILURE_ALERT_INTERVAL_HOURS: u64 = 24;
+const DEFAULT_TOKEN_EXPIRY_MARGIN_SECONDS: u64 = 120;
+const DEFAULT_IDLE_THRESHOLD_SECONDS: u64 = 180;
+const DEFAULT_SAMPLE_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_COLLECT_INTERVAL_MINUTES: u64 = 15;
+const DEFAULT_UPLOAD_INTERVAL_SECONDS: u64 = 60;
+const DEFAULT_MIN_FREE_DISK_MB: u64 = 512;
+/// Device-local usage data may not be kept longer than this.
+const MAX_RETENTION_DAYS: u32 = 30;
+const MIN_SAMPLE_INTERVAL_MS: u64 = 1_000;
+const MAX_SAMPLE_INTERVAL_MS: u64 = 60_000;
+/// Each collect reads every adapter's counters and the DNS log, so it is
+/// kept to at most one a minute.
+const MIN_COLLECT_INTERVAL_MINUTES: u64 = 1;
+const MAX_COLLECT_INTERVAL_MINUTES: u64 = 6 * 60;
+const MIN_UPLOAD_INTERVAL_SECONDS: u64 = 15;
+const MAX_UPLOAD_INTERVAL_SECONDS: u64 = 6 * 60 * 60;
+const MAX_SESSION_BUCKET_MINUTES: u64 = 24 * 60;
+const MAX_PROFILE_NAME_LEN: usize = 32;
+/// `device.json` is rewritten to bump `last_seen` at most this often.
+const DEVICE_LAST_SEEN_PERSIST_MINUTES: i64 = 60;
+/// Headers the agent sets itself; custom headers may not replace them.
+const RESERVED_HEADERS: [&str; 10] = [
+    "authorization",
+    "content-type",
+    "content-encoding",
+    "content-length",
+    "host",
+    "idempotency-key",
+    "user-agent",
+    "x-api-key",
+    "x-nuscape-signature",
+    "x-nuscape-timestamp",
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct ConfigRecord {
     api_base: Option<String>,
+    upload_max_attempts: Option<u32>,
+    upload_initial_backoff_ms: Option<u64>,
+    upload_max_backoff_ms: Option<u64>,
+    proxy_url: Option<String>,
+    proxy_username: Option<String>,
+    /// Plaintext password written by older agents; migrated into
+    /// `proxy_password_protected`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy_password: Option<String>,
+    /// DPAPI-protected, base64-encoded proxy password.
+    proxy_password_protected: Option<String>,
+    #[serde(default)]
+    tls_pins: Vec<String>,
+    request_timeout_ms: Option<u64>,
+    connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    custom_headers: HashMap<String, String>,
+    #[serde(default)]
+    dry_run: bool,
+    dead_letter_threshold: Option<u32>,
+    upload_concurrency: Option<usize>,
+    #[serde(default)]
+    auth_mode: AuthMode,
+    /// Plaintext key written by older agents; migrated into `api_key_protected`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    /// DPAPI-protected, base64-encoded API key.
+    api_key_protected: Option<String>,
+    heartbeat_interval_minutes: Option<u64>,
+    #[serde(default)]
+    ndjson_uploads: bool,
+    #[serde(default)]
+    notifications_silenced: bool,
+    failure_alert_cycles: Option<u32>,
+    failure_alert_interval_hours: Option<u64>,
+    upload_rate_limit_bps: Option<u64>,
+    client_cert_path: Option<String>,
+    /// DPAPI-protected, base64-encoded PKCS#12 passphrase.
+    client_cert_passphrase: Option<String>,
+    #[serde(default)]
+    dpapi_scope: DpapiScope,
+    #[serde(default)]
+    credential_backend: CredentialBackendKind,
+    token_expiry_margin_seconds: Option<u64>,
+    idle_threshold_seconds: Option<u64>,
+    #[serde(default)]
+    split_browser_titles: bool,
+    tracking_blacklist: Option<Vec<String>>,
+    tracking_whitelist: Option<Vec<String>>,
+    sample_interval_ms: Option<u64>,
+    collect_interval_minutes: Option<u64>,
+    upload_interval_seconds: Option<u64>,
+    session_bucket_minutes: Option<u64>,
+    session_min_ms: Option<u64>,
+    session_merge_gap_ms: Option<u64>,
+    session_max_ms: Option<u64>,
+    background_watchlist: Option<Vec<String>>,
+    script_hosts: Option<Vec<String>>,
+    network_exclusions: Option<Vec<String>>,
+    #[serde(default)]
+    track_engagement: bool,
+    #[serde(default)]
+    browser_domains: bool,
+    #[serde(default)]
+    counter_reset_mode: CounterResetMode,
+    #[serde(default)]
+    ssid_collection_disabled: bool,
+    spike_threshold_bytes: Option<u64>,
+    min_free_disk_mb: Option<u64>,
+    retention_days: Option<u32>,
+    #[serde(default)]
+    queue_compression_disabled: bool,
+}
+
+impl ConfigRecord {
+    /// Moves secrets older agents stored in the clear into their
+    /// DPAPI-protected fields. Returns whether anything moved.
+    fn protect_plaintext(&mut self) -> Result<bool> {
+        let mut changed = false;
+        if let Some(api_key) = &self.api_key {
+            self.api_key_protected = Some(dpapi::protect_string(api_key, self.dpapi_scope)?);
+            self.api_key = None;
+            changed = true;
+        }
+        if let Some(password) = &self.proxy_password {
+            self.proxy_password_protected =
+                Some(dpapi::protect_string(password, self.dpapi_scope)?);
+            self.proxy_password = None;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    fn tracking_rules(&self) -> TrackingRules {
+        let defaults = TrackingRules::default();
+        TrackingRules {
+            blacklist: self
+                .tracking_blacklist
+                .clone()
+                .unwrap_or(defaults.blacklist),
+            whitelist: self.tracking_whitelist.clone(),
+        }
+    }
+}
+
+/// The record's decrypted proxy password.
+fn proxy_password(record: &ConfigRecord) -> Result<Option<String>> {
+    match &record.proxy_password_protected {
+        Some(protected) => dpapi::unprotect_string(protected)
+            .context("proxy password is unreadable")
+            .map(Some),
+        None => Ok(record.proxy_password.clone()),
+    }
+}
+
+/// On-disk layout: one [`ConfigRecord`] per named profile.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ConfigFile {
+    active_profile: String,
+    profiles: BTreeMap<String, ConfigRecord>,
 }
 
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self {
+            active_profile: DEFAULT_PROFILE.to_string(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// Layout of config.json.
+const CONFIG_SCHEMA: Schema = Schema {
+    name: "config",
+    migrations: &[config_v1],
+};
+
+/// Moves a single-record file from before profiles into the default profile.
+fn config_v1(data: Value) -> Result<Value> {
+    if data.get("profiles").is_some() {
+        return Ok(data);
+    }
+    Ok(json!({ "active_profile": DEFAULT_PROFILE, "profiles": { DEFAULT_PROFILE: data } }))
+}
+
+/// Settings for the active profile. `cache` is the live copy of the active
+/// record; its entry in `file` is only refreshed when persisting.
 pub struct UsageConfigStore {
     path: PathBuf,
     cache: Mutex<ConfigRecord>,
+    file: Mutex<ConfigFile>,
+    api_base_changed: Notify,
+    tracking_rules: Arc<RwLock<TrackingRules>>,
 }
 
 impl UsageConfigStore {
     pub fn new(paths: &StoragePaths) -> Result<Self> {
         let path = paths.config_path();
-        let cache = if path.exists() {
-            let data = fs::read_to_string(&path)?;
-            serde_json::from_str(&data).unwrap_or_default()
-        } else {
-            ConfigRecord::default()
-        };
-        Ok(Self {
+        let file: ConfigFile =
+            storage::read_versioned_or_quarantine(&path, &CONFIG_SCHEMA)?.unwrap_or_default();
+        let cache = file
+            .profiles
+            .get(&file.active_profile)
+            .cloned()
+            .unwrap_or_default();
+        let store = Self {
             path,
+            tracking_rules: Arc::new(RwLock::new(cache.tracking_rules())),
             cache: Mutex::new(cache),
-        })
+            file: Mutex::new(file),
+            api_base_changed: Notify::new(),
+        };
+        if let Err(err) = store.migrate_plaintext_secrets() {
+            log::warn!("failed to encrypt stored secrets: {err:?}");
+        }
+        Ok(store)
+    }
+
+    /// Protects plaintext secrets in every profile, not just the active one.
+    fn migrate_plaintext_secrets(&self) -> Result<()> {
+        let mut record = self.cache.lock();
+        let mut changed = record.protect_plaintext()?;
+        for profile in self.file.lock().profiles.values_mut() {
+            changed |= profile.protect_plaintext()?;
+        }
+        if !changed {
+            return Ok(());
+        }
+        self.persist_locked(&record)
+    }
+
+    fn persist_locked(&self, record: &ConfigRecord) -> Result<()> {
+        let mut file = self.file.lock();
+        let active = file.active_profile.clone();
+        file.profiles.insert(active, record.clone());
+        storage::atomic_write(&self.path, CONFIG_SCHEMA.encode(&*file)?)
+    }
+
+    pub fn active_profile(&self) -> String {
+        self.file.lock().active_profile.clone()
+    }
+
+    pub fn profiles(&self) -> Vec<ProfileSummary> {
+        let record = self.cache.lock();
+        let file = self.file.lock();
+        let mut summaries: Vec<_> = file
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                let active = *name == file.active_profile;
+                ProfileSummary {
+                    name: name.clone(),
+                    api_base: if active {
+                        record.api_base.clone()
+                    } else {
+                        profile.api_base.clone()
+                    },
+                    active,
+                }
+            })
+            .collect();
+        if !file.profiles.contains_key(&file.active_profile) {
+            summaries.push(ProfileSummary {
+                name: file.active_profile.clone(),
+                api_base: record.api_base.clone(),
+                active: true,
+            });
+        }
+        summaries
+    }
+
+    /// Makes `name` the active profile. A profile that does not exist yet is
+    /// created, which requires `api_base`; for an existing one `api_base`
+    /// replaces its stored value.
+    pub fn switch_profile(&self, name: &str, api_base: Option<&str>) -> Result<()> {
+        validate_profile_name(name)?;
+        let api_base = api_base.map(str::trim).filter(|base| !base.is_empty());
+        let mut record = self.cache.lock();
+        {
+            let mut file = self.file.lock();
+            if file.active_profile != name {
+                let mut next = match file.profiles.get(name) {
+                    Some(existing) => existing.clone(),
+                    None if api_base.is_some() => ConfigRecord::default(),
+                    None => {
+                        return Err(anyhow!(
+                            "profile {name:?} does not exist; an API base is needed to create it"
+                        ))
+                    }
+                };
+                std::mem::swap(&mut *record, &mut next);
+                let previous = std::mem::replace(&mut file.active_profile, name.to_string());
+                file.profiles.insert(previous, next);
+            }
+        }
+        if let Some(base) = api_base {
+            record.api_base = Some(base.to_string());
+        }
+        *self.tracking_rules.write() = record.tracking_rules();
+        self.persist_locked(&record)?;
+        self.api_base_changed.notify_one();
+        Ok(())
+    }
+
+    /// Removes an inactive profile's settings. Its files are the caller's
+    /// to clean up.
+    pub fn delete_profile(&self, name: &str) -> Result<()> {
+        let record = self.cache.lock();
+        {
+            let mut file = self.file.lock();
+            if file.active_profile == name {
+                return Err(anyhow!("cannot delete the active profile {name:?}"));
+            }
+            if file.profiles.remove(name).is_none() {
+                return Err(anyhow!("no profile named {name:?}"));
+            }
+        }
+        self.persist_locked(&record)
     }
 
     pub fn set_api_base(&self, url: &str) -> Result<()> {
         let mut record = self.cache.lock();
         record.api_base = Some(url.to_string());
-        let serialized = serde_json::to_string_pretty(&*record)?;
-        fs::write(&self.path, serialized)?;
+        self.persist_locked(&record)?;
+        self.api_base_changed.notify_one();
         Ok(())
     }
 
+    /// Resolves after the next [`set_api_base`](Self::set_api_base) call.
+    pub async fn api_base_changed(&self) {
+        self.api_base_changed.notified().await;
+    }
+
     pub fn get_api_base(&self) -> Option<String> {
         self.cache.lock().api_base.clone()
     }
 
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let record = self.cache.lock();
+        let defaults = RetryPolicy::default();
+        RetryPolicy {
+            max_attempts: record.upload_max_attempts.unwrap_or(defaults.max_attempts),
+            initial_backoff_ms: record
+                .upload_initial_backoff_ms
+                .unwrap_or(defaults.initial_backoff_ms),
+            max_backoff_ms: record
+                .upload_max_backoff_ms
+                .unwrap_or(defaults.max_backoff_ms),
+        }
+    }
+
+    pub fn set_retry_policy(&self, policy: RetryPolicy) -> Result<()> {
+        if policy.max_attempts == 0 {
+            return Err(anyhow!("upload_max_attempts must be at least 1"));
+        }
+        if policy.initial_backoff_ms > policy.max_backoff_ms {
+            return Err(anyhow!(
+                "upload_initial_backoff_ms must not exceed upload_max_backoff_ms"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.upload_max_attempts = Some(policy.max_attempts);
+        record.upload_initial_backoff_ms = Some(policy.initial_backoff_ms);
+        record.upload_max_backoff_ms = Some(policy.max_backoff_ms);
+        self.persist_locked(&record)
+    }
+
+    pub fn set_proxy(
+        &self,
+        url: Option<&str>,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<()> {
+        let url = url.map(str::trim).filter(|u| !u.is_empty());
+        if let Some(url) = url {
+            build_proxy(url, username, password)?;
+        }
+        let protected = match password {
+            Some(password) => Some(dpapi::protect_string(password, self.dpapi_scope())?),
+            None => None,
+        };
+        let mut record = self.cache.lock();
+        record.proxy_url = url.map(str::to_string);
+        record.proxy_username = username.map(str::to_string);
+        record.proxy_password = None;
+        record.proxy_password_protected = protected;
+        self.persist_locked(&record)
+    }
+
+    /// The explicitly configured proxy URL, without credentials.
+    pub fn proxy_url(&self) -> Option<String> {
+        self.cache.lock().proxy_url.clone()
+    }
+
+    /// Returns the explicitly configured proxy, or `None` to fall back to the
+    /// system proxy settings.
+    pub fn resolve_proxy(&self) -> Result<Option<reqwest::Proxy>> {
+        let record = self.cache.lock().clone();
+        match record.proxy_url.as_deref() {
+            Some(url) if !url.trim().is_empty() => Ok(Some(build_proxy(
+                url.trim(),
+                record.proxy_username.as_deref(),
+                proxy_password(&record)?.as_deref(),
+            )?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Replaces the SPKI SHA-256 pins (`sha256/<base64>`). An empty list
+    /// disables pinning.
+    pub fn set_tls_pins(&self, pins: Vec<String>) -> Result<()> {
+        for pin in &pins {
+            http::parse_pin(pin)?;
+        }
+        let mut record = self.cache.lock();
+        record.tls_pins = pins;
+        self.persist_locked(&record)
+    }
+
+    pub fn tls_pins(&self) -> Vec<String> {
+        self.cache.lock().tls_pins.clone()
+    }
+
+    /// Configures (or with `None`, removes) the PKCS#12 client identity used
+    /// for mutual TLS. The passphrase is stored DPAPI-protected.
+    pub fn set_client_certificate(
+        &self,
+        path: Option<&str>,
+        passphrase: Option<&str>,
+    ) -> Result<()> {
+        let path = path.map(str::trim).filter(|p| !p.is_empty());
+        let scope = self.dpapi_scope();
+        let protected = match (path, passphrase) {
+            (Some(_), Some(passphrase)) => Some(dpapi::protect_string(passphrase, scope)?),
+            _ => None,
+        };
+        if let Some(path) = path {
+            http::load_client_identity(path.as_ref(), passphrase.unwrap_or_default())?;
+        }
+        let mut record = self.cache.lock();
+        record.client_cert_path = path.map(str::to_owned);
+        record.client_cert_passphrase = protected;
+        self.persist_locked(&record)
+    }
+
+    /// PKCS#12 path of the client identity, without touching the passphrase.
+    pub fn client_certificate_path(&self) -> Option<String> {
+        self.cache.lock().client_cert_path.clone()
+    }
+
+    /// PKCS#12 path and decrypted passphrase, when mutual TLS is configured.
+    pub fn client_certificate(&self) -> Result<Option<(PathBuf, String)>> {
+        let (path, protected) = {
+            let record = self.cache.lock();
+            match &record.client_cert_path {
+                Some(path) => (PathBuf::from(path), record.client_cert_passphrase.clone()),
+                None => return Ok(None),
+            }
+        };
+        let passphrase = match protected {
+            Some(protected) => dpapi::unprotect_string(&protected)
+                .context("client certificate passphrase is unreadable")?,
+            None => String::new(),
+        };
+        Ok(Some((path, passphrase)))
+    }
+
+    pub fn request_timeout(&self) -> StdDuration {
+        StdDuration::from_millis(
+            self.cache
+                .lock()
+                .request_timeout_ms
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS),
+        )
+    }
+
+    pub fn connect_timeout(&self) -> StdDuration {
+        StdDuration::from_millis(
+            self.cache
+                .lock()
+                .connect_timeout_ms
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS),
+        )
+    }
+
+    pub fn set_timeouts(&self, request_timeout_ms: u64, connect_timeout_ms: u64) -> Result<()> {
+        if request_timeout_ms == 0 || connect_timeout_ms == 0 {
+            return Err(anyhow!("timeouts must be greater than zero"));
+        }
+        let mut record = self.cache.lock();
+        record.request_timeout_ms = Some(request_timeout_ms);
+        record.connect_timeout_ms = Some(connect_timeout_ms);
+        self.persist_locked(&record)
+    }
+
+    pub fn set_custom_headers(&self, headers: HashMap<String, String>) -> Result<()> {
+        for (name, value) in &headers {
+            validate_custom_header(name, value)?;
+        }
+        let mut record = self.cache.lock();
+        record.custom_headers = headers;
+        self.persist_locked(&record)
+    }
+
+    pub fn custom_headers(&self) -> HashMap<String, String> {
+        self.cache.lock().custom_headers.clone()
+    }
+
+    /// Custom headers ready to attach to a request. Entries that fail
+    /// validation (e.g. from a hand-edited config file) are skipped.
+    pub fn custom_header_map(&self) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in self.custom_headers() {
+            match validate_custom_header(&name, &value) {
+                Ok((name, value)) => {
+                    map.insert(name, value);
+                }
+                Err(err) => log::warn!("ignoring custom header: {err:#}"),
+            }
+        }
+        map
+    }
+
+    pub fn set_dry_run(&self, enabled: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.dry_run = enabled;
+        self.persist_locked(&record)
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.cache.lock().dry_run
+    }
+
+    /// Consecutive server rejections before a batch is moved out of the queue.
+    pub fn dead_letter_threshold(&self) -> u32 {
+        self.cache
+            .lock()
+            .dead_letter_threshold
+            .unwrap_or(DEFAULT_DEAD_LETTER_THRESHOLD)
+    }
+
+    pub fn set_dead_letter_threshold(&self, threshold: u32) -> Result<()> {
+        if threshold == 0 {
+            return Err(anyhow!("dead_letter_threshold must be at least 1"));
+        }
+        let mut record = self.cache.lock();
+        record.dead_letter_threshold = Some(threshold);
+        self.persist_locked(&record)
+    }
+
+    /// How many chunks of a batch may be in flight at once.
+    pub fn upload_concurrency(&self) -> usize {
+        self.cache
+            .lock()
+            .upload_concurrency
+            .unwrap_or(DEFAULT_UPLOAD_CONCURRENCY)
+            .clamp(1, MAX_UPLOAD_CONCURRENCY)
+    }
+
+    pub fn set_upload_concurrency(&self, concurrency: usize) -> Result<()> {
+        if !(1..=MAX_UPLOAD_CONCURRENCY).contains(&concurrency) {
+            return Err(anyhow!(
+                "upload_concurrency must be between 1 and {MAX_UPLOAD_CONCURRENCY}"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.upload_concurrency = Some(concurrency);
+        self.persist_locked(&record)
+    }
+
+    pub fn auth_mode(&self) -> AuthMode {
+        self.cache.lock().auth_mode
+    }
+
+    pub fn api_key(&self) -> Option<String> {
+        let (protected, plaintext) = {
+            let record = self.cache.lock();
+            (record.api_key_protected.clone(), record.api_key.clone())
+        };
+        let api_key = match protected {
+            Some(protected) => match dpapi::unprotect_string(&protected) {
+                Ok(api_key) => Some(api_key),
+                Err(err) => {
+                    log::warn!("stored API key is unreadable: {err:?}");
+                    None
+                }
+            },
+            None => plaintext,
+        };
+        api_key.filter(|key| !key.trim().is_empty())
+    }
+
+    /// Switches authentication mode; api-key mode requires a non-empty key.
+    pub fn set_auth_mode(&self, mode: AuthMode, api_key: Option<&str>) -> Result<()> {
+        let api_key = api_key.map(str::trim).filter(|key| !key.is_empty());
+        if mode == AuthMode::ApiKey && api_key.is_none() {
+            return Err(anyhow!("api_key is required when auth_mode is api_key"));
+        }
+        let protected = match api_key {
+            Some(api_key) => Some(dpapi::protect_string(api_key, self.dpapi_scope())?),
+            None => None,
+        };
+        let mut record = self.cache.lock();
+        record.auth_mode = mode;
+        record.api_key = None;
+        record.api_key_protected = protected;
+        self.persist_locked(&record)
+    }
+
+    pub fn dpapi_scope(&self) -> DpapiScope {
+        self.cache.lock().dpapi_scope
+    }
+
+    /// Changes the DPAPI scope and re-encrypts the secrets held in this file.
+    /// Stored tokens pick up the new scope the next time they are written.
+    pub fn set_dpapi_scope(&self, scope: DpapiScope) -> Result<()> {
+        let mut record = self.cache.lock();
+        let reprotect = |value: &Option<String>| -> Result<Option<String>> {
+            value
+                .as_deref()
+                .map(|value| dpapi::protect_string(&dpapi::unprotect_string(value)?, scope))
+                .transpose()
+        };
+        record.api_key_protected = reprotect(&record.api_key_protected)?;
+        record.client_cert_passphrase = reprotect(&record.client_cert_passphrase)?;
+        record.proxy_password_protected = reprotect(&record.proxy_password_protected)?;
+        record.dpapi_scope = scope;
+        self.persist_locked(&record)
+    }
+
+    pub fn credential_backend(&self) -> CredentialBackendKind {
+        self.cache.lock().credential_backend
+    }
+
+    /// Selects where tokens are stored; takes effect on the next start.
+    pub fn set_credential_backend(&self, kind: CredentialBackendKind) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.credential_backend = kind;
+        self.persist_locked(&record)
+    }
+
+    /// How long before expiry an access token is treated as expired, to
+    /// absorb clock skew and request latency.
+    pub fn token_expiry_margin(&self) -> StdDuration {
+        StdDuration::from_secs(
+            self.cache
+                .lock()
+                .token_expiry_margin_seconds
+                .unwrap_or(DEFAULT_TOKEN_EXPIRY_MARGIN_SECONDS),
+        )
+    }
+
+    pub fn set_token_expiry_margin_seconds(&self, seconds: u64) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.token_expiry_margin_seconds = Some(seconds);
+        self.persist_locked(&record)
+    }
+
+    /// How often the foreground window is sampled, clamped to 1-60 seconds.
+    pub fn sample_interval(&self) -> StdDuration {
+        let ms = self
+            .cache
+            .lock()
+            .sample_interval_ms
+            .unwrap_or(DEFAULT_SAMPLE_INTERVAL_MS)
+            .clamp(MIN_SAMPLE_INTERVAL_MS, MAX_SAMPLE_INTERVAL_MS);
+        StdDuration::from_millis(ms)
+    }
+
+    /// Takes effect at the sampler's next tick.
+    pub fn set_sample_interval_ms(&self, ms: u64) -> Result<()> {
+        if !(MIN_SAMPLE_INTERVAL_MS..=MAX_SAMPLE_INTERVAL_MS).contains(&ms) {
+            return Err(anyhow!(
+                "sample_interval_ms must be between {MIN_SAMPLE_INTERVAL_MS} and {MAX_SAMPLE_INTERVAL_MS}"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.sample_interval_ms = Some(ms);
+        self.persist_locked(&record)
+    }
+
+    /// How often usage is collected into a batch, clamped to 1 minute to 6
+    /// hours.
+    pub fn collect_interval(&self) -> StdDuration {
+        let minutes = self
+            .cache
+            .lock()
+            .collect_interval_minutes
+            .unwrap_or(DEFAULT_COLLECT_INTERVAL_MINUTES)
+            .clamp(MIN_COLLECT_INTERVAL_MINUTES, MAX_COLLECT_INTERVAL_MINUTES);
+        StdDuration::from_secs(minutes * 60)
+    }
+
+    /// Takes effect after the collect already scheduled.
+    pub fn set_collect_interval_minutes(&self, minutes: u64) -> Result<()> {
+        if !(MIN_COLLECT_INTERVAL_MINUTES..=MAX_COLLECT_INTERVAL_MINUTES).contains(&minutes) {
+            return Err(anyhow!(
+                "collect_interval_minutes must be between {MIN_COLLECT_INTERVAL_MINUTES} and {MAX_COLLECT_INTERVAL_MINUTES}"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.collect_interval_minutes = Some(minutes);
+        self.persist_locked(&record)
+    }
+
+    /// Pause between upload attempts while uploads succeed, clamped to 15
+    /// seconds to 6 hours. Failures back off from it.
+    pub fn upload_interval(&self) -> StdDuration {
+        let seconds = self
+            .cache
+            .lock()
+            .upload_interval_seconds
+            .unwrap_or(DEFAULT_UPLOAD_INTERVAL_SECONDS)
+            .clamp(MIN_UPLOAD_INTERVAL_SECONDS, MAX_UPLOAD_INTERVAL_SECONDS);
+        StdDuration::from_secs(seconds)
+    }
+
+    /// Takes effect after the upload already scheduled.
+    pub fn set_upload_interval_seconds(&self, seconds: u64) -> Result<()> {
+        if !(MIN_UPLOAD_INTERVAL_SECONDS..=MAX_UPLOAD_INTERVAL_SECONDS).contains(&seconds) {
+            return Err(anyhow!(
+                "upload_interval_seconds must be between {MIN_UPLOAD_INTERVAL_SECONDS} and {MAX_UPLOAD_INTERVAL_SECONDS}"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.upload_interval_seconds = Some(seconds);
+        self.persist_locked(&record)
+    }
+
+    /// Opt-in: record whether input happened between samples to derive a
+    /// per-session engagement ratio.
+    pub fn track_engagement(&self) -> bool {
+        self.cache.lock().track_engagement
+    }
+
+    pub fn set_track_engagement(&self, enabled: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.track_engagement = enabled;
+        self.persist_locked(&record)
+    }
+
+    /// Opt-in: read browser address bars through UI Automation to attribute
+    /// browser sessions to a domain.
+    pub fn browser_domains(&self) -> bool {
+        self.cache.lock().browser_domains
+    }
+
+    pub fn set_browser_domains(&self, enabled: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.browser_domains = enabled;
+        self.persist_locked(&record)
+    }
+
+    /// Adapter description fragments excluded from network totals, on top of
+    /// the built-in virtual adapter list.
+    pub fn network_exclusions(&self) -> Vec<String> {
+        self.cache
+            .lock()
+            .network_exclusions
+            .clone()
+            .unwrap_or_default()
+    }
+
+    pub fn set_network_exclusions(&self, fragments: Vec<String>) -> Result<()> {
+        if fragments.iter().any(|fragment| fragment.trim().is_empty()) {
+            return Err(anyhow!("network exclusions must not be empty"));
+        }
+        let mut record = self.cache.lock();
+        record.network_exclusions = (!fragments.is_empty()).then_some(fragments);
+        self.persist_locked(&record)
+    }
+
+    /// Whether Wi-Fi network names are reported; on unless disabled for
+    /// privacy.
+    pub fn ssid_collection(&self) -> bool {
+        !self.cache.lock().ssid_collection_disabled
+    }
+
+    pub fn set_ssid_collection(&self, enabled: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.ssid_collection_disabled = !enabled;
+        self.persist_locked(&record)
+    }
+
+    pub fn counter_reset_mode(&self) -> CounterResetMode {
+        self.cache.lock().counter_reset_mode
+    }
+
+    pub fn set_counter_reset_mode(&self, mode: CounterResetMode) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.counter_reset_mode = mode;
+        self.persist_locked(&record)
+    }
+
+    /// Executable name globs whose runtime is reported even in the
+    /// background. Empty disables background tracking.
+    pub fn background_watchlist(&self) -> Vec<String> {
+        self.cache
+            .lock()
+            .background_watchlist
+            .clone()
+            .unwrap_or_default()
+    }
+
+    pub fn set_background_watchlist(&self, patterns: Vec<String>) -> Result<()> {
+        if patterns.iter().any(|pattern| pattern.trim().is_empty()) {
+            return Err(anyhow!("watch-list patterns must not be empty"));
+        }
+        let mut record = self.cache.lock();
+        record.background_watchlist = (!patterns.is_empty()).then_some(patterns);
+        self.persist_locked(&record)
+    }
+
+    /// Interpreter executables whose sessions are attributed to the jar or
+    /// script they run.
+    pub fn script_hosts(&self) -> Vec<String> {
+        self.cache.lock().script_hosts.clone().unwrap_or_else(|| {
+            DEFAULT_SCRIPT_HOSTS
+                .iter()
+                .map(|host| host.to_string())
+                .collect()
+        })
+    }
+
+    pub fn set_script_hosts(&self, hosts: Vec<String>) -> Result<()> {
+        if hosts.iter().any(|host| host.trim().is_empty()) {
+            return Err(anyhow!("script host names must not be empty"));
+        }
+        let mut record = self.cache.lock();
+        record.script_hosts = Some(
+            hosts
+                .into_iter()
+                .map(|host| host.trim().to_lowercase())
+                .collect(),
+        );
+        self.persist_locked(&record)
+    }
+
+    /// Boundary interval at which long sessions are split. `None` keeps the
+    /// legacy behaviour of truncating them.
+    pub fn session_bucket(&self) -> Option<StdDuration> {
+        self.cache
+            .lock()
+            .session_bucket_minutes
+            .map(|minutes| StdDuration::from_secs(minutes * 60))
+    }
+
+    pub fn set_session_bucket_minutes(&self, minutes: Option<u64>) -> Result<()> {
+        if let Some(minutes) = minutes {
+            if minutes == 0 || minutes > MAX_SESSION_BUCKET_MINUTES {
+                return Err(anyhow!(
+                    "session_bucket_minutes must be between 1 and {MAX_SESSION_BUCKET_MINUTES}"
+                ));
+            }
+        }
+        let mut record = self.cache.lock();
+        record.session_bucket_minutes = minutes;
+        self.persist_locked(&record)
+    }
+
+    /// Read on every sample, so changes apply without a restart.
+    pub fn session_policy(&self) -> SessionPolicy {
+        let record = self.cache.lock();
+        let defaults = SessionPolicy::default();
+        SessionPolicy {
+            min_session_ms: record.session_min_ms.unwrap_or(defaults.min_session_ms),
+            merge_gap_ms: record.session_merge_gap_ms.unwrap_or(defaults.merge_gap_ms),
+            max_session_ms: record.session_max_ms.unwrap_or(defaults.max_session_ms),
+        }
+    }
+
+    pub fn set_session_policy(&self, policy: SessionPolicy) -> Result<()> {
+        if policy.min_session_ms >= policy.max_session_ms {
+            return Err(anyhow!("min_session_ms must be less than max_session_ms"));
+        }
+        if policy.max_session_ms > i64::MAX as u64 || policy.merge_gap_ms > i64::MAX as u64 {
+            return Err(anyhow!("session policy values are out of range"));
+        }
+        let mut record = self.cache.lock();
+        record.session_min_ms = Some(policy.min_session_ms);
+        record.session_merge_gap_ms = Some(policy.merge_gap_ms);
+        record.session_max_ms = Some(policy.max_session_ms);
+        self.persist_locked(&record)
+    }
+
+    /// Time without keyboard or mouse input after which the current session
+    /// ends. `None` when idle detection is disabled.
+    pub fn idle_threshold(&self) -> Option<StdDuration> {
+        let seconds = self
+            .cache
+            .lock()
+            .idle_threshold_seconds
+            .unwrap_or(DEFAULT_IDLE_THRESHOLD_SECONDS);
+        (seconds > 0).then(|| StdDuration::from_secs(seconds))
+    }
+
+    /// `0` disables idle detection.
+    pub fn set_idle_threshold_seconds(&self, seconds: u64) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.idle_threshold_seconds = Some(seconds);
+        self.persist_locked(&record)
+    }
+
+    /// Shared with the session sampler, which sees updates immediately.
+    pub fn tracking_rules(&self) -> Arc<RwLock<TrackingRules>> {
+        self.tracking_rules.clone()
+    }
+
+    pub fn set_tracking_rules(&self, rules: TrackingRules) -> Result<()> {
+        if rules
+            .blacklist
+            .iter()
+            .chain(rules.whitelist.iter().flatten())
+            .any(|pattern| pattern.trim().is_empty())
+        {
+            return Err(anyhow!("tracking patterns must not be empty"));
+        }
+        let mut record = self.cache.lock();
+        record.tracking_blacklist = Some(rules.blacklist);
+        record.tracking_whitelist = rules.whitelist;
+        self.persist_locked(&record)?;
+        *self.tracking_rules.write() = record.tracking_rules();
+        Ok(())
+    }
+
+    /// Whether a browser tab (window title) change starts a new session.
+    pub fn split_browser_titles(&self) -> bool {
+        self.cache.lock().split_browser_titles
+    }
+
+    pub fn set_split_browser_titles(&self, enabled: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.split_browser_titles = enabled;
+        self.persist_locked(&record)
+    }
+
+    /// Idle time after which a status-only heartbeat is sent.
+    pub fn heartbeat_interval(&self) -> StdDuration {
+        let minutes = self
+            .cache
+            .lock()
+            .heartbeat_interval_minutes
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_MINUTES);
+        StdDuration::from_secs(minutes * 60)
+    }
+
+    pub fn set_heartbeat_interval_minutes(&self, minutes: u64) -> Result<()> {
+        if minutes == 0 {
+            return Err(anyhow!("heartbeat_interval_minutes must be at least 1"));
+        }
+        let mut record = self.cache.lock();
+        record.heartbeat_interval_minutes = Some(minutes);
+        self.persist_locked(&record)
+    }
+
+    pub fn set_ndjson_uploads(&self, enabled: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.ndjson_uploads = enabled;
+        self.persist_locked(&record)
+    }
+
+    /// Whether whole batches are streamed as NDJSON instead of chunked JSON.
+    pub fn ndjson_uploads(&self) -> bool {
+        self.cache.lock().ndjson_uploads
+    }
+
+    /// Managed deployments can turn off failure notifications entirely.
+    pub fn notifications_silenced(&self) -> bool {
+        self.cache.lock().notifications_silenced
+    }
+
+    pub fn set_notifications_silenced(&self, silenced: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.notifications_silenced = silenced;
+        self.persist_locked(&record)
+    }
+
+    /// Consecutive failed upload cycles before the user is notified.
+    pub fn failure_alert_cycles(&self) -> u32 {
+        self.cache
+            .lock()
+            .failure_alert_cycles
+            .unwrap_or(DEFAULT_FAILURE_ALERT_CYCLES)
+    }
+
+    /// Minimum time between two failure notifications.
+    pub fn failure_alert_interval(&self) -> StdDuration {
+        let hours = self
+            .cache
+            .lock()
+            .failure_alert_interval_hours
+            .unwrap_or(DEFAULT_FAILURE_ALERT_INTERVAL_HOURS);
+        StdDuration::from_secs(hours * 60 * 60)
+    }
+
+    pub fn set_failure_alerts(&self, cycles: u32, interval_hours: u64) -> Result<()> {
+        if cycles == 0 {
+            return Err(anyhow!("failure_alert_cycles must be at least 1"));
+        }
+        if interval_hours == 0 {
+            return Err(anyhow!("failure_alert_interval_hours must be at least 1"));
+        }
+        let mut record = self.cache.lock();
+        record.failure_alert_cycles = Some(cycles);
+        record.failure_alert_interval_hours = Some(interval_hours);
+        self.persist_locked(&record)
+    }
+
+    /// Upload bandwidth cap in bytes per second; `None` is unlimited.
+    pub fn upload_rate_limit(&self) -> Option<u64> {
+        self.cache.lock().upload_rate_limit_bps
+    }
+
+    pub fn set_upload_rate_limit(&self, bytes_per_second: Option<u64>) -> Result<()> {
+        if bytes_per_second == Some(0) {
+            return Err(anyhow!(
+                "upload rate limit must be positive; use none for unlimited"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.upload_rate_limit_bps = bytes_per_second;
+        self.persist_locked(&record)
+    }
+
+    /// Bytes per collection interval above which network usage is flagged
+    /// and uploaded early; `None` disables spike detection.
+    pub fn spike_threshold(&self) -> Option<u64> {
+        self.cache.lock().spike_threshold_bytes
+    }
+
+    pub fn set_spike_threshold(&self, bytes: Option<u64>) -> Result<()> {
+        if bytes == Some(0) {
+            return Err(anyhow!(
+                "spike threshold must be positive; use none to disable"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.spike_threshold_bytes = bytes;
+        self.persist_locked(&record)
+    }
+
+    /// Free disk space below which the queue evicts its oldest batches and
+    /// the status reports low disk. Zero disables both.
+    pub fn min_free_disk_bytes(&self) -> u64 {
+        let mb = self
+            .cache
+            .lock()
+            .min_free_disk_mb
+            .unwrap_or(DEFAULT_MIN_FREE_DISK_MB);
+        mb * 1024 * 1024
+    }
+
+    pub fn set_min_free_disk_mb(&self, mb: u64) -> Result<()> {
+        if mb > u64::MAX / (1024 * 1024) {
+            return Err(anyhow!("min_free_disk_mb is out of range"));
+        }
+        let mut record = self.cache.lock();
+        record.min_free_disk_mb = Some(mb);
+        self.persist_locked(&record)
+    }
+
+    /// Days queued batches, dead letters and quarantined files are kept.
+    pub fn retention_days(&self) -> u32 {
+        self.cache
+            .lock()
+            .retention_days
+            .unwrap_or(MAX_RETENTION_DAYS)
+    }
+
+    pub fn set_retention_days(&self, days: u32) -> Result<()> {
+        if !(1..=MAX_RETENTION_DAYS).contains(&days) {
+            return Err(anyhow!(
+                "retention must be between 1 and {MAX_RETENTION_DAYS} days"
+            ));
+        }
+        let mut record = self.cache.lock();
+        record.retention_days = Some(days);
+        self.persist_locked(&record)
+    }
+
+    /// Whether queued usage is stored gzip-compressed; on unless disabled,
+    /// e.g. to inspect the queue database by hand.
+    pub fn queue_compression(&self) -> bool {
+        !self.cache.lock().queue_compression_disabled
+    }
+
+    pub fn set_queue_compression(&self, enabled: bool) -> Result<()> {
+        let mut record = self.cache.lock();
+        record.queue_compression_disabled = !enabled;
+        self.persist_locked(&record)
+    }
+
     pub fn resolve_upload_config(&self) -> Result<UploadConfig> {
         let base = self.get_api_base().context("api base url not configured")?;
         let mut base_url =
@@ -59,13 +1133,64 @@ impl UsageConfigStore {
             .path_segments_mut()
             .map_err(|_| anyhow!("invalid base url"))?
             .extend(["api", "v1", "usage", "batch"]);
+        let mut heartbeat_url = base_url.clone();
+        heartbeat_url
+            .path_segments_mut()
+            .map_err(|_| anyhow!("invalid base url"))?
+            .extend(["api", "v1", "devices", "heartbeat"]);
         Ok(UploadConfig {
             base_url,
             batch_url,
+            heartbeat_url,
         })
     }
 }
 
+fn validate_profile_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_PROFILE_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow!(
+            "profile names must be 1-{MAX_PROFILE_NAME_LEN} letters, digits, '-' or '_'"
+        ));
+    }
+    Ok(())
+}
+
+fn validate_custom_header(name: &str, value: &str) -> Result<(HeaderName, HeaderValue)> {
+    let header_name = HeaderName::from_bytes(name.trim().as_bytes())
+        .with_context(|| format!("invalid header name {name:?}"))?;
+    if RESERVED_HEADERS.contains(&header_name.as_str()) {
+        return Err(anyhow!("header {name:?} cannot be overridden"));
+    }
+    let header_value = HeaderValue::from_str(value)
+        .with_context(|| format!("invalid value for header {name:?}"))?;
+    Ok((header_name, header_value))
+}
+
+fn build_proxy(
+    url: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<reqwest::Proxy> {
+    let parsed = reqwest::Url::parse(url).with_context(|| format!("invalid proxy_url {url:?}"))?;
+    if !matches!(parsed.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(anyhow!(
+            "invalid proxy_url {url:?}: unsupported scheme {}",
+            parsed.scheme()
+        ));
+    }
+    let mut proxy =
+        reqwest::Proxy::all(parsed).with_context(|| format!("invalid proxy_url {url:?}"))?;
+    if let Some(username) = username {
+        proxy = proxy.basic_auth(username, password.unwrap_or_default());
+    }
+    Ok(proxy)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct DeviceRecord {
     device_id: Uuid,
@@ -74,13 +1199,17 @@ struct DeviceRecord {
 
 pub struct DeviceIdStore {
     path: PathBuf,
-    cache: Mutex<Option<DeviceRecord>>,
+    /// Read on every collect and heartbeat; only registration replaces it.
+    device_id: RwLock<Option<Uuid>>,
+    /// `last_seen` as last written to disk. Held while writing, so writes
+    /// never block readers of the id.
+    persisted: Mutex<Option<DateTime<Utc>>>,
 }
 
 impl DeviceIdStore {
     pub fn new(paths: &StoragePaths) -> Result<Self> {
         let path = paths.device_path();
-        let cache = if path.exists() {
+        let record: Option<DeviceRecord> = if path.exists() {
             let data = fs::read_to_string(&path)?;
             serde_json::from_str(&data).ok()
         } else {
@@ -88,31 +1217,51 @@ impl DeviceIdStore {
         };
         Ok(Self {
             path,
-            cache: Mutex::new(cache),
+            device_id: RwLock::new(record.as_ref().map(|record| record.device_id)),
+            persisted: Mutex::new(record.map(|record| record.last_seen)),
         })
     }
 
-    pub fn current(&self) -> Option<Uuid> {
-        self.cache.lock().as_ref().map(|record| record.device_id)
+    /// The stored device id. Never writes.
+    pub fn get(&self) -> Option<Uuid> {
+        *self.device_id.read()
     }
 
     pub fn save(&self, device_id: Uuid) -> Result<()> {
+        let mut persisted = self.persisted.lock();
+        *self.device_id.write() = Some(device_id);
+        self.persist(device_id, &mut persisted)
+    }
+
+    fn persist(&self, device_id: Uuid, persisted: &mut Option<DateTime<Utc>>) -> Result<()> {
         let record = DeviceRecord {
             device_id,
             last_seen: Utc::now(),
         };
-        let serialized = serde_json::to_string_pretty(&record)?;
-        {
-            let mut guard = self.cache.lock();
-            *guard = Some(record);
-        }
-        fs::write(&self.path, serialized)?;
+        let json = serde_json::to_string_pretty(&record)?;
+        storage::atomic_write_with(&self.path, json, |temp| {
+            if let Err(err) = acl::restrict_to_current_user(temp) {
+                log::warn!("failed to restrict device file permissions: {err:?}");
+            }
+        })?;
+        *persisted = Some(record.last_seen);
         Ok(())
     }
 
+    /// The device id, created on first use. `last_seen` is only rewritten
+    /// once the stored one is more than `DEVICE_LAST_SEEN_PERSIST_MINUTES`
+    /// old.
     pub fn get_or_create(&self) -> Result<Uuid> {
-        if let Some(existing) = self.current() {
-            self.save(existing)?;
+        if let Some(existing) = self.get() {
+            let mut persisted = self.persisted.lock();
+            // Registration may have replaced the id while we waited.
+            let existing = self.get().unwrap_or(existing);
+            let fresh = persisted.is_some_and(|last_seen| {
+                Utc::now() - last_seen < chrono::Duration::minutes(DEVICE_LAST_SEEN_PERSIST_MINUTES)
+            });
+            if !fresh {
+                self.persist(existing, &mut persisted)?;
+            }
             return Ok(existing);
         }
         let new_id = Uuid::new_v4();
@@ -120,3 +1269,175 @@ impl DeviceIdStore {
         Ok(new_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Storage paths under a fresh directory in the system temp dir.
+    fn paths() -> StoragePaths {
+        let root = std::env::temp_dir().join(format!("nuscape-config-{}", Uuid::new_v4()));
+        StoragePaths::with_root(root).unwrap()
+    }
+
+    #[test]
+    fn retry_policy_is_validated_and_survives_a_restart() {
+        let paths = paths();
+        let store = UsageConfigStore::new(&paths).unwrap();
+        let policy = |max_attempts, initial_backoff_ms, max_backoff_ms| RetryPolicy {
+            max_attempts,
+            initial_backoff_ms,
+            max_backoff_ms,
+        };
+        assert!(store.set_retry_policy(policy(0, 1_000, 10_000)).is_err());
+        assert!(store.set_retry_policy(policy(3, 20_000, 10_000)).is_err());
+        assert_eq!(
+            store.retry_policy().max_attempts,
+            RetryPolicy::default().max_attempts
+        );
+
+        store.set_retry_policy(policy(5, 500, 30_000)).unwrap();
+        let reopened = UsageConfigStore::new(&paths).unwrap().retry_policy();
+        assert_eq!(reopened.max_attempts, 5);
+        assert_eq!(reopened.initial_backoff_ms, 500);
+        assert_eq!(reopened.max_backoff_ms, 30_000);
+    }
+
+    #[test]
+    fn proxy_passwords_are_stored_protected() {
+        let paths = paths();
+        let legacy = |password: &str| json!({ "proxy_url": "http://proxy.example:8080", "proxy_password": password });
+        let file = json!({
+            "active_profile": DEFAULT_PROFILE,
+            "profiles": { DEFAULT_PROFILE: legacy("active-secret"), "other": legacy("other-secret") },
+        });
+        fs::write(paths.config_path(), file.to_string()).unwrap();
+
+        let store = UsageConfigStore::new(&paths).unwrap();
+        let saved = fs::read_to_string(paths.config_path()).unwrap();
+        assert!(!saved.contains("active-secret") && !saved.contains("other-secret"));
+        let password = |store: &UsageConfigStore| proxy_password(&store.cache.lock()).unwrap();
+        assert_eq!(password(&store).as_deref(), Some("active-secret"));
+        assert!(store.resolve_proxy().unwrap().is_some());
+        store.switch_profile("other", None).unwrap();
+        assert_eq!(password(&store).as_deref(), Some("other-secret"));
+
+        store
+            .set_proxy(
+                Some("http://proxy.example:8080"),
+                Some("me"),
+                Some("new-secret"),
+            )
+            .unwrap();
+        store.set_dpapi_scope(DpapiScope::Machine).unwrap();
+        let saved = fs::read_to_string(paths.config_path()).unwrap();
+        assert!(!saved.contains("new-secret"));
+        let reopened = UsageConfigStore::new(&paths).unwrap();
+        assert_eq!(password(&reopened).as_deref(), Some("new-secret"));
+    }
+
+    #[test]
+    fn a_config_from_before_profiles_becomes_the_default_profile() {
+        let paths = paths();
+        let legacy = json!({
+            "api_base": "https://legacy.example.com",
+            "upload_max_attempts": 7,
+            "upload_initial_backoff_ms": 250,
+            "upload_max_backoff_ms": 9_000,
+            "api_key": "legacy-key",
+        });
+        fs::write(paths.config_path(), legacy.to_string()).unwrap();
+
+        let store = UsageConfigStore::new(&paths).unwrap();
+        assert_eq!(store.active_profile(), DEFAULT_PROFILE);
+        let profiles = store.profiles();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].name, DEFAULT_PROFILE);
+        assert_eq!(
+            store.get_api_base().as_deref(),
+            Some("https://legacy.example.com")
+        );
+        let policy = store.retry_policy();
+        assert_eq!(policy.max_attempts, 7);
+        assert_eq!(policy.initial_backoff_ms, 250);
+        assert_eq!(policy.max_backoff_ms, 9_000);
+        assert_eq!(store.api_key().as_deref(), Some("legacy-key"));
+
+        // Rewritten in the current layout, with the key no longer in the clear.
+        let saved = fs::read_to_string(paths.config_path()).unwrap();
+        assert!(!saved.contains("legacy-key"));
+        let reopened = UsageConfigStore::new(&paths).unwrap();
+        assert_eq!(reopened.api_key().as_deref(), Some("legacy-key"));
+        assert_eq!(reopened.retry_policy().max_attempts, 7);
+    }
+
+    #[test]
+    fn timeouts_default_and_reject_zero() {
+        let paths = paths();
+        let store = UsageConfigStore::new(&paths).unwrap();
+        assert_eq!(
+            store.request_timeout(),
+            StdDuration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS)
+        );
+        assert_eq!(
+            store.connect_timeout(),
+            StdDuration::from_millis(DEFAULT_CONNECT_TIMEOUT_MS)
+        );
+        assert!(store.set_timeouts(0, 5_000).is_err());
+        assert!(store.set_timeouts(5_000, 0).is_err());
+
+        store.set_timeouts(45_000, 5_000).unwrap();
+        let reopened = UsageConfigStore::new(&paths).unwrap();
+        assert_eq!(reopened.request_timeout(), StdDuration::from_secs(45));
+        assert_eq!(reopened.connect_timeout(), StdDuration::from_secs(5));
+    }
+
+    #[test]
+    fn custom_headers_reject_reserved_and_malformed_entries() {
+        assert!(validate_custom_header("X-Org-Id", "acme").is_ok());
+        assert!(validate_custom_header("Authorization", "Bearer x").is_err());
+        assert!(validate_custom_header("content-type", "text/plain").is_err());
+        assert!(validate_custom_header("bad header", "x").is_err());
+        assert!(validate_custom_header("X-Org-Id", "line\nbreak").is_err());
+
+        let store = UsageConfigStore::new(&paths()).unwrap();
+        let headers = HashMap::from([
+            ("X-Org-Id".to_string(), "acme".to_string()),
+            ("Host".to_string(), "evil.example".to_string()),
+        ]);
+        assert!(store.set_custom_headers(headers).is_err());
+        assert!(store.custom_headers().is_empty());
+    }
+
+    #[test]
+    fn custom_header_map_skips_hand_edited_reserved_entries() {
+        let store = UsageConfigStore::new(&paths()).unwrap();
+        store
+            .set_custom_headers(HashMap::from([(
+                "X-Org-Id".to_string(),
+                "acme".to_string(),
+            )]))
+            .unwrap();
+        store
+            .cache
+            .lock()
+            .custom_headers
+            .insert("Authorization".to_string(), "Bearer forged".to_string());
+
+        let map = store.custom_header_map();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map["x-org-id"], "acme");
+    }
+
+    #[test]
+    fn retention_is_between_one_day_and_the_maximum() {
+        let store = UsageConfigStore::new(&paths()).unwrap();
+        assert_eq!(store.retention_days(), MAX_RETENTION_DAYS);
+        assert!(store.set_retention_days(0).is_err());
+        assert!(store.set_retention_days(MAX_RETENTION_DAYS + 1).is_err());
+        store.set_retention_days(1).unwrap();
+        assert_eq!(store.retention_days(), 1);
+    }
+}
@@ -0,0 +1,182 @@
+use std::fs;
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use windows::core::{PCWSTR, PWSTR};
+use windows::Win32::Foundation::ERROR_NOT_FOUND;
+use windows::Win32::Security::Credentials::{
+    CredDeleteW, CredFree, CredReadW, CredWriteW, CREDENTIALW, CRED_MAX_CREDENTIAL_BLOB_SIZE,
+    CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC,
+};
+
+use crate::acl;
+use crate::dpapi::{self, DpapiScope};
+use crate::storage::{self, StoragePaths, DEFAULT_PROFILE};
+
+const CREDENTIAL_TARGET_PREFIX: &str = "NuScape-Windows-Agent";
+
+/// Where the serialized token record is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialBackendKind {
+    #[default]
+    File,
+    Wincred,
+}
+
+/// Opaque storage for the token record; callers own the serialization.
+pub trait CredentialBackend: Send + Sync {
+    fn read(&self) -> Result<Option<Vec<u8>>>;
+    fn write(&self, data: &[u8]) -> Result<()>;
+    fn delete(&self) -> Result<()>;
+    /// Moves an unreadable record aside for support, returning where it
+    /// went; `None` when the backend cannot keep a copy.
+    fn quarantine(&self) -> Result<Option<PathBuf>>;
+}
+
+pub fn open(
+    kind: CredentialBackendKind,
+    paths: &StoragePaths,
+    scope: DpapiScope,
+) -> Box<dyn CredentialBackend> {
+    match kind {
+        CredentialBackendKind::File => Box::new(FileBackend {
+            path: paths.tokens_path(),
+            scope,
+        }),
+        CredentialBackendKind::Wincred => Box::new(WinCredBackend {
+            target: credential_target(paths.profile())
+                .encode_utf16()
+                .chain([0])
+                .collect(),
+        }),
+    }
+}
+
+/// The default profile keeps the target name used before profiles existed.
+fn credential_target(profile: &str) -> String {
+    if profile == DEFAULT_PROFILE {
+        format!("{CREDENTIAL_TARGET_PREFIX}/tokens")
+    } else {
+        format!("{CREDENTIAL_TARGET_PREFIX}/{profile}/tokens")
+    }
+}
+
+/// DPAPI-encrypted file in the agent's data directory.
+pub struct FileBackend {
+    path: PathBuf,
+    scope: DpapiScope,
+}
+
+impl CredentialBackend for FileBackend {
+    /// Plaintext JSON written by older agents is returned as-is and
+    /// re-saved encrypted.
+    fn read(&self) -> Result<Option<Vec<u8>>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(&self.path)?;
+        if serde_json::from_slice::<serde_json::Value>(&data).is_ok() {
+            if let Err(err) = self.write(&data) {
+                log::warn!("failed to encrypt plaintext token file: {err:?}");
+            }
+            return Ok(Some(data));
+        }
+        Ok(Some(dpapi::unprotect(&data)?))
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        storage::atomic_write_with(&self.path, dpapi::protect(data, self.scope)?, |temp| {
+            if let Err(err) = acl::restrict_to_current_user(temp) {
+                log::warn!("failed to restrict token file permissions: {err:?}");
+            }
+        })
+    }
+
+    fn delete(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn quarantine(&self) -> Result<Option<PathBuf>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        storage::quarantine(&self.path).map(Some)
+    }
+}
+
+/// Generic credential in the Windows Credential Manager, which encrypts it
+/// for the current user.
+pub struct WinCredBackend {
+    target: Vec<u16>,
+}
+
+impl CredentialBackend for WinCredBackend {
+    fn read(&self) -> Result<Option<Vec<u8>>> {
+        let mut credential: *mut CREDENTIALW = ptr::null_mut();
+        let read = unsafe {
+            CredReadW(
+                PCWSTR(self.target.as_ptr()),
+                CRED_TYPE_GENERIC,
+                0,
+                &mut credential,
+            )
+        };
+        match read {
+            Ok(()) => {}
+            Err(err) if err.code() == ERROR_NOT_FOUND.to_hresult() => return Ok(None),
+            Err(err) => return Err(err).context("CredReadW failed"),
+        }
+        unsafe {
+            let blob = &*credential;
+            let data = if blob.CredentialBlob.is_null() {
+                Vec::new()
+            } else {
+                slice::from_raw_parts(blob.CredentialBlob, blob.CredentialBlobSize as usize)
+                    .to_vec()
+            };
+            CredFree(credential.cast());
+            Ok(Some(data))
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        if data.len() > CRED_MAX_CREDENTIAL_BLOB_SIZE as usize {
+            return Err(anyhow!(
+                "token record is {} bytes, over the Credential Manager limit of {}",
+                data.len(),
+                CRED_MAX_CREDENTIAL_BLOB_SIZE
+            ));
+        }
+        let mut target = self.target.clone();
+        let credential = CREDENTIALW {
+            Type: CRED_TYPE_GENERIC,
+            TargetName: PWSTR(target.as_mut_ptr()),
+            CredentialBlobSize: data.len() as u32,
+            CredentialBlob: data.as_ptr() as *mut u8,
+            Persist: CRED_PERSIST_LOCAL_MACHINE,
+            ..Default::default()
+        };
+        unsafe { CredWriteW(&credential, 0) }.context("CredWriteW failed")
+    }
+
+    fn delete(&self) -> Result<()> {
+        match unsafe { CredDeleteW(PCWSTR(self.target.as_ptr()), CRED_TYPE_GENERIC, 0) } {
+            Ok(()) => Ok(()),
+            Err(err) if err.code() == ERROR_NOT_FOUND.to_hresult() => Ok(()),
+            Err(err) => Err(err).context("CredDeleteW failed"),
+        }
+    }
+
+    /// Credential Manager keeps one entry per target; the unreadable one is
+    /// overwritten by the next save.
+    fn quarantine(&self) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
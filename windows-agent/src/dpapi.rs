@@ -0,0 +1,85 @@
+use std::slice;
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{LocalFree, HLOCAL};
+use windows::Win32::Security::Cryptography::{
+    CryptProtectData, CryptUnprotectData, CRYPTPROTECT_LOCAL_MACHINE, CRYPTPROTECT_UI_FORBIDDEN,
+    CRYPT_INTEGER_BLOB,
+};
+
+/// Who can decrypt a protected blob: only the current Windows user, or any
+/// account on this machine (needed when the agent runs as a service).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DpapiScope {
+    #[default]
+    User,
+    Machine,
+}
+
+/// Encrypts `data` with DPAPI for the given scope.
+pub fn protect(data: &[u8], scope: DpapiScope) -> Result<Vec<u8>> {
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut u8,
+    };
+    let flags = match scope {
+        DpapiScope::User => CRYPTPROTECT_UI_FORBIDDEN,
+        DpapiScope::Machine => CRYPTPROTECT_UI_FORBIDDEN | CRYPTPROTECT_LOCAL_MACHINE,
+    };
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    unsafe {
+        CryptProtectData(&input, PCWSTR::null(), None, None, None, flags, &mut output)
+            .context("CryptProtectData failed")?;
+        Ok(take_blob(output))
+    }
+}
+
+/// Decrypts data previously produced by [`protect`]; the scope is recorded in
+/// the blob itself.
+pub fn unprotect(data: &[u8]) -> Result<Vec<u8>> {
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut u8,
+    };
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    unsafe {
+        CryptUnprotectData(
+            &input,
+            None,
+            None,
+            None,
+            None,
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+        .context("CryptUnprotectData failed")?;
+        Ok(take_blob(output))
+    }
+}
+
+/// Protects a string secret for storage in a JSON file as base64.
+pub fn protect_string(value: &str, scope: DpapiScope) -> Result<String> {
+    Ok(BASE64.encode(protect(value.as_bytes(), scope)?))
+}
+
+/// Reverses [`protect_string`].
+pub fn unprotect_string(value: &str) -> Result<String> {
+    let blob = BASE64
+        .decode(value)
+        .context("protected value is not valid base64")?;
+    String::from_utf8(unprotect(&blob)?).context("protected value is not UTF-8")
+}
+
+unsafe fn take_blob(blob: CRYPT_INTEGER_BLOB) -> Vec<u8> {
+    if blob.pbData.is_null() {
+        return Vec::new();
+    }
+    let bytes = slice::from_raw_parts(blob.pbData, blob.cbData as usize).to_vec();
+    let _ = LocalFree(HLOCAL(blob.pbData.cast()));
+    bytes
+}
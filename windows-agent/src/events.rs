@@ -0,0 +1,89 @@
+use serde::Serialize;
+use tauri::api::notification::Notification;
+use tauri::{AppHandle, Manager};
+
+use crate::models::{RegistrationOutcome, UploadResult};
+
+pub const UPLOAD_SUCCEEDED_EVENT: &str = "usage://upload-succeeded";
+pub const UPLOAD_FAILED_EVENT: &str = "usage://upload-failed";
+pub const QUEUE_CHANGED_EVENT: &str = "usage://queue-changed";
+pub const PAIRING_RESULT_EVENT: &str = "usage://pairing-result";
+
+/// Receives agent lifecycle notifications; kept free of Tauri types so the
+/// runtime and stores can be driven without an app handle.
+pub trait AgentEvents: Send + Sync {
+    fn upload_succeeded(&self, result: &UploadResult);
+    fn upload_failed(&self, result: &UploadResult);
+    fn queue_changed(&self, queue_size: usize);
+    /// Uploads have been failing long enough that the user should know.
+    fn failure_alert(&self, message: &str);
+    /// A pairing attempt finished; `Err` carries a message for a retry prompt.
+    fn pairing_result(&self, result: Result<RegistrationOutcome, String>);
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct QueueChangedPayload {
+    queue_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PairingResultPayload {
+    outcome: Option<RegistrationOutcome>,
+    error: Option<String>,
+}
+
+/// Forwards events to every Tauri window.
+pub struct TauriEvents {
+    app: AppHandle,
+}
+
+impl TauriEvents {
+    pub fn new(app: AppHandle) -> Self {
+        Self { app }
+    }
+
+    fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) {
+        if let Err(err) = self.app.emit_all(event, payload) {
+            log::debug!("failed to emit {event}: {err}");
+        }
+    }
+}
+
+impl AgentEvents for TauriEvents {
+    fn upload_succeeded(&self, result: &UploadResult) {
+        self.emit(UPLOAD_SUCCEEDED_EVENT, result.clone());
+    }
+
+    fn upload_failed(&self, result: &UploadResult) {
+        self.emit(UPLOAD_FAILED_EVENT, result.clone());
+    }
+
+    fn queue_changed(&self, queue_size: usize) {
+        self.emit(QUEUE_CHANGED_EVENT, QueueChangedPayload { queue_size });
+    }
+
+    fn failure_alert(&self, message: &str) {
+        let identifier = self.app.config().tauri.bundle.identifier.clone();
+        if let Err(err) = Notification::new(identifier)
+            .title("NuScape")
+            .body(message)
+            .show()
+        {
+            log::warn!("failed to show notification: {err}");
+        }
+    }
+
+    fn pairing_result(&self, result: Result<RegistrationOutcome, String>) {
+        let payload = match result {
+            Ok(outcome) => PairingResultPayload {
+                outcome: Some(outcome),
+                error: None,
+            },
+            Err(error) => PairingResultPayload {
+                outcome: None,
+                error: Some(error),
+            },
+        };
+        self.emit(PAIRING_RESULT_EVENT, payload);
+    }
+}
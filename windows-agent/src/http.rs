@@ -0,0 +1,430 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use once_cell::sync::Lazy;
+use p12_keystore::KeyStore;
+use reqwest::ClientBuilder;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+use crate::collectors::status;
+use crate::config::UsageConfigStore;
+use crate::models::UploadFailureReason;
+
+pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+const PIN_MISMATCH: &str = "certificate pin mismatch";
+/// Winsock resolver codes: WSAHOST_NOT_FOUND, WSATRY_AGAIN, WSANO_RECOVERY, WSANO_DATA.
+const WSA_DNS_ERRORS: [i32; 4] = [11001, 11002, 11003, 11004];
+
+static MTLS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Shared reqwest client configuration for every backend call. The system
+/// proxy is picked up by reqwest unless an explicit proxy is configured.
+pub fn client_builder(config_store: &UsageConfigStore) -> Result<ClientBuilder> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(user_agent())
+        .timeout(config_store.request_timeout())
+        .connect_timeout(config_store.connect_timeout());
+    if let Some(proxy) = config_store.resolve_proxy()? {
+        builder = builder.proxy(proxy);
+    }
+    let pins = config_store.tls_pins();
+    let identity = match config_store.client_certificate()? {
+        Some((path, passphrase)) => Some(load_client_identity(&path, &passphrase)?),
+        None => None,
+    };
+    MTLS_ACTIVE.store(identity.is_some(), Ordering::Relaxed);
+    if !pins.is_empty() || identity.is_some() {
+        builder = builder.use_preconfigured_tls(tls_config(public_roots(), &pins, identity)?);
+    }
+    Ok(builder)
+}
+
+/// Whether the most recently built client presents a client certificate.
+pub fn mtls_active() -> bool {
+    MTLS_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Certificate chain and private key for mutual TLS.
+pub struct ClientIdentity {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+/// Reads a PKCS#12 bundle, failing loudly so a bad path or passphrase is a
+/// startup error rather than a stream of handshake failures.
+pub fn load_client_identity(path: &Path, passphrase: &str) -> Result<ClientIdentity> {
+    let data =
+        fs::read(path).with_context(|| format!("read client certificate {}", path.display()))?;
+    let store = KeyStore::from_pkcs12(&data, passphrase)
+        .map_err(|err| anyhow!("{err:?}"))
+        .with_context(|| format!("open client certificate {}", path.display()))?;
+    let (_, chain) = store
+        .private_key_chain()
+        .ok_or_else(|| anyhow!("client certificate {} has no private key", path.display()))?;
+    if chain.chain().is_empty() {
+        return Err(anyhow!(
+            "client certificate {} has no certificates",
+            path.display()
+        ));
+    }
+    Ok(ClientIdentity {
+        chain: chain
+            .chain()
+            .iter()
+            .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+            .collect(),
+        key: PrivateKeyDer::Pkcs8(chain.key().to_vec().into()),
+    })
+}
+
+/// Parses a pin in `sha256/<base64>` (or bare base64) form into the raw digest.
+pub fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let encoded = pin.trim();
+    let encoded = encoded.strip_prefix("sha256/").unwrap_or(encoded);
+    let bytes = BASE64
+        .decode(encoded)
+        .with_context(|| format!("invalid certificate pin {pin:?}"))?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("certificate pin {pin:?} is not a SHA-256 digest"))
+}
+
+/// `NuScape-Windows-Agent/<version> (Windows <build>; <arch>)`, computed once.
+pub fn user_agent() -> &'static str {
+    static USER_AGENT: Lazy<String> = Lazy::new(|| {
+        format!(
+            "NuScape-Windows-Agent/{AGENT_VERSION} ({}; {})",
+            status::os_version(),
+            std::env::consts::ARCH
+        )
+    });
+    &USER_AGENT
+}
+
+/// True when a request failed because the server chain matched none of the pins.
+pub fn is_pin_mismatch(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if err.to_string().contains(PIN_MISMATCH) {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Maps a transport error onto the most specific failure reason available.
+pub fn classify_transport_error(err: &reqwest::Error) -> UploadFailureReason {
+    if is_pin_mismatch(err) {
+        return UploadFailureReason::PinMismatch;
+    }
+    if err.is_timeout() {
+        return UploadFailureReason::Timeout;
+    }
+    if err.is_connect() {
+        if is_dns_failure(err) {
+            return UploadFailureReason::DnsFailure;
+        }
+        return UploadFailureReason::ConnectFailure;
+    }
+    UploadFailureReason::NetworkError
+}
+
+fn is_dns_failure(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if err.to_string().starts_with("dns error") {
+            return true;
+        }
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if io
+                .raw_os_error()
+                .is_some_and(|code| WSA_DNS_ERRORS.contains(&code))
+            {
+                return true;
+            }
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// The public CAs a backend certificate has to chain to.
+fn public_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    roots
+}
+
+fn tls_config(
+    roots: RootCertStore,
+    pins: &[String],
+    identity: Option<ClientIdentity>,
+) -> Result<ClientConfig> {
+    let pins = pins
+        .iter()
+        .map(|pin| parse_pin(pin))
+        .collect::<Result<Vec<_>>>()?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if pins.is_empty() {
+        builder.with_root_certificates(roots)
+    } else {
+        let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .context("build certificate verifier")?;
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier { inner, pins }))
+    };
+    let config = match identity {
+        Some(identity) => builder
+            .with_client_auth_cert(identity.chain, identity.key)
+            .context("client certificate key is unusable")?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+/// Runs the normal WebPKI chain validation, then additionally requires that
+/// the SPKI of at least one certificate in the presented chain matches a pin.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinnedVerifier {
+    fn matches(&self, cert: &CertificateDer<'_>) -> bool {
+        match webpki::EndEntityCert::try_from(cert) {
+            Ok(parsed) => {
+                let digest: [u8; 32] =
+                    Sha256::digest(parsed.subject_public_key_info().as_ref()).into();
+                self.pins.contains(&digest)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| self.matches(cert))
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            log::error!("{PIN_MISMATCH} for {server_name:?}");
+            Err(rustls::Error::General(PIN_MISMATCH.to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::storage::StoragePaths;
+
+    /// A self-signed, non-CA certificate for localhost and 127.0.0.1 valid
+    /// until 2126, and its P-256 key in PKCS#8; both DER.
+    const TEST_CERT: &[u8] = include_bytes!("../testdata/localhost.crt.der");
+    const TEST_KEY: &[u8] = include_bytes!("../testdata/localhost.key.der");
+
+    fn config_store() -> UsageConfigStore {
+        let root = std::env::temp_dir().join(format!("nuscape-http-{}", uuid::Uuid::new_v4()));
+        UsageConfigStore::new(&StoragePaths::with_root(root).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_server_that_never_responds_times_out() {
+        // Connections complete in the listen backlog but are never answered.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/usage", listener.local_addr().unwrap());
+        let config_store = config_store();
+        config_store.set_timeouts(200, 200).unwrap();
+        let client = client_builder(&config_store).unwrap().build().unwrap();
+
+        let err = client.post(&url).body("{}").send().await.unwrap_err();
+        assert!(matches!(
+            classify_transport_error(&err),
+            UploadFailureReason::Timeout
+        ));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn a_refused_connection_is_a_connect_failure() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/usage", listener.local_addr().unwrap());
+        drop(listener);
+        let client = client_builder(&config_store()).unwrap().build().unwrap();
+
+        let err = client.post(&url).send().await.unwrap_err();
+        assert!(matches!(
+            classify_transport_error(&err),
+            UploadFailureReason::ConnectFailure
+        ));
+    }
+
+    #[tokio::test]
+    async fn an_unresolvable_host_is_a_dns_failure() {
+        let client = client_builder(&config_store()).unwrap().build().unwrap();
+
+        let err = client
+            .post("http://nuscape-agent-test.invalid/usage")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            classify_transport_error(&err),
+            UploadFailureReason::DnsFailure
+        ));
+    }
+
+    #[test]
+    fn parse_pin_accepts_prefixed_and_bare_digests() {
+        let digest = [7u8; 32];
+        let encoded = BASE64.encode(digest);
+        assert_eq!(parse_pin(&format!("sha256/{encoded}")).unwrap(), digest);
+        assert_eq!(parse_pin(&format!(" {encoded} ")).unwrap(), digest);
+    }
+
+    #[test]
+    fn parse_pin_rejects_bad_base64_and_other_digest_sizes() {
+        assert!(parse_pin("sha256/not base64!").is_err());
+        assert!(parse_pin(&format!("sha256/{}", BASE64.encode([7u8; 20]))).is_err());
+        assert!(parse_pin("").is_err());
+    }
+
+    #[test]
+    fn pin_mismatch_is_found_anywhere_in_the_error_chain() {
+        let err = anyhow!(PIN_MISMATCH)
+            .context("tls handshake")
+            .context("upload");
+        assert!(is_pin_mismatch(err.as_ref()));
+        let other = anyhow!("certificate expired").context("upload");
+        assert!(!is_pin_mismatch(other.as_ref()));
+    }
+
+    /// A TLS server on 127.0.0.1 presenting the test certificate and
+    /// answering every request with an empty 200.
+    fn tls_server() -> String {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(TEST_CERT.to_vec())],
+                PrivateKeyDer::Pkcs8(TEST_KEY.to_vec().into()),
+            )
+            .unwrap();
+        let config = Arc::new(config);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for socket in listener.incoming().flatten() {
+                let conn = rustls::ServerConnection::new(config.clone()).unwrap();
+                let mut stream = rustls::StreamOwned::new(conn, socket);
+                // Fails when the client aborts the handshake.
+                let mut request = [0u8; 4096];
+                if stream.read(&mut request).is_ok() {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                    stream.conn.send_close_notify();
+                    let _ = stream.flush();
+                }
+            }
+        });
+        url
+    }
+
+    /// A client trusting the test certificate, as if a public CA had
+    /// issued it, and requiring `pin`.
+    fn pinned_client(pin: &str) -> reqwest::Client {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(TEST_CERT.to_vec())).unwrap();
+        let tls = tls_config(roots, &[pin.to_string()], None).unwrap();
+        reqwest::Client::builder()
+            .use_preconfigured_tls(tls)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_matching_pin_connects() {
+        let cert = CertificateDer::from(TEST_CERT.to_vec());
+        let spki = webpki::EndEntityCert::try_from(&cert)
+            .unwrap()
+            .subject_public_key_info();
+        let pin = format!("sha256/{}", BASE64.encode(Sha256::digest(spki.as_ref())));
+
+        let response = pinned_client(&pin).get(tls_server()).send().await.unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn a_wrong_pin_is_a_pin_mismatch() {
+        let pin = format!("sha256/{}", BASE64.encode([7u8; 32]));
+
+        let err = pinned_client(&pin)
+            .get(tls_server())
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            classify_transport_error(&err),
+            UploadFailureReason::PinMismatch
+        ));
+    }
+}
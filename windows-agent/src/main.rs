@@ -1,48 +1,249 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod acl;
+mod alerts;
 mod auth;
+mod backoff;
+mod clock;
 mod collectors;
+mod commands;
 mod config;
+mod credentials;
+mod dpapi;
+mod events;
+mod http;
 mod manager;
+mod metrics;
 mod models;
 mod runtime;
+mod signing;
 mod storage;
+mod throttle;
 mod uploader;
 
-use auth::{ensure_registered, TokenStore};
+use anyhow::Context;
+use auth::{TokenRefresher, TokenStore};
+use clock::ClockSkew;
+use collectors::background::BackgroundCollector;
+use collectors::dns::DnsBlockCollector;
 use collectors::network::NetworkUsageCollector;
 use collectors::sessions::SessionCollector;
+use collectors::system_events;
 use config::{DeviceIdStore, UsageConfigStore};
+use credentials::CredentialBackendKind;
+use dpapi::DpapiScope;
+use events::{AgentEvents, TauriEvents};
 use serde::Deserialize;
 use std::env;
+use std::future::Future;
 use manager::UsageCollectionManager;
+use metrics::AgentMetrics;
+use models::RegistrationOutcome;
 use parking_lot::Mutex;
 use runtime::AgentRuntime;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::Arc;
-use storage::{NetworkCounterStore, StoragePaths, UsageBatchStore};
+use std::time::Duration;
+use storage::{
+    AuthAuditLog, NetworkCounterStore, StorageLock, StorageLocked, StoragePaths, UsageBatchStore,
+};
 use tauri::async_runtime::JoinHandle;
 use tauri::{AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu};
 use uploader::UsageUploader;
+use windows::core::{w, HSTRING, PCWSTR};
+use windows::Win32::Foundation::{GetLastError, ERROR_ALREADY_EXISTS};
+use windows::Win32::System::Threading::CreateMutexW;
+use windows::Win32::UI::WindowsAndMessaging::{MessageBoxW, MB_ICONWARNING, MB_OK};
+
+const TOOLTIP_REFRESH_SECONDS: u64 = 60;
+/// How long quitting may spend finishing and retrying uploads.
+const QUIT_SHUTDOWN_BUDGET: Duration = Duration::from_secs(10);
+/// Shorter than on quit: Windows offers to kill apps that hold up a
+/// logoff or shutdown for more than about five seconds.
+const SESSION_END_SHUTDOWN_BUDGET: Duration = Duration::from_secs(4);
+/// How long deprovisioning waits for a collection or upload in progress
+/// before wiping the files it writes.
+const DEPROVISION_STOP_BUDGET: Duration = Duration::from_secs(10);
+const PAIRING_SCHEME: &str = "nuscape";
+/// Per-session mutex owned by the running agent.
+const INSTANCE_MUTEX: PCWSTR = w!("Local\\NuscapeAgent");
+
+/// Stores and tasks belonging to the active profile.
+struct ProfileAgent {
+    runtime: Arc<AgentRuntime>,
+    handles: Vec<JoinHandle<()>>,
+    clock: Arc<ClockSkew>,
+    sessions: Arc<SessionCollector>,
+    batch_store: Arc<UsageBatchStore>,
+    token_store: Arc<TokenStore>,
+    device_store: Arc<DeviceIdStore>,
+}
 
-struct AgentState {
+pub(crate) struct AgentState {
     handles: Mutex<Vec<JoinHandle<()>>>,
+    profile: Mutex<ProfileAgent>,
+    pub(crate) metrics: Arc<AgentMetrics>,
+    pub(crate) config_store: Arc<UsageConfigStore>,
+    events: Arc<dyn AgentEvents>,
+    /// Tails the machine-wide dnscrypt log, so it outlives profile switches.
+    dns: Arc<DnsBlockCollector>,
+    root: StoragePaths,
+    /// Set once `shutdown` has run.
+    shutdown: tokio::sync::OnceCell<()>,
+    /// Keeps other processes out of the data directory while the agent runs.
+    _lock: StorageLock,
 }
 
 impl AgentState {
-    fn new(handles: Vec<JoinHandle<()>>) -> Self {
+    fn new(
+        root: StoragePaths,
+        lock: StorageLock,
+        profile: ProfileAgent,
+        metrics: Arc<AgentMetrics>,
+        config_store: Arc<UsageConfigStore>,
+        events: Arc<dyn AgentEvents>,
+        dns: Arc<DnsBlockCollector>,
+    ) -> Self {
         Self {
-            handles: Mutex::new(handles),
+            handles: Mutex::new(Vec::new()),
+            profile: Mutex::new(profile),
+            metrics,
+            config_store,
+            events,
+            dns,
+            root,
+            shutdown: tokio::sync::OnceCell::new(),
+            _lock: lock,
+        }
+    }
+
+    pub(crate) fn batch_store(&self) -> Arc<UsageBatchStore> {
+        self.profile.lock().batch_store.clone()
+    }
+
+    pub(crate) fn token_store(&self) -> Arc<TokenStore> {
+        self.profile.lock().token_store.clone()
+    }
+
+    pub(crate) fn sessions(&self) -> Arc<SessionCollector> {
+        self.profile.lock().sessions.clone()
+    }
+
+    /// Unlinks the device and wipes its local state. The agent's tasks are
+    /// stopped first, and a collection or upload in progress finished, so
+    /// they cannot recreate the files being removed.
+    pub(crate) async fn deprovision(&self, force: bool) -> anyhow::Result<()> {
+        let paths = profile_paths(&self.root, &self.config_store)?;
+        let (token_store, clock) = {
+            let profile = self.profile.lock();
+            (profile.token_store.clone(), profile.clock.clone())
+        };
+        deprovision(
+            &paths,
+            &self.config_store,
+            &token_store,
+            &clock,
+            force,
+            self.stop_all(DEPROVISION_STOP_BUDGET),
+        )
+        .await
+    }
+
+    /// Pairs the device with `code` and reports the result to the UI.
+    pub(crate) async fn pair(&self, code: &str) -> anyhow::Result<RegistrationOutcome> {
+        let (token_store, device_store, clock) = {
+            let profile = self.profile.lock();
+            (
+                profile.token_store.clone(),
+                profile.device_store.clone(),
+                profile.clock.clone(),
+            )
+        };
+        let result =
+            auth::pair_device(&self.config_store, &token_store, &device_store, &clock, code).await;
+        match &result {
+            Ok(outcome) => log::info!("pairing finished: {outcome:?}"),
+            Err(err) => log::warn!("pairing failed: {err:?}"),
         }
+        self.events
+            .pairing_result(result.as_ref().copied().map_err(|err| format!("{err:#}")));
+        result
+    }
+
+    fn push_handle(&self, handle: JoinHandle<()>) {
+        self.handles.lock().push(handle);
     }
 
-    fn abort_all(&self) {
-        let mut handles = self.handles.lock();
-        for handle in handles.drain(..) {
+    /// Activates (or creates) profile `name` and restarts the agent's tasks
+    /// against that profile's stores.
+    pub(crate) fn switch_profile(&self, name: &str, api_base: Option<&str>) -> anyhow::Result<()> {
+        self.config_store.switch_profile(name, api_base)?;
+        let mut profile = self.profile.lock();
+        for handle in profile.handles.drain(..) {
             handle.abort();
         }
+        *profile = start_profile(
+            &self.root,
+            &self.config_store,
+            &self.metrics,
+            &self.events,
+            &self.dns,
+        )
+        .with_context(|| format!("start profile {name:?}"))?;
+        log::info!("switched to profile {name:?}");
+        Ok(())
+    }
+
+    /// Removes an inactive profile along with its tokens, device id and queue.
+    pub(crate) fn delete_profile(&self, name: &str) -> anyhow::Result<()> {
+        self.config_store.delete_profile(name)?;
+        let paths = self.root.for_profile(name)?;
+        credentials::open(
+            CredentialBackendKind::Wincred,
+            &paths,
+            DpapiScope::default(),
+        )
+        .delete()?;
+        paths.remove_profile_dir()?;
+        log::info!("deleted profile {name:?}");
+        Ok(())
+    }
+
+    /// Stops the agent in order before it exits: the active profile's tasks
+    /// are cancelled, its unsaved usage is queued and a final upload gets
+    /// whatever remains of `budget`. Concurrent and later calls wait for the
+    /// first one.
+    pub(crate) async fn shutdown(&self, budget: Duration) {
+        self.shutdown
+            .get_or_init(|| async {
+                log::info!("shutting down");
+                for handle in self.handles.lock().drain(..) {
+                    handle.abort();
+                }
+                let (runtime, handles) = {
+                    let mut profile = self.profile.lock();
+                    (profile.runtime.clone(), std::mem::take(&mut profile.handles))
+                };
+                runtime.shutdown(handles, budget).await;
+            })
+            .await;
+    }
+
+    /// Aborts the app-wide tasks and stops the active profile's runtime,
+    /// waiting up to `budget` for its tasks to return.
+    async fn stop_all(&self, budget: Duration) {
+        for handle in self.handles.lock().drain(..) {
+            handle.abort();
+        }
+        let (runtime, handles) = {
+            let mut profile = self.profile.lock();
+            (profile.runtime.clone(), std::mem::take(&mut profile.handles))
+        };
+        runtime
+            .stop(handles, tokio::time::Instant::now() + budget)
+            .await;
     }
 }
 
@@ -123,6 +324,45 @@ fn set_system_dns(adapter_name: &str) -> io::Result<()> {
     }
 }
 
+fn reset_system_dns(adapter_name: &str) -> io::Result<()> {
+    let name = format!("name=\"{}\"", adapter_name);
+    let status = Command::new("netsh")
+        .args(["interface", "ip", "set", "dns", &name, "dhcp"])
+        .status()?;
+    if status.success() {
+        log::info!("DNS on \"{}\" restored to DHCP", adapter_name);
+        Ok(())
+    } else {
+        Err(io::Error::other(
+            "netsh reset dns failed (likely needs admin)",
+        ))
+    }
+}
+
+// Skip system DNS changes by default to avoid requiring Administrator privileges.
+// To enable system DNS changes set NUSCAPE_SKIP_DNS=0 (or "false").
+fn system_dns_enabled() -> bool {
+    std::env::var("NUSCAPE_SKIP_DNS")
+        .map(|v| matches!(v.as_str(), "0" | "false" | "False" | "FALSE"))
+        .unwrap_or(false)
+}
+
+/// Undoes the DNS change made by `setup_background`, if it was made.
+fn revert_system_dns() {
+    if !system_dns_enabled() {
+        return;
+    }
+    match get_active_adapter_name() {
+        Ok(Some(adapter)) => {
+            if let Err(e) = reset_system_dns(&adapter) {
+                log::error!("Failed to restore system DNS: {e}");
+            }
+        }
+        Ok(None) => log::warn!("No connected adapter found"),
+        Err(e) => log::error!("Adapter detection error: {e}"),
+    }
+}
+
 fn setup_background(handle: &AppHandle) {
     match find_dnscrypt_paths(handle) {
         Some((exe, cfg)) => {
@@ -135,12 +375,7 @@ fn setup_background(handle: &AppHandle) {
         }
     }
 
-    // Skip system DNS changes by default to avoid requiring Administrator privileges.
-    // To enable system DNS changes set NUSCAPE_SKIP_DNS=0 (or "false").
-    let enable_dns = std::env::var("NUSCAPE_SKIP_DNS")
-        .map(|v| matches!(v.as_str(), "0" | "false" | "False" | "FALSE"))
-        .unwrap_or(false);
-    if !enable_dns {
+    if !system_dns_enabled() {
         log::info!("Skipping system DNS configuration by default; set NUSCAPE_SKIP_DNS=0 to enable");
         return;
     }
@@ -166,10 +401,13 @@ fn on_tray_event(app: &AppHandle, event: SystemTrayEvent) {
     match event {
         SystemTrayEvent::MenuItemClick { id, .. } => {
             if id == "quit" {
-                if let Some(state) = app.try_state::<AgentState>() {
-                    state.abort_all();
-                }
-                app.exit(0);
+                let app = app.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Some(state) = app.try_state::<AgentState>() {
+                        state.shutdown(QUIT_SHUTDOWN_BUDGET).await;
+                    }
+                    app.exit(0);
+                });
             }
         }
         _ => {}
@@ -253,51 +491,382 @@ fn seed_api_base_if_missing(
     Ok(())
 }
 
+fn profile_paths(
+    root: &StoragePaths,
+    config_store: &UsageConfigStore,
+) -> anyhow::Result<StoragePaths> {
+    root.for_profile(&config_store.active_profile())
+}
 
-fn init_agent() -> anyhow::Result<Vec<JoinHandle<()>>> {
-    let paths = StoragePaths::new()?;
-    let batch_store = Arc::new(UsageBatchStore::new(&paths)?);
-    let counter_store = Arc::new(NetworkCounterStore::new(&paths)?);
-    let token_store = Arc::new(TokenStore::new(&paths)?);
-    let config_store = Arc::new(UsageConfigStore::new(&paths)?);
-    seed_api_base_if_missing(config_store.as_ref(), &paths)?;
-    let device_store = Arc::new(DeviceIdStore::new(&paths)?);
+fn open_token_store(paths: &StoragePaths, config_store: &UsageConfigStore) -> TokenStore {
+    let scope = config_store.dpapi_scope();
+    TokenStore::new(
+        credentials::open(config_store.credential_backend(), paths, scope),
+        scope,
+        Arc::new(AuthAuditLog::new(paths)),
+    )
+}
+
+/// Tells the backend to unlink this device, then removes tokens, the device
+/// id, the usage queue and counters and reverts system DNS. If the backend
+/// cannot be reached nothing is removed unless `force` is set. `stop_agent`
+/// runs to completion before anything is removed.
+async fn deprovision(
+    paths: &StoragePaths,
+    config_store: &UsageConfigStore,
+    token_store: &TokenStore,
+    clock: &ClockSkew,
+    force: bool,
+    stop_agent: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    match auth::unregister(config_store, token_store, clock).await {
+        Ok(()) => log::info!("device unregistered"),
+        Err(err) if force => log::warn!("unregister failed, wiping local state anyway: {err:#}"),
+        Err(err) => {
+            return Err(err.context("unregister failed; use force to wipe local state anyway"))
+        }
+    }
+    stop_agent.await;
+    token_store.clear()?;
+    paths.wipe_device_state()?;
+    revert_system_dns();
+    log::info!("local device state removed");
+    Ok(())
+}
 
-    tauri::async_runtime::block_on(ensure_registered(
+/// `--deprovision [--force]`: runs without starting the agent or any window.
+fn run_deprovision_cli(force: bool) -> anyhow::Result<()> {
+    let root = StoragePaths::new()?;
+    let _lock = root.lock().context("stop the agent before deprovisioning")?;
+    let config_store = UsageConfigStore::new(&root)?;
+    let paths = profile_paths(&root, &config_store)?;
+    let token_store = open_token_store(&paths, &config_store);
+    tauri::async_runtime::block_on(deprovision(
+        &paths,
         &config_store,
         &token_store,
-        &device_store,
-    ))?;
+        &ClockSkew::new(),
+        force,
+        async {},
+    ))
+}
+
+/// Points `nuscape://` links at this executable for the current user so
+/// dashboard pairing links open the agent.
+fn register_pairing_scheme() -> io::Result<()> {
+    let exe = env::current_exe()?;
+    let key = format!(r"HKCU\Software\Classes\{PAIRING_SCHEME}");
+    let command_key = format!(r"{key}\shell\open\command");
+    let command = format!("\"{}\" \"%1\"", exe.display());
+    let entries: [&[&str]; 3] = [
+        &["add", &key, "/ve", "/d", "URL:NuScape pairing"],
+        &["add", &key, "/v", "URL Protocol", "/d", ""],
+        &["add", &command_key, "/ve", "/d", &command],
+    ];
+    for args in entries {
+        let status = Command::new("reg")
+            .args(args)
+            .arg("/f")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other("reg add failed"));
+        }
+    }
+    Ok(())
+}
+
+/// Extracts the code from a `nuscape://pair?code=...` launch argument.
+fn pairing_code_from_args(args: &[String]) -> Option<String> {
+    args.iter().skip(1).find_map(|arg| {
+        let url = reqwest::Url::parse(arg).ok()?;
+        if url.scheme() != PAIRING_SCHEME || url.host_str() != Some("pair") {
+            return None;
+        }
+        url.query_pairs()
+            .find(|(key, _)| key == "code")
+            .map(|(_, code)| code.into_owned())
+    })
+}
+
+fn spawn_tooltip_refresher(app: AppHandle, metrics: Arc<AgentMetrics>) -> JoinHandle<()> {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            if let Err(err) = app.tray_handle().set_tooltip(&metrics.tray_tooltip()) {
+                log::debug!("failed to update tray tooltip: {err}");
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(TOOLTIP_REFRESH_SECONDS)).await;
+        }
+    })
+}
+
+fn init_agent(
+    root: StoragePaths,
+    metrics: Arc<AgentMetrics>,
+    events: Arc<dyn AgentEvents>,
+    dnscrypt_config: Option<PathBuf>,
+) -> anyhow::Result<AgentState> {
+    log::info!("agent data directory: {}", root.root().display());
+    let lock = root.lock()?;
+    let config_store = Arc::new(UsageConfigStore::new(&root)?);
+    seed_api_base_if_missing(config_store.as_ref(), &root)?;
+    let dns = Arc::new(DnsBlockCollector::new(dnscrypt_config));
+    let profile = start_profile(&root, &config_store, &metrics, &events, &dns)?;
+    Ok(AgentState::new(
+        root,
+        lock,
+        profile,
+        metrics,
+        config_store,
+        events,
+        dns,
+    ))
+}
+
+/// Opens the active profile's stores and spawns the agent's tasks on them.
+fn start_profile(
+    root: &StoragePaths,
+    config_store: &Arc<UsageConfigStore>,
+    metrics: &Arc<AgentMetrics>,
+    events: &Arc<dyn AgentEvents>,
+    dns: &Arc<DnsBlockCollector>,
+) -> anyhow::Result<ProfileAgent> {
+    let paths = profile_paths(root, config_store)?;
+    let batch_store = Arc::new(UsageBatchStore::new(&paths, events.clone())?);
+    let counter_store = Arc::new(NetworkCounterStore::new(&paths)?);
+    let token_store = Arc::new(open_token_store(&paths, config_store));
+    let device_store = Arc::new(DeviceIdStore::new(&paths)?);
+    for path in [paths.tokens_path(), paths.device_path()] {
+        if let Err(err) = acl::verify_restricted(&path) {
+            log::warn!(
+                "failed to verify permissions on {}: {err:?}",
+                path.display()
+            );
+        }
+    }
 
-    let session_collector = Arc::new(SessionCollector::new());
-    let network_collector = Arc::new(NetworkUsageCollector::new(counter_store));
+    let session_collector = Arc::new(SessionCollector::new(&paths, config_store.clone()));
+    let network_collector = Arc::new(NetworkUsageCollector::new(
+        counter_store,
+        config_store.clone(),
+    ));
+    let background_collector = Arc::new(BackgroundCollector::new(config_store.clone()));
 
+    let clock = Arc::new(ClockSkew::new());
     let manager = Arc::new(UsageCollectionManager::new(
+        config_store.clone(),
         session_collector.clone(),
         network_collector,
-        device_store,
+        background_collector,
+        dns.clone(),
+        device_store.clone(),
         batch_store.clone(),
+        metrics.clone(),
+        clock.clone(),
     ));
 
-    let uploader = Arc::new(UsageUploader::new(config_store, token_store, batch_store)?);
+    let refresher = Arc::new(TokenRefresher::new(
+        config_store.clone(),
+        token_store.clone(),
+        clock.clone(),
+    )?);
+    let uploader = Arc::new(UsageUploader::new(
+        &paths,
+        config_store.clone(),
+        token_store.clone(),
+        batch_store.clone(),
+        metrics.clone(),
+        clock.clone(),
+        refresher,
+    )?);
 
-    let runtime = Arc::new(AgentRuntime::new(session_collector, manager, uploader));
+    let runtime = Arc::new(AgentRuntime::new(
+        session_collector.clone(),
+        manager,
+        uploader,
+        config_store.clone(),
+        token_store.clone(),
+        device_store.clone(),
+        clock.clone(),
+        metrics.clone(),
+        events.clone(),
+    ));
 
-    Ok(runtime.spawn())
+    Ok(ProfileAgent {
+        handles: runtime.clone().spawn(),
+        runtime,
+        clock,
+        sessions: session_collector,
+        batch_store,
+        token_store,
+        device_store,
+    })
+}
+
+/// Takes the per-session instance mutex, returning false if another agent
+/// already holds it. The handle is left open for the life of the process.
+fn claim_single_instance() -> bool {
+    match unsafe { CreateMutexW(None, true, INSTANCE_MUTEX) } {
+        Ok(_) => {
+            let last_error = unsafe { GetLastError() };
+            last_error != ERROR_ALREADY_EXISTS
+        }
+        Err(err) => {
+            log::warn!("failed to create instance mutex: {err:?}");
+            true
+        }
+    }
+}
+
+fn show_already_running(detail: &str) {
+    log::error!("another NuScape Agent is already running: {detail}");
+    let text = HSTRING::from(format!("NuScape Agent is already running.\n\n{detail}"));
+    unsafe {
+        MessageBoxW(None, &text, w!("NuScape Agent"), MB_OK | MB_ICONWARNING);
+    }
 }
 
 fn main() {
     let _ = env_logger::builder().format_timestamp_secs().try_init();
 
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|arg| arg == "--deprovision") {
+        let force = args.iter().any(|arg| arg == "--force");
+        if let Err(err) = run_deprovision_cli(force) {
+            log::error!("deprovision failed: {err:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if !claim_single_instance() {
+        show_already_running("Use the tray icon of the running agent.");
+        std::process::exit(1);
+    }
+
     tauri::Builder::default()
         .system_tray(build_tray())
         .on_system_tray_event(on_tray_event)
+        .invoke_handler(tauri::generate_handler![
+            commands::get_retry_policy,
+            commands::set_retry_policy,
+            commands::get_proxy_url,
+            commands::set_proxy,
+            commands::get_tls_pins,
+            commands::set_tls_pins,
+            commands::get_agent_metrics,
+            commands::get_timeouts,
+            commands::set_timeouts,
+            commands::get_today_usage,
+            commands::get_custom_headers,
+            commands::set_custom_headers,
+            commands::get_dry_run,
+            commands::set_dry_run,
+            commands::get_tracking_rules,
+            commands::set_tracking_rules,
+            commands::get_session_policy,
+            commands::set_session_policy,
+            commands::get_background_watchlist,
+            commands::set_background_watchlist,
+            commands::get_script_hosts,
+            commands::set_script_hosts,
+            commands::get_network_exclusions,
+            commands::set_network_exclusions,
+            commands::get_counter_reset_mode,
+            commands::set_counter_reset_mode,
+            commands::get_ssid_collection,
+            commands::set_ssid_collection,
+            commands::get_collect_interval_minutes,
+            commands::set_collect_interval_minutes,
+            commands::get_upload_interval_seconds,
+            commands::set_upload_interval_seconds,
+            commands::get_dead_letter_count,
+            commands::retry_dead_letters,
+            commands::get_dead_letter_threshold,
+            commands::set_dead_letter_threshold,
+            commands::get_upload_concurrency,
+            commands::set_upload_concurrency,
+            commands::get_auth_mode,
+            commands::set_auth_mode,
+            commands::get_heartbeat_interval_minutes,
+            commands::set_heartbeat_interval_minutes,
+            commands::get_ndjson_uploads,
+            commands::set_ndjson_uploads,
+            commands::get_notifications_silenced,
+            commands::set_notifications_silenced,
+            commands::get_failure_alert_policy,
+            commands::set_failure_alert_policy,
+            commands::get_client_certificate_path,
+            commands::set_client_certificate,
+            commands::get_upload_rate_limit,
+            commands::set_upload_rate_limit,
+            commands::get_spike_threshold,
+            commands::set_spike_threshold,
+            commands::get_min_free_disk_mb,
+            commands::set_min_free_disk_mb,
+            commands::get_storage_stats,
+            commands::get_retention_days,
+            commands::set_retention_days,
+            commands::get_dpapi_scope,
+            commands::set_dpapi_scope,
+            commands::get_credential_backend,
+            commands::set_credential_backend,
+            commands::get_queue_compression,
+            commands::set_queue_compression,
+            commands::pair_device,
+            commands::get_token_expiry_margin_seconds,
+            commands::set_token_expiry_margin_seconds,
+            commands::deprovision,
+            commands::read_auth_audit,
+            commands::list_profiles,
+            commands::switch_profile,
+            commands::delete_profile,
+            commands::get_idle_threshold_seconds,
+            commands::set_idle_threshold_seconds,
+            commands::get_split_browser_titles,
+            commands::set_split_browser_titles,
+            commands::get_sample_interval_ms,
+            commands::set_sample_interval_ms,
+            commands::get_session_bucket_minutes,
+            commands::set_session_bucket_minutes,
+            commands::get_track_engagement,
+            commands::set_track_engagement,
+            commands::get_browser_domains,
+            commands::set_browser_domains,
+        ])
         .setup(|app| {
             let handle = app.handle();
             setup_background(&handle);
-            match init_agent() {
-                Ok(handles) => {
-                    app.manage(AgentState::new(handles));
+            if let Err(err) = register_pairing_scheme() {
+                log::warn!("failed to register {PAIRING_SCHEME}:// links: {err}");
+            }
+            let metrics = Arc::new(AgentMetrics::new());
+            let events = Arc::new(TauriEvents::new(handle.clone()));
+            let dnscrypt_config = find_dnscrypt_paths(&handle).map(|(_, cfg)| cfg);
+            let init = StoragePaths::new()
+                .and_then(|root| init_agent(root, metrics.clone(), events, dnscrypt_config));
+            match init {
+                Ok(state) => {
+                    state.push_handle(spawn_tooltip_refresher(handle.clone(), metrics));
+                    app.manage(state);
+                    let session_end = handle.clone();
+                    system_events::on_session_end(move || {
+                        let state = session_end.state::<AgentState>();
+                        tauri::async_runtime::block_on(state.shutdown(SESSION_END_SHUTDOWN_BUDGET));
+                    });
+                    system_events::start();
+                    if let Some(code) = pairing_code_from_args(&app.env().args) {
+                        let handle = handle.clone();
+                        tauri::async_runtime::spawn(async move {
+                            let state = handle.state::<AgentState>();
+                            let _ = state.pair(&code).await;
+                        });
+                    }
+                }
+                Err(err) if err.downcast_ref::<StorageLocked>().is_some() => {
+                    show_already_running(&format!("{err:#}"));
+                    std::process::exit(1);
                 }
                 Err(err) => {
                     log::error!("agent init failed: {err:?}");
@@ -1,70 +1,358 @@
-﻿use std::sync::Arc;
+﻿use std::path::Path;
+use std::sync::Arc;
 
 use anyhow::Result;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use uuid::Uuid;
 
-use crate::collectors::network::NetworkUsageCollector;
-use crate::collectors::sessions::SessionCollector;
+use crate::clock::ClockSkew;
+use crate::collectors::background::BackgroundCollector;
+use crate::collectors::dns::{DnsBlockCollector, DnsLogPosition, LocalResolver};
+use crate::collectors::network::{NetworkBaseline, NetworkUsageCollector};
+use crate::collectors::sessions::{DrainedSessions, SessionCollector};
 use crate::collectors::status::DeviceStatusProvider;
-use crate::config::DeviceIdStore;
-use crate::models::UsageBatch;
+use crate::config::{DeviceIdStore, UsageConfigStore};
+use crate::metrics::AgentMetrics;
+use crate::models::{DeviceStatus, Heartbeat, NetworkDelta, UsageBatch, UsageSession};
 use crate::storage::UsageBatchStore;
 
 pub struct UsageCollectionManager {
+    config_store: Arc<UsageConfigStore>,
     sessions: Arc<SessionCollector>,
     network: Arc<NetworkUsageCollector>,
+    background: Arc<BackgroundCollector>,
+    dns: Arc<DnsBlockCollector>,
     status: DeviceStatusProvider,
     device_store: Arc<DeviceIdStore>,
     batch_store: Arc<UsageBatchStore>,
+    metrics: Arc<AgentMetrics>,
+    clock: Arc<ClockSkew>,
+    last_check_in: Mutex<DateTime<Utc>>,
+    /// Held from collecting until the batch is stored, so an early collect
+    /// cannot race the regular one over the network counter baseline.
+    collecting: Mutex<()>,
+    /// Whether a spike may trigger an early collect. Cleared when one does
+    /// and set again once an interval stays below half the threshold, so a
+    /// sustained download triggers it only once.
+    spike_armed: Mutex<bool>,
+    /// Tamper flags seen by the last check, so only new ones trigger a
+    /// heartbeat.
+    tamper_seen: Mutex<Vec<String>>,
 }
 
 impl UsageCollectionManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        config_store: Arc<UsageConfigStore>,
         sessions: Arc<SessionCollector>,
         network: Arc<NetworkUsageCollector>,
+        background: Arc<BackgroundCollector>,
+        dns: Arc<DnsBlockCollector>,
         device_store: Arc<DeviceIdStore>,
         batch_store: Arc<UsageBatchStore>,
+        metrics: Arc<AgentMetrics>,
+        clock: Arc<ClockSkew>,
     ) -> Self {
+        let status = DeviceStatusProvider::new(
+            sessions.clone(),
+            batch_store.clone(),
+            Arc::new(LocalResolver::new(dns.config_path().map(Path::to_path_buf))),
+        );
         Self {
+            config_store,
             sessions,
             network,
-            status: DeviceStatusProvider::new(),
+            background,
+            dns,
+            status,
             device_store,
             batch_store,
+            metrics,
+            clock,
+            last_check_in: Mutex::new(Utc::now()),
+            collecting: Mutex::new(()),
+            spike_armed: Mutex::new(true),
+            tamper_seen: Mutex::new(Vec::new()),
         }
     }
 
-    pub fn collect_batch(&self) -> Result<Option<UsageBatch>> {
+    /// Builds the next batch. The sessions are drained last, after every
+    /// step that can fail. Nothing collected is marked as sent until the
+    /// caller commits it.
+    fn collect_batch(&self) -> Result<Option<(UsageBatch, Collected<'_>)>> {
         let device_id = self.device_store.get_or_create()?;
         let now = Utc::now();
-        let window = Duration::hours(24);
-        let sessions = self.sessions.drain_sessions(window);
-        let network_deltas = self.network.collect()?;
-        let status = self.status.build_status();
+        let (mut network_deltas, network) = self.network.collect()?;
+        self.flag_spikes(&mut network_deltas);
+        let (dns_events, dns) = match self.dns.collect() {
+            Ok((events, position)) => (events, Some(position)),
+            Err(err) => {
+                log::warn!("failed to read blocked DNS queries: {err:?}");
+                (Vec::new(), None)
+            }
+        };
+        let mut drained = self.sessions.drain_sessions();
+        let mut sessions = std::mem::take(&mut drained.sessions);
+        match self.background.collect() {
+            Ok(background) => sessions.extend(merge_background(background)),
+            Err(err) => log::warn!("background process scan failed: {err:?}"),
+        }
+        let status = self.device_status();
+        let collected = Collected {
+            sessions: drained,
+            network,
+            dns,
+        };
 
-        if sessions.is_empty() && network_deltas.is_empty() {
+        if sessions.is_empty() && network_deltas.is_empty() && dns_events.is_empty() {
+            collected.commit();
             return Ok(None);
         }
 
         let batch = UsageBatch {
+            batch_id: Uuid::new_v4(),
             device_id,
             sent_at: now,
             sessions,
             network_deltas,
+            dns_events,
             status: Some(status),
+            chunk_index: None,
+            chunk_total: None,
         };
-        Ok(Some(batch))
+        Ok(Some((batch, collected)))
     }
 
     pub fn collect_and_store(&self) -> Result<bool> {
-        if let Some(batch) = self.collect_batch()? {
-            self.batch_store.enqueue(batch)?;
+        let _collecting = self.collecting.lock();
+        if let Some((batch, collected)) = self.collect_batch()? {
+            let sessions = batch.sessions.len();
+            self.batch_store.enqueue(
+                batch,
+                self.config_store.min_free_disk_bytes(),
+                self.config_store.queue_compression(),
+            )?;
+            collected.commit();
+            *self.last_check_in.lock() = Utc::now();
+            self.metrics
+                .record_collection(sessions, self.batch_store.queue_size());
             return Ok(true);
         }
+        self.metrics
+            .record_collection(0, self.batch_store.queue_size());
         Ok(false)
     }
 
+    /// Drops stored usage older than the configured retention window.
+    pub fn enforce_retention(&self) -> Result<()> {
+        let days = self.config_store.retention_days();
+        let cutoff = Utc::now() - chrono::Duration::days(i64::from(days));
+        let sweep = self.batch_store.purge_older_than(cutoff)?;
+        if sweep.batches > 0 || sweep.files > 0 || sweep.rejected > 0 {
+            log::info!(
+                "retention ({days} days): purged {} batch(es) with {} session(s), {} rejected session(s) and {} quarantined file(s)",
+                sweep.batches,
+                sweep.sessions,
+                sweep.rejected,
+                sweep.files
+            );
+        }
+        Ok(())
+    }
+
+    /// Rewrites queued usage in the configured form, compressed or not.
+    pub fn compact_queue(&self) -> Result<()> {
+        self.batch_store
+            .compact(self.config_store.queue_compression())
+    }
+
+    /// True when the traffic since the last collect crossed the spike
+    /// threshold while the fast path is armed, disarming it. The caller
+    /// collects and uploads right away.
+    pub fn spike_detected(&self) -> bool {
+        let Some(threshold) = self.config_store.spike_threshold() else {
+            return false;
+        };
+        let mut armed = self.spike_armed.lock();
+        if !*armed {
+            return false;
+        }
+        match self.network.pending_bytes() {
+            Ok(bytes) if bytes > threshold => {
+                log::info!("network spike: {bytes} bytes since the last collect");
+                *armed = false;
+                true
+            }
+            Ok(_) => false,
+            Err(err) => {
+                log::debug!("spike check failed: {err:?}");
+                false
+            }
+        }
+    }
+
+    /// Marks deltas above the spike threshold, or the largest one when only
+    /// the interval as a whole is, and re-arms the fast path after a quiet
+    /// interval.
+    fn flag_spikes(&self, deltas: &mut [NetworkDelta]) {
+        let Some(threshold) = self.config_store.spike_threshold() else {
+            return;
+        };
+        let bytes =
+            |delta: &NetworkDelta| delta.wifi_bytes + delta.cellular_bytes + delta.ethernet_bytes;
+        let total: u64 = deltas.iter().map(bytes).sum();
+        if total < threshold / 2 {
+            *self.spike_armed.lock() = true;
+        }
+        if total <= threshold {
+            return;
+        }
+        let mut flagged = false;
+        for delta in deltas.iter_mut().filter(|delta| bytes(delta) > threshold) {
+            delta.spike = true;
+            flagged = true;
+        }
+        if !flagged {
+            if let Some(largest) = deltas.iter_mut().max_by_key(|delta| bytes(delta)) {
+                largest.spike = true;
+            }
+        }
+    }
+
+    /// The device status sent with batches and heartbeats, completed with
+    /// the clock skew, Wi-Fi network and low-disk flag the provider leaves
+    /// unset.
+    fn device_status(&self) -> DeviceStatus {
+        let mut status = self.status.build_status();
+        status.clock_skew_ms = self.clock.skew_ms();
+        status.current_ssid = self.network.current_ssid();
+        let floor = self.config_store.min_free_disk_bytes();
+        status.low_disk = status.disk_free_bytes.is_some_and(|free| free < floor);
+        status
+    }
+
+    /// Builds a heartbeat when neither a batch nor a heartbeat has been
+    /// produced within `interval`.
+    pub fn heartbeat_due(&self, interval: std::time::Duration) -> Result<Option<Heartbeat>> {
+        let now = Utc::now();
+        let idle = now - *self.last_check_in.lock();
+        if idle.to_std().map_or(true, |idle| idle < interval) {
+            return Ok(None);
+        }
+        self.heartbeat(now).map(Some)
+    }
+
+    /// Builds a heartbeat when a tamper flag appeared since the last check.
+    /// A flag that clears and comes back counts as new again.
+    pub fn tamper_heartbeat(&self) -> Result<Option<Heartbeat>> {
+        let flags = self.status.tamper_flags();
+        let new = newly_raised(&mut self.tamper_seen.lock(), flags);
+        if new.is_empty() {
+            return Ok(None);
+        }
+        log::warn!("tamper detected: {new:?}");
+        self.heartbeat(Utc::now()).map(Some)
+    }
+
+    fn heartbeat(&self, now: DateTime<Utc>) -> Result<Heartbeat> {
+        let status = self.device_status();
+        Ok(Heartbeat {
+            device_id: self.device_store.get_or_create()?,
+            sent_at: now,
+            status,
+        })
+    }
+
+    pub fn record_heartbeat(&self, sent_at: DateTime<Utc>) {
+        *self.last_check_in.lock() = sent_at;
+    }
+
     pub fn batch_store(&self) -> Arc<UsageBatchStore> {
         Arc::clone(&self.batch_store)
     }
 }
+
+/// What a batch was built from. Committing it once the batch is stored
+/// moves the session tracker, network baseline and DNS log past it;
+/// dropping it leaves them, so the next collect picks the same usage up.
+struct Collected<'a> {
+    sessions: DrainedSessions,
+    network: NetworkBaseline<'a>,
+    dns: Option<DnsLogPosition<'a>>,
+}
+
+impl Collected<'_> {
+    fn commit(self) {
+        self.sessions.commit();
+        self.network.commit();
+        if let Some(dns) = self.dns {
+            dns.commit();
+        }
+    }
+}
+
+/// The flags not present in `seen`, which then becomes `flags`.
+fn newly_raised(seen: &mut Vec<String>, flags: Vec<String>) -> Vec<String> {
+    let new = flags
+        .iter()
+        .filter(|flag| !seen.contains(flag))
+        .cloned()
+        .collect();
+    *seen = flags;
+    new
+}
+
+/// Joins overlapping or back-to-back spans of the same process, e.g. several
+/// instances of one executable, into a single session.
+fn merge_background(mut sessions: Vec<UsageSession>) -> Vec<UsageSession> {
+    sessions.sort_by(|a, b| {
+        a.package
+            .cmp(&b.package)
+            .then(a.window_start.cmp(&b.window_start))
+    });
+    let mut merged: Vec<UsageSession> = Vec::new();
+    for session in sessions {
+        if let Some(last) = merged.last_mut() {
+            if last.package == session.package && session.window_start <= last.window_end {
+                last.window_end = last.window_end.max(session.window_end);
+                last.total_ms = (last.window_end - last.window_start)
+                    .num_milliseconds()
+                    .max(0) as u64;
+                continue;
+            }
+        }
+        merged.push(session);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn only_newly_raised_tamper_flags_are_reported() {
+        let mut seen = Vec::new();
+        assert!(newly_raised(&mut seen, Vec::new()).is_empty());
+        assert_eq!(
+            newly_raised(&mut seen, flags(&["dns_changed"])),
+            ["dns_changed"]
+        );
+        // Still raised, plus one more.
+        assert_eq!(
+            newly_raised(&mut seen, flags(&["dns_changed", "dnscrypt_stopped"])),
+            ["dnscrypt_stopped"]
+        );
+        assert!(newly_raised(&mut seen, flags(&["dnscrypt_stopped"])).is_empty());
+        // Cleared and raised again.
+        assert_eq!(
+            newly_raised(&mut seen, flags(&["dns_changed", "dnscrypt_stopped"])),
+            ["dns_changed"]
+        );
+    }
+}
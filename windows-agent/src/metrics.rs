@@ -0,0 +1,121 @@
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::models::{AuthAuditEntry, CircuitState, UploadFailureReason, UploadResult};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricsSnapshot {
+    pub uploads_succeeded: u64,
+    pub uploads_failed: u64,
+    pub last_successful_upload: Option<DateTime<Utc>>,
+    pub last_failure_reason: Option<UploadFailureReason>,
+    pub circuit: CircuitState,
+    pub queue_size: usize,
+    pub sessions_collected_today: u64,
+    /// Bytes per second achieved by the last sync that sent anything.
+    pub last_upload_rate_bps: Option<u64>,
+    pub registrations_attempted: u64,
+    pub registrations_failed: u64,
+    pub last_registration: Option<DateTime<Utc>>,
+    /// Most recent auth audit entry; filled in from the audit log on read.
+    pub last_auth_event: Option<AuthAuditEntry>,
+    /// Corrupt store files moved aside since startup; filled in on read.
+    pub corrupt_files_quarantined: u64,
+    /// Usage batches evicted or too large to queue since startup; filled in
+    /// on read.
+    pub usage_batches_dropped: u64,
+    /// Sessions removed by the retention sweep since startup; filled in on
+    /// read.
+    pub sessions_purged: u64,
+    /// Batches skipped as repeats of a recently queued one since startup;
+    /// filled in on read.
+    pub batches_deduplicated: u64,
+    /// Background tasks restarted after a panic or stall since startup;
+    /// filled in on read.
+    pub task_restarts: u64,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    snapshot: MetricsSnapshot,
+    sessions_day: Option<NaiveDate>,
+}
+
+/// In-memory health counters shared by the collection and upload tasks.
+#[derive(Default)]
+pub struct AgentMetrics {
+    state: Mutex<MetricsState>,
+}
+
+impl AgentMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_upload(&self, result: &UploadResult, queue_size: usize) {
+        let mut state = self.state.lock();
+        let snapshot = &mut state.snapshot;
+        snapshot.queue_size = queue_size;
+        snapshot.circuit = result.circuit;
+        match result.failure_reason {
+            Some(reason) => {
+                snapshot.uploads_failed += 1;
+                snapshot.last_failure_reason = Some(reason);
+            }
+            None if result.uploaded_batches > 0 => {
+                snapshot.uploads_succeeded += 1;
+                snapshot.last_successful_upload = Some(Utc::now());
+            }
+            None => {}
+        }
+    }
+
+    pub fn record_upload_rate(&self, bytes: u64, elapsed: std::time::Duration) {
+        if bytes == 0 {
+            return;
+        }
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.state.lock().snapshot.last_upload_rate_bps = Some(rate as u64);
+    }
+
+    pub fn record_registration(&self, succeeded: bool) {
+        let mut state = self.state.lock();
+        let snapshot = &mut state.snapshot;
+        snapshot.registrations_attempted += 1;
+        if succeeded {
+            snapshot.last_registration = Some(Utc::now());
+        } else {
+            snapshot.registrations_failed += 1;
+        }
+    }
+
+    pub fn record_collection(&self, sessions: usize, queue_size: usize) {
+        let today = Local::now().date_naive();
+        let mut state = self.state.lock();
+        if state.sessions_day != Some(today) {
+            state.sessions_day = Some(today);
+            state.snapshot.sessions_collected_today = 0;
+        }
+        state.snapshot.sessions_collected_today += sessions as u64;
+        state.snapshot.queue_size = queue_size;
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut state = self.state.lock();
+        if state.sessions_day != Some(Local::now().date_naive()) {
+            state.snapshot.sessions_collected_today = 0;
+        }
+        state.snapshot.clone()
+    }
+
+    pub fn tray_tooltip(&self) -> String {
+        match self.snapshot().last_successful_upload {
+            Some(at) => format!(
+                "NuScape Agent - last upload {}",
+                at.with_timezone(&Local).format("%H:%M")
+            ),
+            None => "NuScape Agent - no uploads yet".to_string(),
+        }
+    }
+}
@@ -1,17 +1,56 @@
 use chrono::{DateTime, Duration, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 pub const MAX_PAYLOAD_BYTES: usize = 1_000_000;
 pub const DEFAULT_CHUNK_SESSION_LIMIT: usize = 100;
 pub const DEFAULT_CHUNK_BYTE_LIMIT: usize = 100_000;
+pub const DEFAULT_CHUNK_WIRE_BYTE_LIMIT: usize = 50_000;
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UsageSession {
     #[serde(rename = "package")]
     pub package: String,
+    /// Distinguishes apps that share an executable name: the AppUserModelID
+    /// of packaged apps, else the full image path.
+    #[serde(rename = "appId", default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    /// Jar, class or script run by an interpreter such as `javaw.exe`.
+    #[serde(rename = "argsHint", default, skip_serializing_if = "Option::is_none")]
+    pub args_hint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Share of samples with keyboard or mouse input (0.0-1.0), when
+    /// engagement tracking is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engagement: Option<f64>,
+    /// Registrable domain of the page, for browser sessions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Whether the app filled its monitor for most of the session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fullscreen: Option<bool>,
+    /// Monitor the app was on for most of the session; 0 is the primary.
+    #[serde(
+        rename = "monitorIndex",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub monitor_index: Option<u32>,
+    /// Whether the app was playing audio for most of the session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<bool>,
+    /// Whether the session was used over Remote Desktop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<bool>,
+    /// Windows account the session was recorded in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
     #[serde(rename = "windowStart")]
     #[serde_as(as = "DisplayFromStr")]
     pub window_start: DateTime<Utc>,
@@ -25,6 +64,28 @@ pub struct UsageSession {
 }
 
 impl UsageSession {
+    /// A span during which a watch-listed process ran, in or out of the
+    /// foreground.
+    pub fn background(package: String, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        Self {
+            package,
+            app_id: None,
+            args_hint: None,
+            title: None,
+            engagement: None,
+            domain: None,
+            fullscreen: None,
+            monitor_index: None,
+            media: None,
+            remote: None,
+            user: None,
+            window_start: start,
+            window_end: end,
+            total_ms: (end - start).num_milliseconds().max(0) as u64,
+            foreground: false,
+        }
+    }
+
     pub fn duration(&self) -> Duration {
         Duration::milliseconds(self.total_ms as i64)
     }
@@ -42,6 +103,23 @@ pub struct NetworkDelta {
     pub wifi_bytes: u64,
     #[serde(rename = "cell_bytes")]
     pub cellular_bytes: u64,
+    #[serde(rename = "ethernet_bytes", default)]
+    pub ethernet_bytes: u64,
+    /// Traffic over a connection Windows considers metered.
+    #[serde(default)]
+    pub metered: bool,
+    /// Received and sent bytes; together they equal the category fields.
+    #[serde(rename = "rx_bytes", default)]
+    pub rx_bytes: u64,
+    #[serde(rename = "tx_bytes", default)]
+    pub tx_bytes: u64,
+    /// Network name of a Wi-Fi adapter's traffic; absent for other
+    /// categories or when SSID collection is off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    /// Traffic above the configured spike threshold.
+    #[serde(default)]
+    pub spike: bool,
 }
 
 #[serde_as]
@@ -51,9 +129,26 @@ pub struct NetworkCounters {
     pub wifi_total: u64,
     #[serde(rename = "cell")]
     pub cell_total: u64,
+    /// Zero in counters stored before Ethernet was reported on its own,
+    /// when wired traffic was counted as Wi-Fi.
+    #[serde(rename = "ethernet", default)]
+    pub ethernet_total: u64,
+    /// Received and sent octets, across all categories.
+    #[serde(rename = "rx", default)]
+    pub rx_total: u64,
+    #[serde(rename = "tx", default)]
+    pub tx_total: u64,
     #[serde(rename = "sampled_at")]
     #[serde_as(as = "DisplayFromStr")]
     pub sampled_at: DateTime<Utc>,
+    /// Adapter description, used as the label of its deltas.
+    #[serde(default)]
+    pub description: String,
+    /// Connection cost and Wi-Fi network at sampling time; not persisted.
+    #[serde(skip)]
+    pub metered: bool,
+    #[serde(skip)]
+    pub ssid: Option<String>,
 }
 
 #[serde_as]
@@ -61,21 +156,168 @@ pub struct NetworkCounters {
 pub struct DeviceStatus {
     #[serde(rename = "usage_access")]
     pub usage_access: bool,
+    /// Kept from the Android agent: the foreground app is being observed,
+    /// i.e. `sampler_alive`.
     #[serde(rename = "accessibility")]
     pub accessibility: bool,
+    /// Kept from the Android agent: filtering is enforced, i.e.
+    /// `dnscrypt_running && dns_configured`.
     #[serde(rename = "overlay")]
     pub overlay: bool,
+    /// The session sampler ran within the last two sample intervals.
+    #[serde(default)]
+    pub sampler_alive: bool,
+    /// Background tasks restarted after a panic or stall since startup.
+    #[serde(default)]
+    pub task_restarts: u64,
+    /// dnscrypt-proxy is running and answers on 127.0.0.1:53.
+    #[serde(default)]
+    pub dnscrypt_running: bool,
+    /// Every DNS server of every connected adapter is 127.0.0.1.
+    #[serde(default)]
+    pub dns_configured: bool,
+    /// Signs that DNS filtering was bypassed: `dnscrypt_stopped`,
+    /// `dns_changed` or `dnscrypt_config_modified`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tamper_flags: Vec<String>,
+    #[serde(default)]
+    pub queue_size: usize,
     #[serde(rename = "vpn")]
     pub vpn: bool,
+    /// Vendor of the detected VPN, or its interface name when unknown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vpn_name: Option<String>,
+    /// Battery charge in percent (0-100); null without a battery.
     #[serde(rename = "battery_pct")]
-    pub battery_pct: f64,
+    pub battery_pct: Option<f64>,
+    /// On AC power with a battery present.
+    #[serde(default)]
+    pub charging: bool,
+    #[serde(default)]
+    pub battery_saver: bool,
     #[serde(rename = "tz")]
     pub time_zone_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_skew_ms: Option<i64>,
+    #[serde(default)]
+    pub agent_version: String,
+    /// Kernel version and build plus the release, e.g. `Windows 10.0.18363 (1909)`.
+    #[serde(default)]
+    pub os_version: String,
+    /// Edition such as `Professional`, when the registry names one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_edition: Option<String>,
+    #[serde(default)]
+    pub mtls: bool,
+    /// Workstation locked or screensaver running, as opposed to merely idle.
+    #[serde(default)]
+    pub screen_locked: bool,
+    /// The console display is on (or dimmed). Off counts as no screen time.
+    #[serde(default)]
+    pub display_on: bool,
+    /// The agent's session is a Remote Desktop session.
+    #[serde(default)]
+    pub remote: bool,
+    /// The internet connection is metered (hotspot, capped plan).
+    #[serde(default)]
+    pub metered_connection: bool,
+    /// Wi-Fi network the machine is connected to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_ssid: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub adapters: Vec<AdapterInfo>,
+    /// Free space on the system drive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_free_bytes: Option<u64>,
+    /// Size of the agent's data directory, refreshed every few minutes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_bytes: Option<u64>,
+    /// Free space is below the configured floor and old batches are being
+    /// evicted.
+    #[serde(default)]
+    pub low_disk: bool,
+    /// Physical memory in use, in percent (0-100).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_used_pct: Option<u32>,
+    /// Whether an antivirus product has real-time protection on, per
+    /// Windows Security Center; null when it could not be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub antivirus_enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub antivirus_name: Option<String>,
+}
+
+/// A connected physical network adapter, for support diagnostics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdapterInfo {
+    pub description: String,
+    /// `wifi`, `ethernet`, `cellular` or `other`.
+    pub media: String,
+    /// Transmit link speed in bits per second.
+    pub link_speed_bps: u64,
+    /// The adapter carries a default route.
+    pub default_route: bool,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+struct NdjsonPreamble<'a> {
+    batch_id: Uuid,
+    device_id: Uuid,
+    #[serde_as(as = "DisplayFromStr")]
+    sent_at: DateTime<Utc>,
+    #[serde(rename = "net_deltas")]
+    network_deltas: &'a [NetworkDelta],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    dns_events: &'a [DnsBlockEvent],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a DeviceStatus>,
+    session_count: usize,
+}
+
+/// What `UsageBatch::content_hash` covers.
+#[derive(Serialize)]
+struct BatchContent<'a> {
+    device_id: Uuid,
+    sessions: &'a [UsageSession],
+    network_deltas: &'a [NetworkDelta],
+    dns_events: &'a [DnsBlockEvent],
+}
+
+/// Queries for one domain blocked by the DNS filter within a batch's interval.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsBlockEvent {
+    #[serde(rename = "domain")]
+    pub domain: String,
+    #[serde(rename = "count")]
+    pub count: u64,
+    #[serde(rename = "first_seen")]
+    #[serde_as(as = "DisplayFromStr")]
+    pub first_seen: DateTime<Utc>,
+    #[serde(rename = "last_seen")]
+    #[serde_as(as = "DisplayFromStr")]
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Status-only check-in sent when no usage has been collected for a while.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    #[serde(rename = "device_id")]
+    pub device_id: Uuid,
+    #[serde(rename = "sent_at")]
+    #[serde_as(as = "DisplayFromStr")]
+    pub sent_at: DateTime<Utc>,
+    #[serde(rename = "status")]
+    pub status: DeviceStatus,
 }
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UsageBatch {
+    #[serde(rename = "batch_id", default = "Uuid::new_v4")]
+    pub batch_id: Uuid,
     #[serde(rename = "device_id")]
     pub device_id: Uuid,
     #[serde(rename = "sent_at")]
@@ -85,28 +327,157 @@ pub struct UsageBatch {
     pub sessions: Vec<UsageSession>,
     #[serde(rename = "net_deltas")]
     pub network_deltas: Vec<NetworkDelta>,
+    #[serde(rename = "dns_events", default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_events: Vec<DnsBlockEvent>,
     #[serde(rename = "status", skip_serializing_if = "Option::is_none")]
     pub status: Option<DeviceStatus>,
+    #[serde(
+        rename = "chunk_index",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub chunk_index: Option<usize>,
+    #[serde(
+        rename = "chunk_total",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub chunk_total: Option<usize>,
 }
 
 impl UsageBatch {
+    /// Key sent as `Idempotency-Key` so the server can discard replays.
+    pub fn idempotency_key(&self) -> String {
+        format!("{}-{}", self.batch_id, self.chunk_index.unwrap_or(0))
+    }
+
+    /// Combines consecutive batches from the same device into one, keeping the
+    /// newest status. The merged id is derived from the source ids so a retry
+    /// of the same set of batches reuses the same idempotency key.
+    pub fn merge(batches: &[UsageBatch]) -> Option<UsageBatch> {
+        let (first, rest) = batches.split_first()?;
+        if rest.iter().any(|b| b.device_id != first.device_id) {
+            return None;
+        }
+        if rest.is_empty() {
+            return Some(first.clone());
+        }
+        let mut hasher = Sha256::new();
+        for batch in batches {
+            hasher.update(batch.batch_id.as_bytes());
+        }
+        let digest = hasher.finalize();
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&digest[..16]);
+
+        let last = batches.last().unwrap_or(first);
+        Some(UsageBatch {
+            batch_id: Uuid::from_bytes(id),
+            device_id: first.device_id,
+            sent_at: last.sent_at,
+            sessions: batches.iter().flat_map(|b| b.sessions.clone()).collect(),
+            network_deltas: batches
+                .iter()
+                .flat_map(|b| b.network_deltas.clone())
+                .collect(),
+            dns_events: batches.iter().flat_map(|b| b.dns_events.clone()).collect(),
+            status: batches.iter().rev().find_map(|b| b.status.clone()),
+            chunk_index: None,
+            chunk_total: None,
+        })
+    }
+
+    /// Removes the sessions at `indexes` (positions within this batch) and
+    /// returns them. Out-of-range indexes are ignored.
+    pub fn remove_sessions(&mut self, indexes: &[usize]) -> Vec<UsageSession> {
+        let mut removed = Vec::new();
+        let mut position = 0usize;
+        self.sessions.retain(|session| {
+            let keep = !indexes.contains(&position);
+            if !keep {
+                removed.push(session.clone());
+            }
+            position += 1;
+            keep
+        });
+        removed
+    }
+
     pub fn to_json_string(&self) -> anyhow::Result<String> {
         Ok(serde_json::to_string(self)?)
     }
 
+    pub fn to_gzip_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, self)?;
+        Ok(encoder.finish()?)
+    }
+
+    /// NDJSON body: a preamble line with the batch metadata followed by one
+    /// line per session. Each line ends in `\n`.
+    pub fn to_ndjson_lines(&self) -> impl Iterator<Item = serde_json::Result<String>> + '_ {
+        (0..self.ndjson_line_count()).map(move |index| self.ndjson_line(index))
+    }
+
+    pub fn ndjson_line_count(&self) -> usize {
+        self.sessions.len() + 1
+    }
+
+    /// Line `index` of the NDJSON body; 0 is the preamble.
+    pub fn ndjson_line(&self, index: usize) -> serde_json::Result<String> {
+        let mut line = match index.checked_sub(1) {
+            None => serde_json::to_string(&NdjsonPreamble {
+                batch_id: self.batch_id,
+                device_id: self.device_id,
+                sent_at: self.sent_at,
+                network_deltas: &self.network_deltas,
+                dns_events: &self.dns_events,
+                status: self.status.as_ref(),
+                session_count: self.sessions.len(),
+            })?,
+            Some(session) => serde_json::to_string(&self.sessions[session])?,
+        };
+        line.push('\n');
+        Ok(line)
+    }
+
+    /// Hex SHA-256 of the usage the batch reports: device, sessions, network
+    /// deltas and DNS events. The id, `sent_at`, chunk numbering and status
+    /// snapshot are left out, so the same window collected twice hashes the
+    /// same. `None` for a batch with no usage, which cannot be double-counted.
+    pub fn content_hash(&self) -> anyhow::Result<Option<String>> {
+        if self.sessions.is_empty() && self.network_deltas.is_empty() && self.dns_events.is_empty()
+        {
+            return Ok(None);
+        }
+        let content = BatchContent {
+            device_id: self.device_id,
+            sessions: &self.sessions,
+            network_deltas: &self.network_deltas,
+            dns_events: &self.dns_events,
+        };
+        let digest = Sha256::digest(serde_json::to_vec(&content)?);
+        Ok(Some(format!("{digest:x}")))
+    }
+
     pub fn size_fits(&self) -> bool {
         self.to_json_string()
             .map(|s| s.as_bytes().len() <= MAX_PAYLOAD_BYTES)
             .unwrap_or(false)
     }
 
+    /// Splits the batch into upload-sized pieces. The result depends only on
+    /// the batch contents and limits, so a persisted chunk cursor stays valid
+    /// across restarts.
     pub fn chunked(
         &self,
         max_sessions: usize,
         max_bytes: usize,
     ) -> anyhow::Result<Vec<UsageBatch>> {
         if self.sessions.is_empty() {
-            return Ok(vec![self.clone()]);
+            let mut single = self.clone();
+            number_chunks(std::slice::from_mut(&mut single));
+            return Ok(vec![single]);
         }
 
         let mut result = Vec::new();
@@ -117,6 +488,7 @@ impl UsageBatch {
             let mut end = (index + max_sessions).min(self.sessions.len());
             let mut slice = &self.sessions[index..end];
             let mut chunk = UsageBatch {
+                batch_id: self.batch_id,
                 device_id: self.device_id,
                 sent_at: self.sent_at,
                 sessions: slice.to_vec(),
@@ -125,11 +497,18 @@ impl UsageBatch {
                 } else {
                     Vec::new()
                 },
+                dns_events: if include_meta {
+                    self.dns_events.clone()
+                } else {
+                    Vec::new()
+                },
                 status: if include_meta {
                     self.status.clone()
                 } else {
                     None
                 },
+                chunk_index: None,
+                chunk_total: None,
             };
 
             let mut payload_bytes = chunk.to_json_string()?.into_bytes().len();
@@ -142,6 +521,11 @@ impl UsageBatch {
                 } else {
                     Vec::new()
                 };
+                chunk.dns_events = if include_meta {
+                    self.dns_events.clone()
+                } else {
+                    Vec::new()
+                };
                 chunk.status = if include_meta {
                     self.status.clone()
                 } else {
@@ -157,35 +541,314 @@ impl UsageBatch {
 
         if result.is_empty() {
             result.push(UsageBatch {
+                batch_id: self.batch_id,
                 device_id: self.device_id,
                 sent_at: self.sent_at,
                 sessions: Vec::new(),
                 network_deltas: self.network_deltas.clone(),
+                dns_events: self.dns_events.clone(),
                 status: self.status.clone(),
+                chunk_index: None,
+                chunk_total: None,
             });
         }
 
+        number_chunks(&mut result);
         Ok(result)
     }
+
+    /// Splits the sessions, network deltas and DNS events of the batch in
+    /// halves until every piece serializes to at most `max_bytes`, keeping the
+    /// status on the first piece. Pieces after the first get fresh ids so
+    /// their idempotency keys stay distinct. A single record too large on its
+    /// own still yields an oversized piece.
+    pub fn split_to_fit(&self, max_bytes: usize) -> anyhow::Result<Vec<UsageBatch>> {
+        let mut pieces = Vec::new();
+        self.split_into(max_bytes, &mut pieces)?;
+        for piece in pieces.iter_mut().skip(1) {
+            piece.batch_id = Uuid::new_v4();
+        }
+        Ok(pieces)
+    }
+
+    fn split_into(&self, max_bytes: usize, pieces: &mut Vec<UsageBatch>) -> anyhow::Result<()> {
+        let records = self.sessions.len() + self.network_deltas.len() + self.dns_events.len();
+        if records <= 1 || self.to_json_string()?.len() <= max_bytes {
+            pieces.push(UsageBatch {
+                chunk_index: None,
+                chunk_total: None,
+                ..self.clone()
+            });
+            return Ok(());
+        }
+        // The first half of the records, in field order, so each piece gets
+        // at least one whatever mix of records the batch holds.
+        let mut keep = records / 2;
+        let mut first = self.clone();
+        let mut split = |len: usize| {
+            let at = keep.min(len);
+            keep -= at;
+            at
+        };
+        let sessions = split(first.sessions.len());
+        let network_deltas = split(first.network_deltas.len());
+        let dns_events = split(first.dns_events.len());
+        let second = UsageBatch {
+            sessions: first.sessions.split_off(sessions),
+            network_deltas: first.network_deltas.split_off(network_deltas),
+            dns_events: first.dns_events.split_off(dns_events),
+            status: None,
+            ..first.clone()
+        };
+        first.split_into(max_bytes, pieces)?;
+        second.split_into(max_bytes, pieces)
+    }
+
+    /// Like `chunked`, but additionally splits any chunk whose gzip-compressed
+    /// size exceeds `max_wire_bytes`. The uncompressed limit still applies.
+    pub fn chunked_for_wire(
+        &self,
+        max_sessions: usize,
+        max_bytes: usize,
+        max_wire_bytes: usize,
+    ) -> anyhow::Result<Vec<UsageBatch>> {
+        let mut result = Vec::new();
+        for chunk in self.chunked(max_sessions, max_bytes)? {
+            if chunk.sessions.len() > 1 && chunk.to_gzip_bytes()?.len() > max_wire_bytes {
+                let half = chunk.sessions.len() / 2;
+                result.extend(chunk.chunked_for_wire(half, max_bytes, max_wire_bytes)?);
+            } else {
+                result.push(chunk);
+            }
+        }
+        number_chunks(&mut result);
+        Ok(result)
+    }
+}
+
+fn number_chunks(chunks: &mut [UsageBatch]) {
+    let total = chunks.len();
+    for (index, chunk) in chunks.iter_mut().enumerate() {
+        chunk.chunk_index = Some(index);
+        chunk.chunk_total = Some(total);
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct UploadConfig {
     pub base_url: reqwest::Url,
     pub batch_url: reqwest::Url,
+    pub heartbeat_url: reqwest::Url,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 1_000,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+/// Limits on backend requests, as read and set through commands.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Timeouts {
+    pub request_timeout_ms: u64,
+    pub connect_timeout_ms: u64,
+}
+
+/// When the user is told that uploads keep failing.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FailureAlertPolicy {
+    /// Consecutive failed upload cycles before the first notification.
+    pub cycles: u32,
+    /// Minimum time between two notifications.
+    pub interval_hours: u64,
+}
+
+/// Thresholds that turn foreground samples into sessions.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SessionPolicy {
+    /// Sessions shorter than this are dropped.
+    pub min_session_ms: u64,
+    /// Samples of the same app at most this far apart form one session.
+    pub merge_gap_ms: u64,
+    /// Longer sessions are truncated unless bucketing is enabled.
+    pub max_session_ms: u64,
+}
+
+impl SessionPolicy {
+    pub fn min_session(&self) -> Duration {
+        Duration::milliseconds(self.min_session_ms as i64)
+    }
+
+    pub fn merge_gap(&self) -> Duration {
+        Duration::milliseconds(self.merge_gap_ms as i64)
+    }
+
+    pub fn max_session(&self) -> Duration {
+        Duration::milliseconds(self.max_session_ms as i64)
+    }
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self {
+            min_session_ms: 5_000,
+            merge_gap_ms: 10_000,
+            max_session_ms: 8 * 60 * 60 * 1_000,
+        }
+    }
+}
+
+/// How the agent authenticates to the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// Register the device and use rotating bearer tokens.
+    #[default]
+    DeviceToken,
+    /// Send a static per-org key in `X-Api-Key`.
+    ApiKey,
+}
+
+/// What to report for an interface whose byte counters restarted (reboot or
+/// adapter re-enumeration).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CounterResetMode {
+    /// Everything counted since the reset.
+    #[default]
+    SinceReset,
+    /// Nothing; the new counters only become the baseline.
+    Zero,
+}
+
+/// How a registration attempt ended, as far as the user needs to know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationOutcome {
+    Registered,
+    /// The pairing code is past its lifetime (HTTP 410).
+    CodeExpired,
+    /// The pairing code was already used to link a device (HTTP 409).
+    AlreadyLinked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthEvent {
+    Registration,
+    Refresh,
+    TokensCleared,
+    /// The backend answered an upload with 401.
+    Unauthorized,
+}
+
+/// One line of the local auth audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthAuditEntry {
+    pub at: DateTime<Utc>,
+    pub event: AuthEvent,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuthAuditEntry {
+    pub fn new(event: AuthEvent, success: bool) -> Self {
+        Self {
+            at: Utc::now(),
+            event,
+            success,
+            status: None,
+            detail: None,
+        }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Foreground time for one app, as shown in the tray.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppUsageTotal {
+    pub package: String,
+    pub total_ms: u64,
+}
+
+/// A named backend configuration as shown in the profile picker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileSummary {
+    pub name: String,
+    pub api_base: Option<String>,
+    pub active: bool,
+}
+
+/// Space the agent's data directory takes, for support.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub total_bytes: u64,
+    /// The queue database with its write-ahead log.
+    pub queue_bytes: u64,
+    /// Every file under the data directory, largest first.
+    pub files: Vec<StoredFileSize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredFileSize {
+    /// Relative to the data directory.
+    pub path: String,
+    pub bytes: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestOutcome {
     pub success: bool,
+    pub status: Option<u16>,
     pub failure: Option<UploadFailureReason>,
     pub body: Option<String>,
 }
 
+/// Body returned by the backend when it refuses specific sessions in a chunk.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RejectedSessionsResponse {
+    pub rejected: Vec<usize>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UploadResult {
     pub uploaded_batches: usize,
     pub failure_reason: Option<UploadFailureReason>,
+    pub circuit: CircuitState,
+    #[serde(default)]
+    pub dead_lettered_batches: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -197,15 +860,223 @@ pub enum UploadFailureReason {
     Unauthorized,
     NetworkError,
     ServerError,
+    RateLimited,
+    PinMismatch,
+    Offline,
+    DnsFailure,
+    ConnectFailure,
+    Timeout,
 }
 
 impl UploadFailureReason {
+    /// Failures that indicate the backend itself is unhealthy.
+    pub fn trips_circuit(&self) -> bool {
+        matches!(
+            self,
+            UploadFailureReason::NetworkError
+                | UploadFailureReason::ServerError
+                | UploadFailureReason::DnsFailure
+                | UploadFailureReason::ConnectFailure
+                | UploadFailureReason::Timeout
+        )
+    }
+
+    /// Whether a failure needs the device re-linked rather than the network fixed.
+    pub fn needs_relink(&self) -> bool {
+        matches!(
+            self,
+            UploadFailureReason::MissingToken
+                | UploadFailureReason::TokenExpired
+                | UploadFailureReason::Unauthorized
+        )
+    }
+
     pub fn retryable(&self) -> bool {
         matches!(
             self,
             UploadFailureReason::MissingConfig
                 | UploadFailureReason::MissingToken
                 | UploadFailureReason::TokenExpired
+                | UploadFailureReason::RateLimited
+                | UploadFailureReason::Offline
+                | UploadFailureReason::DnsFailure
+                | UploadFailureReason::ConnectFailure
+                | UploadFailureReason::Timeout
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(package: &str, sampled_at: DateTime<Utc>) -> NetworkDelta {
+        NetworkDelta {
+            package: package.to_string(),
+            sampled_at,
+            wifi_bytes: 1_000,
+            cellular_bytes: 0,
+            ethernet_bytes: 0,
+            metered: false,
+            rx_bytes: 600,
+            tx_bytes: 400,
+            ssid: None,
+            spike: false,
+        }
+    }
+
+    fn dns_event(domain: &str, seen: DateTime<Utc>) -> DnsBlockEvent {
+        DnsBlockEvent {
+            domain: domain.to_string(),
+            count: 1,
+            first_seen: seen,
+            last_seen: seen,
+        }
+    }
+
+    fn batch(sessions: usize, deltas: usize, dns_events: usize) -> UsageBatch {
+        let now = Utc::now();
+        UsageBatch {
+            batch_id: Uuid::new_v4(),
+            device_id: Uuid::new_v4(),
+            sent_at: now,
+            sessions: (0..sessions)
+                .map(|n| {
+                    UsageSession::background(format!("app{n}.exe"), now - Duration::minutes(1), now)
+                })
+                .collect(),
+            network_deltas: (0..deltas)
+                .map(|n| delta(&format!("net{n}.exe"), now))
+                .collect(),
+            dns_events: (0..dns_events)
+                .map(|n| dns_event(&format!("blocked{n}.example.com"), now))
+                .collect(),
+            status: None,
+            chunk_index: None,
+            chunk_total: None,
+        }
+    }
+
+    #[test]
+    fn gzip_body_decodes_to_the_json_body() {
+        use std::io::Read;
+
+        let batch = batch(50, 20, 0);
+        let gzip = batch.to_gzip_bytes().unwrap();
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(json, batch.to_json_string().unwrap());
+        assert!(gzip.len() < json.len() / 4);
+    }
+
+    #[test]
+    fn chunked_for_wire_keeps_every_compressed_chunk_under_the_limit() {
+        let mut batch = batch(60, 0, 0);
+        for session in &mut batch.sessions {
+            session.title = Some(Uuid::new_v4().to_string());
+        }
+        let chunks = batch.chunked_for_wire(20, 100_000, 500).unwrap();
+        assert!(chunks.len() > 3);
+        for chunk in &chunks {
+            assert!(chunk.to_gzip_bytes().unwrap().len() <= 500);
+        }
+        let sessions: usize = chunks.iter().map(|chunk| chunk.sessions.len()).sum();
+        assert_eq!(sessions, 60);
+        assert_eq!(chunks.last().unwrap().chunk_total, Some(chunks.len()));
+    }
+
+    #[test]
+    fn split_to_fit_spreads_network_and_dns_data_across_pieces() {
+        // A couple of sessions and over 2 MB of network deltas and DNS
+        // events.
+        let batch = batch(2, 8_000, 8_000);
+        assert!(batch.to_json_string().unwrap().len() > 2_000_000);
+
+        let pieces = batch.split_to_fit(MAX_PAYLOAD_BYTES).unwrap();
+        assert!(pieces.len() > 1);
+        assert!(pieces.iter().all(UsageBatch::size_fits));
+        let count = |field: fn(&UsageBatch) -> usize| pieces.iter().map(field).sum::<usize>();
+        assert_eq!(count(|piece| piece.sessions.len()), 2);
+        assert_eq!(count(|piece| piece.network_deltas.len()), 8_000);
+        assert_eq!(count(|piece| piece.dns_events.len()), 8_000);
+        assert_eq!(pieces[0].batch_id, batch.batch_id);
+    }
+
+    #[test]
+    fn split_to_fit_stops_at_a_single_oversized_record() {
+        let mut batch = batch(1, 1, 0);
+        batch.sessions[0].title = Some("x".repeat(2_000));
+        let pieces = batch.split_to_fit(1_000).unwrap();
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].sessions.len(), 1);
+        assert_eq!(pieces[1].network_deltas.len(), 1);
+    }
+
+    #[test]
+    fn failure_reasons_keep_their_wire_names() {
+        let names: Vec<String> = [
+            UploadFailureReason::NetworkError,
+            UploadFailureReason::DnsFailure,
+            UploadFailureReason::ConnectFailure,
+            UploadFailureReason::Timeout,
+        ]
+        .iter()
+        .map(|reason| serde_json::to_string(reason).unwrap())
+        .collect();
+        assert_eq!(
+            names,
+            [
+                "\"NETWORK_ERROR\"",
+                "\"DNS_FAILURE\"",
+                "\"CONNECT_FAILURE\"",
+                "\"TIMEOUT\""
+            ]
+        );
+    }
+
+    #[test]
+    fn transport_failures_are_retried_and_trip_the_circuit() {
+        for reason in [
+            UploadFailureReason::DnsFailure,
+            UploadFailureReason::ConnectFailure,
+            UploadFailureReason::Timeout,
+        ] {
+            assert!(reason.retryable());
+            assert!(reason.trips_circuit());
+            assert!(!reason.needs_relink());
+        }
+    }
+
+    #[test]
+    fn content_hash_ignores_the_id_and_send_time() {
+        let original = batch(2, 1, 1);
+        let recollected = UsageBatch {
+            batch_id: Uuid::new_v4(),
+            sent_at: original.sent_at + Duration::minutes(5),
+            ..original.clone()
+        };
+        assert_eq!(
+            original.content_hash().unwrap(),
+            recollected.content_hash().unwrap()
+        );
+        assert!(original.content_hash().unwrap().is_some());
+        assert_eq!(batch(0, 0, 0).content_hash().unwrap(), None);
+    }
+
+    #[test]
+    fn content_hash_changes_with_any_usage() {
+        let original = batch(2, 1, 1);
+        let hash = original.content_hash().unwrap();
+        let mut longer = original.clone();
+        longer.sessions[0].window_end += Duration::milliseconds(1);
+        let mut other_device = original.clone();
+        other_device.device_id = Uuid::new_v4();
+        let mut more_traffic = original;
+        more_traffic.network_deltas[0].wifi_bytes += 1;
+        for near_miss in [longer, other_device, more_traffic] {
+            assert_ne!(near_miss.content_hash().unwrap(), hash);
+        }
+    }
+}
@@ -1,63 +1,700 @@
-﻿use std::sync::Arc;
+﻿use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+use chrono::Utc;
+use futures::FutureExt;
 use tauri::async_runtime::{self, JoinHandle};
-use tokio::time::{interval, sleep, Duration};
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep, timeout_at, Duration, Instant, Interval};
+use tokio_util::sync::CancellationToken;
 
+use crate::alerts::FailureAlerts;
+use crate::auth::{self, RegistrationRejected, TokenStore};
+use crate::backoff::{apply_jitter, JitterSource, RandomJitter};
+use crate::clock::ClockSkew;
+use crate::collectors::connectivity;
 use crate::collectors::sessions::SessionCollector;
+use crate::config::{DeviceIdStore, UsageConfigStore};
+use crate::events::AgentEvents;
 use crate::manager::UsageCollectionManager;
+use crate::metrics::AgentMetrics;
+use crate::models::{AuthMode, UploadFailureReason};
 use crate::uploader::UsageUploader;
 
-const COLLECT_INTERVAL_MINUTES: u64 = 15;
-const UPLOAD_INTERVAL_SECONDS: u64 = 60;
+const RATE_LIMITED_MAX_INTERVAL_SECONDS: u64 = 15 * 60;
+const OFFLINE_MAX_INTERVAL_SECONDS: u64 = 10 * 60;
+const CONNECTIVITY_POLL_SECONDS: u64 = 15;
+const HEARTBEAT_CHECK_SECONDS: u64 = 60;
+const SPIKE_CHECK_SECONDS: u64 = 60;
+const TAMPER_CHECK_SECONDS: u64 = 60;
+const RETENTION_SWEEP_HOURS: u64 = 24;
+const REGISTRATION_CHECK_SECONDS: u64 = 60;
+const REGISTRATION_MAX_BACKOFF_SECONDS: u64 = 60 * 60;
+const SUPERVISOR_CHECK_SECONDS: u64 = 30;
+/// Failed sampler liveness checks in a row after which it counts as stalled.
+const STALL_CHECKS: u32 = 2;
+/// Delay before the first restart of a task; it doubles with each further
+/// restart.
+const TASK_RESTART_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_TASK_RESTARTS: u32 = 5;
+/// A restarted task that runs this long is considered healthy again.
+const TASK_RESTART_RESET: Duration = Duration::from_secs(60 * 60);
+
+/// Background tasks restarted since startup.
+static TASK_RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+/// The tasks `AgentRuntime::spawn` starts and supervises.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Task {
+    Sampler,
+    Collect,
+    Upload,
+    Heartbeat,
+    Spike,
+    Tamper,
+    Retention,
+    Registration,
+}
+
+const TASKS: [Task; 8] = [
+    Task::Sampler,
+    Task::Collect,
+    Task::Upload,
+    Task::Heartbeat,
+    Task::Spike,
+    Task::Tamper,
+    Task::Retention,
+    Task::Registration,
+];
+
+impl Task {
+    fn name(self) -> &'static str {
+        match self {
+            Task::Sampler => "session sampler",
+            Task::Collect => "collection",
+            Task::Upload => "upload",
+            Task::Heartbeat => "heartbeat",
+            Task::Spike => "spike check",
+            Task::Tamper => "tamper check",
+            Task::Retention => "retention",
+            Task::Registration => "registration",
+        }
+    }
+}
+
+/// A supervised task. Dropping it aborts the task, so aborting the
+/// supervisor stops every task it started.
+struct Child {
+    task: Task,
+    /// `None` once the task has finished; a finished handle must not be
+    /// polled again.
+    handle: Option<JoinHandle<()>>,
+    health: TaskHealth,
+}
+
+impl Child {
+    fn new(task: Task, handle: JoinHandle<()>) -> Self {
+        Self {
+            task,
+            handle: Some(handle),
+            health: TaskHealth::new(Instant::now()),
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.handle {
+            handle.abort();
+        }
+    }
+}
+
+/// Restart bookkeeping of one supervised task.
+#[derive(Debug)]
+struct TaskHealth {
+    /// When the current run of the task started.
+    started: Instant,
+    /// Restarts since the task last ran for `TASK_RESTART_RESET`.
+    restarts: u32,
+    /// When a dead task is due to be respawned.
+    restart_at: Option<Instant>,
+    /// Failed liveness checks in a row.
+    missed: u32,
+    given_up: bool,
+}
+
+impl TaskHealth {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            restarts: 0,
+            restart_at: None,
+            missed: 0,
+            given_up: false,
+        }
+    }
+
+    /// Whether a dead task's backoff has passed.
+    fn restart_due(&self, now: Instant) -> bool {
+        !self.given_up && self.restart_at.is_some_and(|restart_at| now >= restart_at)
+    }
+
+    fn restarted(&mut self, now: Instant) {
+        self.started = now;
+        self.restart_at = None;
+        self.missed = 0;
+    }
+
+    /// Called while the task runs; a run of `TASK_RESTART_RESET` clears the
+    /// restarts before it.
+    fn running(&mut self, now: Instant) {
+        if now - self.started >= TASK_RESTART_RESET {
+            self.restarts = 0;
+        }
+    }
+
+    /// Records a liveness check, returning true once `STALL_CHECKS` in a
+    /// row failed. Checks within a supervisor period of a (re)start do not
+    /// count, since the task may not have reported yet.
+    fn missed_check(&mut self, alive: bool, now: Instant) -> bool {
+        if alive || now - self.started < Duration::from_secs(SUPERVISOR_CHECK_SECONDS) {
+            self.missed = 0;
+            return false;
+        }
+        self.missed += 1;
+        self.missed >= STALL_CHECKS
+    }
+
+    /// Schedules a restart of a task that died, returning the backoff, or
+    /// gives up and returns `None` once it died `MAX_TASK_RESTARTS` times
+    /// without then running for `TASK_RESTART_RESET`.
+    fn died(&mut self, now: Instant) -> Option<Duration> {
+        if self.restarts >= MAX_TASK_RESTARTS {
+            self.given_up = true;
+            return None;
+        }
+        let delay = TASK_RESTART_BACKOFF * 2u32.pow(self.restarts);
+        self.restarts += 1;
+        self.restart_at = Some(now + delay);
+        Some(delay)
+    }
+}
 
 pub struct AgentRuntime {
     sessions: Arc<SessionCollector>,
     manager: Arc<UsageCollectionManager>,
     uploader: Arc<UsageUploader>,
+    config_store: Arc<UsageConfigStore>,
+    token_store: Arc<TokenStore>,
+    device_store: Arc<DeviceIdStore>,
+    clock: Arc<ClockSkew>,
+    metrics: Arc<AgentMetrics>,
+    events: Arc<dyn AgentEvents>,
+    jitter: Arc<dyn JitterSource>,
+    upload_now: Arc<Notify>,
+    /// Fired by `shutdown`; every task stops at its next wait.
+    cancel: CancellationToken,
 }
 
 impl AgentRuntime {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         sessions: Arc<SessionCollector>,
         manager: Arc<UsageCollectionManager>,
         uploader: Arc<UsageUploader>,
+        config_store: Arc<UsageConfigStore>,
+        token_store: Arc<TokenStore>,
+        device_store: Arc<DeviceIdStore>,
+        clock: Arc<ClockSkew>,
+        metrics: Arc<AgentMetrics>,
+        events: Arc<dyn AgentEvents>,
     ) -> Self {
         Self {
             sessions,
             manager,
             uploader,
+            config_store,
+            token_store,
+            device_store,
+            clock,
+            metrics,
+            events,
+            jitter: Arc::new(RandomJitter),
+            upload_now: Arc::new(Notify::new()),
+            cancel: CancellationToken::new(),
         }
     }
 
+    /// Starts the supervisor, which starts every task and restarts one that
+    /// panics, exits or (for the sampler) stops sampling. Aborting the
+    /// returned handle aborts the tasks too.
     pub fn spawn(self: Arc<Self>) -> Vec<JoinHandle<()>> {
-        let sampler = self.sessions.clone().spawn_sampler();
+        vec![async_runtime::spawn(self.supervise())]
+    }
+
+    fn spawn_task(self: &Arc<Self>, task: Task) -> JoinHandle<()> {
+        match task {
+            Task::Sampler => self.sessions.spawn_sampler(self.cancel.clone()),
+            Task::Collect => self.spawn_collect(),
+            Task::Upload => self.spawn_upload(),
+            Task::Heartbeat => self.spawn_heartbeat(),
+            Task::Spike => self.spawn_spike(),
+            Task::Tamper => self.spawn_tamper(),
+            Task::Retention => self.spawn_retention(),
+            Task::Registration => async_runtime::spawn(self.clone().registration_loop()),
+        }
+    }
+
+    /// Checks the tasks every `SUPERVISOR_CHECK_SECONDS` until shutdown, then
+    /// waits for them to stop.
+    async fn supervise(self: Arc<Self>) {
+        let mut children: Vec<Child> = TASKS
+            .into_iter()
+            .map(|task| Child::new(task, self.spawn_task(task)))
+            .collect();
+        let mut ticker = interval(Duration::from_secs(SUPERVISOR_CHECK_SECONDS));
+        while next_tick(&mut ticker, &self.cancel).await {
+            for child in &mut children {
+                self.check(child);
+            }
+        }
+        for child in &mut children {
+            if let Some(handle) = child.handle.as_mut() {
+                let _ = handle.await;
+            }
+        }
+    }
+
+    /// Respawns `child` once its backoff has passed, or schedules a respawn
+    /// if it died. A task that dies `MAX_TASK_RESTARTS` times without then
+    /// running for `TASK_RESTART_RESET` is left dead, since it would most
+    /// likely crash the same way again.
+    ///
+    /// Only the sampler reports liveness (`sampler_alive`); the other tasks
+    /// may legitimately spend a long time in one upload or sweep.
+    fn check(self: &Arc<Self>, child: &mut Child) {
+        // Tasks end on their own once shutdown begins.
+        if child.health.given_up || self.cancel.is_cancelled() {
+            return;
+        }
+        let now = Instant::now();
+        let name = child.task.name();
+        let Some(handle) = child.handle.as_mut() else {
+            if child.health.restart_due(now) {
+                child.handle = Some(self.spawn_task(child.task));
+                child.health.restarted(now);
+                TASK_RESTARTS.fetch_add(1, Ordering::Relaxed);
+                log::info!("restarted the {name} task");
+            }
+            return;
+        };
+        match handle.now_or_never() {
+            Some(Ok(())) => log::error!("the {name} task exited"),
+            Some(Err(err)) => log::error!("the {name} task panicked: {err}"),
+            None if child.task == Task::Sampler
+                && child
+                    .health
+                    .missed_check(self.sessions.sampler_alive(), now) =>
+            {
+                log::error!("the {name} task stopped responding");
+                handle.abort();
+            }
+            None => {
+                child.health.running(now);
+                return;
+            }
+        }
+        child.handle = None;
+        match child.health.died(now) {
+            Some(delay) => log::warn!("restarting the {name} task in {delay:?}"),
+            None => log::error!(
+                "the {name} task died {MAX_TASK_RESTARTS} times in a row; not restarting it"
+            ),
+        }
+    }
+
+    /// Collects at startup and then once per collect interval, which is
+    /// re-read on every pass so a changed setting applies from the next
+    /// wait on.
+    fn spawn_collect(&self) -> JoinHandle<()> {
         let manager = self.manager.clone();
-        let collect_handle = async_runtime::spawn(async move {
-            if let Err(err) = manager.collect_and_store() {
+        let config_store = self.config_store.clone();
+        let cancel = self.cancel.clone();
+        async_runtime::spawn(async move {
+            let collector = manager.clone();
+            if let Err(err) = blocking(move || collector.collect_and_store()).await {
                 log::error!("usage collection failed: {err:?}");
             }
-            let mut ticker = interval(Duration::from_secs(COLLECT_INTERVAL_MINUTES * 60));
-            loop {
-                ticker.tick().await;
-                if let Err(err) = manager.collect_and_store() {
+            while wait(config_store.collect_interval(), &cancel).await {
+                let collector = manager.clone();
+                if let Err(err) = blocking(move || collector.collect_and_store()).await {
                     log::error!("usage collection failed: {err:?}");
                 }
             }
-        });
+        })
+    }
 
+    /// Uploads every upload interval, backing off while rate limited or
+    /// offline. An upload in progress is not interrupted by shutdown; the
+    /// loop stops at its next wait.
+    fn spawn_upload(&self) -> JoinHandle<()> {
         let uploader = self.uploader.clone();
-        let upload_handle = async_runtime::spawn(async move {
-            if let Err(err) = uploader.upload_pending().await {
-                log::error!("usage upload failed: {err:?}");
-            }
+        let jitter = self.jitter.clone();
+        let events = self.events.clone();
+        let alerts = FailureAlerts::new(self.config_store.clone());
+        let upload_now = self.upload_now.clone();
+        let config_store = self.config_store.clone();
+        let cancel = self.cancel.clone();
+        async_runtime::spawn(async move {
+            let mut delay = config_store.upload_interval();
             loop {
-                sleep(Duration::from_secs(UPLOAD_INTERVAL_SECONDS)).await;
-                if let Err(err) = uploader.upload_pending().await {
-                    log::error!("usage upload failed: {err:?}");
+                let outcome = uploader.upload_pending().await;
+                // Backoff never waits less than the configured interval.
+                let base = config_store.upload_interval();
+                if let Ok(result) = &outcome {
+                    if result.failure_reason.is_some() {
+                        events.upload_failed(result);
+                    } else if result.uploaded_batches > 0 {
+                        events.upload_succeeded(result);
+                    }
+                    if let Some(message) = alerts.observe(result, Utc::now()) {
+                        events.failure_alert(message);
+                    }
+                }
+                match outcome {
+                    Ok(result)
+                        if matches!(
+                            result.failure_reason,
+                            Some(UploadFailureReason::RateLimited)
+                        ) =>
+                    {
+                        delay = (delay * 2)
+                            .min(Duration::from_secs(RATE_LIMITED_MAX_INTERVAL_SECONDS).max(base));
+                        log::warn!("uploads rate limited; next attempt in {delay:?}");
+                    }
+                    Ok(result)
+                        if matches!(result.failure_reason, Some(UploadFailureReason::Offline)) =>
+                    {
+                        delay = (delay * 2)
+                            .min(Duration::from_secs(OFFLINE_MAX_INTERVAL_SECONDS).max(base));
+                        tokio::select! {
+                            _ = wait_for_connectivity(delay) => continue,
+                            _ = cancel.cancelled() => break,
+                        }
+                    }
+                    Ok(_) => delay = base,
+                    Err(err) => log::error!("usage upload failed: {err:?}"),
+                }
+                tokio::select! {
+                    _ = sleep(apply_jitter(delay, jitter.as_ref())) => {}
+                    _ = upload_now.notified() => {}
+                    _ = cancel.cancelled() => break,
                 }
             }
-        });
+        })
+    }
+
+    /// Heartbeats run on their own task so a long backlog upload never
+    /// delays them.
+    fn spawn_heartbeat(&self) -> JoinHandle<()> {
+        let manager = self.manager.clone();
+        let uploader = self.uploader.clone();
+        let config_store = self.config_store.clone();
+        let cancel = self.cancel.clone();
+        async_runtime::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(HEARTBEAT_CHECK_SECONDS));
+            while next_tick(&mut ticker, &cancel).await {
+                let due = {
+                    let manager = manager.clone();
+                    let interval = config_store.heartbeat_interval();
+                    blocking(move || manager.heartbeat_due(interval)).await
+                };
+                let heartbeat = match due {
+                    Ok(Some(heartbeat)) => heartbeat,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        log::error!("failed to build heartbeat: {err:?}");
+                        continue;
+                    }
+                };
+                match uploader.send_heartbeat(&heartbeat).await {
+                    Ok(true) => manager.record_heartbeat(heartbeat.sent_at),
+                    Ok(false) => {}
+                    Err(err) => log::warn!("heartbeat failed: {err:?}"),
+                }
+            }
+        })
+    }
+
+    /// Checks for a data-usage spike between collects, collecting and
+    /// uploading it early instead of at the next regular collect.
+    fn spawn_spike(&self) -> JoinHandle<()> {
+        let manager = self.manager.clone();
+        let upload_now = self.upload_now.clone();
+        let cancel = self.cancel.clone();
+        async_runtime::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(SPIKE_CHECK_SECONDS));
+            while next_tick(&mut ticker, &cancel).await {
+                if !manager.spike_detected() {
+                    continue;
+                }
+                let collector = manager.clone();
+                match blocking(move || collector.collect_and_store()).await {
+                    Ok(_) => upload_now.notify_one(),
+                    Err(err) => log::error!("spike collection failed: {err:?}"),
+                }
+            }
+        })
+    }
+
+    /// Sends a heartbeat as soon as DNS filtering is bypassed, rather than
+    /// leaving the flag for the next batch.
+    fn spawn_tamper(&self) -> JoinHandle<()> {
+        let manager = self.manager.clone();
+        let uploader = self.uploader.clone();
+        let cancel = self.cancel.clone();
+        async_runtime::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(TAMPER_CHECK_SECONDS));
+            while next_tick(&mut ticker, &cancel).await {
+                let tamper = {
+                    let manager = manager.clone();
+                    blocking(move || manager.tamper_heartbeat()).await
+                };
+                let heartbeat = match tamper {
+                    Ok(Some(heartbeat)) => heartbeat,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        log::error!("failed to build tamper heartbeat: {err:?}");
+                        continue;
+                    }
+                };
+                match uploader.send_heartbeat(&heartbeat).await {
+                    Ok(true) => manager.record_heartbeat(heartbeat.sent_at),
+                    Ok(false) => {}
+                    Err(err) => log::warn!("tamper heartbeat failed: {err:?}"),
+                }
+            }
+        })
+    }
+
+    /// Ages out stored usage and compacts the queue at startup and then
+    /// daily.
+    fn spawn_retention(&self) -> JoinHandle<()> {
+        let manager = self.manager.clone();
+        let cancel = self.cancel.clone();
+        async_runtime::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(RETENTION_SWEEP_HOURS * 60 * 60));
+            while next_tick(&mut ticker, &cancel).await {
+                let manager = manager.clone();
+                let swept = blocking(move || {
+                    if let Err(err) = manager.enforce_retention() {
+                        log::error!("retention sweep failed: {err:?}");
+                    }
+                    manager.compact_queue()
+                })
+                .await;
+                if let Err(err) = swept {
+                    log::error!("queue compaction failed: {err:?}");
+                }
+            }
+        })
+    }
+
+    /// Stops the tasks in `handles`, queues the active session with the
+    /// usage collected since the last run, then makes one last upload.
+    /// Waiting for the tasks (including an upload in progress), the final
+    /// collection and the final upload share `budget`; tasks still running
+    /// when it ends are aborted, and batches not sent stay queued for the
+    /// next start.
+    pub async fn shutdown(&self, handles: Vec<JoinHandle<()>>, budget: Duration) {
+        let deadline = Instant::now() + budget;
+        self.stop(handles, deadline).await;
+        self.sessions.finalize();
+        let manager = self.manager.clone();
+        match timeout_at(deadline, blocking(move || manager.collect_and_store())).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => log::error!("final usage collection failed: {err:?}"),
+            Err(_) => log::warn!("final usage collection did not finish within {budget:?}"),
+        }
+        match timeout_at(deadline, self.uploader.upload_pending()).await {
+            Ok(Ok(result)) => log::info!(
+                "final upload sent {} batch(es); {} left queued",
+                result.uploaded_batches,
+                self.manager.batch_store().queue_size()
+            ),
+            Ok(Err(err)) => log::warn!("final upload failed: {err:?}"),
+            Err(_) => log::warn!("final upload did not finish within {budget:?}"),
+        }
+    }
+
+    /// Cancels the runtime and waits for the tasks in `handles` to return,
+    /// aborting those still running at `deadline`.
+    pub async fn stop(&self, handles: Vec<JoinHandle<()>>, deadline: Instant) {
+        self.cancel.cancel();
+        for mut handle in handles {
+            if timeout_at(deadline, &mut handle).await.is_err() {
+                handle.abort();
+            }
+        }
+    }
+
+    /// Registers at startup and again whenever the tokens have been cleared
+    /// (e.g. the refresh token was rejected), backing off exponentially
+    /// between failures. A rejection from the backend stops retries until the
+    /// API base changes; a missing API base waits for one to be configured.
+    async fn registration_loop(self: Arc<Self>) {
+        let check = Duration::from_secs(REGISTRATION_CHECK_SECONDS);
+        let max_backoff = Duration::from_secs(REGISTRATION_MAX_BACKOFF_SECONDS);
+        let mut delay = Duration::ZERO;
+        let mut backoff = check;
+        let mut rejected_base: Option<String> = None;
+        loop {
+            tokio::select! {
+                _ = sleep(apply_jitter(delay, self.jitter.as_ref())) => {}
+                _ = self.config_store.api_base_changed() => rejected_base = None,
+                _ = self.cancel.cancelled() => return,
+            }
+            delay = check;
+            if self.config_store.auth_mode() == AuthMode::ApiKey || self.token_store.has_tokens() {
+                continue;
+            }
+            let base = self
+                .config_store
+                .get_api_base()
+                .filter(|base| !base.trim().is_empty());
+            if base.is_none() {
+                log::debug!("api base not configured; registration deferred");
+                continue;
+            }
+            if base == rejected_base {
+                continue;
+            }
+            log::info!("device has no tokens; registering");
+            let registered = auth::ensure_registered(
+                &self.config_store,
+                &self.token_store,
+                &self.device_store,
+                &self.clock,
+            )
+            .await;
+            match registered {
+                Ok(()) => {
+                    log::info!("device registered");
+                    self.metrics.record_registration(true);
+                    backoff = check;
+                    self.upload_now.notify_one();
+                }
+                Err(err) if err.downcast_ref::<RegistrationRejected>().is_some() => {
+                    self.metrics.record_registration(false);
+                    log::error!("{err:#}; not retrying until the api base changes");
+                    backoff = check;
+                    rejected_base = base;
+                }
+                Err(err) => {
+                    self.metrics.record_registration(false);
+                    delay = backoff;
+                    backoff = (backoff * 2).min(max_backoff);
+                    log::warn!("registration failed: {err:?}; next attempt in {delay:?}");
+                }
+            }
+        }
+    }
+}
+
+pub fn task_restarts() -> u64 {
+    TASK_RESTARTS.load(Ordering::Relaxed)
+}
+
+/// Waits for `ticker`'s next tick, or returns false once `cancel` fires.
+async fn next_tick(ticker: &mut Interval, cancel: &CancellationToken) -> bool {
+    tokio::select! {
+        _ = ticker.tick() => true,
+        _ = cancel.cancelled() => false,
+    }
+}
+
+/// Sleeps for `period`, or returns false once `cancel` fires.
+async fn wait(period: Duration, cancel: &CancellationToken) -> bool {
+    tokio::select! {
+        _ = sleep(period) => true,
+        _ = cancel.cancelled() => false,
+    }
+}
+
+/// Runs `work` on the blocking pool, so collection and status checks that hit
+/// the disk or WMI never stall the runtime's worker threads.
+async fn blocking<T, F>(work: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    async_runtime::spawn_blocking(work).await?
+}
+
+/// Sleeps for up to `max_wait` while offline, returning early once the
+/// network comes back so uploads resume promptly.
+async fn wait_for_connectivity(max_wait: Duration) {
+    let poll = Duration::from_secs(CONNECTIVITY_POLL_SECONDS);
+    let mut waited = Duration::ZERO;
+    while waited < max_wait {
+        sleep(poll).await;
+        waited += poll;
+        if connectivity::is_online() {
+            log::info!("network connectivity restored");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_back_off_and_then_give_up() {
+        let now = Instant::now();
+        let mut health = TaskHealth::new(now);
+        let mut delays = Vec::new();
+        while let Some(delay) = health.died(now) {
+            assert!(!health.restart_due(now + delay - Duration::from_millis(1)));
+            assert!(health.restart_due(now + delay));
+            health.restarted(now + delay);
+            delays.push(delay.as_secs());
+        }
+        assert_eq!(delays, [5, 10, 20, 40, 80]);
+        assert!(health.given_up);
+        assert!(!health.restart_due(now + Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn a_long_healthy_run_clears_earlier_restarts() {
+        let now = Instant::now();
+        let mut health = TaskHealth::new(now);
+        for _ in 0..MAX_TASK_RESTARTS {
+            health.died(now);
+            health.restarted(now);
+        }
+        health.running(now + TASK_RESTART_RESET - Duration::from_secs(1));
+        assert_eq!(health.restarts, MAX_TASK_RESTARTS);
+        health.running(now + TASK_RESTART_RESET);
+        assert_eq!(
+            health.died(now + TASK_RESTART_RESET),
+            Some(TASK_RESTART_BACKOFF)
+        );
+    }
 
-        vec![sampler, collect_handle, upload_handle]
+    #[test]
+    fn stall_needs_consecutive_missed_checks_after_the_grace_period() {
+        let now = Instant::now();
+        let check = Duration::from_secs(SUPERVISOR_CHECK_SECONDS);
+        let mut health = TaskHealth::new(now);
+        // Not yet reported right after starting.
+        assert!(!health.missed_check(false, now + check / 2));
+        assert!(!health.missed_check(false, now + check));
+        // A live check in between resets the count.
+        assert!(!health.missed_check(true, now + check * 2));
+        assert!(!health.missed_check(false, now + check * 3));
+        assert!(health.missed_check(false, now + check * 4));
     }
 }
@@ -1,43 +1,237 @@
 use std::collections::{HashMap, VecDeque};
-use std::fs;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use directories::ProjectDirs;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use parking_lot::Mutex;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, Value as SqlValue, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+use windows::core::HSTRING;
+use windows::Win32::Foundation::{
+    CloseHandle, ERROR_SHARING_VIOLATION, GENERIC_READ, GENERIC_WRITE, HANDLE,
+};
+use windows::Win32::Storage::FileSystem::{
+    CreateFileW, GetDiskFreeSpaceExW, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_NONE, OPEN_ALWAYS,
+};
 
-use crate::models::{NetworkCounters, UsageBatch};
+use crate::backoff::PersistedBackoff;
+use crate::events::AgentEvents;
+use crate::models::{
+    AuthAuditEntry, NetworkCounters, StorageStats, StoredFileSize, UsageBatch, UsageSession,
+    MAX_PAYLOAD_BYTES,
+};
 
 const APP_QUALIFIER: (&str, &str, &str) = ("com", "NuScape", "NuScapeAgent");
 const QUEUE_FILE: &str = "usage_queue.json";
+const QUEUE_DB_FILE: &str = "usage_queue.db";
+/// Write-ahead log and its index, present while the database is open or
+/// after a crash.
+const QUEUE_WAL_FILE: &str = "usage_queue.db-wal";
+const QUEUE_SHM_FILE: &str = "usage_queue.db-shm";
 const COUNTERS_FILE: &str = "network_counters.json";
+const CONNECTION_COUNTERS_FILE: &str = "connection_counters.json";
 const DEVICE_FILE: &str = "device.json";
 const TOKENS_FILE: &str = "tokens.json";
 const CONFIG_FILE: &str = "config.json";
+const REJECTS_FILE: &str = "rejected_sessions.jsonl";
+const REJECTS_ROTATED_FILE: &str = "rejected_sessions.1.jsonl";
+/// Size at which the rejected sessions file is rotated; one rotated file is
+/// kept.
+const REJECTS_MAX_BYTES: u64 = 1024 * 1024;
+const BACKOFF_FILE: &str = "upload_backoff.json";
+const DEAD_LETTER_FILE: &str = "dead_letter.json";
+const AUTH_AUDIT_FILE: &str = "auth_audit.jsonl";
+const AUTH_AUDIT_ROTATED_FILE: &str = "auth_audit.1.jsonl";
+/// Size at which the audit log is rotated; one rotated file is kept.
+const AUTH_AUDIT_MAX_BYTES: u64 = 256 * 1024;
+const SESSION_STATE_FILE: &str = "session_state.json";
+const DRY_RUN_DIR: &str = "dryrun";
+const PROFILES_DIR: &str = "profiles";
+/// Profile whose files live directly in the data directory, as they did
+/// before profiles existed.
+pub const DEFAULT_PROFILE: &str = "default";
+/// Overrides the data directory, e.g. to keep it out of roaming profiles.
+pub const DATA_DIR_ENV: &str = "NUSCAPE_DATA_DIR";
+/// Written and removed again to check that the data directory is writable.
+const WRITE_PROBE_FILE: &str = ".write-probe";
+/// Held open without sharing by the agent that owns the data directory.
+const LOCK_FILE: &str = "agent.lock";
 
+/// Files moved aside by `quarantine` since the agent started.
+static QUARANTINED_FILES: AtomicU64 = AtomicU64::new(0);
+/// Usage batches discarded since the agent started, by low-disk eviction or
+/// for exceeding `MAX_PAYLOAD_BYTES` on their own.
+static DROPPED_BATCHES: AtomicU64 = AtomicU64::new(0);
+/// Sessions in batches removed by `UsageBatchStore::purge_older_than`.
+static PURGED_SESSIONS: AtomicU64 = AtomicU64::new(0);
+/// Batches `enqueue` skipped as repeats of a recent one.
+static DEDUPLICATED_BATCHES: AtomicU64 = AtomicU64::new(0);
+/// Inserted by `quarantine` between the file stem and its timestamp.
+const QUARANTINE_MARKER: &str = ".corrupt-";
+const QUARANTINE_STAMP: &str = "%Y%m%dT%H%M%SZ";
+
+/// Locations of the agent's files. The config and network counters are
+/// shared; everything tied to a backend lives in the profile's directory.
+#[derive(Debug, Clone)]
 pub struct StoragePaths {
     root: PathBuf,
+    dir: PathBuf,
+    profile: String,
 }
 
 impl StoragePaths {
+    /// Paths under `NUSCAPE_DATA_DIR` when set, else the per-user data
+    /// directory.
     pub fn new() -> Result<Self> {
+        Self::with_data_dir(std::env::var_os(DATA_DIR_ENV))
+    }
+
+    /// Paths under `data_dir`, the value of `NUSCAPE_DATA_DIR`, unless it is
+    /// unset or empty.
+    fn with_data_dir(data_dir: Option<OsString>) -> Result<Self> {
+        if let Some(root) = data_dir.filter(|root| !root.is_empty()) {
+            return Self::with_root(PathBuf::from(root))
+                .with_context(|| format!("{DATA_DIR_ENV} is not usable"));
+        }
         let dirs = ProjectDirs::from(APP_QUALIFIER.0, APP_QUALIFIER.1, APP_QUALIFIER.2)
             .context("unable to resolve storage directory")?;
-        let root = dirs.data_dir().to_path_buf();
-        fs::create_dir_all(&root)?;
-        Ok(Self { root })
+        Self::with_root(dirs.data_dir().to_path_buf())
+    }
+
+    /// Paths under `root`, which is created if needed and must be writable.
+    pub fn with_root(root: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&root).with_context(|| format!("create {}", root.display()))?;
+        let probe = root.join(WRITE_PROBE_FILE);
+        fs::write(&probe, b"").with_context(|| format!("{} is not writable", root.display()))?;
+        let _ = fs::remove_file(&probe);
+        Ok(Self {
+            dir: root.clone(),
+            root,
+            profile: DEFAULT_PROFILE.to_string(),
+        })
+    }
+
+    /// The data directory shared by all profiles.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Sizes of every file under the data directory, largest first, with
+    /// paths relative to it.
+    pub fn file_sizes(&self) -> Result<Vec<StoredFileSize>> {
+        let mut files = Vec::new();
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir).with_context(|| format!("list {}", dir.display()))? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if meta.is_dir() {
+                    pending.push(entry.path());
+                } else {
+                    let path = entry.path();
+                    let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                    files.push(StoredFileSize {
+                        path: relative.display().to_string(),
+                        bytes: meta.len(),
+                    });
+                }
+            }
+        }
+        files.sort_by_key(|file| std::cmp::Reverse(file.bytes));
+        Ok(files)
+    }
+
+    /// Bytes taken by everything under the data directory.
+    pub fn total_size(&self) -> Result<u64> {
+        Ok(self.file_sizes()?.iter().map(|file| file.bytes).sum())
+    }
+
+    /// Directories holding this profile's files and the shared ones.
+    fn store_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![self.root.clone()];
+        if self.dir != self.root {
+            dirs.push(self.dir.clone());
+        }
+        dirs
+    }
+
+    /// Takes the data directory for this process until the returned lock is
+    /// dropped. Fails with `StorageLocked` while another process holds it.
+    pub fn lock(&self) -> Result<StorageLock> {
+        let path = self.root.join(LOCK_FILE);
+        let handle = unsafe {
+            CreateFileW(
+                &HSTRING::from(path.to_string_lossy().as_ref()),
+                (GENERIC_READ | GENERIC_WRITE).0,
+                FILE_SHARE_NONE,
+                None,
+                OPEN_ALWAYS,
+                FILE_ATTRIBUTE_NORMAL,
+                None,
+            )
+        };
+        match handle {
+            Ok(handle) => Ok(StorageLock { handle }),
+            Err(err) if err.code() == ERROR_SHARING_VIOLATION.to_hresult() => {
+                Err(StorageLocked { path }.into())
+            }
+            Err(err) => Err(anyhow!(err).context(format!("lock {}", path.display()))),
+        }
+    }
+
+    /// Paths for `profile`, creating its directory if needed.
+    pub fn for_profile(&self, profile: &str) -> Result<Self> {
+        let dir = if profile == DEFAULT_PROFILE {
+            self.root.clone()
+        } else {
+            self.root.join(PROFILES_DIR).join(profile)
+        };
+        fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+        Ok(Self {
+            root: self.root.clone(),
+            dir,
+            profile: profile.to_string(),
+        })
+    }
+
+    pub fn profile(&self) -> &str {
+        &self.profile
     }
 
     fn join(&self, name: &str) -> PathBuf {
-        self.root.join(name)
+        self.dir.join(name)
     }
 
+    /// The JSON queue of older agents, imported into the database.
     pub fn queue_path(&self) -> PathBuf {
         self.join(QUEUE_FILE)
     }
 
+    pub fn queue_db_path(&self) -> PathBuf {
+        self.join(QUEUE_DB_FILE)
+    }
+
     pub fn counters_path(&self) -> PathBuf {
-        self.join(COUNTERS_FILE)
+        self.root.join(COUNTERS_FILE)
+    }
+
+    pub fn connection_counters_path(&self) -> PathBuf {
+        self.root.join(CONNECTION_COUNTERS_FILE)
     }
 
     pub fn device_path(&self) -> PathBuf {
@@ -49,110 +243,2246 @@ impl StoragePaths {
     }
 
     pub fn config_path(&self) -> PathBuf {
-        self.join(CONFIG_FILE)
+        self.root.join(CONFIG_FILE)
+    }
+
+    pub fn rejects_path(&self) -> PathBuf {
+        self.join(REJECTS_FILE)
+    }
+
+    pub fn backoff_path(&self) -> PathBuf {
+        self.join(BACKOFF_FILE)
+    }
+
+    /// Dead letters of older agents, imported into the database.
+    pub fn dead_letter_path(&self) -> PathBuf {
+        self.join(DEAD_LETTER_FILE)
+    }
+
+    pub fn dry_run_dir(&self) -> PathBuf {
+        self.join(DRY_RUN_DIR)
+    }
+
+    pub fn auth_audit_path(&self) -> PathBuf {
+        self.join(AUTH_AUDIT_FILE)
+    }
+
+    pub fn session_state_path(&self) -> PathBuf {
+        self.join(SESSION_STATE_FILE)
+    }
+
+    /// Deletes everything tied to this device's identity and collected usage.
+    /// The config file is kept; tokens are owned by the token store.
+    pub fn wipe_device_state(&self) -> Result<()> {
+        let names = [
+            QUEUE_FILE,
+            QUEUE_DB_FILE,
+            QUEUE_WAL_FILE,
+            QUEUE_SHM_FILE,
+            DEVICE_FILE,
+            REJECTS_FILE,
+            REJECTS_ROTATED_FILE,
+            BACKOFF_FILE,
+            DEAD_LETTER_FILE,
+            AUTH_AUDIT_FILE,
+            AUTH_AUDIT_ROTATED_FILE,
+            SESSION_STATE_FILE,
+        ];
+        let paths = names.map(|name| self.join(name));
+        let shared = [self.counters_path(), self.connection_counters_path()];
+        for path in paths.iter().chain(&shared) {
+            if path.exists() {
+                fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+            }
+        }
+        let dry_run = self.dry_run_dir();
+        if dry_run.exists() {
+            fs::remove_dir_all(&dry_run)
+                .with_context(|| format!("remove {}", dry_run.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a non-default profile's directory and everything in it.
+    pub fn remove_profile_dir(&self) -> Result<()> {
+        if self.dir == self.root {
+            return Err(anyhow!("the {DEFAULT_PROFILE} profile cannot be removed"));
+        }
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)
+                .with_context(|| format!("remove {}", self.dir.display()))?;
+        }
+        Ok(())
     }
 }
 
+/// Upload cursor for the batch at the head of a JSON queue.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct HeadProgress {
+    batch_id: Uuid,
+    chunks_uploaded: usize,
+}
+
+/// Consecutive server rejections of the batch at the head of a JSON queue.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct HeadFailures {
+    batch_id: Uuid,
+    count: u32,
+}
+
+/// `usage_queue.json`, which held the queue before the database did. It is
+/// imported once and then removed.
+#[derive(Debug, Default, Deserialize)]
+struct QueueFile {
+    batches: VecDeque<UsageBatch>,
+    #[serde(default)]
+    head_progress: Option<HeadProgress>,
+    /// Head batches a coalesced upload took that has not completed yet; the
+    /// retry takes the same ones, so the merged batch id is unchanged.
+    #[serde(default)]
+    in_flight: usize,
+    /// Head batches of a rejected coalesced upload, resent one at a time so
+    /// a failure is counted against the batch that causes it.
+    #[serde(default)]
+    split: usize,
+    #[serde(default)]
+    head_failures: Option<HeadFailures>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredQueue {
+    Current(QueueFile),
+    Legacy(VecDeque<UsageBatch>),
+}
+
+/// `batches.status` values.
+const STATUS_QUEUED: &str = "queued";
+const STATUS_DEAD_LETTER: &str = "dead_letter";
+/// How long `UsageBatchStore::storage_bytes` reuses a directory walk.
+const FOOTPRINT_TTL: Duration = Duration::from_secs(10 * 60);
+/// How long an operation waits for another connection's write lock.
+const QUEUE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Steps that upgrade the queue database; `PRAGMA user_version` counts the
+/// ones applied. Each must also be safe on a database that already has it,
+/// since the first agents to use SQLite did not set a version.
+const QUEUE_MIGRATIONS: [&str; 3] = [QUEUE_SCHEMA_V1, QUEUE_SCHEMA_V2, QUEUE_SCHEMA_V3];
+/// `upload_state` held the queue's in-flight and split counts until
+/// version 2.
+const QUEUE_SCHEMA_V1: &str = "
+    CREATE TABLE IF NOT EXISTS batches (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        batch_id TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        failure_count INTEGER NOT NULL DEFAULT 0,
+        chunks_uploaded INTEGER NOT NULL DEFAULT 0,
+        status TEXT NOT NULL DEFAULT 'queued'
+    );
+    CREATE INDEX IF NOT EXISTS batches_status ON batches (status, id);
+    CREATE TABLE IF NOT EXISTS upload_state (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        in_flight INTEGER NOT NULL DEFAULT 0,
+        split INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT OR IGNORE INTO upload_state (id) VALUES (1);
+";
+/// Marks the queued rows an upload has taken and ties rows uploaded together
+/// to the id of the oldest; see `begin_upload` and `split_upload`. They
+/// replace the queue-wide counts in `upload_state`, which carry over.
+const QUEUE_SCHEMA_V2: &str = "
+    ALTER TABLE batches ADD COLUMN in_flight INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE batches ADD COLUMN upload_group INTEGER;
+    UPDATE batches
+        SET in_flight = 1,
+            upload_group = (SELECT MIN(id) FROM batches WHERE status = 'queued')
+        WHERE id IN (
+            SELECT id FROM batches WHERE status = 'queued'
+                ORDER BY id LIMIT (SELECT in_flight FROM upload_state)
+        );
+    UPDATE batches SET in_flight = 1, upload_group = id
+        WHERE id IN (
+            SELECT id FROM batches WHERE status = 'queued'
+                ORDER BY id LIMIT (SELECT split FROM upload_state)
+        );
+    DROP TABLE upload_state;
+";
+/// Content hashes of the most recently enqueued batches; see `enqueue`.
+const QUEUE_SCHEMA_V3: &str = "
+    CREATE TABLE IF NOT EXISTS recent_hashes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        hash TEXT NOT NULL
+    );
+";
+/// How many content hashes `enqueue` remembers.
+const DEDUPE_WINDOW: i64 = 64;
+/// First bytes of a gzip stream; plain JSON payloads start with `{`.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Batches waiting for upload, oldest first, and dead-lettered batches, in
+/// one SQLite table. Each operation opens its own connection so the file is
+/// never held open between them and can be wiped once the agent stops.
+///
+/// The database runs in WAL mode: an enqueue or pop appends its pages to the
+/// write-ahead log, which SQLite replays after a crash (dropping a torn
+/// trailing transaction) and checkpoints into the main file as it grows or
+/// when the last connection closes.
 pub struct UsageBatchStore {
-    queue: Mutex<VecDeque<UsageBatch>>,
+    events: Arc<dyn AgentEvents>,
     path: PathBuf,
+    rejects_path: PathBuf,
+    rejects_rotated_path: PathBuf,
+    /// Serializes appends to the rejected sessions file with its rotation
+    /// and retention rewrites.
+    rejects_write: Mutex<()>,
+    /// Searched for quarantined files and walked for the footprint.
+    paths: StoragePaths,
+    /// Total size of the data directory and when it was measured.
+    footprint: Mutex<Option<(Instant, u64)>>,
+}
+
+/// Queued batches taken by `UsageBatchStore::begin_upload`. They stay in the
+/// queue, marked in flight, until the upload is completed or dead-lettered.
+/// A failed upload is not released: regrouping it would change the merged
+/// batch id the server deduplicates on.
+pub struct UploadGuard {
+    ids: Vec<i64>,
+    batches: Vec<UsageBatch>,
+    chunks_uploaded: usize,
+}
+
+impl UploadGuard {
+    /// The batches being uploaded, oldest first.
+    pub fn batches(&self) -> &[UsageBatch] {
+        &self.batches
+    }
+
+    /// Chunks already accepted by the server, from an earlier attempt.
+    pub fn chunks_uploaded(&self) -> usize {
+        self.chunks_uploaded
+    }
+
+    /// Row holding the upload cursor and failure count.
+    fn first(&self) -> i64 {
+        self.ids[0]
+    }
+}
+
+/// What one retention sweep removed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionSweep {
+    pub batches: usize,
+    pub sessions: usize,
+    pub files: usize,
+    /// Records dropped from the rejected sessions file.
+    pub rejected: usize,
+}
+
+#[derive(Serialize)]
+struct RejectedSessionRecord<'a> {
+    rejected_at: DateTime<Utc>,
+    batch_id: Uuid,
+    session: &'a UsageSession,
+}
+
+/// The part of a `RejectedSessionRecord` retention looks at.
+#[derive(Deserialize)]
+struct RejectedAt {
+    rejected_at: DateTime<Utc>,
 }
 
 impl UsageBatchStore {
-    pub fn new(paths: &StoragePaths) -> Result<Self> {
-        let path = paths.queue_path();
-        let queue = if path.exists() {
-            let data = fs::read_to_string(&path)?;
-            let parsed: Vec<UsageBatch> = serde_json::from_str(&data)?;
-            VecDeque::from(parsed)
-        } else {
-            VecDeque::new()
+    /// Opens the queue database, quarantining one that cannot be read, and
+    /// imports the JSON queue and dead letters of older agents.
+    pub fn new(paths: &StoragePaths, events: Arc<dyn AgentEvents>) -> Result<Self> {
+        let store = Self {
+            events,
+            path: paths.queue_db_path(),
+            rejects_path: paths.rejects_path(),
+            rejects_rotated_path: paths.join(REJECTS_ROTATED_FILE),
+            rejects_write: Mutex::new(()),
+            paths: paths.clone(),
+            footprint: Mutex::new(None),
         };
-        Ok(Self {
-            queue: Mutex::new(queue),
-            path,
-        })
+        if let Err(err) = store.create_schema() {
+            if !store.path.exists() {
+                return Err(err);
+            }
+            let moved = quarantine(&store.path)?;
+            // The log would be replayed into the fresh database, so it goes
+            // with the old one; the index is rebuilt from scratch.
+            let wal = paths.join(QUEUE_WAL_FILE);
+            let shm = paths.join(QUEUE_SHM_FILE);
+            if wal.exists() {
+                quarantine(&wal)?;
+            }
+            if shm.exists() {
+                fs::remove_file(&shm).with_context(|| format!("remove {}", shm.display()))?;
+            }
+            log::error!(
+                "{} is corrupt ({err:#}); moved it to {} and starting empty",
+                store.path.display(),
+                moved.display()
+            );
+            store.create_schema()?;
+        }
+        store.import_json(&paths.queue_path(), &paths.dead_letter_path())?;
+        Ok(store)
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)
+            .with_context(|| format!("open {}", self.path.display()))?;
+        conn.busy_timeout(QUEUE_BUSY_TIMEOUT)?;
+        Ok(conn)
+    }
+
+    /// Brings the database to the current schema. A database from a newer
+    /// agent is refused, which quarantines it.
+    fn create_schema(&self) -> Result<()> {
+        let mut conn = self.open()?;
+        // Full auto-vacuum hands deleted pages back to the file system, so
+        // evicting batches on a full disk actually frees space. It only
+        // takes effect before the first table is created.
+        conn.execute_batch("PRAGMA auto_vacuum = FULL")?;
+        // The write-ahead log is the queue's journal, in place of an
+        // Add/Remove journal of our own: each enqueue and pop appends to it,
+        // a torn tail is dropped on the next open and checkpoints stand in
+        // for compaction. Persistent, so setting it once per start is enough.
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        let found: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let supported = QUEUE_MIGRATIONS.len() as u32;
+        let Some(pending) = QUEUE_MIGRATIONS.get(found as usize..) else {
+            return Err(NewerSchema {
+                name: "usage queue",
+                found: found.into(),
+                supported,
+            }
+            .into());
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let tx = conn.transaction()?;
+        for migration in pending {
+            tx.execute_batch(migration)?;
+        }
+        tx.pragma_update(None, "user_version", supported)?;
+        tx.commit()?;
+        Ok(())
     }
 
-    fn persist_locked(queue: &VecDeque<UsageBatch>, path: &Path) -> Result<()> {
-        let serialized = serde_json::to_string_pretty(queue)?;
-        fs::write(path, serialized)?;
+    /// Moves `usage_queue.json` and `dead_letter.json` into the database,
+    /// keeping the head's upload cursor and failure count and the batches in
+    /// flight, then deletes them.
+    fn import_json(&self, queue_path: &Path, dead_letter_path: &Path) -> Result<()> {
+        let queue = match read_json_or_quarantine(queue_path)? {
+            Some(StoredQueue::Current(file)) => Some(file),
+            Some(StoredQueue::Legacy(batches)) => Some(QueueFile {
+                batches,
+                ..QueueFile::default()
+            }),
+            None => None,
+        };
+        let dead_letters: Option<Vec<UsageBatch>> = read_json_or_quarantine(dead_letter_path)?;
+        if queue.is_none() && dead_letters.is_none() {
+            return Ok(());
+        }
+        let queue = queue.unwrap_or_default();
+        let dead_letters = dead_letters.unwrap_or_default();
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        for (index, batch) in queue.batches.iter().enumerate() {
+            let head = index == 0;
+            let chunks_uploaded = queue
+                .head_progress
+                .filter(|progress| head && progress.batch_id == batch.batch_id)
+                .map_or(0, |progress| progress.chunks_uploaded);
+            let failure_count = queue
+                .head_failures
+                .filter(|failures| head && failures.batch_id == batch.batch_id)
+                .map_or(0, |failures| failures.count);
+            insert_batch(
+                &tx,
+                batch,
+                STATUS_QUEUED,
+                chunks_uploaded,
+                failure_count,
+                false,
+            )?;
+        }
+        for batch in &dead_letters {
+            insert_batch(&tx, batch, STATUS_DEAD_LETTER, 0, 0, false)?;
+        }
+        // The JSON queue's in-flight upload resumes as one group; a split
+        // one resends its batches one at a time.
+        tx.execute(
+            "UPDATE batches
+                SET in_flight = 1,
+                    upload_group = (SELECT MIN(id) FROM batches WHERE status = ?1)
+                WHERE id IN (SELECT id FROM batches WHERE status = ?1 ORDER BY id LIMIT ?2)",
+            params![STATUS_QUEUED, queue.in_flight as i64],
+        )?;
+        tx.execute(
+            "UPDATE batches SET in_flight = 1, upload_group = id
+                WHERE id IN (SELECT id FROM batches WHERE status = ?1 ORDER BY id LIMIT ?2)",
+            params![STATUS_QUEUED, queue.split as i64],
+        )?;
+        tx.commit()?;
+        for path in [queue_path, dead_letter_path] {
+            if path.exists() {
+                fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+            }
+        }
+        log::info!(
+            "imported {} queued and {} dead-lettered batch(es) into {}",
+            queue.batches.len(),
+            dead_letters.len(),
+            self.path.display()
+        );
         Ok(())
     }
 
-    pub fn enqueue(&self, batch: UsageBatch) -> Result<()> {
-        if !batch.size_fits() {
-            log::warn!(
-                "skipping oversized batch ({} sessions)",
-                batch.sessions.len()
+    /// Appends `batch`. While the disk has less than `min_free_bytes` free,
+    /// the oldest batches are evicted to make room, so a full disk costs old
+    /// usage rather than the ability to persist new usage. A batch over
+    /// `MAX_PAYLOAD_BYTES` is split and its pieces queued separately. A batch
+    /// with the same content as one of the last `DEDUPE_WINDOW` enqueued is
+    /// skipped, so the backend never counts the same usage twice. With
+    /// `compress` the payload is stored gzip-compressed.
+    pub fn enqueue(&self, batch: UsageBatch, min_free_bytes: u64, compress: bool) -> Result<()> {
+        let hash = batch.content_hash()?;
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        if let Some(hash) = &hash {
+            let seen = tx
+                .query_row(
+                    "SELECT 1 FROM recent_hashes WHERE hash = ?1",
+                    [hash],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if seen {
+                DEDUPLICATED_BATCHES.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "skipping batch {}: same usage as a recent batch",
+                    batch.batch_id
+                );
+                return Ok(());
+            }
+            tx.execute("INSERT INTO recent_hashes (hash) VALUES (?1)", [hash])?;
+            tx.execute(
+                "DELETE FROM recent_hashes
+                    WHERE id <= (SELECT MAX(id) FROM recent_hashes) - ?1",
+                [DEDUPE_WINDOW],
+            )?;
+        }
+        let pieces = if batch.size_fits() {
+            vec![batch]
+        } else {
+            let pieces = batch.split_to_fit(MAX_PAYLOAD_BYTES)?;
+            log::info!(
+                "split oversized batch ({} sessions) into {} piece(s)",
+                batch.sessions.len(),
+                pieces.len()
             );
+            pieces
+        };
+        for piece in &pieces {
+            if !piece.size_fits() {
+                log::warn!(
+                    "dropping a session too large to upload in batch {}",
+                    piece.batch_id
+                );
+                DROPPED_BATCHES.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            insert_batch(&tx, piece, STATUS_QUEUED, 0, 0, compress)?;
+        }
+        if let Some(free) = free_space(&self.path).filter(|&free| free < min_free_bytes) {
+            Self::evict_oldest(&tx, min_free_bytes - free)?;
+        }
+        let size = queued_count(&tx)?;
+        tx.commit()?;
+        self.events.queue_changed(size);
+        Ok(())
+    }
+
+    /// Drops the oldest batches until about `bytes` are freed, keeping the
+    /// newest one.
+    fn evict_oldest(tx: &Transaction, bytes: u64) -> Result<()> {
+        let mut statement = tx.prepare(
+            "SELECT id, length(payload) FROM batches
+                    WHERE status = ?1 AND in_flight = 0 ORDER BY id",
+        )?;
+        let rows = statement
+            .query_map([STATUS_QUEUED], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut freed = 0u64;
+        let mut evicted = 0usize;
+        for (id, len) in rows.iter().take(rows.len().saturating_sub(1)) {
+            if freed >= bytes {
+                break;
+            }
+            tx.execute("DELETE FROM batches WHERE id = ?1", [id])?;
+            freed += *len as u64;
+            evicted += 1;
+        }
+        if evicted > 0 {
+            DROPPED_BATCHES.fetch_add(evicted as u64, Ordering::Relaxed);
+            log::warn!("low disk space: evicted {evicted} queued batch(es), about {freed} bytes");
+        }
+        Ok(())
+    }
+
+    /// Marks batches at the head of the queue in flight and returns them.
+    /// `choose` sees up to `limit` queued batches and picks how many of the
+    /// oldest to take (at least one). Batches left in flight by an earlier
+    /// attempt, failed or cut short by a crash, are resumed instead, one
+    /// upload group at a time, so the same set is retried under the same
+    /// batch id and with its upload cursor.
+    pub fn begin_upload(
+        &self,
+        limit: usize,
+        choose: impl FnOnce(&[UsageBatch]) -> usize,
+    ) -> Result<Option<UploadGuard>> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        let in_flight = queued_rows(&tx, true, limit)?;
+        let rows = if in_flight.is_empty() {
+            let mut rows = queued_rows(&tx, false, limit)?;
+            // A cursor left on the head belongs to that batch alone.
+            let take = if rows.first().is_some_and(|row| row.chunks_uploaded > 0) {
+                1
+            } else {
+                let batches: Vec<UsageBatch> = rows.iter().map(|row| row.batch.clone()).collect();
+                choose(&batches).max(1)
+            };
+            rows.truncate(take);
+            if let Some(group) = rows.first().map(|row| row.id) {
+                for row in &rows {
+                    tx.execute(
+                        "UPDATE batches SET in_flight = 1, upload_group = ?2 WHERE id = ?1",
+                        params![row.id, group],
+                    )?;
+                }
+            }
+            rows
+        } else {
+            in_flight
+        };
+        tx.commit()?;
+        let Some(first) = rows.first() else {
+            return Ok(None);
+        };
+        let chunks_uploaded = first.chunks_uploaded;
+        let (ids, batches) = rows.into_iter().map(|row| (row.id, row.batch)).unzip();
+        Ok(Some(UploadGuard {
+            ids,
+            batches,
+            chunks_uploaded,
+        }))
+    }
+
+    /// Removes exactly the batches `guard` covers after the server accepted
+    /// them.
+    pub fn complete(&self, guard: &UploadGuard) -> Result<()> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        for id in &guard.ids {
+            tx.execute("DELETE FROM batches WHERE id = ?1", [id])?;
+        }
+        let size = queued_count(&tx)?;
+        tx.commit()?;
+        self.events.queue_changed(size);
+        Ok(())
+    }
+
+    /// Appends sessions the server refused to a local JSONL file for support.
+    pub fn record_rejected(&self, batch_id: Uuid, sessions: &[UsageSession]) -> Result<()> {
+        let _write = self.rejects_write.lock();
+        let size = fs::metadata(&self.rejects_path)
+            .map(|meta| meta.len())
+            .unwrap_or(0);
+        if size >= REJECTS_MAX_BYTES {
+            fs::rename(&self.rejects_path, &self.rejects_rotated_path)
+                .context("rotate rejected sessions")?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.rejects_path)?;
+        let rejected_at = Utc::now();
+        for session in sessions {
+            let record = RejectedSessionRecord {
+                rejected_at,
+                batch_id,
+                session,
+            };
+            writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        }
+        Ok(())
+    }
+
+    /// Splits a coalesced upload the server rejected into single-batch
+    /// uploads that stay in flight, so `begin_upload` resends its batches one
+    /// at a time and a failure is counted against the batch that causes it.
+    pub fn split_upload(&self, guard: &UploadGuard) -> Result<()> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        for id in &guard.ids {
+            tx.execute(
+                "UPDATE batches SET upload_group = id, chunks_uploaded = 0 WHERE id = ?1",
+                [id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Persists how many chunks of the upload the server has accepted.
+    pub fn set_upload_progress(&self, guard: &UploadGuard, chunks_uploaded: usize) -> Result<()> {
+        self.open()?.execute(
+            "UPDATE batches SET chunks_uploaded = ?2 WHERE id = ?1 AND in_flight = 1",
+            params![guard.first(), chunks_uploaded as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Counts another server rejection of the upload and returns the running
+    /// total for it.
+    pub fn record_upload_failure(&self, guard: &UploadGuard) -> Result<u32> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE batches SET failure_count = failure_count + 1 WHERE id = ?1",
+            [guard.first()],
+        )?;
+        let count = tx
+            .query_row(
+                "SELECT failure_count FROM batches WHERE id = ?1",
+                [guard.first()],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0);
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Dead-letters the oldest batch of the upload so the rest of the queue
+    /// can drain, and returns the others to the queue.
+    pub fn dead_letter(&self, guard: &UploadGuard) -> Result<Option<UsageBatch>> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        let moved = tx.execute(
+            "UPDATE batches SET status = ?2, in_flight = 0, chunks_uploaded = 0, failure_count = 0
+                WHERE id = ?1",
+            params![guard.first(), STATUS_DEAD_LETTER],
+        )?;
+        for id in &guard.ids[1..] {
+            tx.execute("UPDATE batches SET in_flight = 0 WHERE id = ?1", [id])?;
+        }
+        let size = queued_count(&tx)?;
+        tx.commit()?;
+        if moved == 0 {
+            return Ok(None);
+        }
+        self.events.queue_changed(size);
+        Ok(guard.batches.first().cloned())
+    }
+
+    /// Deletes queued and dead-lettered batches sent before `cutoff`,
+    /// rejected sessions recorded before it and quarantined files set aside
+    /// before it. A row whose payload no longer parses is judged by when it
+    /// was queued.
+    pub fn purge_older_than(&self, cutoff: DateTime<Utc>) -> Result<RetentionSweep> {
+        let mut sweep = RetentionSweep::default();
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        let rows = {
+            let mut statement =
+                tx.prepare("SELECT id, created_at, payload FROM batches WHERE in_flight = 0")?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Payload>(2)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows
+        };
+        for (id, created_at, payload) in rows {
+            let (sent_at, sessions) = match payload.decode() {
+                Ok(batch) => (Some(batch.sent_at), batch.sessions.len()),
+                Err(_) => (
+                    DateTime::parse_from_rfc3339(&created_at)
+                        .ok()
+                        .map(|at| at.with_timezone(&Utc)),
+                    0,
+                ),
+            };
+            if sent_at.is_some_and(|sent_at| sent_at < cutoff) {
+                tx.execute("DELETE FROM batches WHERE id = ?1", [id])?;
+                sweep.batches += 1;
+                sweep.sessions += sessions;
+            }
+        }
+        let size = queued_count(&tx)?;
+        tx.commit()?;
+        if sweep.batches > 0 {
+            PURGED_SESSIONS.fetch_add(sweep.sessions as u64, Ordering::Relaxed);
+            self.events.queue_changed(size);
+        }
+        sweep.rejected = self.purge_rejected(cutoff)?;
+        for dir in &self.paths.store_dirs() {
+            sweep.files += purge_quarantined(dir, cutoff)?;
+        }
+        Ok(sweep)
+    }
+
+    /// Rewrites the rejected sessions files without the records from before
+    /// `cutoff`, or unreadable ones. Returns how many were dropped.
+    fn purge_rejected(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let _write = self.rejects_write.lock();
+        let mut dropped = 0;
+        for path in [&self.rejects_rotated_path, &self.rejects_path] {
+            let data = match fs::read_to_string(path) {
+                Ok(data) => data,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+            };
+            let mut kept = String::new();
+            for line in data.lines() {
+                match serde_json::from_str::<RejectedAt>(line) {
+                    Ok(record) if record.rejected_at >= cutoff => {
+                        kept.push_str(line);
+                        kept.push('\n');
+                    }
+                    _ => dropped += 1,
+                }
+            }
+            if kept.len() == data.len() {
+                continue;
+            }
+            if kept.is_empty() {
+                fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+            } else {
+                atomic_write(path, kept)?;
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Rewrites stored payloads gzip-compressed, or back to plain JSON when
+    /// `compress` is off, then logs the queue's size before and after.
+    /// Batches in flight and payloads that no longer decode are left as
+    /// they are.
+    pub fn compact(&self, compress: bool) -> Result<()> {
+        let before = self.disk_usage();
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        let rows = {
+            let mut statement =
+                tx.prepare("SELECT id, payload FROM batches WHERE in_flight = 0")?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, Payload>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows
+        };
+        let mut rewritten = 0usize;
+        for (id, payload) in rows {
+            if payload.is_compressed() == compress {
+                continue;
+            }
+            let Ok(json) = payload.json() else {
+                continue;
+            };
+            tx.execute(
+                "UPDATE batches SET payload = ?1 WHERE id = ?2",
+                params![stored_payload(json, compress)?, id],
+            )?;
+            rewritten += 1;
+        }
+        tx.commit()?;
+        if rewritten == 0 {
             return Ok(());
         }
-        let mut guard = self.queue.lock();
-        guard.push_back(batch);
-        Self::persist_locked(&guard, &self.path)
+        // Fold the rewritten pages into the main file so the sizes below
+        // reflect the new payloads rather than a grown WAL.
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        drop(conn);
+        let after = self.disk_usage();
+        log::info!(
+            "compacted the usage queue: {} {rewritten} batch(es), {before} -> {after} bytes",
+            if compress {
+                "compressed"
+            } else {
+                "decompressed"
+            }
+        );
+        Ok(())
+    }
+
+    /// Bytes taken by the queue database and its WAL and shared-memory files.
+    pub fn disk_usage(&self) -> u64 {
+        [QUEUE_DB_FILE, QUEUE_WAL_FILE, QUEUE_SHM_FILE]
+            .iter()
+            .filter_map(|name| fs::metadata(self.paths.join(name)).ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+
+    /// Walks the data directory now, for support.
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        let files = self.paths.file_sizes()?;
+        let total_bytes = files.iter().map(|file| file.bytes).sum();
+        *self.footprint.lock() = Some((Instant::now(), total_bytes));
+        Ok(StorageStats {
+            total_bytes,
+            queue_bytes: self.disk_usage(),
+            files,
+        })
+    }
+
+    /// Size of the data directory, walked at most once per `FOOTPRINT_TTL`.
+    pub fn storage_bytes(&self) -> Option<u64> {
+        let cached = *self.footprint.lock();
+        if let Some((measured, bytes)) = cached {
+            if measured.elapsed() < FOOTPRINT_TTL {
+                return Some(bytes);
+            }
+        }
+        match self.paths.total_size() {
+            Ok(bytes) => {
+                *self.footprint.lock() = Some((Instant::now(), bytes));
+                Some(bytes)
+            }
+            Err(err) => {
+                log::debug!("failed to measure the data directory: {err:?}");
+                None
+            }
+        }
     }
 
-    pub fn peek(&self) -> Option<UsageBatch> {
-        self.queue.lock().front().cloned()
+    pub fn dead_letter_count(&self) -> usize {
+        self.count(STATUS_DEAD_LETTER)
     }
 
-    pub fn pop(&self) -> Result<Option<UsageBatch>> {
-        let mut guard = self.queue.lock();
-        let popped = guard.pop_front();
-        Self::persist_locked(&guard, &self.path)?;
-        Ok(popped)
+    /// Puts every dead-lettered batch back at the end of the queue.
+    pub fn retry_dead_letters(&self) -> Result<usize> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        // Reinserted rather than updated, so they get ids after every
+        // queued batch.
+        let count = tx.execute(
+            "INSERT INTO batches (batch_id, created_at, payload)
+                SELECT batch_id, created_at, payload FROM batches WHERE status = ?1
+                ORDER BY id",
+            [STATUS_DEAD_LETTER],
+        )?;
+        if count == 0 {
+            return Ok(0);
+        }
+        tx.execute(
+            "DELETE FROM batches WHERE status = ?1",
+            [STATUS_DEAD_LETTER],
+        )?;
+        let size = queued_count(&tx)?;
+        tx.commit()?;
+        self.events.queue_changed(size);
+        Ok(count)
     }
 
     pub fn has_pending(&self) -> bool {
-        !self.queue.lock().is_empty()
+        self.queue_size() > 0
     }
 
     pub fn queue_size(&self) -> usize {
-        self.queue.lock().len()
+        self.count(STATUS_QUEUED)
+    }
+
+    fn count(&self, status: &str) -> usize {
+        let count = self.open().and_then(|conn| {
+            Ok(conn.query_row(
+                "SELECT COUNT(*) FROM batches WHERE status = ?1",
+                [status],
+                |row| row.get::<_, i64>(0),
+            )?)
+        });
+        count.map_or_else(
+            |err| {
+                log::warn!("failed to count {status} batches: {err:?}");
+                0
+            },
+            |count| count as usize,
+        )
     }
 
     pub fn clear_queue(&self) -> Result<()> {
-        let mut guard = self.queue.lock();
-        guard.clear();
-        Self::persist_locked(&guard, &self.path)
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        let removed = tx.execute(
+            "DELETE FROM batches WHERE status = ?1 AND in_flight = 0",
+            [STATUS_QUEUED],
+        )?;
+        let size = queued_count(&tx)?;
+        tx.commit()?;
+        if removed > 0 {
+            self.events.queue_changed(size);
+        }
+        Ok(())
     }
 
     pub fn queue_preview(&self, limit: usize) -> Vec<UsageBatch> {
-        let guard = self.queue.lock();
-        guard.iter().take(limit).cloned().collect()
+        let preview = self.open().and_then(|conn| {
+            let mut statement =
+                conn.prepare("SELECT payload FROM batches WHERE status = ?1 ORDER BY id LIMIT ?2")?;
+            let payloads = statement
+                .query_map(params![STATUS_QUEUED, limit as i64], |row| {
+                    row.get::<_, Payload>(0)
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(payloads)
+        });
+        match preview {
+            Ok(payloads) => payloads
+                .into_iter()
+                .filter_map(|payload| payload.decode().ok())
+                .collect(),
+            Err(err) => {
+                log::warn!("failed to read the usage queue: {err:?}");
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// A queued batch with its row id and upload cursor.
+struct QueueRow {
+    id: i64,
+    batch: UsageBatch,
+    chunks_uploaded: usize,
+}
+
+/// Up to `limit` of the oldest queued batches that are (or are not) in
+/// flight. Rows whose payload no longer parses are deleted on the way, since
+/// they could never be uploaded.
+fn queued_rows(conn: &Connection, in_flight: bool, limit: usize) -> Result<Vec<QueueRow>> {
+    let rows = {
+        // In-flight rows are limited to the oldest one's upload group. Rows
+        // taken before groups existed all share a NULL group.
+        let mut statement = conn.prepare(
+            "SELECT id, payload, chunks_uploaded FROM batches
+                WHERE status = ?1 AND in_flight = ?2 AND (?2 = 0 OR upload_group IS (
+                    SELECT upload_group FROM batches
+                        WHERE status = ?1 AND in_flight = 1 ORDER BY id LIMIT 1
+                ))
+                ORDER BY id LIMIT ?3",
+        )?;
+        let rows = statement
+            .query_map(params![STATUS_QUEUED, in_flight, limit as i64], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Payload>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+    let mut parsed = Vec::new();
+    for (id, payload, chunks_uploaded) in rows {
+        match payload.decode() {
+            Ok(batch) => parsed.push(QueueRow {
+                id,
+                batch,
+                chunks_uploaded: chunks_uploaded as usize,
+            }),
+            Err(err) => {
+                log::error!("dropping queued batch {id} that no longer parses: {err:#}");
+                conn.execute("DELETE FROM batches WHERE id = ?1", [id])?;
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// A `batches.payload` value: the batch as JSON text, or that JSON
+/// gzip-compressed in a blob. Queues written before compression hold only
+/// text, so both are read regardless of the current setting.
+struct Payload(Vec<u8>);
+
+impl Payload {
+    fn is_compressed(&self) -> bool {
+        self.0.starts_with(&GZIP_MAGIC)
+    }
+
+    /// The batch JSON, decompressed if needed.
+    fn json(self) -> Result<Vec<u8>> {
+        if !self.is_compressed() {
+            return Ok(self.0);
+        }
+        let mut json = Vec::new();
+        GzDecoder::new(self.0.as_slice())
+            .read_to_end(&mut json)
+            .context("decompress payload")?;
+        Ok(json)
+    }
+
+    fn decode(self) -> Result<UsageBatch> {
+        Ok(serde_json::from_slice(&self.json()?)?)
+    }
+}
+
+impl FromSql for Payload {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => Ok(Self(bytes.to_vec())),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Batch JSON as it is stored: a gzip blob with `compress`, text otherwise.
+fn stored_payload(json: Vec<u8>, compress: bool) -> Result<SqlValue> {
+    if !compress {
+        return Ok(SqlValue::Text(String::from_utf8(json)?));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json)?;
+    Ok(SqlValue::Blob(encoder.finish()?))
+}
+
+fn insert_batch(
+    conn: &Connection,
+    batch: &UsageBatch,
+    status: &str,
+    chunks_uploaded: usize,
+    failure_count: u32,
+    compress: bool,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO batches (batch_id, created_at, payload, failure_count, chunks_uploaded, status)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            batch.batch_id.to_string(),
+            Utc::now().to_rfc3339(),
+            stored_payload(batch.to_json_string()?.into_bytes(), compress)?,
+            failure_count,
+            chunks_uploaded as i64,
+            status,
+        ],
+    )?;
+    Ok(())
+}
+
+fn queued_count(conn: &Connection) -> Result<usize> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM batches WHERE status = ?1",
+        [STATUS_QUEUED],
+        |row| row.get(0),
+    )?;
+    Ok(count as usize)
+}
+
+/// Meaning of the stored counters, as opposed to the file's layout. Older
+/// files keyed counters by description or lacked the Ethernet and rx/tx
+/// totals; their counters cannot be diffed against current ones.
+const COUNTER_FILE_VERSION: u32 = 1;
+
+/// Layout of network_counters.json.
+const COUNTERS_SCHEMA: Schema = Schema {
+    name: "network counters",
+    migrations: &[counters_v1],
+};
+
+/// Wraps the bare counter map written before the boot time was recorded.
+fn counters_v1(data: Value) -> Result<Value> {
+    if data.get("counters").is_some() {
+        return Ok(data);
+    }
+    Ok(json!({ "version": 0, "boot_time": null, "counters": data }))
+}
+
+/// On-disk network counters with the boot they were read in.
+#[derive(Clone, Serialize, Deserialize)]
+struct CounterFile {
+    #[serde(default)]
+    version: u32,
+    boot_time: Option<DateTime<Utc>>,
+    counters: HashMap<String, NetworkCounters>,
+}
+
+impl Default for CounterFile {
+    fn default() -> Self {
+        Self {
+            version: COUNTER_FILE_VERSION,
+            boot_time: None,
+            counters: HashMap::new(),
+        }
     }
 }
 
 pub struct NetworkCounterStore {
     path: PathBuf,
-    cache: Mutex<HashMap<String, NetworkCounters>>,
+    cache: Mutex<CounterFile>,
+    connections_path: PathBuf,
+    /// Byte counts of the TCP connections seen at the previous collect.
+    connections: Mutex<HashMap<String, u64>>,
+    /// Serializes file writes, which happen after the caches are released
+    /// so readers never wait on the disk.
+    write: Mutex<()>,
 }
 
 impl NetworkCounterStore {
     pub fn new(paths: &StoragePaths) -> Result<Self> {
         let path = paths.counters_path();
-        let cache = if path.exists() {
-            let data = fs::read_to_string(&path)?;
-            serde_json::from_str(&data).unwrap_or_default()
-        } else {
-            HashMap::new()
-        };
+        let cache = read_versioned_or_quarantine(&path, &COUNTERS_SCHEMA)?.unwrap_or_default();
+        let connections_path = paths.connection_counters_path();
+        let connections = fs::read_to_string(&connections_path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
         Ok(Self {
             path,
             cache: Mutex::new(cache),
+            connections_path,
+            connections: Mutex::new(connections),
+            write: Mutex::new(()),
         })
     }
 
     pub fn load(&self) -> HashMap<String, NetworkCounters> {
-        self.cache.lock().clone()
+        self.cache.lock().counters.clone()
+    }
+
+    /// Boot time of the stored counters; `None` for files from older agents.
+    pub fn boot_time(&self) -> Option<DateTime<Utc>> {
+        self.cache.lock().boot_time
+    }
+
+    /// The stored counters were written in an older format and can only be
+    /// replaced by a fresh baseline.
+    pub fn outdated(&self) -> bool {
+        self.cache.lock().version < COUNTER_FILE_VERSION
+    }
+
+    pub fn save(
+        &self,
+        counters: HashMap<String, NetworkCounters>,
+        boot_time: DateTime<Utc>,
+    ) -> Result<()> {
+        let _write = self.write.lock();
+        let encoded = {
+            let mut guard = self.cache.lock();
+            *guard = CounterFile {
+                version: COUNTER_FILE_VERSION,
+                boot_time: Some(boot_time),
+                counters,
+            };
+            COUNTERS_SCHEMA.encode(&*guard)?
+        };
+        atomic_write(&self.path, encoded)
+    }
+
+    pub fn load_connections(&self) -> HashMap<String, u64> {
+        self.connections.lock().clone()
+    }
+
+    pub fn save_connections(&self, connections: HashMap<String, u64>) -> Result<()> {
+        let _write = self.write.lock();
+        let encoded = {
+            let mut guard = self.connections.lock();
+            *guard = connections;
+            serde_json::to_string(&*guard)?
+        };
+        atomic_write(&self.connections_path, encoded)
     }
+}
 
-    pub fn save(&self, counters: HashMap<String, NetworkCounters>) -> Result<()> {
-        let mut guard = self.cache.lock();
-        *guard = counters;
-        let serialized = serde_json::to_string_pretty(&*guard)?;
-        fs::write(&self.path, serialized)?;
+pub struct BackoffStore {
+    path: PathBuf,
+}
+
+impl BackoffStore {
+    pub fn new(paths: &StoragePaths) -> Self {
+        Self {
+            path: paths.backoff_path(),
+        }
+    }
+
+    pub fn load(&self) -> Option<PersistedBackoff> {
+        let data = fs::read_to_string(&self.path).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    pub fn save(&self, backoff: &PersistedBackoff) -> Result<()> {
+        atomic_write(&self.path, serde_json::to_string_pretty(backoff)?)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append-only JSONL timeline of registration, refresh and token events, kept
+/// for support. Writes never fail the caller; problems are only logged.
+pub struct AuthAuditLog {
+    path: PathBuf,
+    rotated_path: PathBuf,
+    last: Mutex<Option<AuthAuditEntry>>,
+    /// Serializes appends so `last` is never locked across a write.
+    write: Mutex<()>,
+}
+
+impl AuthAuditLog {
+    pub fn new(paths: &StoragePaths) -> Self {
+        let log = Self {
+            path: paths.auth_audit_path(),
+            rotated_path: paths.join(AUTH_AUDIT_ROTATED_FILE),
+            last: Mutex::new(None),
+            write: Mutex::new(()),
+        };
+        *log.last.lock() = log.read(1).ok().and_then(|mut entries| entries.pop());
+        log
+    }
+
+    pub fn record(&self, entry: AuthAuditEntry) {
+        let _write = self.write.lock();
+        *self.last.lock() = Some(entry.clone());
+        if let Err(err) = self.append(&entry) {
+            log::warn!("failed to write auth audit entry: {err:?}");
+        }
+    }
+
+    fn append(&self, entry: &AuthAuditEntry) -> Result<()> {
+        let size = fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0);
+        if size >= AUTH_AUDIT_MAX_BYTES {
+            fs::rename(&self.path, &self.rotated_path).context("rotate auth audit log")?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
         Ok(())
     }
+
+    /// The most recent `limit` entries, oldest first.
+    pub fn read(&self, limit: usize) -> Result<Vec<AuthAuditEntry>> {
+        let mut entries = Vec::new();
+        for path in [&self.rotated_path, &self.path] {
+            if !path.exists() {
+                continue;
+            }
+            let data = fs::read_to_string(path)?;
+            entries.extend(
+                data.lines()
+                    .filter_map(|line| serde_json::from_str::<AuthAuditEntry>(line).ok()),
+            );
+        }
+        let skip = entries.len().saturating_sub(limit);
+        Ok(entries.split_off(skip))
+    }
+
+    pub fn last(&self) -> Option<AuthAuditEntry> {
+        self.last.lock().clone()
+    }
+}
+
+/// Replaces `path` with `contents` so that a crash leaves either the old or
+/// the new file, never a truncated one: the data is written to `<name>.tmp`
+/// beside it, flushed to disk and renamed over the target. A temp file left
+/// by an interrupted write is overwritten by the next one.
+pub fn atomic_write(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    atomic_write_with(path, contents, |_| {})
+}
+
+/// Like `atomic_write`, running `before_rename` on the temp file once it is
+/// written, e.g. to restrict its permissions before it takes the target's
+/// place.
+pub fn atomic_write_with(
+    path: &Path,
+    contents: impl AsRef<[u8]>,
+    before_rename: impl FnOnce(&Path),
+) -> Result<()> {
+    let mut temp_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?
+        .to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file =
+        File::create(&temp_path).with_context(|| format!("create {}", temp_path.display()))?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    drop(file);
+    before_rename(&temp_path);
+    fs::rename(&temp_path, path).with_context(|| format!("replace {}", path.display()))
+}
+
+/// Parses the JSON file at `path`, or `None` when there is none. A file
+/// that does not parse is quarantined and treated as missing, so one bad
+/// write cannot keep the agent from starting.
+fn read_json_or_quarantine<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    read_or_quarantine(path, |data| Ok(serde_json::from_slice(data)?))
+}
+
+/// Like `read_json_or_quarantine` for a versioned document, upgrading older
+/// versions on the way. Only a version newer than `schema` knows, or a file
+/// that does not parse, is quarantined.
+pub fn read_versioned_or_quarantine<T: DeserializeOwned>(
+    path: &Path,
+    schema: &Schema,
+) -> Result<Option<T>> {
+    read_or_quarantine(path, |data| schema.decode(data))
+}
+
+fn read_or_quarantine<T>(
+    path: &Path,
+    decode: impl FnOnce(&[u8]) -> Result<T>,
+) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    match decode(&data) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) => {
+            let moved = quarantine(path)?;
+            log::error!(
+                "{} is unreadable ({err:#}); moved it to {} and starting empty",
+                path.display(),
+                moved.display()
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Upgrades a document's `data` from one version to the next.
+pub type Migration = fn(Value) -> Result<Value>;
+
+/// Format history of a persisted JSON document, stored as
+/// `{ "schema": N, "data": ... }`. Files from before versioning are version
+/// 0 and hold `data` bare. `migrations[n]` upgrades version `n` to `n + 1`,
+/// so the current version is the number of migrations.
+pub struct Schema {
+    pub name: &'static str,
+    pub migrations: &'static [Migration],
+}
+
+/// A document written by a newer agent, which this one cannot downgrade.
+#[derive(Debug)]
+pub struct NewerSchema {
+    pub name: &'static str,
+    pub found: u64,
+    pub supported: u32,
+}
+
+impl fmt::Display for NewerSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} schema {} is newer than the supported {}",
+            self.name, self.found, self.supported
+        )
+    }
+}
+
+impl std::error::Error for NewerSchema {}
+
+impl Schema {
+    pub fn version(&self) -> u32 {
+        self.migrations.len() as u32
+    }
+
+    pub fn envelope<T: Serialize>(&self, data: &T) -> Value {
+        json!({ "schema": self.version(), "data": data })
+    }
+
+    pub fn encode<T: Serialize>(&self, data: &T) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.envelope(data))?)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        let document: Value = serde_json::from_slice(bytes)?;
+        let (found, mut data) = match document {
+            Value::Object(mut envelope) if envelope.len() == 2 && envelope.contains_key("data") => {
+                let found = envelope
+                    .get("schema")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| anyhow!("{} schema is not a version number", self.name))?;
+                (found, envelope.remove("data").unwrap_or_default())
+            }
+            unversioned => (0, unversioned),
+        };
+        let Some(pending) = self.migrations.get(found as usize..) else {
+            return Err(NewerSchema {
+                name: self.name,
+                found,
+                supported: self.version(),
+            }
+            .into());
+        };
+        for (step, migrate) in pending.iter().enumerate() {
+            let from = found as usize + step;
+            data = migrate(data)
+                .with_context(|| format!("migrate {} from schema {from}", self.name))?;
+        }
+        Ok(serde_json::from_value(data)?)
+    }
+}
+
+/// Migration for documents whose first versioned format only added the
+/// envelope.
+pub fn unversioned(data: Value) -> Result<Value> {
+    Ok(data)
+}
+
+/// Renames an unreadable file to `<stem>.corrupt-<timestamp>.<ext>` beside
+/// it, keeping it for support while the agent starts over without it.
+pub fn quarantine(path: &Path) -> Result<PathBuf> {
+    let stem = path
+        .file_stem()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?
+        .to_string_lossy();
+    let mut name = format!(
+        "{stem}{QUARANTINE_MARKER}{}",
+        Utc::now().format(QUARANTINE_STAMP)
+    );
+    if let Some(extension) = path.extension() {
+        name = format!("{name}.{}", extension.to_string_lossy());
+    }
+    let target = path.with_file_name(name);
+    fs::rename(path, &target).with_context(|| format!("quarantine {}", path.display()))?;
+    QUARANTINED_FILES.fetch_add(1, Ordering::Relaxed);
+    Ok(target)
+}
+
+/// Removes files in `dir` quarantined before `cutoff`, going by the
+/// timestamp in their name. Returns how many were removed.
+fn purge_quarantined(dir: &Path, cutoff: DateTime<Utc>) -> Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let Some((_, rest)) = name.split_once(QUARANTINE_MARKER) else {
+            continue;
+        };
+        let stamp = rest.split('.').next().unwrap_or(rest);
+        let Ok(quarantined_at) = NaiveDateTime::parse_from_str(stamp, QUARANTINE_STAMP) else {
+            continue;
+        };
+        if quarantined_at.and_utc() < cutoff {
+            fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Exclusive hold on a data directory; see `StoragePaths::lock`.
+pub struct StorageLock {
+    handle: HANDLE,
+}
+
+// SAFETY: the handle is only closed, once, on drop.
+unsafe impl Send for StorageLock {}
+unsafe impl Sync for StorageLock {}
+
+impl Drop for StorageLock {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
+
+/// Another process, usually a second agent, holds the data directory.
+#[derive(Debug)]
+pub struct StorageLocked {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for StorageLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is held by another process", self.path.display())
+    }
+}
+
+impl std::error::Error for StorageLocked {}
+
+/// Number of corrupt files quarantined since the agent started.
+pub fn quarantined_files() -> u64 {
+    QUARANTINED_FILES.load(Ordering::Relaxed)
+}
+
+/// Number of usage batches dropped since the agent started.
+pub fn dropped_batches() -> u64 {
+    DROPPED_BATCHES.load(Ordering::Relaxed)
+}
+
+/// Number of batches skipped as duplicates since the agent started.
+pub fn deduplicated_batches() -> u64 {
+    DEDUPLICATED_BATCHES.load(Ordering::Relaxed)
+}
+
+/// Number of sessions removed by retention sweeps since the agent started.
+pub fn purged_sessions() -> u64 {
+    PURGED_SESSIONS.load(Ordering::Relaxed)
+}
+
+/// Bytes available to the agent on the volume holding `path`.
+pub fn free_space(path: &Path) -> Option<u64> {
+    let dir = if path.is_dir() { path } else { path.parent()? };
+    let mut available = 0u64;
+    unsafe {
+        GetDiskFreeSpaceExW(
+            &HSTRING::from(dir.to_string_lossy().as_ref()),
+            Some(&mut available),
+            None,
+            None,
+        )
+    }
+    .ok()?;
+    Some(available)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{RegistrationOutcome, UploadResult};
+
+    struct NoEvents;
+
+    impl AgentEvents for NoEvents {
+        fn upload_succeeded(&self, _: &UploadResult) {}
+        fn upload_failed(&self, _: &UploadResult) {}
+        fn queue_changed(&self, _: usize) {}
+        fn failure_alert(&self, _: &str) {}
+        fn pairing_result(&self, _: Result<RegistrationOutcome, String>) {}
+    }
+
+    /// Records every queue size reported.
+    #[derive(Default)]
+    struct QueueSizes(Mutex<Vec<usize>>);
+
+    impl AgentEvents for QueueSizes {
+        fn upload_succeeded(&self, _: &UploadResult) {}
+        fn upload_failed(&self, _: &UploadResult) {}
+        fn queue_changed(&self, queue_size: usize) {
+            self.0.lock().push(queue_size);
+        }
+        fn failure_alert(&self, _: &str) {}
+        fn pairing_result(&self, _: Result<RegistrationOutcome, String>) {}
+    }
+
+    /// Paths under a fresh directory in the system temp dir.
+    fn paths() -> StoragePaths {
+        let root = std::env::temp_dir().join(format!("nuscape-storage-{}", Uuid::new_v4()));
+        StoragePaths::with_root(root).unwrap()
+    }
+
+    fn open(paths: &StoragePaths) -> UsageBatchStore {
+        UsageBatchStore::new(paths, Arc::new(NoEvents)).unwrap()
+    }
+
+    fn store() -> UsageBatchStore {
+        open(&paths())
+    }
+
+    fn batch(device_id: Uuid, package: &str) -> UsageBatch {
+        let end = Utc::now();
+        UsageBatch {
+            batch_id: Uuid::new_v4(),
+            device_id,
+            sent_at: end,
+            sessions: vec![UsageSession {
+                package: package.to_string(),
+                app_id: None,
+                args_hint: None,
+                title: None,
+                engagement: None,
+                domain: None,
+                fullscreen: None,
+                monitor_index: None,
+                media: None,
+                remote: None,
+                user: None,
+                window_start: end - chrono::Duration::minutes(5),
+                window_end: end,
+                total_ms: 300_000,
+                foreground: false,
+            }],
+            network_deltas: Vec::new(),
+            dns_events: Vec::new(),
+            status: None,
+            chunk_index: None,
+            chunk_total: None,
+        }
+    }
+
+    #[test]
+    fn queue_changes_are_reported_with_the_new_size() {
+        let events = Arc::new(QueueSizes::default());
+        let store = UsageBatchStore::new(&paths(), events.clone()).unwrap();
+        let device_id = Uuid::new_v4();
+        for package in ["a.exe", "b.exe"] {
+            store.enqueue(batch(device_id, package), 0, true).unwrap();
+        }
+        let guard = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        store.complete(&guard).unwrap();
+        assert_eq!(*events.0.lock(), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn a_slow_write_does_not_delay_reads() {
+        let store = store();
+        let device_id = Uuid::new_v4();
+        let queued = batch(device_id, "a.exe");
+        store.enqueue(queued.clone(), 0, true).unwrap();
+
+        // A write that has taken the lock and not finished yet.
+        let mut writer = store.open().unwrap();
+        let slow = writer
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .unwrap();
+        insert_batch(&slow, &batch(device_id, "b.exe"), STATUS_QUEUED, 0, 0, true).unwrap();
+
+        let started = Instant::now();
+        let head = store.queue_preview(1).remove(0);
+        assert!(started.elapsed() < QUEUE_BUSY_TIMEOUT / 5);
+        assert_eq!(head.batch_id, queued.batch_id);
+        assert_eq!(store.queue_size(), 1);
+
+        slow.commit().unwrap();
+        assert_eq!(store.queue_size(), 2);
+    }
+
+    #[test]
+    fn atomic_write_prepares_the_temp_file_before_replacing_the_target() {
+        let dir = std::env::temp_dir().join(format!("nuscape-atomic-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("device.json");
+        fs::write(&path, "old").unwrap();
+
+        let mut seen = None;
+        atomic_write_with(&path, "new", |temp| {
+            seen = Some((
+                fs::read_to_string(temp).unwrap(),
+                fs::read_to_string(&path).unwrap(),
+            ));
+        })
+        .unwrap();
+        assert_eq!(seen, Some(("new".to_string(), "old".to_string())));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!dir.join("device.json.tmp").exists());
+    }
+
+    #[test]
+    fn a_temp_file_left_by_an_interrupted_write_is_ignored_and_replaced() {
+        let paths = paths();
+        let boot = Utc::now() - chrono::Duration::hours(1);
+        NetworkCounterStore::new(&paths)
+            .unwrap()
+            .save(HashMap::new(), boot)
+            .unwrap();
+        let mut temp = paths.counters_path().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        fs::write(&temp, "{\"version\": 1, \"coun").unwrap();
+
+        let store = NetworkCounterStore::new(&paths).unwrap();
+        assert_eq!(store.boot_time(), Some(boot));
+        store.save(HashMap::new(), Utc::now()).unwrap();
+        assert!(!temp.exists());
+        assert!(NetworkCounterStore::new(&paths).unwrap().boot_time() > Some(boot));
+    }
+
+    /// Names of the files quarantined beside `path`.
+    fn quarantined_beside(path: &Path) -> Vec<String> {
+        let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+        fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(&format!("{stem}{QUARANTINE_MARKER}")))
+            .collect()
+    }
+
+    #[test]
+    fn a_torn_log_tail_is_dropped_and_committed_batches_survive() {
+        let live = paths();
+        let store = open(&live);
+        // Another connection keeps the store's from checkpointing the log
+        // away when they close, leaving it as a crash would.
+        let held = Connection::open(live.queue_db_path()).unwrap();
+        held.query_row("PRAGMA user_version", [], |_| Ok(()))
+            .unwrap();
+        let wal = live.join(QUEUE_WAL_FILE);
+        let device_id = Uuid::new_v4();
+        let kept = batch(device_id, "a.exe");
+        let kept_id = kept.batch_id;
+        store.enqueue(kept, 0, true).unwrap();
+        let committed = fs::metadata(&wal).unwrap().len();
+        store.enqueue(batch(device_id, "b.exe"), 0, true).unwrap();
+        let written = fs::metadata(&wal).unwrap().len();
+        assert!(written > committed);
+
+        for truncate in [true, false] {
+            let crashed = paths();
+            let crashed_wal = crashed.join(QUEUE_WAL_FILE);
+            fs::copy(live.queue_db_path(), crashed.queue_db_path()).unwrap();
+            let mut log = fs::read(&wal).unwrap();
+            if truncate {
+                log.truncate((committed + (written - committed) / 2) as usize);
+            } else {
+                let len = log.len();
+                log[len - 64..].fill(0xa5);
+            }
+            fs::write(&crashed_wal, log).unwrap();
+
+            let store = open(&crashed);
+            assert_eq!(store.queue_size(), 1);
+            let head = store
+                .begin_upload(10, |batches| batches.len())
+                .unwrap()
+                .unwrap();
+            assert_eq!(batch_ids(&head), [kept_id]);
+        }
+    }
+
+    #[test]
+    fn corrupt_json_stores_are_quarantined() {
+        let paths = paths();
+        fs::write(paths.queue_path(), b"[{\"batch_id\": ").unwrap();
+        fs::write(paths.counters_path(), b"\xff\xfe").unwrap();
+
+        let store = open(&paths);
+        assert_eq!(store.queue_size(), 0);
+        assert_eq!(quarantined_beside(&paths.queue_path()).len(), 1);
+        assert!(!paths.queue_path().exists());
+        assert!(NetworkCounterStore::new(&paths).unwrap().load().is_empty());
+        assert_eq!(quarantined_beside(&paths.counters_path()).len(), 1);
+    }
+
+    #[test]
+    fn a_corrupt_queue_database_is_quarantined() {
+        let paths = paths();
+        let wal = paths.join(QUEUE_WAL_FILE);
+        let shm = paths.join(QUEUE_SHM_FILE);
+        // Past the 100-byte header, so SQLite reads it rather than
+        // treating the file as empty.
+        fs::write(
+            paths.queue_db_path(),
+            b"\x00garbage that is no database".repeat(64),
+        )
+        .unwrap();
+        fs::write(&wal, b"\x00a log left by the corrupt database").unwrap();
+        fs::write(&shm, b"\x00its index").unwrap();
+        let before = quarantined_files();
+
+        let store = open(&paths);
+        let quarantined = quarantined_beside(&paths.queue_db_path());
+        assert_eq!(
+            quarantined
+                .iter()
+                .filter(|name| name.ends_with(".db"))
+                .count(),
+            1
+        );
+        assert!(quarantined_files() > before);
+        // SQLite may already have dropped the unreadable log when the failed
+        // connection closed; either way nothing of it is left for the fresh
+        // database to replay.
+        for sidecar in [wal, shm] {
+            assert!(fs::read(sidecar).map_or(true, |data| !data.starts_with(b"\x00")));
+        }
+        assert_eq!(store.queue_size(), 0);
+        store
+            .enqueue(batch(Uuid::new_v4(), "a.exe"), 0, true)
+            .unwrap();
+        assert_eq!(store.queue_size(), 1);
+    }
+
+    #[test]
+    fn a_json_queue_is_imported_with_its_head_cursor() {
+        let paths = paths();
+        let device_id = Uuid::new_v4();
+        let (head, next, dead) = (
+            batch(device_id, "a.exe"),
+            batch(device_id, "b.exe"),
+            batch(device_id, "c.exe"),
+        );
+        let queue = json!({
+            "batches": [head, next],
+            "head_progress": HeadProgress {
+                batch_id: head.batch_id,
+                chunks_uploaded: 3,
+            },
+            "in_flight": 2,
+        });
+        fs::write(paths.queue_path(), queue.to_string()).unwrap();
+        fs::write(paths.dead_letter_path(), json!([dead]).to_string()).unwrap();
+
+        let store = open(&paths);
+        assert!(!paths.queue_path().exists());
+        assert!(!paths.dead_letter_path().exists());
+        assert_eq!(store.queue_size(), 2);
+        assert_eq!(store.dead_letter_count(), 1);
+        let resumed = store.begin_upload(10, |_| 1).unwrap().unwrap();
+        assert_eq!(batch_ids(&resumed), [head.batch_id, next.batch_id]);
+        assert_eq!(resumed.chunks_uploaded(), 3);
+    }
+
+    #[test]
+    fn a_bare_json_array_queue_is_imported() {
+        let paths = paths();
+        let queued = batch(Uuid::new_v4(), "a.exe");
+        fs::write(paths.queue_path(), json!([queued]).to_string()).unwrap();
+
+        let store = open(&paths);
+        let guard = store.begin_upload(10, |_| 1).unwrap().unwrap();
+        assert_eq!(guard.batches()[0].batch_id, queued.batch_id);
+        assert!(!paths.queue_path().exists());
+    }
+
+    /// Version 0 was bare, version 1 added the envelope and version 2
+    /// renamed `name` to `label`.
+    const TEST_SCHEMA: Schema = Schema {
+        name: "test document",
+        migrations: &[unversioned, rename_name_to_label],
+    };
+
+    fn rename_name_to_label(mut data: Value) -> Result<Value> {
+        let name = data
+            .as_object_mut()
+            .and_then(|object| object.remove("name"))
+            .ok_or_else(|| anyhow!("name missing"))?;
+        Ok(json!({ "label": name }))
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Labelled {
+        label: String,
+    }
+
+    #[test]
+    fn older_documents_are_migrated_stepwise() {
+        let decode =
+            |document: Value| TEST_SCHEMA.decode::<Labelled>(document.to_string().as_bytes());
+        let expected = Labelled {
+            label: "kitchen".to_string(),
+        };
+        assert_eq!(decode(json!({ "name": "kitchen" })).unwrap(), expected);
+        assert_eq!(
+            decode(json!({ "schema": 1, "data": { "name": "kitchen" } })).unwrap(),
+            expected
+        );
+        assert_eq!(
+            decode(TEST_SCHEMA.envelope(&json!({ "label": "kitchen" }))).unwrap(),
+            expected
+        );
+        let failed = decode(json!({ "schema": 1, "data": { "label": "kitchen" } })).unwrap_err();
+        assert!(format!("{failed:#}").contains("migrate test document from schema 1"));
+    }
+
+    #[test]
+    fn newer_documents_are_refused_and_quarantined() {
+        let newer = json!({ "schema": 9, "data": {} }).to_string();
+        let err = TEST_SCHEMA
+            .decode::<Labelled>(newer.as_bytes())
+            .unwrap_err();
+        let newer_schema = err.downcast_ref::<NewerSchema>().unwrap();
+        assert_eq!((newer_schema.found, newer_schema.supported), (9, 2));
+
+        let paths = paths();
+        fs::write(paths.counters_path(), &newer).unwrap();
+        assert!(NetworkCounterStore::new(&paths).unwrap().load().is_empty());
+        assert_eq!(quarantined_beside(&paths.counters_path()).len(), 1);
+    }
+
+    #[test]
+    fn a_storage_root_is_created_and_must_be_usable() {
+        let base = std::env::temp_dir().join(format!("nuscape-root-{}", Uuid::new_v4()));
+        let root = base.join("ProgramData").join("NuScape");
+        let paths = StoragePaths::with_root(root.clone()).unwrap();
+        assert_eq!(paths.root(), root);
+        assert!(root.is_dir());
+        assert!(!root.join(WRITE_PROBE_FILE).exists());
+
+        let file = base.join("not-a-dir");
+        fs::write(&file, "").unwrap();
+        assert!(StoragePaths::with_root(file.join("data")).is_err());
+    }
+
+    #[test]
+    fn the_data_dir_variable_overrides_the_storage_root() {
+        let root = std::env::temp_dir().join(format!("nuscape-env-{}", Uuid::new_v4()));
+        let paths = StoragePaths::with_data_dir(Some(root.clone().into())).unwrap();
+        assert_eq!(paths.root(), root);
+
+        let file = root.join("not-a-directory");
+        fs::write(&file, b"").unwrap();
+        let err = StoragePaths::with_data_dir(Some(file.into())).unwrap_err();
+        assert!(err.to_string().contains(DATA_DIR_ENV));
+    }
+
+    #[test]
+    fn a_locked_data_directory_refuses_a_second_lock() {
+        let paths = paths();
+        let lock = paths.lock().unwrap();
+        let Err(err) = paths.lock() else {
+            panic!("the data directory was locked twice");
+        };
+        assert!(err.downcast_ref::<StorageLocked>().is_some());
+
+        drop(lock);
+        paths.lock().unwrap();
+    }
+
+    #[test]
+    fn retention_purges_only_what_predates_the_cutoff() {
+        let paths = paths();
+        let store = open(&paths);
+        let device_id = Uuid::new_v4();
+        let cutoff = Utc::now() - chrono::Duration::days(30);
+        let just_before = cutoff - chrono::Duration::milliseconds(1);
+        let sent = |package, sent_at| UsageBatch {
+            sent_at,
+            ..batch(device_id, package)
+        };
+        store
+            .enqueue(sent("dead.exe", just_before), 0, true)
+            .unwrap();
+        let dead = store.begin_upload(1, |_| 1).unwrap().unwrap();
+        store.dead_letter(&dead).unwrap();
+        store
+            .enqueue(sent("stale.exe", just_before), 0, true)
+            .unwrap();
+        store.enqueue(sent("edge.exe", cutoff), 0, true).unwrap();
+        let quarantined = |at: DateTime<Utc>| {
+            let name = format!(
+                "usage_queue{QUARANTINE_MARKER}{}.json",
+                at.format(QUARANTINE_STAMP)
+            );
+            let path = paths.root().join(name);
+            fs::write(&path, "{").unwrap();
+            path
+        };
+        let old_file = quarantined(cutoff - chrono::Duration::days(1));
+        let recent_file = quarantined(cutoff + chrono::Duration::days(1));
+
+        let sweep = store.purge_older_than(cutoff).unwrap();
+        assert_eq!((sweep.batches, sweep.sessions, sweep.files), (2, 2, 1));
+        assert_eq!(store.queue_size(), 1);
+        assert_eq!(store.dead_letter_count(), 0);
+        assert!(!old_file.exists());
+        assert!(recent_file.exists());
+        let kept = store.begin_upload(10, |_| 1).unwrap().unwrap();
+        assert_eq!(kept.batches()[0].sessions[0].package, "edge.exe");
+    }
+
+    #[test]
+    fn a_repeated_batch_is_not_enqueued_twice() {
+        let paths = paths();
+        let store = open(&paths);
+        let original = batch(Uuid::new_v4(), "a.exe");
+        let repeat = UsageBatch {
+            batch_id: Uuid::new_v4(),
+            sent_at: original.sent_at + chrono::Duration::minutes(1),
+            ..original.clone()
+        };
+        let before = deduplicated_batches();
+        store.enqueue(original.clone(), 0, true).unwrap();
+        store.enqueue(repeat, 0, false).unwrap();
+        assert_eq!(store.queue_size(), 1);
+        assert!(deduplicated_batches() > before);
+
+        // Remembered across restarts, and after the original was uploaded.
+        let guard = store.begin_upload(10, |_| 1).unwrap().unwrap();
+        store.complete(&guard).unwrap();
+        let store = open(&paths);
+        store.enqueue(original.clone(), 0, true).unwrap();
+        assert_eq!(store.queue_size(), 0);
+
+        let mut near_miss = original;
+        near_miss.sessions[0].total_ms += 1;
+        store.enqueue(near_miss, 0, true).unwrap();
+        assert_eq!(store.queue_size(), 1);
+    }
+
+    #[test]
+    fn rejected_sessions_expire_and_rotate() {
+        let paths = paths();
+        let store = open(&paths);
+        let cutoff = Utc::now() - chrono::Duration::days(30);
+        let session = &batch(Uuid::new_v4(), "a.exe").sessions[0];
+        let record = |rejected_at| {
+            let record = RejectedSessionRecord {
+                rejected_at,
+                batch_id: Uuid::new_v4(),
+                session,
+            };
+            serde_json::to_string(&record).unwrap() + "\n"
+        };
+        let expired = record(cutoff - chrono::Duration::milliseconds(1));
+        fs::write(paths.join(REJECTS_ROTATED_FILE), &expired).unwrap();
+        let recent = record(cutoff);
+        fs::write(paths.rejects_path(), expired + &recent).unwrap();
+        store
+            .record_rejected(Uuid::new_v4(), std::slice::from_ref(session))
+            .unwrap();
+
+        let sweep = store.purge_older_than(cutoff).unwrap();
+        assert_eq!(sweep.rejected, 2);
+        assert!(!paths.join(REJECTS_ROTATED_FILE).exists());
+        let kept = fs::read_to_string(paths.rejects_path()).unwrap();
+        assert_eq!(kept.lines().count(), 2);
+        assert!(kept.starts_with(&recent));
+
+        // A full file is rotated before the next append.
+        let full = "x".repeat(REJECTS_MAX_BYTES as usize);
+        fs::write(paths.rejects_path(), &full).unwrap();
+        store
+            .record_rejected(Uuid::new_v4(), std::slice::from_ref(session))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(paths.join(REJECTS_ROTATED_FILE)).unwrap(),
+            full
+        );
+        assert_eq!(
+            fs::read_to_string(paths.rejects_path())
+                .unwrap()
+                .lines()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn upload_counts_from_before_upload_groups_carry_over() {
+        let paths = paths();
+        let device_id = Uuid::new_v4();
+        let batches: Vec<UsageBatch> = ["a.exe", "b.exe", "c.exe", "d.exe"]
+            .into_iter()
+            .map(|package| batch(device_id, package))
+            .collect();
+        {
+            let conn = Connection::open(paths.queue_db_path()).unwrap();
+            conn.execute_batch(QUEUE_SCHEMA_V1).unwrap();
+            conn.pragma_update(None, "user_version", 1).unwrap();
+            for batch in &batches {
+                insert_batch(&conn, batch, STATUS_QUEUED, 0, 0, false).unwrap();
+            }
+            conn.execute("UPDATE upload_state SET split = 2", [])
+                .unwrap();
+        }
+
+        let store = open(&paths);
+        for expected in &batches[..2] {
+            let guard = store
+                .begin_upload(10, |batches| batches.len())
+                .unwrap()
+                .unwrap();
+            assert_eq!(batch_ids(&guard), [expected.batch_id]);
+            store.complete(&guard).unwrap();
+        }
+        let rest = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rest.batches().len(), 2);
+    }
+
+    #[test]
+    fn network_counters_keep_their_boot_time() {
+        let paths = paths();
+        let store = NetworkCounterStore::new(&paths).unwrap();
+        assert_eq!(store.boot_time(), None);
+        let boot = Utc::now() - chrono::Duration::hours(3);
+        store.save(HashMap::new(), boot).unwrap();
+
+        let reopened = NetworkCounterStore::new(&paths).unwrap();
+        assert_eq!(reopened.boot_time(), Some(boot));
+    }
+
+    #[test]
+    fn description_keyed_counters_are_outdated_until_rebaselined() {
+        let paths = paths();
+        let legacy = json!({
+            "Intel(R) Wi-Fi 6 AX201": {
+                "wifi": 1_000,
+                "cell": 0,
+                "sampled_at": "2024-05-01 10:00:00 UTC"
+            }
+        });
+        fs::write(paths.counters_path(), legacy.to_string()).unwrap();
+
+        let store = NetworkCounterStore::new(&paths).unwrap();
+        assert!(store.outdated());
+        assert_eq!(store.boot_time(), None);
+        assert!(store.load().contains_key("Intel(R) Wi-Fi 6 AX201"));
+
+        store.save(HashMap::new(), Utc::now()).unwrap();
+        assert!(!NetworkCounterStore::new(&paths).unwrap().outdated());
+    }
+
+    #[test]
+    fn counters_without_directions_are_outdated() {
+        let paths = paths();
+        let older = json!({
+            "boot_time": null,
+            "counters": {
+                "luid:0000000000000001": {
+                    "wifi": 1_000,
+                    "cell": 0,
+                    "sampled_at": "2024-05-01 10:00:00 UTC"
+                }
+            }
+        });
+        fs::write(paths.counters_path(), older.to_string()).unwrap();
+
+        let store = NetworkCounterStore::new(&paths).unwrap();
+        assert!(store.outdated());
+        let counters = &store.load()["luid:0000000000000001"];
+        assert_eq!((counters.rx_total, counters.tx_total), (0, 0));
+    }
+
+    fn batch_ids(guard: &UploadGuard) -> Vec<Uuid> {
+        guard.batches().iter().map(|batch| batch.batch_id).collect()
+    }
+
+    #[test]
+    fn a_failed_coalesced_upload_is_resumed_as_the_same_set() {
+        let store = store();
+        let device_id = Uuid::new_v4();
+        for package in ["a.exe", "b.exe"] {
+            store.enqueue(batch(device_id, package), 0, true).unwrap();
+        }
+        let first = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.batches().len(), 2);
+
+        // The upload failed; a newer batch arrives before the retry.
+        store.enqueue(batch(device_id, "c.exe"), 0, true).unwrap();
+        let retry = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch_ids(&retry), batch_ids(&first));
+        let merged = |guard: &UploadGuard| UsageBatch::merge(guard.batches()).unwrap().batch_id;
+        assert_eq!(merged(&retry), merged(&first));
+
+        store.complete(&retry).unwrap();
+        let next = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.batches()[0].sessions[0].package, "c.exe");
+    }
+
+    #[test]
+    fn upload_progress_survives_a_restart() {
+        let paths = paths();
+        let store = open(&paths);
+        let device_id = Uuid::new_v4();
+        for package in ["a.exe", "b.exe"] {
+            store.enqueue(batch(device_id, package), 0, true).unwrap();
+        }
+        let guard = store.begin_upload(1, |_| 1).unwrap().unwrap();
+        store.set_upload_progress(&guard, 2).unwrap();
+        drop(store);
+
+        let store = open(&paths);
+        let resumed = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch_ids(&resumed), batch_ids(&guard));
+        assert_eq!(resumed.chunks_uploaded(), 2);
+    }
+
+    #[test]
+    fn a_head_with_a_cursor_is_uploaded_alone() {
+        let store = store();
+        let device_id = Uuid::new_v4();
+        for package in ["a.exe", "b.exe", "c.exe"] {
+            store.enqueue(batch(device_id, package), 0, true).unwrap();
+        }
+        // A cursor on a queued head, as left by a queue from before upload
+        // groups.
+        store
+            .open()
+            .unwrap()
+            .execute(
+                "UPDATE batches SET chunks_uploaded = 1 WHERE id = (SELECT MIN(id) FROM batches)",
+                [],
+            )
+            .unwrap();
+
+        let head = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(head.batches().len(), 1);
+        assert_eq!(head.batches()[0].sessions[0].package, "a.exe");
+        assert_eq!(head.chunks_uploaded(), 1);
+    }
+
+    #[test]
+    fn a_split_upload_resends_its_batches_one_at_a_time() {
+        let store = store();
+        let device_id = Uuid::new_v4();
+        for package in ["a.exe", "b.exe", "c.exe"] {
+            store.enqueue(batch(device_id, package), 0, true).unwrap();
+        }
+        let group = store
+            .begin_upload(2, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(group.batches().len(), 2);
+        store.split_upload(&group).unwrap();
+
+        let first = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch_ids(&first), batch_ids(&group)[..1]);
+        assert_eq!(store.record_upload_failure(&first).unwrap(), 1);
+        let dead = store.dead_letter(&first).unwrap().unwrap();
+        assert_eq!(dead.batch_id, batch_ids(&group)[0]);
+
+        let second = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch_ids(&second), batch_ids(&group)[1..]);
+        store.complete(&second).unwrap();
+
+        let rest = store
+            .begin_upload(10, |batches| batches.len())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rest.batches()[0].sessions[0].package, "c.exe");
+        assert_eq!(store.dead_letter_count(), 1);
+    }
 }
@@ -0,0 +1,31 @@
+use parking_lot::Mutex;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Paces outgoing bytes to a bytes-per-second budget shared by every
+/// request, including concurrent chunk uploads.
+#[derive(Default)]
+pub struct UploadThrottle {
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl UploadThrottle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits until `bytes` may go out under `limit`; `None` means unlimited.
+    pub async fn acquire(&self, bytes: usize, limit: Option<u64>) {
+        let limit = match limit {
+            Some(limit) if limit > 0 => limit,
+            _ => return,
+        };
+        let start = {
+            let mut next_slot = self.next_slot.lock();
+            let now = Instant::now();
+            let start = next_slot.map_or(now, |slot| slot.max(now));
+            *next_slot = Some(start + Duration::from_secs_f64(bytes as f64 / limit as f64));
+            start
+        };
+        sleep_until(start).await;
+    }
+}
@@ -1,45 +1,255 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration as StdDuration;
+use std::time::{Duration as StdDuration, Instant};
 
 use anyhow::{anyhow, Context, Result};
-use chrono::Utc;
-use reqwest::Client;
-use serde_json::Value;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Body, Client, RequestBuilder};
+use tokio::task;
 use tokio::time::sleep;
+use uuid::Uuid;
 
-use crate::auth::TokenStore;
+use crate::auth::{TokenRefresher, TokenStore};
+use crate::backoff::{apply_jitter, CircuitBreaker, JitterSource, RandomJitter};
+use crate::clock::ClockSkew;
+use crate::collectors::connectivity;
 use crate::config::UsageConfigStore;
+use crate::http;
+use crate::metrics::AgentMetrics;
 use crate::models::{
-    RequestOutcome, UploadFailureReason, UploadResult, DEFAULT_CHUNK_BYTE_LIMIT,
-    DEFAULT_CHUNK_SESSION_LIMIT,
+    AuthAuditEntry, AuthEvent, AuthMode, CircuitState, Heartbeat, RejectedSessionsResponse,
+    RequestOutcome, UploadConfig, UploadFailureReason, UploadResult, UsageBatch,
+    DEFAULT_CHUNK_BYTE_LIMIT, DEFAULT_CHUNK_SESSION_LIMIT, DEFAULT_CHUNK_WIRE_BYTE_LIMIT,
 };
-use crate::storage::UsageBatchStore;
+use crate::signing::{self, RequestSigner};
+use crate::storage::{BackoffStore, StoragePaths, UploadGuard, UsageBatchStore};
+use crate::throttle::UploadThrottle;
 
-const USER_AGENT: &str = "NuScape-Windows-Agent/1.0";
+const MAX_RETRY_AFTER_SECONDS: u64 = 60;
+const MAX_COALESCED_BATCHES: usize = 50;
+const CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+const CIRCUIT_BASE_COOLDOWN_SECONDS: u64 = 2 * 60;
+const CIRCUIT_MAX_COOLDOWN_SECONDS: u64 = 60 * 60;
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Credential attached to an upload request.
+enum Credential {
+    /// Access token and the token generation it was read at.
+    Bearer {
+        token: String,
+        generation: u64,
+    },
+    ApiKey(String),
+}
+
+/// Result of trying to stream a batch as NDJSON.
+enum StreamOutcome {
+    Uploaded,
+    /// Not accepted in this form; upload it as chunked JSON instead.
+    Fallback,
+    Failed(UploadFailureReason),
+}
+
+impl Credential {
+    fn apply(&self, builder: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credential::Bearer { token, .. } => builder.bearer_auth(token),
+            Credential::ApiKey(key) => builder.header("X-Api-Key", key),
+        }
+    }
+}
 
 pub struct UsageUploader {
     client: Client,
     config_store: Arc<UsageConfigStore>,
     token_store: Arc<TokenStore>,
     batch_store: Arc<UsageBatchStore>,
+    compress: AtomicBool,
+    ndjson_supported: AtomicBool,
+    throttle: Arc<UploadThrottle>,
+    bytes_sent: Arc<AtomicU64>,
+    jitter: Arc<dyn JitterSource>,
+    metrics: Arc<AgentMetrics>,
+    breaker: Mutex<CircuitBreaker>,
+    backoff_store: BackoffStore,
+    resume_at: Mutex<Option<DateTime<Utc>>>,
+    dry_run_dir: PathBuf,
+    clock: Arc<ClockSkew>,
+    refresher: Arc<TokenRefresher>,
 }
 
 impl UsageUploader {
     pub fn new(
+        paths: &StoragePaths,
         config_store: Arc<UsageConfigStore>,
         token_store: Arc<TokenStore>,
         batch_store: Arc<UsageBatchStore>,
+        metrics: Arc<AgentMetrics>,
+        clock: Arc<ClockSkew>,
+        refresher: Arc<TokenRefresher>,
     ) -> Result<Self> {
-        let client = Client::builder().user_agent(USER_AGENT).build()?;
+        let client = http::client_builder(&config_store)?.build()?;
+        let mut breaker = CircuitBreaker::new(
+            CIRCUIT_FAILURE_THRESHOLD,
+            StdDuration::from_secs(CIRCUIT_BASE_COOLDOWN_SECONDS),
+            StdDuration::from_secs(CIRCUIT_MAX_COOLDOWN_SECONDS),
+        );
+        let backoff_store = BackoffStore::new(paths);
+        let resume_at = backoff_store.load().and_then(|saved| {
+            breaker.restore(&saved);
+            let resume_at = saved.resume_at();
+            (resume_at > Utc::now()).then(|| {
+                log::info!("holding uploads until {resume_at} after earlier failures");
+                resume_at
+            })
+        });
         Ok(Self {
             client,
             config_store,
             token_store,
             batch_store,
+            compress: AtomicBool::new(true),
+            ndjson_supported: AtomicBool::new(true),
+            throttle: Arc::new(UploadThrottle::new()),
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+            jitter: Arc::new(RandomJitter),
+            metrics,
+            breaker: Mutex::new(breaker),
+            backoff_store,
+            resume_at: Mutex::new(resume_at),
+            dry_run_dir: paths.dry_run_dir(),
+            clock,
+            refresher,
         })
     }
 
     pub async fn upload_pending(&self) -> Result<UploadResult> {
+        let resume_at = *self.resume_at.lock();
+        if let Some(resume_at) = resume_at {
+            if Utc::now() < resume_at {
+                log::debug!("upload backoff from previous run active until {resume_at}");
+                return Ok(UploadResult {
+                    uploaded_batches: 0,
+                    failure_reason: None,
+                    circuit: self.breaker.lock().state(Utc::now()),
+                    dead_lettered_batches: 0,
+                });
+            }
+            *self.resume_at.lock() = None;
+        }
+
+        let circuit = self.breaker.lock().state(Utc::now());
+        if circuit == CircuitState::Open {
+            log::debug!("upload circuit open; skipping attempt");
+            return Ok(UploadResult {
+                uploaded_batches: 0,
+                failure_reason: None,
+                circuit,
+                dead_lettered_batches: 0,
+            });
+        }
+
+        self.bytes_sent.store(0, Ordering::Relaxed);
+        let started = Instant::now();
+        let mut result = self.upload_queue().await?;
+        self.metrics
+            .record_upload_rate(self.bytes_sent.load(Ordering::Relaxed), started.elapsed());
+        let persisted = {
+            let mut breaker = self.breaker.lock();
+            let now = Utc::now();
+            let persisted = match result.failure_reason {
+                Some(reason) if reason.trips_circuit() => {
+                    breaker.record_failure(now);
+                    Some(breaker.persist(now, self.restart_backoff(&breaker)))
+                }
+                Some(_) if result.uploaded_batches == 0 => None,
+                _ => {
+                    breaker.record_success();
+                    None
+                }
+            };
+            result.circuit = breaker.state(now);
+            persisted
+        };
+        let saved = match &persisted {
+            Some(backoff) => self.backoff_store.save(backoff),
+            None if result.failure_reason.is_none() => self.backoff_store.clear(),
+            None => Ok(()),
+        };
+        if let Err(err) = saved {
+            log::warn!("failed to persist upload backoff: {err:?}");
+        }
+        let queue_size = self.on_queue(UsageBatchStore::queue_size).await?;
+        self.metrics.record_upload(&result, queue_size);
+        Ok(result)
+    }
+
+    /// Runs `op` on the blocking pool; every queue operation goes to the
+    /// database file.
+    async fn on_queue<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&UsageBatchStore) -> T + Send + 'static,
+    {
+        let store = self.batch_store.clone();
+        Ok(task::spawn_blocking(move || op(&store)).await?)
+    }
+
+    /// Posts a status-only heartbeat straight to the backend, bypassing the
+    /// batch queue. Returns whether the server accepted it.
+    pub async fn send_heartbeat(&self, heartbeat: &Heartbeat) -> Result<bool> {
+        if self.config_store.dry_run() {
+            log::debug!("dry run: skipping heartbeat");
+            return Ok(true);
+        }
+        if self.breaker.lock().state(Utc::now()) == CircuitState::Open {
+            return Ok(false);
+        }
+        let config = self.config_store.resolve_upload_config()?;
+        let credential = match self.credential(&config).await? {
+            Ok(credential) => credential,
+            Err(reason) => {
+                log::debug!("heartbeat skipped: {reason:?}");
+                return Ok(false);
+            }
+        };
+        let body = serde_json::to_vec(heartbeat)?;
+        let builder = credential
+            .apply(self.client.post(config.heartbeat_url.clone()))
+            .headers(self.config_store.custom_header_map())
+            .header("Content-Type", "application/json");
+        let request = self.sign(builder, &body).body(body).build()?;
+        let outcome = self.execute_request(request).await?;
+        if !outcome.success {
+            log::warn!("heartbeat failed: {:?}", outcome.failure);
+        }
+        Ok(outcome.success)
+    }
+
+    /// Wait a restarted agent should observe before retrying, exponential in
+    /// the failure count. An open circuit is restored separately.
+    fn restart_backoff(&self, breaker: &CircuitBreaker) -> StdDuration {
+        let policy = self.config_store.retry_policy();
+        let exponent = breaker.consecutive_failures().saturating_sub(1).min(16);
+        let backoff = StdDuration::from_millis(policy.initial_backoff_ms)
+            .saturating_mul(1 << exponent)
+            .min(StdDuration::from_secs(CIRCUIT_MAX_COOLDOWN_SECONDS));
+        apply_jitter(backoff, self.jitter.as_ref())
+    }
+
+    async fn upload_queue(&self) -> Result<UploadResult> {
+        if self.config_store.dry_run() {
+            let dir = self.dry_run_dir.clone();
+            return self
+                .on_queue(move |store| dry_run_pending(store, &dir))
+                .await?;
+        }
+
         let config = match self.config_store.resolve_upload_config() {
             Ok(cfg) => cfg,
             Err(err) => {
@@ -47,198 +257,1133 @@ impl UsageUploader {
                 return Ok(UploadResult {
                     uploaded_batches: 0,
                     failure_reason: Some(UploadFailureReason::MissingConfig),
+                    circuit: CircuitState::Closed,
+                    dead_lettered_batches: 0,
                 });
             }
         };
 
+        if self.on_queue(UsageBatchStore::has_pending).await? && !connectivity::is_online() {
+            log::debug!("no network connectivity; skipping upload");
+            return Ok(UploadResult {
+                uploaded_batches: 0,
+                failure_reason: Some(UploadFailureReason::Offline),
+                circuit: CircuitState::Closed,
+                dead_lettered_batches: 0,
+            });
+        }
+
         let mut uploaded = 0usize;
+        let mut dead_lettered = 0usize;
         loop {
-            let maybe_batch = self.batch_store.peek();
-            let batch = match maybe_batch {
-                Some(b) => b,
+            let guard = match self.on_queue(begin_upload).await?? {
+                Some(guard) => Arc::new(guard),
                 None => break,
             };
-            let chunks = batch
-                .chunked(DEFAULT_CHUNK_SESSION_LIMIT, DEFAULT_CHUNK_BYTE_LIMIT)
-                .context("failed to chunk batch")?;
-            let mut chunk_index = 0usize;
-            let mut refreshed = false;
-            let mut failure: Option<UploadFailureReason> = None;
-
-            while chunk_index < chunks.len() {
-                let token = match self.token_store.access_token() {
-                    Some(t) => t,
-                    None => {
-                        return Ok(UploadResult {
-                            uploaded_batches: uploaded,
-                            failure_reason: Some(UploadFailureReason::MissingToken),
-                        });
-                    }
-                };
-                if self.token_store.is_access_token_expired(Utc::now()) {
-                    if !refreshed && self.try_refresh(&config).await? {
-                        refreshed = true;
-                        continue;
-                    }
-                    return Ok(UploadResult {
-                        uploaded_batches: uploaded,
-                        failure_reason: Some(UploadFailureReason::TokenExpired),
-                    });
-                }
+            let batch = upload_unit(&guard)?;
+            let streamed = if self.ndjson_enabled(&guard) {
+                self.upload_ndjson(&config, &batch).await?
+            } else {
+                StreamOutcome::Fallback
+            };
+            let failure = match streamed {
+                StreamOutcome::Uploaded => None,
+                StreamOutcome::Failed(reason) => Some(reason),
+                StreamOutcome::Fallback => self.upload_chunks(&config, &batch, &guard).await?,
+            };
 
-                let chunk_json = chunks[chunk_index]
-                    .to_json_string()
-                    .context("serialize chunk")?;
-                let request = self
-                    .client
-                    .post(config.batch_url.clone())
-                    .bearer_auth(&token)
-                    .header("Content-Type", "application/json")
-                    .body(chunk_json)
-                    .build()?;
-                let outcome = self.execute_request(request).await?;
-                if outcome.success {
-                    chunk_index += 1;
-                    refreshed = false;
+            if let Some(UploadFailureReason::ServerError) = failure {
+                if guard.batches().len() > 1 {
+                    // Find the batch the server objects to before counting
+                    // failures against anything.
+                    log::warn!(
+                        "coalesced upload of {} batches rejected; retrying them one by one",
+                        guard.batches().len()
+                    );
+                    let failed = guard.clone();
+                    self.on_queue(move |store| store.split_upload(&failed))
+                        .await?
+                        .context("split rejected upload")?;
                     continue;
                 }
-
-                let reason = outcome.failure.unwrap_or(UploadFailureReason::ServerError);
-                if matches!(reason, UploadFailureReason::Unauthorized) && !refreshed {
-                    if self.try_refresh(&config).await? {
-                        refreshed = true;
-                        continue;
+                let failed = guard.clone();
+                let failures = self
+                    .on_queue(move |store| store.record_upload_failure(&failed))
+                    .await?
+                    .context("record batch failure")?;
+                if failures >= self.config_store.dead_letter_threshold() {
+                    let failed = guard.clone();
+                    if let Some(dead) = self
+                        .on_queue(move |store| store.dead_letter(&failed))
+                        .await?
+                        .context("dead-letter batch")?
+                    {
+                        log::error!(
+                            "batch {} rejected {failures} times; moved to dead letters",
+                            dead.batch_id
+                        );
+                        dead_lettered += 1;
                     }
+                    continue;
                 }
-                if matches!(reason, UploadFailureReason::Unauthorized) {
-                    let _ = self.token_store.clear();
-                }
-                failure = Some(reason);
-                break;
             }
 
             if let Some(reason) = failure {
+                // The batches stay in flight so the next pass resends the
+                // same set under the same Idempotency-Key.
                 return Ok(UploadResult {
                     uploaded_batches: uploaded,
                     failure_reason: Some(reason),
+                    circuit: CircuitState::Closed,
+                    dead_lettered_batches: dead_lettered,
                 });
             }
 
-            if chunk_index == chunks.len() {
-                self.batch_store
-                    .pop()
-                    .context("pop batch after success")?
-                    .ok_or_else(|| anyhow!("batch disappeared before removal"))?;
-                uploaded += chunks.len();
-                continue;
-            }
+            let sources = guard.batches().len();
+            let done = guard.clone();
+            self.on_queue(move |store| store.complete(&done))
+                .await?
+                .context("remove batches after success")?;
+            uploaded += sources;
         }
 
         Ok(UploadResult {
             uploaded_batches: uploaded,
             failure_reason: None,
+            circuit: CircuitState::Closed,
+            dead_lettered_batches: dead_lettered,
         })
     }
 
+    /// Uploads the chunks of `batch` not yet accepted, up to the configured
+    /// number at a time. Returns the failure that stopped the batch, if any.
+    async fn upload_chunks(
+        &self,
+        config: &UploadConfig,
+        batch: &UsageBatch,
+        guard: &Arc<UploadGuard>,
+    ) -> Result<Option<UploadFailureReason>> {
+        let chunks = batch
+            .chunked_for_wire(
+                DEFAULT_CHUNK_SESSION_LIMIT,
+                DEFAULT_CHUNK_BYTE_LIMIT,
+                DEFAULT_CHUNK_WIRE_BYTE_LIMIT,
+            )
+            .context("failed to chunk batch")?;
+        let total = chunks.len();
+        let batch_id = batch.batch_id;
+        let mut progress = guard.chunks_uploaded().min(total);
+        if progress > 0 {
+            log::info!("resuming batch {batch_id} at chunk {progress}/{total}");
+        }
+
+        // Chunks may finish out of order; only the contiguous prefix of
+        // finished chunks is persisted as progress.
+        let mut finished = vec![false; total];
+        finished[..progress].fill(true);
+        let mut pending = stream::iter(chunks.into_iter().enumerate().skip(progress))
+            .map(|(index, chunk)| async move {
+                (index, self.upload_chunk(config, batch_id, chunk).await)
+            })
+            .buffer_unordered(self.config_store.upload_concurrency());
+        while let Some((index, outcome)) = pending.next().await {
+            if let Some(reason) = outcome? {
+                return Ok(Some(reason));
+            }
+            finished[index] = true;
+            let advanced = progress + finished[progress..].iter().take_while(|f| **f).count();
+            if advanced > progress {
+                progress = advanced;
+                let guard = guard.clone();
+                self.on_queue(move |store| store.set_upload_progress(&guard, advanced))
+                    .await?
+                    .context("persist upload progress")?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Streaming is only used for fresh batches; a partially uploaded batch
+    /// resumes with chunks.
+    fn ndjson_enabled(&self, guard: &UploadGuard) -> bool {
+        self.config_store.ndjson_uploads()
+            && self.ndjson_supported.load(Ordering::SeqCst)
+            && guard.chunks_uploaded() == 0
+    }
+
+    /// Streams the whole batch as NDJSON in one request.
+    async fn upload_ndjson(
+        &self,
+        config: &UploadConfig,
+        batch: &UsageBatch,
+    ) -> Result<StreamOutcome> {
+        let credential = match self.credential(config).await? {
+            Ok(credential) => credential,
+            Err(reason) => return Ok(StreamOutcome::Failed(reason)),
+        };
+        // The signature has to go out in the headers, so hash the lines in a
+        // first pass rather than buffering the body.
+        let signer = match self.token_store.signing_secret() {
+            Some(secret) => {
+                let mut signer = RequestSigner::new(&secret, self.clock.server_now());
+                for line in batch.to_ndjson_lines() {
+                    signer.update(line?.as_bytes());
+                }
+                Some(signer)
+            }
+            None => None,
+        };
+        let batch = Arc::new(batch.clone());
+        let outcome = self
+            .execute_with(|| {
+                let lines = batch.clone();
+                let throttle = self.throttle.clone();
+                let bytes_sent = self.bytes_sent.clone();
+                let limit = self.config_store.upload_rate_limit();
+                let body = stream::iter(0..lines.ndjson_line_count()).then(move |index| {
+                    let line = lines.ndjson_line(index);
+                    let throttle = throttle.clone();
+                    let bytes_sent = bytes_sent.clone();
+                    async move {
+                        if let Ok(line) = &line {
+                            throttle.acquire(line.len(), limit).await;
+                            bytes_sent.fetch_add(line.len() as u64, Ordering::Relaxed);
+                        }
+                        line
+                    }
+                });
+                let builder = credential
+                    .apply(self.client.post(config.batch_url.clone()))
+                    .headers(self.config_store.custom_header_map())
+                    .header("Content-Type", NDJSON_CONTENT_TYPE)
+                    .header("Idempotency-Key", format!("{}-ndjson", batch.batch_id));
+                let builder = match &signer {
+                    Some(signer) => signer.clone().apply(builder),
+                    None => builder,
+                };
+                Ok(builder.body(Body::wrap_stream(body)).build()?)
+            })
+            .await?;
+        if outcome.success {
+            return Ok(StreamOutcome::Uploaded);
+        }
+        if outcome.status == Some(415) {
+            if self.ndjson_supported.swap(false, Ordering::SeqCst) {
+                log::warn!("server rejected NDJSON uploads; falling back to chunked JSON");
+            }
+            return Ok(StreamOutcome::Fallback);
+        }
+        match outcome.failure.unwrap_or(UploadFailureReason::ServerError) {
+            // Let the chunked path refresh the token and retry.
+            UploadFailureReason::Unauthorized
+                if matches!(credential, Credential::Bearer { .. }) =>
+            {
+                Ok(StreamOutcome::Fallback)
+            }
+            reason => Ok(StreamOutcome::Failed(reason)),
+        }
+    }
+
+    /// Credential for the configured auth mode, refreshing an expired device
+    /// token first.
+    async fn credential(
+        &self,
+        config: &UploadConfig,
+    ) -> Result<std::result::Result<Credential, UploadFailureReason>> {
+        if self.config_store.auth_mode() == AuthMode::ApiKey {
+            return Ok(self
+                .config_store
+                .api_key()
+                .map(Credential::ApiKey)
+                .ok_or(UploadFailureReason::MissingConfig));
+        }
+        let generation = self.token_store.generation();
+        let token = match self.token_store.access_token() {
+            Some(token) => token,
+            None => return Ok(Err(UploadFailureReason::MissingToken)),
+        };
+        if !self.token_store.is_access_token_expired(
+            self.clock.server_now(),
+            self.config_store.token_expiry_margin(),
+        ) {
+            return Ok(Ok(Credential::Bearer { token, generation }));
+        }
+        if !self.refresher.refresh(config, generation).await? {
+            return Ok(Err(UploadFailureReason::TokenExpired));
+        }
+        let generation = self.token_store.generation();
+        Ok(self
+            .token_store
+            .access_token()
+            .map(|token| Credential::Bearer { token, generation })
+            .ok_or(UploadFailureReason::MissingToken))
+    }
+
+    /// Sends one chunk, handling token refresh, the gzip fallback and
+    /// server-side session rejections. Returns the failure that stopped it,
+    /// if any.
+    async fn upload_chunk(
+        &self,
+        config: &UploadConfig,
+        batch_id: Uuid,
+        mut chunk: UsageBatch,
+    ) -> Result<Option<UploadFailureReason>> {
+        let mut refreshed = false;
+        let mut slimmed = false;
+        loop {
+            let credential = match self.credential(config).await? {
+                Ok(credential) => credential,
+                Err(reason) => return Ok(Some(reason)),
+            };
+
+            let compressed = self.compress.load(Ordering::SeqCst);
+            let request = self.build_chunk_request(config, &credential, &chunk)?;
+            let outcome = self.execute_request(request).await?;
+            if outcome.success {
+                return Ok(None);
+            }
+            if outcome.status == Some(415) && compressed {
+                if self.compress.swap(false, Ordering::SeqCst) {
+                    log::warn!(
+                        "server rejected gzip payload; falling back to uncompressed uploads"
+                    );
+                }
+                continue;
+            }
+
+            if !slimmed {
+                if let Some(rejected) = parse_rejected(&outcome) {
+                    slimmed = true;
+                    let removed = chunk.remove_sessions(&rejected.rejected);
+                    log::warn!(
+                        "server rejected {} session(s) in batch {batch_id}",
+                        removed.len()
+                    );
+                    self.on_queue(move |store| store.record_rejected(batch_id, &removed))
+                        .await?
+                        .context("record rejected sessions")?;
+                    if chunk.sessions.is_empty()
+                        && chunk.network_deltas.is_empty()
+                        && chunk.dns_events.is_empty()
+                    {
+                        log::warn!("every session in the chunk was rejected; skipping it");
+                        return Ok(None);
+                    }
+                    continue;
+                }
+            }
+
+            let reason = outcome.failure.unwrap_or(UploadFailureReason::ServerError);
+            // A rejected API key cannot be refreshed, so it is terminal.
+            if let (UploadFailureReason::Unauthorized, Credential::Bearer { generation, .. }) =
+                (reason, &credential)
+            {
+                self.token_store.audit().record(
+                    AuthAuditEntry::new(AuthEvent::Unauthorized, false)
+                        .with_status(401)
+                        .with_detail(format!("batch {batch_id}")),
+                );
+                if !refreshed && self.refresher.refresh(config, *generation).await? {
+                    refreshed = true;
+                    continue;
+                }
+                let _ = self.token_store.clear();
+            }
+            return Ok(Some(reason));
+        }
+    }
+
+    fn build_chunk_request(
+        &self,
+        config: &UploadConfig,
+        credential: &Credential,
+        chunk: &UsageBatch,
+    ) -> Result<reqwest::Request> {
+        let builder = credential
+            .apply(self.client.post(config.batch_url.clone()))
+            .headers(self.config_store.custom_header_map())
+            .header("Content-Type", "application/json")
+            .header("Idempotency-Key", chunk.idempotency_key());
+        let (builder, body) = if self.compress.load(Ordering::SeqCst) {
+            (
+                builder.header("Content-Encoding", "gzip"),
+                chunk.to_gzip_bytes().context("compress chunk")?,
+            )
+        } else {
+            (
+                builder,
+                chunk
+                    .to_json_string()
+                    .context("serialize chunk")?
+                    .into_bytes(),
+            )
+        };
+        Ok(self.sign(builder, &body).body(body).build()?)
+    }
+
+    /// Adds the HMAC signature headers when the device has a signing secret;
+    /// older backends never issue one, so their requests go out unsigned.
+    fn sign(&self, builder: RequestBuilder, body: &[u8]) -> RequestBuilder {
+        signing::sign(
+            builder,
+            self.token_store.signing_secret().as_deref(),
+            self.clock.server_now(),
+            body,
+        )
+    }
+
     async fn execute_request(&self, request: reqwest::Request) -> Result<RequestOutcome> {
+        self.execute_with(|| request.try_clone().context("failed to clone request"))
+            .await
+    }
+
+    /// Sends the request produced by `build` with retries, building a fresh
+    /// request for every attempt so streamed bodies can be replayed.
+    async fn execute_with<F>(&self, build: F) -> Result<RequestOutcome>
+    where
+        F: Fn() -> Result<reqwest::Request>,
+    {
+        let policy = self.config_store.retry_policy();
+        let max_attempts = policy.max_attempts.max(1);
+        let max_backoff = StdDuration::from_millis(policy.max_backoff_ms);
         let mut attempt = 0;
-        let mut backoff = StdDuration::from_millis(1_000);
-        let max_attempts = 3;
+        let mut backoff = StdDuration::from_millis(policy.initial_backoff_ms).min(max_backoff);
         loop {
             attempt += 1;
-            match self
-                .client
-                .execute(request.try_clone().context("failed to clone request")?)
-                .await
-            {
+            let request = build()?;
+            if let Some(bytes) = request.body().and_then(|body| body.as_bytes()) {
+                self.throttle
+                    .acquire(bytes.len(), self.config_store.upload_rate_limit())
+                    .await;
+                self.bytes_sent
+                    .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+            }
+            match self.client.execute(request).await {
                 Ok(response) => {
                     let status = response.status();
+                    self.clock.observe(response.headers());
+                    let retry_after = parse_retry_after(response.headers(), Utc::now());
                     let body = response.text().await.ok();
                     if status.is_success() {
                         return Ok(RequestOutcome {
                             success: true,
+                            status: Some(status.as_u16()),
                             failure: None,
                             body,
                         });
                     }
                     let failure = if status.as_u16() == 401 {
                         UploadFailureReason::Unauthorized
+                    } else if status.as_u16() == 429 || status.as_u16() == 503 {
+                        UploadFailureReason::RateLimited
                     } else if status.as_u16() == 408 || (500..=504).contains(&status.as_u16()) {
                         UploadFailureReason::NetworkError
                     } else {
                         UploadFailureReason::ServerError
                     };
-                    if attempt < max_attempts
-                        && matches!(failure, UploadFailureReason::NetworkError)
-                    {
-                        sleep(backoff).await;
-                        backoff = (backoff * 2).min(StdDuration::from_millis(10_000));
-                        continue;
+                    if attempt < max_attempts {
+                        match failure {
+                            UploadFailureReason::RateLimited => {
+                                let wait = retry_after
+                                    .unwrap_or_else(|| apply_jitter(backoff, self.jitter.as_ref()));
+                                if wait <= StdDuration::from_secs(MAX_RETRY_AFTER_SECONDS) {
+                                    log::info!("rate limited; retrying in {wait:?}");
+                                    sleep(wait).await;
+                                    backoff = (backoff * 2).min(max_backoff);
+                                    continue;
+                                }
+                                log::warn!(
+                                    "server asked to retry after {wait:?}; deferring upload"
+                                );
+                            }
+                            UploadFailureReason::NetworkError => {
+                                sleep(apply_jitter(backoff, self.jitter.as_ref())).await;
+                                backoff = (backoff * 2).min(max_backoff);
+                                continue;
+                            }
+                            _ => {}
+                        }
                     }
                     return Ok(RequestOutcome {
                         success: false,
+                        status: Some(status.as_u16()),
                         failure: Some(failure),
                         body,
                     });
                 }
                 Err(err) => {
                     log::warn!("upload attempt {attempt} failed: {err:?}");
-                    if attempt >= max_attempts {
+                    let reason = http::classify_transport_error(&err);
+                    if matches!(reason, UploadFailureReason::PinMismatch) || attempt >= max_attempts
+                    {
                         return Ok(RequestOutcome {
                             success: false,
-                            failure: Some(UploadFailureReason::NetworkError),
+                            status: None,
+                            failure: Some(reason),
                             body: None,
                         });
                     }
-                    sleep(backoff).await;
-                    backoff = (backoff * 2).min(StdDuration::from_millis(10_000));
+                    sleep(apply_jitter(backoff, self.jitter.as_ref())).await;
+                    backoff = (backoff * 2).min(max_backoff);
                 }
             }
         }
     }
+}
 
-    async fn try_refresh(&self, config: &crate::models::UploadConfig) -> Result<bool> {
-        let refresh = match self.token_store.refresh_token() {
-            Some(token) => token,
-            None => return Ok(false),
+/// Takes the next thing to upload: the head batch, or several small queued
+/// batches that together still fit in a single chunk.
+fn begin_upload(store: &UsageBatchStore) -> Result<Option<UploadGuard>> {
+    store.begin_upload(MAX_COALESCED_BATCHES, coalesced_count)
+}
+
+/// How many of the oldest `candidates` to merge into one upload.
+fn coalesced_count(candidates: &[UsageBatch]) -> usize {
+    let mut best = 1usize;
+    for count in 2..=candidates.len() {
+        let Some(merged) = UsageBatch::merge(&candidates[..count]) else {
+            break;
         };
-        let refresh_url = config
-            .base_url
-            .clone()
-            .join("api/v1/devices/refresh")
-            .context("refresh url")?;
-        let request = self
-            .client
-            .post(refresh_url)
-            .bearer_auth(&refresh)
-            .header("Content-Type", "application/json")
-            .body("{}")
-            .build()?;
-        let response = self.client.execute(request).await?;
-        if !response.status().is_success() {
-            log::warn!("refresh failed: {}", response.status());
-            if response.status() == reqwest::StatusCode::UNAUTHORIZED {
-                let _ = self.token_store.clear();
+        let fits = merged.sessions.len() <= DEFAULT_CHUNK_SESSION_LIMIT
+            && merged
+                .to_json_string()
+                .is_ok_and(|json| json.len() <= DEFAULT_CHUNK_BYTE_LIMIT);
+        if !fits {
+            break;
+        }
+        best = count;
+    }
+    best
+}
+
+/// The batch sent for `guard`: its only batch, or its batches merged. The
+/// merged id derives from the source ids, so a resumed upload reuses it.
+fn upload_unit(guard: &UploadGuard) -> Result<UsageBatch> {
+    let batches = guard.batches();
+    if batches.len() > 1 {
+        log::info!("coalesced {} queued batches into one upload", batches.len());
+    }
+    UsageBatch::merge(batches).ok_or_else(|| anyhow!("batches in one upload differ in device"))
+}
+
+/// Writes every pending chunk to `dir` instead of POSTing it, then treats
+/// the batches as uploaded.
+fn dry_run_pending(store: &UsageBatchStore, dir: &Path) -> Result<UploadResult> {
+    fs::create_dir_all(dir).context("create dry-run directory")?;
+    let mut uploaded = 0usize;
+    while let Some(guard) = begin_upload(store)? {
+        let batch = upload_unit(&guard)?;
+        let chunks = batch
+            .chunked_for_wire(
+                DEFAULT_CHUNK_SESSION_LIMIT,
+                DEFAULT_CHUNK_BYTE_LIMIT,
+                DEFAULT_CHUNK_WIRE_BYTE_LIMIT,
+            )
+            .context("failed to chunk batch")?;
+        let stamp = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        for chunk in &chunks {
+            let path = dir.join(format!(
+                "{stamp}-{}-{}.json",
+                batch.batch_id,
+                chunk.chunk_index.unwrap_or(0)
+            ));
+            fs::write(&path, serde_json::to_string_pretty(chunk)?)
+                .with_context(|| format!("write dry-run payload {}", path.display()))?;
+            log::info!("dry run: wrote {}", path.display());
+        }
+        store
+            .complete(&guard)
+            .context("remove batches after dry run")?;
+        uploaded += guard.batches().len();
+    }
+    Ok(UploadResult {
+        uploaded_batches: uploaded,
+        failure_reason: None,
+        circuit: CircuitState::Closed,
+        dead_lettered_batches: 0,
+    })
+}
+
+/// Parses a `Retry-After` header in either delta-seconds or HTTP-date form.
+fn parse_retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<StdDuration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(StdDuration::from_secs(seconds));
+    }
+    let at = DateTime::parse_from_rfc2822(value)
+        .ok()?
+        .with_timezone(&Utc);
+    Some((at - now).to_std().unwrap_or(StdDuration::ZERO))
+}
+
+/// Extracts the `{"rejected": [...]}` body the backend sends for client errors.
+fn parse_rejected(outcome: &RequestOutcome) -> Option<RejectedSessionsResponse> {
+    let status = outcome.status?;
+    if !(400..500).contains(&status) || matches!(status, 401 | 408 | 415 | 429) {
+        return None;
+    }
+    let parsed: RejectedSessionsResponse = serde_json::from_str(outcome.body.as_deref()?).ok()?;
+    if parsed.rejected.is_empty() {
+        None
+    } else {
+        Some(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use flate2::read::GzDecoder;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use parking_lot::Mutex;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::credentials::{self, CredentialBackendKind};
+    use crate::dpapi::DpapiScope;
+    use crate::events::AgentEvents;
+    use crate::models::{RegistrationOutcome, RetryPolicy, UsageSession};
+    use crate::storage::AuthAuditLog;
+
+    struct NoEvents;
+
+    impl AgentEvents for NoEvents {
+        fn upload_succeeded(&self, _: &UploadResult) {}
+        fn upload_failed(&self, _: &UploadResult) {}
+        fn queue_changed(&self, _: usize) {}
+        fn failure_alert(&self, _: &str) {}
+        fn pairing_result(&self, _: Result<RegistrationOutcome, String>) {}
+    }
+
+    /// A request as the mock server received it.
+    struct Received {
+        path: String,
+        /// Lowercase names, in the order sent.
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Received {
+        /// The value of a header that must not be repeated.
+        fn header(&self, name: &str) -> Option<&str> {
+            let mut values = self
+                .headers
+                .iter()
+                .filter(|(sent, _)| sent == name)
+                .map(|(_, value)| value.as_str());
+            let value = values.next();
+            assert_eq!(values.next(), None, "{name} sent more than once");
+            value
+        }
+
+        /// The JSON body, inflated when it was sent gzipped.
+        fn batch(&self) -> UsageBatch {
+            let mut json = Vec::new();
+            if self.header("content-encoding") == Some("gzip") {
+                GzDecoder::new(&self.body[..])
+                    .read_to_end(&mut json)
+                    .unwrap();
+            } else {
+                json.clone_from(&self.body);
             }
-            return Ok(false);
+            serde_json::from_slice(&json).unwrap()
+        }
+    }
+
+    type Requests = Arc<Mutex<Vec<Received>>>;
+
+    /// Answers every request with the status `respond` picks, given how many
+    /// requests came before it, and keeps the requests for inspection. A
+    /// successful token refresh returns the pair `access-2`/`refresh-2`.
+    async fn mock_server(
+        respond: impl Fn(usize, &Received) -> u16 + Send + 'static,
+    ) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut socket = BufReader::new(socket);
+                let Some(request) = read_request(&mut socket).await else {
+                    continue;
+                };
+                let status = respond(seen.lock().len(), &request);
+                let body = if request.path.ends_with("/devices/refresh") && status == 200 {
+                    r#"{"access_token":"access-2","refresh_token":"refresh-2","expires_in":3600}"#
+                } else {
+                    ""
+                };
+                seen.lock().push(request);
+                let response = format!(
+                    "HTTP/1.1 {status} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    async fn read_request(socket: &mut BufReader<TcpStream>) -> Option<Received> {
+        let mut line = String::new();
+        socket.read_line(&mut line).await.ok()?;
+        let path = line.split(' ').nth(1)?.to_string();
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            socket.read_line(&mut line).await.ok()?;
+            let Some((name, value)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let mut body = Vec::new();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(sent, _)| sent == name)
+                .map(|(_, value)| value.clone())
+        };
+        if header("transfer-encoding").as_deref() == Some("chunked") {
+            loop {
+                line.clear();
+                socket.read_line(&mut line).await.ok()?;
+                let size = usize::from_str_radix(line.trim_end(), 16).ok()?;
+                // Each chunk ends in CRLF, the last one too.
+                let mut chunk = vec![0; size + 2];
+                socket.read_exact(&mut chunk).await.ok()?;
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else if let Some(len) = header("content-length") {
+            body.resize(len.parse().ok()?, 0);
+            socket.read_exact(&mut body).await.ok()?;
         }
-        let body = response.text().await.unwrap_or_default();
-        let json: Value = serde_json::from_str(&body)?;
-        let access = json
-            .get("access_token")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow!("access_token missing"))?;
-        let refresh_token = json
-            .get("refresh_token")
-            .and_then(|v| v.as_str())
-            .unwrap_or(&refresh)
-            .to_string();
-        let expires = json
-            .get("expires_in")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(86_400);
-        self.token_store
-            .save_tokens(access.to_string(), refresh_token, expires, Utc::now())?;
-        Ok(true)
+        Some(Received {
+            path,
+            headers,
+            body,
+        })
+    }
+
+    /// The files of one agent install, uploading to `api_base` with a valid
+    /// device token and without retries.
+    struct Agent {
+        paths: StoragePaths,
+        config_store: Arc<UsageConfigStore>,
+        token_store: Arc<TokenStore>,
+    }
+
+    impl Agent {
+        fn new(api_base: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("nuscape-uploader-{}", Uuid::new_v4()));
+            let paths = StoragePaths::with_root(root).unwrap();
+            let config_store = Arc::new(UsageConfigStore::new(&paths).unwrap());
+            config_store.set_api_base(api_base).unwrap();
+            config_store
+                .set_retry_policy(RetryPolicy {
+                    max_attempts: 1,
+                    initial_backoff_ms: 10,
+                    max_backoff_ms: 10,
+                })
+                .unwrap();
+            let token_store = Arc::new(TokenStore::new(
+                credentials::open(CredentialBackendKind::File, &paths, DpapiScope::default()),
+                DpapiScope::default(),
+                Arc::new(AuthAuditLog::new(&paths)),
+            ));
+            token_store
+                .save_tokens(
+                    "access".to_string(),
+                    "refresh".to_string(),
+                    Some(3600),
+                    Utc::now(),
+                )
+                .unwrap();
+            Self {
+                paths,
+                config_store,
+                token_store,
+            }
+        }
+
+        /// Opens the queue and an uploader as a starting agent would.
+        fn start(&self) -> (Arc<UsageBatchStore>, UsageUploader) {
+            let batch_store =
+                Arc::new(UsageBatchStore::new(&self.paths, Arc::new(NoEvents)).unwrap());
+            let clock = Arc::new(ClockSkew::new());
+            let refresher = Arc::new(
+                TokenRefresher::new(
+                    self.config_store.clone(),
+                    self.token_store.clone(),
+                    clock.clone(),
+                )
+                .unwrap(),
+            );
+            let uploader = UsageUploader::new(
+                &self.paths,
+                self.config_store.clone(),
+                self.token_store.clone(),
+                batch_store.clone(),
+                Arc::new(AgentMetrics::new()),
+                clock,
+                refresher,
+            )
+            .unwrap();
+            (batch_store, uploader)
+        }
+    }
+
+    fn batch(sessions: usize) -> UsageBatch {
+        let end = Utc::now();
+        UsageBatch {
+            batch_id: Uuid::new_v4(),
+            device_id: Uuid::new_v4(),
+            sent_at: end,
+            sessions: (0..sessions)
+                .map(|index| UsageSession {
+                    package: format!("app-{index}.exe"),
+                    app_id: None,
+                    args_hint: None,
+                    title: None,
+                    engagement: None,
+                    domain: None,
+                    fullscreen: None,
+                    monitor_index: None,
+                    media: None,
+                    remote: None,
+                    user: None,
+                    window_start: end - chrono::Duration::minutes(5),
+                    window_end: end,
+                    total_ms: 300_000,
+                    foreground: false,
+                })
+                .collect(),
+            network_deltas: Vec::new(),
+            dns_events: Vec::new(),
+            status: None,
+            chunk_index: None,
+            chunk_total: None,
+        }
+    }
+
+    /// The chunks `batch` goes out as.
+    fn wire_chunks(batch: &UsageBatch) -> Vec<serde_json::Value> {
+        batch
+            .chunked_for_wire(
+                DEFAULT_CHUNK_SESSION_LIMIT,
+                DEFAULT_CHUNK_BYTE_LIMIT,
+                DEFAULT_CHUNK_WIRE_BYTE_LIMIT,
+            )
+            .unwrap()
+            .iter()
+            .map(|chunk| serde_json::to_value(chunk).unwrap())
+            .collect()
+    }
+
+    fn sent(request: &Received) -> serde_json::Value {
+        serde_json::to_value(request.batch()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunks_are_sent_gzipped() {
+        let (url, requests) = mock_server(|_, _| 200).await;
+        let agent = Agent::new(&url);
+        let (store, uploader) = agent.start();
+        let queued = batch(3);
+        store.enqueue(queued.clone(), 0, true).unwrap();
+
+        let result = uploader.upload_pending().await.unwrap();
+        assert_eq!(result.uploaded_batches, 1);
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/api/v1/usage/batch");
+        assert_eq!(requests[0].header("content-encoding"), Some("gzip"));
+        assert_eq!(requests[0].header("content-type"), Some("application/json"));
+        assert_eq!(vec![sent(&requests[0])], wire_chunks(&queued));
+    }
+
+    #[tokio::test]
+    async fn a_415_falls_back_to_uncompressed_chunks() {
+        let (url, requests) = mock_server(|_, request| {
+            if request.header("content-encoding").is_some() {
+                415
+            } else {
+                200
+            }
+        })
+        .await;
+        let agent = Agent::new(&url);
+        let (store, uploader) = agent.start();
+        let first = batch(2);
+        store.enqueue(first.clone(), 0, true).unwrap();
+        assert_eq!(uploader.upload_pending().await.unwrap().uploaded_batches, 1);
+        // Later uploads stay uncompressed.
+        store.enqueue(batch(1), 0, true).unwrap();
+        assert_eq!(uploader.upload_pending().await.unwrap().uploaded_batches, 1);
+
+        let requests = requests.lock();
+        let encodings: Vec<_> = requests
+            .iter()
+            .map(|request| request.header("content-encoding"))
+            .collect();
+        assert_eq!(encodings, [Some("gzip"), None, None]);
+        assert_eq!(sent(&requests[0]), sent(&requests[1]));
+        assert_eq!(vec![sent(&requests[1])], wire_chunks(&first));
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_a_date() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:26:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let retry_after = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            parse_retry_after(&headers, now)
+        };
+        assert_eq!(retry_after("120"), Some(StdDuration::from_secs(120)));
+        assert_eq!(retry_after(" 0 "), Some(StdDuration::ZERO));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(StdDuration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:20:00 GMT"),
+            Some(StdDuration::ZERO)
+        );
+        assert_eq!(retry_after("-5"), None);
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(parse_retry_after(&HeaderMap::new(), now), None);
+    }
+
+    #[tokio::test]
+    async fn a_streamed_batch_is_signed_over_the_body_sent() {
+        let secret = b"signing-secret";
+        let (url, requests) = mock_server(|_, _| 200).await;
+        let agent = Agent::new(&url);
+        agent.config_store.set_ndjson_uploads(true).unwrap();
+        agent.token_store.save_signing_secret(secret).unwrap();
+        let (store, uploader) = agent.start();
+        store.enqueue(batch(3), 0, true).unwrap();
+
+        assert_eq!(uploader.upload_pending().await.unwrap().uploaded_batches, 1);
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.header("content-type"), Some(NDJSON_CONTENT_TYPE));
+        assert_eq!(request.header("transfer-encoding"), Some("chunked"));
+        let timestamp = request.header("x-nuscape-timestamp").unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(&request.body);
+        let expected = BASE64.encode(mac.finalize().into_bytes());
+        assert_eq!(
+            request.header("x-nuscape-signature"),
+            Some(expected.as_str())
+        );
+    }
+
+    /// Uploads one batch to a server that always answers 502, allowing
+    /// `max_attempts`, and returns how many requests it saw.
+    async fn attempts_against_a_failing_server(max_attempts: u32) -> usize {
+        let (url, requests) = mock_server(|_, _| 502).await;
+        let agent = Agent::new(&url);
+        agent
+            .config_store
+            .set_retry_policy(RetryPolicy {
+                max_attempts,
+                initial_backoff_ms: 10,
+                max_backoff_ms: 20,
+            })
+            .unwrap();
+        let (store, uploader) = agent.start();
+        store.enqueue(batch(1), 0, true).unwrap();
+
+        let result = uploader.upload_pending().await.unwrap();
+        assert_eq!(result.uploaded_batches, 0);
+        assert!(matches!(
+            result.failure_reason,
+            Some(UploadFailureReason::NetworkError)
+        ));
+        assert_eq!(store.queue_size(), 1);
+        let seen = requests.lock().len();
+        seen
+    }
+
+    #[tokio::test]
+    async fn a_single_attempt_is_not_retried() {
+        assert_eq!(attempts_against_a_failing_server(1).await, 1);
+    }
+
+    #[tokio::test]
+    async fn every_allowed_attempt_is_sent() {
+        assert_eq!(attempts_against_a_failing_server(5).await, 5);
+    }
+
+    #[tokio::test]
+    async fn a_restart_resumes_at_the_chunk_that_failed() {
+        // Chunk 1 of the first attempt fails, everything else succeeds.
+        let (url, requests) = mock_server(|index, _| if index == 1 { 502 } else { 200 }).await;
+        let agent = Agent::new(&url);
+        // Two sessions fill a chunk.
+        let mut queued = batch(5);
+        for session in &mut queued.sessions {
+            session.package = format!("{}-{}.exe", session.package, "x".repeat(40_000));
+        }
+        assert_eq!(wire_chunks(&queued).len(), 3);
+        {
+            let (store, uploader) = agent.start();
+            store.enqueue(queued.clone(), 0, true).unwrap();
+            let result = uploader.upload_pending().await.unwrap();
+            assert!(result.failure_reason.is_some());
+        }
+        // Past the backoff the failure persisted for the next start.
+        sleep(StdDuration::from_millis(100)).await;
+
+        let (store, uploader) = agent.start();
+        let result = uploader.upload_pending().await.unwrap();
+        assert!(result.failure_reason.is_none());
+        assert_eq!(store.queue_size(), 0);
+        let requests = requests.lock();
+        let sent: Vec<_> = requests.iter().map(sent).collect();
+        let chunks = wire_chunks(&queued);
+        let expected = [0, 1, 1, 2].map(|index| chunks[index].clone());
+        assert_eq!(sent, expected);
+    }
+
+    #[tokio::test]
+    async fn a_failed_coalesced_upload_is_retried_as_the_same_set() {
+        let (url, requests) = mock_server(|index, _| if index == 0 { 502 } else { 200 }).await;
+        let agent = Agent::new(&url);
+        let (store, uploader) = agent.start();
+        let mut queued = vec![batch(1), batch(1)];
+        let device_id = queued[0].device_id;
+        for batch in &mut queued {
+            batch.device_id = device_id;
+            store.enqueue(batch.clone(), 0, true).unwrap();
+        }
+        assert!(uploader
+            .upload_pending()
+            .await
+            .unwrap()
+            .failure_reason
+            .is_some());
+
+        // A newer batch arrives before the retry.
+        let mut newer = batch(1);
+        newer.device_id = device_id;
+        store.enqueue(newer.clone(), 0, true).unwrap();
+        let result = uploader.upload_pending().await.unwrap();
+        assert_eq!(result.uploaded_batches, 3);
+        assert_eq!(store.queue_size(), 0);
+
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(sent(&requests[1]), sent(&requests[0]));
+        assert_eq!(
+            requests[1].header("idempotency-key"),
+            requests[0].header("idempotency-key")
+        );
+        assert_eq!(
+            sent(&requests[0]),
+            wire_chunks(&UsageBatch::merge(&queued).unwrap())[0]
+        );
+        assert_eq!(vec![sent(&requests[2])], wire_chunks(&newer));
+    }
+
+    #[tokio::test]
+    async fn custom_headers_go_out_without_replacing_reserved_ones() {
+        let (url, requests) = mock_server(|_, _| 200).await;
+        let mut agent = Agent::new(&url);
+        // Edited by hand; the settings command refuses reserved names.
+        let config_path = agent.paths.config_path();
+        let mut config: serde_json::Value =
+            serde_json::from_slice(&fs::read(&config_path).unwrap()).unwrap();
+        config["data"]["profiles"]["default"]["custom_headers"] = serde_json::json!({
+            "X-Org-Id": "acme",
+            "Authorization": "Bearer forged",
+            "Content-Type": "text/plain",
+        });
+        fs::write(&config_path, config.to_string()).unwrap();
+        agent.config_store = Arc::new(UsageConfigStore::new(&agent.paths).unwrap());
+        // Expired, so the upload refreshes it first.
+        agent
+            .token_store
+            .save_tokens(
+                "access".to_string(),
+                "refresh".to_string(),
+                Some(60),
+                Utc::now() - chrono::Duration::hours(1),
+            )
+            .unwrap();
+        let (store, uploader) = agent.start();
+        store.enqueue(batch(1), 0, true).unwrap();
+
+        assert_eq!(uploader.upload_pending().await.unwrap().uploaded_batches, 1);
+        let requests = requests.lock();
+        let paths: Vec<_> = requests
+            .iter()
+            .map(|request| request.path.as_str())
+            .collect();
+        assert_eq!(paths, ["/api/v1/devices/refresh", "/api/v1/usage/batch"]);
+        for request in requests.iter() {
+            assert_eq!(request.header("x-org-id"), Some("acme"));
+            assert_eq!(request.header("content-type"), Some("application/json"));
+        }
+        assert_eq!(requests[0].header("authorization"), Some("Bearer refresh"));
+        assert_eq!(requests[1].header("authorization"), Some("Bearer access-2"));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_coalesced_upload_is_retried_batch_by_batch() {
+        let (url, requests) = mock_server(|_, request| {
+            let sessions = request.batch().sessions;
+            if sessions.iter().any(|session| session.package == "bad.exe") {
+                400
+            } else {
+                200
+            }
+        })
+        .await;
+        let agent = Agent::new(&url);
+        let (store, uploader) = agent.start();
+        let good = batch(1);
+        let mut bad = batch(1);
+        bad.device_id = good.device_id;
+        bad.sessions[0].package = "bad.exe".to_string();
+        store.enqueue(good.clone(), 0, true).unwrap();
+        store.enqueue(bad.clone(), 0, true).unwrap();
+
+        let result = uploader.upload_pending().await.unwrap();
+        assert_eq!(result.uploaded_batches, 1);
+        assert!(matches!(
+            result.failure_reason,
+            Some(UploadFailureReason::ServerError)
+        ));
+        assert_eq!(store.queue_preview(1)[0].batch_id, bad.batch_id);
+        let requests = requests.lock();
+        let sent: Vec<_> = requests.iter().map(sent).collect();
+        let merged = UsageBatch::merge(&[good.clone(), bad.clone()]).unwrap();
+        assert_eq!(
+            sent,
+            [
+                wire_chunks(&merged)[0].clone(),
+                wire_chunks(&good)[0].clone(),
+                wire_chunks(&bad)[0].clone(),
+            ]
+        );
     }
 }